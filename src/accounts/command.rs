@@ -0,0 +1,80 @@
+use std::io::{self, Write};
+use std::process::{Child, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use super::{AuthProvider, Verified};
+use crate::scan::shell;
+
+/// Checks taking longer are failed, the request waits for them
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `--auth-command`: a command given the user name and the password on two lines of its
+/// standard input (as `pwauth` and Apache's `mod_authnz_external` do) and exiting with 0
+/// for right credentials. Nothing is passed in its arguments nor environment, where other
+/// processes could read the password.
+pub struct CommandAccounts {
+    command: String,
+    verified: Verified,
+}
+
+impl CommandAccounts {
+    pub fn new(command: &str) -> CommandAccounts {
+        CommandAccounts {
+            command: command.to_owned(),
+            verified: Verified::default(),
+        }
+    }
+
+    fn run(&self, username: &str, password: &str) -> io::Result<bool> {
+        let mut child = shell(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        let written = child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(format!("{}\n{}\n", username, password).as_bytes());
+        // A command not reading its input is judged by its exit status alone
+        if let Err(err) = written {
+            if err.kind() != io::ErrorKind::BrokenPipe {
+                kill(&mut child);
+                return Err(err);
+            }
+        }
+        let started = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status.success());
+            }
+            if started.elapsed() > COMMAND_TIMEOUT {
+                kill(&mut child);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+impl AuthProvider for CommandAccounts {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        // The user name is a line of the input
+        if username.contains('\n') || password.contains('\n') || password.is_empty() {
+            return false;
+        }
+        self.verified.check(username, password, || {
+            self.run(username, password).unwrap_or_else(|err| {
+                warn!("Auth command failed: {}", err);
+                false
+            })
+        })
+    }
+}
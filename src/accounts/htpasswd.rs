@@ -0,0 +1,183 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use argon2::password_hash::{self, PasswordHash, PasswordVerifier};
+use argon2::Argon2;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use super::AuthProvider;
+
+struct Accounts {
+    modified: Option<SystemTime>,
+    /// Password hash of each user
+    hashes: HashMap<String, String>,
+    /// Digests of the `user`, hash and password combinations found right, not to pay for a
+    /// slow hash on each request of a session
+    verified: HashSet<[u8; 32]>,
+}
+
+/// `--auth-file`: the accounts of an htpasswd file, `<user>:<hash>` lines with bcrypt
+/// (`htpasswd -B`) or Argon2 hashes. Reloaded when it changes, so that accounts are added
+/// and removed without restarting.
+pub struct Htpasswd {
+    path: PathBuf,
+    accounts: Mutex<Accounts>,
+}
+
+impl Htpasswd {
+    pub fn load(path: PathBuf) -> io::Result<Htpasswd> {
+        let modified = modified(&path);
+        let hashes = load_hashes(&path)?;
+        Ok(Htpasswd {
+            path,
+            accounts: Mutex::new(Accounts {
+                modified,
+                hashes,
+                verified: HashSet::new(),
+            }),
+        })
+    }
+}
+
+impl AuthProvider for Htpasswd {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        let (hash, digest) = {
+            let mut accounts = self.accounts.lock().unwrap();
+            let modified = modified(&self.path);
+            if modified != accounts.modified {
+                // Removed accounts must not survive an unreadable file
+                let hashes = load_hashes(&self.path).unwrap_or_else(|err| {
+                    warn!("Can not reload {}: {}", self.path.display(), err);
+                    HashMap::new()
+                });
+                *accounts = Accounts {
+                    modified,
+                    hashes,
+                    verified: HashSet::new(),
+                };
+            }
+            let hash = match accounts.hashes.get(username) {
+                Some(hash) => hash.clone(),
+                None => return false,
+            };
+            let digest: [u8; 32] = Sha256::new()
+                .chain_update(username)
+                .chain_update([0])
+                .chain_update(&hash)
+                .chain_update([0])
+                .chain_update(password)
+                .finalize()
+                .into();
+            if accounts.verified.contains(&digest) {
+                return true;
+            }
+            (hash, digest)
+        };
+        // Not holding the lock, the hashes are slow on purpose
+        match verify_hash(&hash, password) {
+            Some(true) => {
+                self.accounts.lock().unwrap().verified.insert(digest);
+                true
+            }
+            Some(false) => false,
+            None => {
+                warn!(
+                    "Invalid password hash of {} in {}",
+                    username,
+                    self.path.display()
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Whether `password` hashes to `hash`, `None` when `hash` is not a valid hash
+fn verify_hash(hash: &str, password: &str) -> Option<bool> {
+    if hash.starts_with("$argon2") {
+        // With the variant, version and costs of the hash
+        let hash = PasswordHash::new(hash)
+            .ok()
+            .filter(|hash| hash.hash.is_some())?;
+        match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => Some(true),
+            Err(password_hash::Error::Password) => Some(false),
+            Err(_) => None,
+        }
+    } else {
+        bcrypt::verify(password, hash).ok()
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn load_hashes(path: &Path) -> io::Result<HashMap<String, String>> {
+    let mut hashes = HashMap::new();
+    for line in BufReader::new(fs::File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (username, hash) = match line.split_once(':') {
+            Some(account) => account,
+            None => continue,
+        };
+        if !["$2a$", "$2b$", "$2y$", "$argon2"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            // MD5 (`$apr1$`), SHA-1 and crypt hashes are too weak, plain text even more so
+            warn!(
+                "Skipping {} in {}: only bcrypt and Argon2 password hashes are supported",
+                username,
+                path.display()
+            );
+            continue;
+        }
+        hashes.insert(username.to_owned(), hash.to_owned());
+    }
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `htpasswd -B` style hashes, from libxcrypt
+    const BCRYPT_2A: &str = "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW";
+    const BCRYPT_2Y: &str = "$2y$05$abcdefghijklmnopqrstuuOQiyCxlgf/oeuTqixKmWdcYUh4Hjl0a";
+    // From the test vectors of the Argon2 reference implementation
+    const ARGON2I: &str =
+        "$argon2i$v=19$m=65536,t=2,p=1$c29tZXNhbHQ$wWKIMhR9lyDFvRz9YTZweHKfbftvj+qf+YFY4NeBbtA";
+    const ARGON2ID: &str =
+        "$argon2id$v=19$m=65536,t=2,p=1$c29tZXNhbHQ$CTFhFdXPJO1aFaMaO6Mm5c8y7cJHAph8ArZWb2GRPPc";
+
+    #[test]
+    fn bcrypt_hashes() {
+        assert_eq!(verify_hash(BCRYPT_2A, "U*U"), Some(true));
+        assert_eq!(verify_hash(BCRYPT_2A, "U*V"), Some(false));
+        assert_eq!(verify_hash(BCRYPT_2Y, "secret"), Some(true));
+        assert_eq!(verify_hash(BCRYPT_2Y, "Secret"), Some(false));
+        assert_eq!(verify_hash(BCRYPT_2Y, ""), Some(false));
+        assert_eq!(verify_hash("$2y$05$tooshort", "secret"), None);
+    }
+
+    #[test]
+    fn argon2_hashes() {
+        assert_eq!(verify_hash(ARGON2I, "password"), Some(true));
+        assert_eq!(verify_hash(ARGON2I, "passwore"), Some(false));
+        assert_eq!(verify_hash(ARGON2ID, "password"), Some(true));
+        assert_eq!(verify_hash(ARGON2ID, "Password"), Some(false));
+        assert_eq!(verify_hash("$argon2id$v=19$m=65536", "password"), None);
+    }
+}
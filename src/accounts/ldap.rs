@@ -0,0 +1,223 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use openssl::ssl::{SslConnector, SslMethod};
+use tracing::warn;
+use url::Url;
+
+use super::{AuthProvider, Verified};
+
+/// Connection, and each read or write, to the server
+const LDAP_TIMEOUT: Duration = Duration::from_secs(5);
+/// `invalidCredentials`, the other result codes are server side errors
+const INVALID_CREDENTIALS: u8 = 49;
+
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// `--auth-ldap`: a simple bind as the DN of the user (`--auth-ldap-dn`, its `{user}`
+/// replaced), over `ldaps://` or a clear `ldap://` connection. One connection per check,
+/// the successful ones are remembered for a while.
+pub struct LdapAccounts {
+    host: String,
+    port: u16,
+    tls: bool,
+    dn_template: String,
+    verified: Verified,
+}
+
+impl LdapAccounts {
+    pub fn new(url: &str, dn_template: &str) -> Result<LdapAccounts, String> {
+        let invalid = || format!("invalid LDAP URL: {}", url);
+        let parsed = Url::parse(url).map_err(|_| invalid())?;
+        let tls = match parsed.scheme() {
+            "ldap" => false,
+            "ldaps" => true,
+            _ => return Err(invalid()),
+        };
+        let host = parsed.host_str().ok_or_else(invalid)?.to_owned();
+        if !dn_template.contains("{user}") {
+            return Err("the LDAP DN template has no {user}".to_owned());
+        }
+        Ok(LdapAccounts {
+            port: parsed.port().unwrap_or(if tls { 636 } else { 389 }),
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_owned(),
+            tls,
+            dn_template: dn_template.to_owned(),
+            verified: Verified::default(),
+        })
+    }
+
+    fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+        let stream = TcpStream::connect_timeout(&addr, LDAP_TIMEOUT)?;
+        stream.set_read_timeout(Some(LDAP_TIMEOUT))?;
+        stream.set_write_timeout(Some(LDAP_TIMEOUT))?;
+        if !self.tls {
+            return Ok(Box::new(stream));
+        }
+        let connector = SslConnector::builder(SslMethod::tls())
+            .map_err(io::Error::other)?
+            .build();
+        let stream = connector
+            .connect(&self.host, stream)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(Box::new(stream))
+    }
+
+    /// The result code of a simple bind
+    fn bind(&self, dn: &str, password: &str) -> io::Result<u8> {
+        let mut stream = self.connect()?;
+        let bind = ber(
+            0x60,
+            &[
+                ber(0x02, &[3]),
+                ber(0x04, dn.as_bytes()),
+                ber(0x80, password.as_bytes()),
+            ]
+            .concat(),
+        );
+        stream.write_all(&ber(0x30, &[ber(0x02, &[1]), bind].concat()))?;
+        let response = read_ber(&mut stream)?;
+        // Unbind, the server closes the connection
+        let _ = stream.write_all(&[0x30, 0x05, 0x02, 0x01, 0x02, 0x42, 0x00]);
+        bind_result(&response)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad bind response"))
+    }
+}
+
+/// A BER element, definite length
+fn ber(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(value);
+    out
+}
+
+/// `(tag, value, rest)` of the BER element at the start of `data`
+fn parse_ber(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&first, mut data) = data.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || data.len() < count {
+            return None;
+        }
+        let len = data[..count]
+            .iter()
+            .fold(0usize, |len, b| len << 8 | *b as usize);
+        data = &data[count..];
+        len
+    };
+    (data.len() >= len).then(|| (tag, &data[..len], &data[len..]))
+}
+
+/// One LDAP message from the server, whole
+fn read_ber(stream: &mut dyn Stream) -> io::Result<Vec<u8>> {
+    let mut header = [0; 2];
+    stream.read_exact(&mut header)?;
+    let mut message = header.to_vec();
+    let len = if header[1] < 0x80 {
+        header[1] as usize
+    } else {
+        let count = (header[1] & 0x7f) as usize;
+        if count == 0 || count > 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad length"));
+        }
+        let mut bytes = vec![0; count];
+        stream.read_exact(&mut bytes)?;
+        message.extend_from_slice(&bytes);
+        bytes.iter().fold(0usize, |len, b| len << 8 | *b as usize)
+    };
+    // Bind responses are small, more is not one
+    if len > 64 * 1024 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "response too long",
+        ));
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+    message.extend_from_slice(&body);
+    Ok(message)
+}
+
+/// The `resultCode` of a `BindResponse` message
+fn bind_result(message: &[u8]) -> Option<u8> {
+    let (0x30, message, _) = parse_ber(message)? else {
+        return None;
+    };
+    let (0x02, _, rest) = parse_ber(message)? else {
+        return None;
+    };
+    let (0x61, response, _) = parse_ber(rest)? else {
+        return None;
+    };
+    match parse_ber(response)? {
+        (0x0a, &[code], _) => Some(code),
+        _ => None,
+    }
+}
+
+/// Escape a DN attribute value (RFC 4514), so that a user name can not name another entry
+fn escape_dn(value: &str) -> String {
+    let mut escaped = String::new();
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\0' => escaped.push_str("\\00"),
+            ' ' | '#' if i == 0 => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            ' ' if i == last => escaped.push_str("\\ "),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl AuthProvider for LdapAccounts {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        // A bind without password is an unauthenticated bind, which servers accept
+        if username.is_empty() || password.is_empty() {
+            return false;
+        }
+        let dn = self.dn_template.replace("{user}", &escape_dn(username));
+        self.verified
+            .check(username, password, || match self.bind(&dn, password) {
+                Ok(0) => true,
+                Ok(INVALID_CREDENTIALS) => false,
+                Ok(code) => {
+                    warn!("LDAP bind of {} failed: result code {}", dn, code);
+                    false
+                }
+                Err(err) => {
+                    warn!("LDAP bind of {} failed: {}", dn, err);
+                    false
+                }
+            })
+    }
+}
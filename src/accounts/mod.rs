@@ -0,0 +1,100 @@
+mod command;
+mod htpasswd;
+#[cfg(feature = "ldap")]
+mod ldap;
+#[cfg(unix)]
+mod pam;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use crate::util::{constant_time_eq, StringError};
+
+pub use self::command::CommandAccounts;
+pub use self::htpasswd::Htpasswd;
+#[cfg(feature = "ldap")]
+pub use self::ldap::LdapAccounts;
+#[cfg(unix)]
+pub use self::pam::PamAccounts;
+
+/// Successful checks are trusted this long by the providers paying a process, a PAM stack
+/// or a round trip to a server per check, so a password change or a lock out only applies
+/// after it
+const VERIFIED_TTL: Duration = Duration::from_secs(60);
+
+/// Where the `--auth*` credentials are checked. The site specific ones (eg: a single sign-on
+/// service) can be plugged with `--auth-command` without changing the middleware.
+pub trait AuthProvider: Send + Sync {
+    /// Whether `password` is the one of `username`
+    fn verify(&self, username: &str, password: &str) -> bool;
+
+    /// The clear text password of `username`, which Digest authentication needs. Only known
+    /// to the `--auth` account, the other providers can only verify.
+    fn password(&self, _username: &str) -> Option<&str> {
+        None
+    }
+}
+
+/// `--auth username:password`
+pub struct StaticAccount {
+    username: String,
+    password: String,
+}
+
+impl StaticAccount {
+    pub fn parse(s: &str) -> Result<StaticAccount, StringError> {
+        match s.split_once(':') {
+            Some((username, password)) => Ok(StaticAccount {
+                username: username.to_owned(),
+                password: password.to_owned(),
+            }),
+            None => Err(StringError("not valid format user & password".to_owned())),
+        }
+    }
+}
+
+impl AuthProvider for StaticAccount {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        // Both compared in full, the time taken tells neither which nor where they differ
+        constant_time_eq(username.as_bytes(), self.username.as_bytes())
+            & constant_time_eq(password.as_bytes(), self.password.as_bytes())
+    }
+
+    fn password(&self, username: &str) -> Option<&str> {
+        Some(self.password.as_str()).filter(|_| username == self.username)
+    }
+}
+
+/// The credentials found right in the last `VERIFIED_TTL`, by digest
+#[derive(Default)]
+struct Verified {
+    entries: Mutex<HashMap<[u8; 32], Instant>>,
+}
+
+impl Verified {
+    /// Whether the credentials are right, asking `verify` unless they were recently
+    fn check(&self, username: &str, password: &str, verify: impl FnOnce() -> bool) -> bool {
+        let digest: [u8; 32] = Sha256::new()
+            .chain_update(username)
+            .chain_update([0])
+            .chain_update(password)
+            .finalize()
+            .into();
+        {
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|_, verified| verified.elapsed() < VERIFIED_TTL);
+            if entries.contains_key(&digest) {
+                return true;
+            }
+        }
+        // Not holding the lock, a check can take seconds (PAM delays the failures)
+        if !verify() {
+            return false;
+        }
+        self.entries.lock().unwrap().insert(digest, Instant::now());
+        true
+    }
+}
@@ -0,0 +1,166 @@
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::ptr;
+
+use libc::{c_char, c_int, c_void};
+use tracing::warn;
+
+use super::{AuthProvider, Verified};
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_CONV_ERR: c_int = 19;
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+const PAM_SILENT: c_int = 0x8000;
+const PAM_DISALLOW_NULL_AUTHTOK: c_int = 0x0001;
+
+/// Names of the library, it is loaded at startup rather than linked so that the builds
+/// do not need its development files and run where it is missing
+const LIBRARIES: &[&[u8]] = &[b"libpam.so.0\0", b"libpam.so\0", b"libpam.dylib\0"];
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+#[repr(C)]
+struct PamConv {
+    conv: PamConvFn,
+    appdata_ptr: *mut c_void,
+}
+
+type PamConvFn =
+    extern "C" fn(c_int, *mut *const PamMessage, *mut *mut PamResponse, *mut c_void) -> c_int;
+type PamStart =
+    unsafe extern "C" fn(*const c_char, *const c_char, *const PamConv, *mut *mut c_void) -> c_int;
+type PamCall = unsafe extern "C" fn(*mut c_void, c_int) -> c_int;
+
+/// `--auth-pam`: the accounts of the system (or whatever the PAM stack of the service
+/// checks), authenticated and their account checked (expired, locked...) with the
+/// service's `auth` and `account` modules. Checking other users' passwords with
+/// `pam_unix` needs root.
+pub struct PamAccounts {
+    service: CString,
+    start: PamStart,
+    authenticate: PamCall,
+    acct_mgmt: PamCall,
+    end: PamCall,
+    verified: Verified,
+}
+
+impl PamAccounts {
+    pub fn new(service: &str) -> Result<PamAccounts, String> {
+        let service = CString::new(service).map_err(|_| "invalid PAM service".to_owned())?;
+        // Never closed, the library stays loaded for the life of the process
+        let library = LIBRARIES
+            .iter()
+            .map(|name| name.as_ptr() as *const c_char)
+            .map(|name| unsafe { libc::dlopen(name, libc::RTLD_NOW) })
+            .find(|library| !library.is_null())
+            .ok_or_else(|| "can not load libpam".to_owned())?;
+        let symbol = |name: &str| {
+            let c_name = CString::new(name).unwrap();
+            let symbol = unsafe { libc::dlsym(library, c_name.as_ptr()) };
+            if symbol.is_null() {
+                Err(format!("libpam has no {}", name))
+            } else {
+                Ok(symbol)
+            }
+        };
+        // `pam_unix` checks the others' passwords in /etc/shadow, only readable by root
+        if unsafe { libc::geteuid() } != 0 {
+            warn!(
+                "Not running as root, PAM service {} may only accept the password of the user running the server",
+                service.to_string_lossy()
+            );
+        }
+        let call = |name| {
+            symbol(name).map(|symbol| unsafe { mem::transmute::<*mut c_void, PamCall>(symbol) })
+        };
+        Ok(PamAccounts {
+            service,
+            start: unsafe { mem::transmute::<*mut c_void, PamStart>(symbol("pam_start")?) },
+            authenticate: call("pam_authenticate")?,
+            acct_mgmt: call("pam_acct_mgmt")?,
+            end: call("pam_end")?,
+            verified: Verified::default(),
+        })
+    }
+
+    fn authenticate(&self, username: &CStr, password: &CStr) -> bool {
+        let credentials = (username, password);
+        let conv = PamConv {
+            conv: converse,
+            appdata_ptr: &credentials as *const (&CStr, &CStr) as *mut c_void,
+        };
+        let mut handle = ptr::null_mut();
+        unsafe {
+            let mut status =
+                (self.start)(self.service.as_ptr(), username.as_ptr(), &conv, &mut handle);
+            if status != PAM_SUCCESS {
+                return false;
+            }
+            status = (self.authenticate)(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK);
+            if status == PAM_SUCCESS {
+                status = (self.acct_mgmt)(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK);
+            }
+            (self.end)(handle, status);
+            status == PAM_SUCCESS
+        }
+    }
+}
+
+/// Answers the password prompts with the password and the others with the user name, the
+/// responses are freed by PAM
+extern "C" fn converse(
+    count: c_int,
+    messages: *mut *const PamMessage,
+    responses: *mut *mut PamResponse,
+    credentials: *mut c_void,
+) -> c_int {
+    if count <= 0 {
+        return PAM_CONV_ERR;
+    }
+    unsafe {
+        let (username, password) = *(credentials as *const (&CStr, &CStr));
+        let replies =
+            libc::calloc(count as usize, mem::size_of::<PamResponse>()) as *mut PamResponse;
+        if replies.is_null() {
+            return PAM_BUF_ERR;
+        }
+        for i in 0..count as usize {
+            let message = &**messages.add(i);
+            let reply = match message.msg_style {
+                PAM_PROMPT_ECHO_OFF => libc::strdup(password.as_ptr()),
+                PAM_PROMPT_ECHO_ON => libc::strdup(username.as_ptr()),
+                // Informational and error messages need no answer
+                _ => ptr::null_mut(),
+            };
+            (*replies.add(i)).resp = reply;
+        }
+        *responses = replies;
+    }
+    PAM_SUCCESS
+}
+
+impl AuthProvider for PamAccounts {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        let (username, password) = match (CString::new(username), CString::new(password)) {
+            (Ok(username), Ok(password)) if !password.is_empty() => (username, password),
+            _ => return false,
+        };
+        self.verified.check(
+            &username.to_string_lossy(),
+            &password.to_string_lossy(),
+            || self.authenticate(&username, &password),
+        )
+    }
+}
@@ -0,0 +1,597 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use hyper::client::Client;
+use hyper::header::{Headers, Host, Location};
+use hyper::net::{Fresh, HttpStream, HttpsConnector, SslServer};
+use hyper::server::{Request as HttpRequest, Response as HttpResponse, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use hyper_native_tls::{NativeTlsClient, NativeTlsServer, TlsStream};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509ReqBuilder, X509};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::util::json_escape;
+
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+/// Path of the HTTP-01 challenge responses, followed by the token
+const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+/// Certificates expiring sooner are renewed (Let's Encrypt issues them for 90 days)
+const RENEW_BEFORE_DAYS: i32 = 30;
+/// How often the expiry is checked, and a failed renewal tried again
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+/// Polls of a pending authorization or order before giving up
+const MAX_POLLS: u32 = 60;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+type Result<T> = std::result::Result<T, String>;
+
+fn err(err: impl std::fmt::Display) -> String {
+    err.to_string()
+}
+
+/// Key authorizations of the pending HTTP-01 challenges, by token
+type Challenges = Arc<RwLock<HashMap<String, String>>>;
+
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    pub email: Option<String>,
+    /// URL of the directory of the ACME server
+    pub directory: String,
+    /// Existing directory keeping the account key, the certificate and its key
+    pub cache: PathBuf,
+}
+
+/// HTTPS with a certificate obtained from an ACME server (`--acme`), Let's Encrypt by
+/// default, with HTTP-01 challenges answered by a plain HTTP listener (on port 80, which
+/// redirects everything else to HTTPS). The certificate is kept in the cache directory and
+/// renewed in the background 30 days before it expires, new connections get the new one.
+#[derive(Clone)]
+pub struct AcmeServer {
+    current: Arc<RwLock<NativeTlsServer>>,
+}
+
+impl SslServer for AcmeServer {
+    type Stream = TlsStream<HttpStream>;
+
+    fn wrap_server(&self, stream: HttpStream) -> hyper::Result<Self::Stream> {
+        let server = self.current.read().unwrap().clone();
+        server.wrap_server(stream)
+    }
+}
+
+impl AcmeServer {
+    /// Answer the challenges on `http_addr`, then load the cached certificate or obtain one
+    pub fn start(config: AcmeConfig, http_addr: &str, https_port: u16) -> Result<AcmeServer> {
+        restrict_dir(&config.cache).map_err(|e| format!("{}: {}", config.cache.display(), e))?;
+        let challenges = Challenges::default();
+        let listening = Server::http(http_addr)
+            .and_then(|server| server.handle_threads(responder(challenges.clone(), https_port), 2))
+            .map_err(|e| format!("ACME challenge listener on {}: {}", http_addr, e))?;
+        // Serves for the life of the process, dropping it would wait for it
+        std::mem::forget(listening);
+
+        let store = Store { config, challenges };
+        if store
+            .days_left()
+            .is_none_or(|days| days < RENEW_BEFORE_DAYS)
+        {
+            store.renew()?;
+        }
+        let server = AcmeServer {
+            current: Arc::new(RwLock::new(store.server()?)),
+        };
+        let current = server.current.clone();
+        thread::Builder::new()
+            .name("acme".to_owned())
+            .spawn(move || loop {
+                thread::sleep(CHECK_INTERVAL);
+                match store.days_left() {
+                    Some(days) if days >= RENEW_BEFORE_DAYS => continue,
+                    _ => {}
+                }
+                match store.renew().and_then(|()| store.server()) {
+                    Ok(server) => *current.write().unwrap() = server,
+                    Err(e) => warn!("ACME renewal failed, retrying in 12 hours: {}", e),
+                }
+            })
+            .unwrap();
+        Ok(server)
+    }
+}
+
+/// The HTTP-01 challenge responses, and redirects to HTTPS for the rest
+fn responder(
+    challenges: Challenges,
+    https_port: u16,
+) -> impl Fn(HttpRequest, HttpResponse<Fresh>) + Send + Sync {
+    move |req, mut resp| {
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.clone(),
+            _ => String::new(),
+        };
+        if let Some(token) = path.strip_prefix(CHALLENGE_PREFIX) {
+            let key_authorization = challenges.read().unwrap().get(token).cloned();
+            let _ = match key_authorization {
+                Some(key_authorization) => {
+                    resp.headers_mut()
+                        .set_raw("Content-Type", vec![b"text/plain".to_vec()]);
+                    resp.send(key_authorization.as_bytes())
+                }
+                None => {
+                    *resp.status_mut() = StatusCode::NotFound;
+                    resp.send(b"")
+                }
+            };
+            return;
+        }
+        let _ = match req.headers.get::<Host>() {
+            Some(host) => {
+                let port = if https_port == 443 {
+                    String::new()
+                } else {
+                    format!(":{}", https_port)
+                };
+                *resp.status_mut() = StatusCode::MovedPermanently;
+                resp.headers_mut().set(Location(format!(
+                    "https://{}{}{}",
+                    host.hostname, port, path
+                )));
+                resp.send(b"")
+            }
+            None => {
+                *resp.status_mut() = StatusCode::BadRequest;
+                resp.send(b"")
+            }
+        };
+    }
+}
+
+#[cfg(unix)]
+fn restrict_dir(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o700))
+}
+
+#[cfg(not(unix))]
+fn restrict_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Write a file only readable by the owner, atomically
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+fn new_key() -> Result<PKey<Private>> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(err)?;
+    EcKey::generate(&group)
+        .and_then(PKey::from_ec_key)
+        .map_err(err)
+}
+
+fn b64url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// The files of the cache directory
+struct Store {
+    config: AcmeConfig,
+    challenges: Challenges,
+}
+
+impl Store {
+    fn path(&self, extension: &str) -> PathBuf {
+        self.config
+            .cache
+            .join(format!("{}.{}", self.config.domains[0], extension))
+    }
+
+    /// Days before the cached certificate expires, `None` without one for the domains
+    fn days_left(&self) -> Option<i32> {
+        let pem = fs::read(self.path("crt")).ok()?;
+        let chain = X509::stack_from_pem(&pem).ok()?;
+        let leaf = chain.first()?;
+        let names = leaf
+            .subject_alt_names()?
+            .iter()
+            .filter_map(|name| name.dnsname().map(str::to_owned))
+            .collect::<Vec<String>>();
+        if !self
+            .config
+            .domains
+            .iter()
+            .all(|domain| names.contains(domain))
+        {
+            return None;
+        }
+        let now = Asn1Time::days_from_now(0).ok()?;
+        now.diff(leaf.not_after()).ok().map(|diff| diff.days)
+    }
+
+    fn server(&self) -> Result<NativeTlsServer> {
+        let path = self.path("p12");
+        NativeTlsServer::new(&path, "").map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn account_key(&self) -> Result<PKey<Private>> {
+        let path = self.config.cache.join("account.key");
+        match fs::read(&path) {
+            Ok(pem) => {
+                PKey::private_key_from_pem(&pem).map_err(|e| format!("{}: {}", path.display(), e))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let key = new_key()?;
+                let pem = key.private_key_to_pem_pkcs8().map_err(err)?;
+                write_private(&path, &pem).map_err(|e| format!("{}: {}", path.display(), e))?;
+                Ok(key)
+            }
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    /// Obtain a new certificate and put it in the cache
+    fn renew(&self) -> Result<()> {
+        let domains = &self.config.domains;
+        info!("ACME: requesting a certificate for {}", domains.join(", "));
+        let mut client = AcmeClient::new(&self.config.directory, self.account_key()?)?;
+        client.register(self.config.email.as_deref())?;
+        let key = new_key()?;
+        let chain = client.order(domains, &key, &self.challenges)?;
+
+        let certs = X509::stack_from_pem(chain.as_bytes()).map_err(err)?;
+        let (leaf, rest) = certs
+            .split_first()
+            .ok_or_else(|| "ACME: empty certificate chain".to_owned())?;
+        let mut ca = Stack::new().map_err(err)?;
+        for cert in rest {
+            ca.push(cert.clone()).map_err(err)?;
+        }
+        let p12 = Pkcs12::builder()
+            .name(&domains[0])
+            .pkey(&key)
+            .cert(leaf)
+            .ca(ca)
+            .build2("")
+            .and_then(|p12| p12.to_der())
+            .map_err(err)?;
+        let key_pem = key.private_key_to_pem_pkcs8().map_err(err)?;
+        for (extension, data) in [
+            ("key", &key_pem[..]),
+            ("crt", chain.as_bytes()),
+            ("p12", &p12),
+        ] {
+            let path = self.path(extension);
+            write_private(&path, data).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        info!("ACME: certificate for {} obtained", domains.join(", "));
+        Ok(())
+    }
+}
+
+/// The requests of RFC 8555, signed with the account key
+struct AcmeClient {
+    client: Client,
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+    key: PKey<Private>,
+    jwk: String,
+    // URL of the account, once registered
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    fn new(directory: &str, key: PKey<Private>) -> Result<AcmeClient> {
+        let tls = NativeTlsClient::new().map_err(err)?;
+        let mut client = Client::with_connector(HttpsConnector::new(tls));
+        client.set_read_timeout(Some(REQUEST_TIMEOUT));
+        client.set_write_timeout(Some(REQUEST_TIMEOUT));
+        let mut body = String::new();
+        client
+            .get(directory)
+            .send()
+            .and_then(|mut resp| Ok(resp.read_to_string(&mut body)?))
+            .map_err(|e| format!("{}: {}", directory, e))?;
+        let directory_json =
+            serde_json::from_str::<Value>(&body).map_err(|e| format!("{}: {}", directory, e))?;
+        let url = |name: &str| {
+            directory_json[name]
+                .as_str()
+                .map(str::to_owned)
+                .ok_or_else(|| format!("{}: no {}", directory, name))
+        };
+
+        let ec = key.ec_key().map_err(err)?;
+        let mut ctx = BigNumContext::new().map_err(err)?;
+        let (mut x, mut y) = (BigNum::new().map_err(err)?, BigNum::new().map_err(err)?);
+        ec.public_key()
+            .affine_coordinates_gfp(ec.group(), &mut x, &mut y, &mut ctx)
+            .map_err(err)?;
+        // The members in lexicographic order, as hashed for the thumbprint (RFC 7638)
+        let jwk = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            b64url(&x.to_vec_padded(32).map_err(err)?),
+            b64url(&y.to_vec_padded(32).map_err(err)?)
+        );
+        Ok(AcmeClient {
+            client,
+            new_nonce: url("newNonce")?,
+            new_account: url("newAccount")?,
+            new_order: url("newOrder")?,
+            key,
+            jwk,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    fn thumbprint(&self) -> String {
+        b64url(&Sha256::digest(self.jwk.as_bytes()))
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key).map_err(err)?;
+        let der = signer.sign_oneshot_to_vec(data).map_err(err)?;
+        // JWS wants the raw R and S rather than DER
+        let signature = EcdsaSig::from_der(&der).map_err(err)?;
+        let mut raw = signature.r().to_vec_padded(32).map_err(err)?;
+        raw.extend(signature.s().to_vec_padded(32).map_err(err)?);
+        Ok(raw)
+    }
+
+    fn nonce(&mut self) -> Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let resp = self.client.head(&self.new_nonce).send().map_err(err)?;
+        replay_nonce(&resp.headers).ok_or_else(|| format!("{}: no nonce", self.new_nonce))
+    }
+
+    /// POST the signed `payload` to `url`, a POST-as-GET without payload
+    fn post(&mut self, url: &str, payload: Option<&str>) -> Result<(Headers, Value)> {
+        let mut retried = false;
+        loop {
+            let nonce = self.nonce()?;
+            let key = match self.kid {
+                Some(ref kid) => format!(r#""kid":"{}""#, json_escape(kid)),
+                None => format!(r#""jwk":{}"#, self.jwk),
+            };
+            let protected = b64url(
+                format!(
+                    r#"{{"alg":"ES256",{},"nonce":"{}","url":"{}"}}"#,
+                    key,
+                    json_escape(&nonce),
+                    json_escape(url)
+                )
+                .as_bytes(),
+            );
+            let payload = payload
+                .map(|payload| b64url(payload.as_bytes()))
+                .unwrap_or_default();
+            let signature = b64url(&self.sign(format!("{}.{}", protected, payload).as_bytes())?);
+            let body = format!(
+                r#"{{"protected":"{}","payload":"{}","signature":"{}"}}"#,
+                protected, payload, signature
+            );
+            let mut headers = Headers::new();
+            headers.set_raw("Content-Type", vec![b"application/jose+json".to_vec()]);
+            let mut resp = self
+                .client
+                .post(url)
+                .headers(headers)
+                .body(body.as_str())
+                .send()
+                .map_err(err)?;
+            self.nonce = replay_nonce(&resp.headers);
+            let mut text = String::new();
+            resp.read_to_string(&mut text).map_err(err)?;
+            let is_pem = text.starts_with("-----BEGIN");
+            let json = if is_pem {
+                Value::String(text)
+            } else {
+                serde_json::from_str(&text).map_err(|e| format!("{}: {}", url, e))?
+            };
+            if resp.status.is_success() {
+                return Ok((resp.headers.clone(), json));
+            }
+            // The nonces expire, a fresh one comes with the error
+            if !retried && json["type"].as_str() == Some("urn:ietf:params:acme:error:badNonce") {
+                retried = true;
+                continue;
+            }
+            return Err(format!(
+                "{}: {} {}",
+                url,
+                resp.status,
+                json["detail"].as_str().unwrap_or_default()
+            ));
+        }
+    }
+
+    fn register(&mut self, email: Option<&str>) -> Result<()> {
+        let contact = match email {
+            Some(email) => format!(r#","contact":["mailto:{}"]"#, json_escape(email)),
+            None => String::new(),
+        };
+        let payload = format!(r#"{{"termsOfServiceAgreed":true{}}}"#, contact);
+        let url = self.new_account.clone();
+        let (headers, _) = self.post(&url, Some(&payload))?;
+        self.kid = Some(location(&headers).ok_or_else(|| format!("{}: no account URL", url))?);
+        Ok(())
+    }
+
+    /// POST-as-GET `url` until its status is no longer pending
+    fn poll(&mut self, url: &str) -> Result<Value> {
+        for _ in 0..MAX_POLLS {
+            let (_, json) = self.post(url, None)?;
+            match json["status"].as_str() {
+                Some("pending") | Some("processing") => thread::sleep(POLL_INTERVAL),
+                _ => return Ok(json),
+            }
+        }
+        Err(format!("{}: still pending", url))
+    }
+
+    /// Order a certificate for `domains` with `key`, the PEM chain
+    fn order(
+        &mut self,
+        domains: &[String],
+        key: &PKey<Private>,
+        challenges: &Challenges,
+    ) -> Result<String> {
+        let identifiers = domains
+            .iter()
+            .map(|domain| format!(r#"{{"type":"dns","value":"{}"}}"#, json_escape(domain)))
+            .collect::<Vec<String>>();
+        let payload = format!(r#"{{"identifiers":[{}]}}"#, identifiers.join(","));
+        let url = self.new_order.clone();
+        let (headers, order) = self.post(&url, Some(&payload))?;
+        let order_url = location(&headers).ok_or_else(|| format!("{}: no order URL", url))?;
+
+        let authorizations = order["authorizations"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str);
+        for authorization in authorizations {
+            self.authorize(authorization, challenges)?;
+        }
+
+        let finalize = order["finalize"]
+            .as_str()
+            .ok_or_else(|| format!("{}: no finalize URL", order_url))?
+            .to_owned();
+        let payload = format!(r#"{{"csr":"{}"}}"#, b64url(&csr(domains, key)?));
+        self.post(&finalize, Some(&payload))?;
+        let order = self.poll(&order_url)?;
+        let certificate = match (order["status"].as_str(), order["certificate"].as_str()) {
+            (Some("valid"), Some(certificate)) => certificate.to_owned(),
+            (status, _) => {
+                return Err(format!(
+                    "{}: order {}",
+                    order_url,
+                    status.unwrap_or("failed")
+                ))
+            }
+        };
+        match self.post(&certificate, None)? {
+            (_, Value::String(chain)) => Ok(chain),
+            _ => Err(format!("{}: not a PEM certificate chain", certificate)),
+        }
+    }
+
+    /// Complete the HTTP-01 challenge of the authorization `url`
+    fn authorize(&mut self, url: &str, challenges: &Challenges) -> Result<()> {
+        let (_, authorization) = self.post(url, None)?;
+        if authorization["status"].as_str() == Some("valid") {
+            return Ok(());
+        }
+        let domain = authorization["identifier"]["value"]
+            .as_str()
+            .unwrap_or(url)
+            .to_owned();
+        let challenge = authorization["challenges"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|challenge| challenge["type"] == "http-01")
+            .ok_or_else(|| format!("{}: no http-01 challenge", domain))?;
+        let (token, challenge_url) = match (challenge["token"].as_str(), challenge["url"].as_str())
+        {
+            (Some(token), Some(url)) => (token.to_owned(), url.to_owned()),
+            _ => return Err(format!("{}: invalid http-01 challenge", domain)),
+        };
+        let key_authorization = format!("{}.{}", token, self.thumbprint());
+        challenges
+            .write()
+            .unwrap()
+            .insert(token.clone(), key_authorization);
+        let result = self
+            .post(&challenge_url, Some("{}"))
+            .and_then(|_| self.poll(url));
+        challenges.write().unwrap().remove(&token);
+        let authorization = result?;
+        match authorization["status"].as_str() {
+            Some("valid") => Ok(()),
+            status => {
+                let detail = authorization["challenges"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .find_map(|challenge| challenge["error"]["detail"].as_str())
+                    .unwrap_or_default();
+                Err(format!(
+                    "authorization of {} {}: {}",
+                    domain,
+                    status.unwrap_or("failed"),
+                    detail
+                ))
+            }
+        }
+    }
+}
+
+fn csr(domains: &[String], key: &PKey<Private>) -> Result<Vec<u8>> {
+    let mut name = X509NameBuilder::new().map_err(err)?;
+    name.append_entry_by_nid(Nid::COMMONNAME, &domains[0])
+        .map_err(err)?;
+    let mut san = SubjectAlternativeName::new();
+    for domain in domains {
+        san.dns(domain);
+    }
+    let mut req = X509ReqBuilder::new().map_err(err)?;
+    req.set_subject_name(&name.build()).map_err(err)?;
+    req.set_pubkey(key).map_err(err)?;
+    let mut extensions = Stack::new().map_err(err)?;
+    extensions
+        .push(san.build(&req.x509v3_context(None)).map_err(err)?)
+        .map_err(err)?;
+    req.add_extensions(&extensions).map_err(err)?;
+    req.sign(key, MessageDigest::sha256()).map_err(err)?;
+    req.build().to_der().map_err(err)
+}
+
+fn replay_nonce(headers: &Headers) -> Option<String> {
+    headers
+        .get_raw("Replay-Nonce")
+        .and_then(|values| values.first())
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+fn location(headers: &Headers) -> Option<String> {
+    headers
+        .get::<Location>()
+        .map(|location| location.to_string())
+}
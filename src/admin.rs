@@ -0,0 +1,249 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use iron::headers::ContentType;
+use iron::method;
+use iron::status;
+use iron::{IronError, IronResult, Request, Response};
+use percent_encoding::percent_decode;
+use tracing::info;
+
+use crate::keys::KeyAuth;
+use crate::tags::Tags;
+use crate::util::{constant_time_eq, error_io2iron, StringError};
+
+pub const ADMIN_PATH_PREFIX: &str = "/-/admin/";
+
+/// Server state which can be changed at runtime through the admin endpoint.
+#[derive(Default)]
+pub struct RuntimeState {
+    pub maintenance: AtomicBool,
+    pub upload: Toggle,
+    pub delete: Toggle,
+    pub listing: Toggle,
+    pub compression: Toggle,
+}
+
+impl RuntimeState {
+    fn features(&self) -> [(&'static str, &Toggle); 4] {
+        [
+            ("upload", &self.upload),
+            ("delete", &self.delete),
+            ("listing", &self.listing),
+            ("compression", &self.compression),
+        ]
+    }
+
+    fn features_json(&self) -> String {
+        let features = self
+            .features()
+            .iter()
+            .map(|(name, toggle)| format!(r#""{}":{}"#, name, toggle.is_on()))
+            .collect::<Vec<String>>();
+        format!("{{{}}}", features.join(","))
+    }
+}
+
+/// A feature enabled at startup, which can be switched off (and back on) at runtime, eg: to
+/// lock a server down during an incident without restarting the transfers.
+#[derive(Default)]
+pub struct Toggle {
+    available: bool,
+    on: AtomicBool,
+}
+
+impl Toggle {
+    pub fn new(available: bool) -> Toggle {
+        Toggle {
+            available,
+            on: AtomicBool::new(available),
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on.load(Ordering::SeqCst)
+    }
+
+    /// Refuse the request while `feature` is switched off
+    pub fn check(&self, feature: &str) -> IronResult<()> {
+        if self.is_on() {
+            Ok(())
+        } else {
+            Err(IronError::new(
+                StringError(format!("{} is disabled", feature)),
+                status::Forbidden,
+            ))
+        }
+    }
+}
+
+/// Admin endpoint: `/-/admin/*`, authorized by the `X-Admin-Token` header.
+pub struct Admin {
+    token: String,
+    state: Arc<RuntimeState>,
+    tags: Option<Arc<Tags>>,
+}
+
+impl Admin {
+    pub fn new(token: &str, state: Arc<RuntimeState>, tags: Option<Arc<Tags>>) -> Admin {
+        Admin {
+            token: token.to_owned(),
+            state,
+            tags,
+        }
+    }
+
+    pub fn handle(&self, req: &mut Request, path: &[String]) -> IronResult<Response> {
+        let token = req
+            .headers
+            .get_raw("X-Admin-Token")
+            .and_then(|values| values.first())
+            .map(|value| String::from_utf8_lossy(value).to_string());
+        // Or an API key with the admin scope, checked by `ApiKeyChecker`
+        let token_ok =
+            token.is_some_and(|token| constant_time_eq(token.as_bytes(), self.token.as_bytes()));
+        if !token_ok && !req.extensions.contains::<KeyAuth>() {
+            return Err(IronError::new(
+                StringError("admin token required".to_owned()),
+                status::Forbidden,
+            ));
+        }
+
+        if let (Some("tags"), Some(tags)) = (path.first().map(String::as_str), &self.tags) {
+            return self.handle_tags(req, &path[1..], tags);
+        }
+        if path.first().map(String::as_str) == Some("features") {
+            return self.handle_features(req);
+        }
+        match (&req.method, path.first().map(String::as_str)) {
+            (method::Get, Some("maintenance")) => {}
+            (method::Post, Some("maintenance")) => {
+                let enable = req
+                    .url
+                    .as_ref()
+                    .query_pairs()
+                    .find(|(k, _)| k == "enable")
+                    .map(|(_, v)| v == "true" || v == "1");
+                match enable {
+                    Some(enable) => self.state.maintenance.store(enable, Ordering::SeqCst),
+                    None => {
+                        return Err(IronError::new(
+                            StringError("enable parameter not provided".to_owned()),
+                            status::BadRequest,
+                        ))
+                    }
+                }
+            }
+            (_, Some("maintenance")) => return Ok(Response::with(status::MethodNotAllowed)),
+            _ => {
+                return Err(IronError::new(
+                    StringError("unknown admin endpoint".to_owned()),
+                    status::NotFound,
+                ))
+            }
+        }
+
+        let mut resp = Response::with((
+            status::Ok,
+            format!(
+                r#"{{"maintenance":{}}}"#,
+                self.state.maintenance.load(Ordering::SeqCst)
+            ),
+        ));
+        resp.headers.set(ContentType::json());
+        Ok(resp)
+    }
+
+    /// `/-/admin/features`: GET lists the runtime switchable features, POST
+    /// `?upload=false&delete=false` switches them (only those enabled at startup can be
+    /// switched on), with `listing` and `compression` as well.
+    fn handle_features(&self, req: &mut Request) -> IronResult<Response> {
+        match req.method {
+            method::Get => {}
+            method::Post => {
+                let features = self.state.features();
+                let mut changes = Vec::new();
+                for (k, v) in req.url.as_ref().query_pairs() {
+                    let toggle = match features.iter().find(|(name, _)| *name == k) {
+                        Some((_, toggle)) => toggle,
+                        None => {
+                            return Err(IronError::new(
+                                StringError(format!("unknown feature: {}", k)),
+                                status::BadRequest,
+                            ))
+                        }
+                    };
+                    let on = v == "true" || v == "1";
+                    if on && !toggle.available {
+                        return Err(IronError::new(
+                            StringError(format!("{} is not enabled at startup", k)),
+                            status::Conflict,
+                        ));
+                    }
+                    changes.push((k.to_string(), *toggle, on));
+                }
+                for (name, toggle, on) in changes {
+                    if toggle.on.swap(on, Ordering::SeqCst) != on {
+                        info!(
+                            "Feature {} switched {}",
+                            name,
+                            if on { "on" } else { "off" }
+                        );
+                    }
+                }
+            }
+            _ => return Ok(Response::with(status::MethodNotAllowed)),
+        }
+        let mut resp = Response::with((status::Ok, self.state.features_json()));
+        resp.headers.set(ContentType::json());
+        Ok(resp)
+    }
+
+    /// `/-/admin/tags/<path>`: GET lists the tags of the file, POST `?key=<key>&value=<value>`
+    /// sets one, DELETE `?key=<key>` removes one (or all without `key`).
+    fn handle_tags(&self, req: &mut Request, path: &[String], tags: &Tags) -> IronResult<Response> {
+        let path = path
+            .iter()
+            .filter(|s| !s.is_empty())
+            .map(|s| percent_decode(s.as_bytes()).decode_utf8_lossy().to_string())
+            .collect::<Vec<String>>()
+            .join("/");
+        let mut key = None;
+        let mut value = String::new();
+        for (k, v) in req.url.as_ref().query_pairs() {
+            if k == "key" {
+                key = Some(v.to_string());
+            } else if k == "value" {
+                value = v.to_string();
+            }
+        }
+        if [path.as_str(), key.as_deref().unwrap_or(""), value.as_str()]
+            .iter()
+            .any(|s| s.contains(['\t', '\n', '\r']))
+        {
+            return Err(IronError::new(
+                StringError("tab and newline are not allowed in tags".to_owned()),
+                status::BadRequest,
+            ));
+        }
+
+        match (&req.method, key) {
+            (method::Get, _) => {}
+            (method::Post, Some(key)) if !path.is_empty() && !key.is_empty() => {
+                tags.set(&path, &key, &value).map_err(error_io2iron)?
+            }
+            (method::Post, _) => {
+                return Err(IronError::new(
+                    StringError("path and key parameter required".to_owned()),
+                    status::BadRequest,
+                ))
+            }
+            (method::Delete, key) => tags.remove(&path, key.as_deref()).map_err(error_io2iron)?,
+            _ => return Ok(Response::with(status::MethodNotAllowed)),
+        }
+
+        let mut resp = Response::with((status::Ok, tags.json(&path)));
+        resp.headers.set(ContentType::json());
+        Ok(resp)
+    }
+}
@@ -0,0 +1,868 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Datelike, Local, Timelike};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::{Compression, Crc};
+use htmlescape::encode_minimal;
+use iron::headers::{CacheControl, CacheDirective, ContentLength, ContentType};
+use iron::response::WriteBody;
+use iron::status;
+use iron::{IronError, IronResult, Request, Response};
+use mime_guess as mime_types;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use pretty_bytes::converter::convert;
+use tracing::warn;
+use zip::ZipArchive;
+
+use crate::middlewares::{walk_excluded, FileBody};
+use crate::storage::{Metadata, Storage};
+use crate::util::{
+    brand_html, encode_link_path, error_io2iron, favicon_image, json_escape, prefers_json,
+    root_link, StringError,
+};
+
+/// Marks the end of an archive path in url: `/bundle.zip!/docs/index.html`
+const ZIP_MEMBER_SEPARATOR: &str = ".zip!";
+
+fn zip_error(err: zip::result::ZipError) -> io::Error {
+    io::Error::other(err)
+}
+
+/// Split the root relative `path/bundle.zip!/docs/index.html` into the archive path and the
+/// member name
+pub fn split_zip_member(storage: &dyn Storage, path: &Path) -> Option<(PathBuf, String)> {
+    let mut archive = PathBuf::new();
+    let mut components = path.components();
+    while let Some(component) = components.next() {
+        let name = component.as_os_str().to_string_lossy();
+        if name.ends_with(ZIP_MEMBER_SEPARATOR) {
+            archive.push(&name[..name.len() - 1]);
+            let member = components
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect::<Vec<String>>()
+                .join("/");
+            return if storage
+                .stat(&archive)
+                .is_ok_and(|metadata| metadata.is_file)
+            {
+                Some((archive, member))
+            } else {
+                None
+            };
+        }
+        archive.push(component);
+    }
+    None
+}
+
+// Read the member when writing the body, so it is streamed instead of buffered
+struct ZipMemberBody {
+    archive: PathBuf,
+    name: String,
+}
+
+impl WriteBody for ZipMemberBody {
+    fn write_body(&mut self, w: &mut dyn io::Write) -> io::Result<()> {
+        let mut archive = ZipArchive::new(fs::File::open(&self.archive)?).map_err(zip_error)?;
+        let mut member = archive.by_name(&self.name).map_err(zip_error)?;
+        io::copy(&mut member, w).map(|_| ())
+    }
+}
+
+/// Serve one member of the zip archive `path`, `{member}/index.html` is tried for directories
+pub fn send_zip_member(storage: &dyn Storage, path: &Path, member: &str) -> IronResult<Response> {
+    let archive_path = storage.local_path(path).ok_or_else(|| {
+        IronError::new(
+            StringError("zip members are only served from the file system".to_owned()),
+            status::NotImplemented,
+        )
+    })?;
+    let file = fs::File::open(&archive_path).map_err(error_io2iron)?;
+    let mut archive = ZipArchive::new(file).map_err(|err| {
+        IronError::new(
+            StringError(format!("invalid zip archive: {}", err)),
+            status::BadRequest,
+        )
+    })?;
+    let candidates = if member.is_empty() {
+        vec!["index.html".to_owned()]
+    } else {
+        vec![member.to_owned(), format!("{}/index.html", member)]
+    };
+    for name in candidates {
+        let size = match archive.by_name(&name) {
+            Ok(ref entry) if entry.is_file() => entry.size(),
+            _ => continue,
+        };
+        let mut resp = Response::with(status::Ok);
+        resp.extensions.insert::<FileBody>(());
+        let mime = mime_types::from_path(&name).first_or_octet_stream();
+        resp.headers
+            .set_raw("content-type", vec![mime.to_string().into_bytes()]);
+        resp.headers.set(ContentLength(size));
+        resp.body = Some(Box::new(ZipMemberBody {
+            archive: archive_path,
+            name,
+        }));
+        return Ok(resp);
+    }
+    Err(IronError::new(
+        StringError(format!("member not found in archive: {}", member)),
+        status::NotFound,
+    ))
+}
+
+/// Members listed at most by `?view=archive`
+const MAX_VIEW_ENTRIES: usize = 10_000;
+
+struct ArchiveEntry {
+    name: String,
+    size: u64,
+    is_dir: bool,
+}
+
+/// `/bundle.tar.gz?view=archive`: the members of a zip or tar (optionally gzipped) file
+/// with their sizes, read without extracting anything, linked for download when zip
+/// members are served.
+pub fn view_archive(
+    req: &Request,
+    storage: &dyn Storage,
+    path: &Path,
+    path_prefix: &[String],
+    title: &str,
+    base_url: &str,
+    zip_members: bool,
+) -> IronResult<Response> {
+    let name = path_prefix.last().map(String::as_str).unwrap_or("");
+    let lower = name.to_ascii_lowercase();
+    let bad_archive = |err: io::Error| {
+        IronError::new(
+            StringError(format!("invalid archive: {}", err)),
+            status::BadRequest,
+        )
+    };
+    let (entries, is_zip) = if lower.ends_with(".zip") {
+        let fs_path = storage.local_path(path).ok_or_else(|| {
+            IronError::new(
+                StringError("zip archives are only listed from the file system".to_owned()),
+                status::NotImplemented,
+            )
+        })?;
+        let file = fs::File::open(&fs_path).map_err(error_io2iron)?;
+        (zip_entries(file).map_err(bad_archive)?, true)
+    } else if lower.ends_with(".tar") || lower.ends_with(".tar.gz") || lower.ends_with(".tgz") {
+        let file = storage.open_range(path, 0, None).map_err(error_io2iron)?;
+        let entries = if lower.ends_with(".tar") {
+            tar_entries(file)
+        } else {
+            tar_entries(GzDecoder::new(file))
+        };
+        (entries.map_err(bad_archive)?, false)
+    } else if lower.ends_with(".7z") {
+        // The headers of 7z archives are LZMA compressed by default
+        return Err(IronError::new(
+            StringError("7z archives can not be listed".to_owned()),
+            status::UnsupportedMediaType,
+        ));
+    } else {
+        return Err(IronError::new(
+            StringError(format!("not an archive: {}", name)),
+            status::BadRequest,
+        ));
+    };
+
+    if prefers_json(req) {
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    r#"{{"name":"{}","size":{},"dir":{}}}"#,
+                    json_escape(&entry.name),
+                    entry.size,
+                    entry.is_dir
+                )
+            })
+            .collect::<Vec<String>>();
+        let mut resp = Response::with((status::Ok, format!("[{}]", entries.join(","))));
+        resp.headers.set(ContentType::json());
+        return Ok(resp);
+    }
+    let rows = entries
+        .iter()
+        .map(|entry| {
+            let label = encode_minimal(&entry.name);
+            let label = if zip_members && is_zip && !entry.is_dir {
+                let mut link = path_prefix.to_owned();
+                let last = link.pop().unwrap_or_default();
+                link.push(format!("{}!", last));
+                link.extend(entry.name.split('/').map(str::to_owned));
+                format!(
+                    r#"<a href="{}{}">{}</a>"#,
+                    base_url,
+                    encode_link_path(&link),
+                    label
+                )
+            } else {
+                label
+            };
+            let size = if entry.is_dir {
+                "-".to_owned()
+            } else {
+                convert(entry.size as f64)
+            };
+            format!("<tr><td>{}</td><td>{}</td></tr>", label, size)
+        })
+        .collect::<Vec<String>>();
+    let truncated = if entries.len() >= MAX_VIEW_ENTRIES {
+        format!(
+            "<p>Only the first {} members are shown.</p>",
+            MAX_VIEW_ENTRIES
+        )
+    } else {
+        "".to_owned()
+    };
+    let mut resp = Response::with((
+        status::Ok,
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  {favicon_image}
+  <title>{title} · {name}</title>
+</head>
+<body>
+  {brand}
+  {root_link}
+  <hr />
+  <div>{count} members in <a href="{base_url}{link}">/{link}</a></div>
+  {truncated}
+  <table>
+    {rows}
+  </table>
+</body>
+</html>
+"#,
+            favicon_image = favicon_image(),
+            brand = brand_html(),
+            title = encode_minimal(title),
+            name = encode_minimal(name),
+            root_link = root_link(base_url),
+            count = entries.len(),
+            base_url = base_url,
+            link = encode_link_path(path_prefix),
+            truncated = truncated,
+            rows = rows.join("\n"),
+        ),
+    ));
+    resp.headers.set(ContentType::html());
+    Ok(resp)
+}
+
+fn zip_entries(file: fs::File) -> io::Result<Vec<ArchiveEntry>> {
+    let mut archive = ZipArchive::new(file).map_err(zip_error)?;
+    let mut entries = Vec::new();
+    for index in 0..archive.len().min(MAX_VIEW_ENTRIES) {
+        let member = archive.by_index_raw(index).map_err(zip_error)?;
+        entries.push(ArchiveEntry {
+            name: member.name().trim_end_matches('/').to_owned(),
+            size: member.size(),
+            is_dir: member.is_dir(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Numeric field of a tar header, octal or base-256 when the high bit is set
+fn tar_number(field: &[u8]) -> io::Result<u64> {
+    if field.first().is_some_and(|byte| byte & 0x80 != 0) {
+        return Ok(field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |n, byte| {
+                (n << 8) | u64::from(*byte)
+            }));
+    }
+    let digits = String::from_utf8_lossy(field);
+    let digits = digits.trim_matches(|c: char| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8)
+        .map_err(|_err| io::Error::new(io::ErrorKind::InvalidData, "bad tar header number"))
+}
+
+fn tar_string(field: &[u8]) -> String {
+    let end = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).to_string()
+}
+
+// Walks the headers, reading past the member data since the stream may be compressed
+fn tar_entries<R: Read>(mut tar: R) -> io::Result<Vec<ArchiveEntry>> {
+    let mut entries = Vec::new();
+    let mut header = [0u8; 512];
+    // Name and size of the next member from a GNU long name or a pax header
+    let mut long_name = None;
+    let mut pax_size = None;
+    while entries.len() < MAX_VIEW_ENTRIES {
+        tar.read_exact(&mut header)?;
+        if header.iter().all(|byte| *byte == 0) {
+            break;
+        }
+        let size = tar_number(&header[124..136])?;
+        let padded = size.div_ceil(512) * 512;
+        match header[156] {
+            b'L' | b'x' => {
+                let mut data = Vec::new();
+                (&mut tar).take(padded).read_to_end(&mut data)?;
+                data.truncate(size as usize);
+                if header[156] == b'L' {
+                    long_name = Some(tar_string(&data));
+                    continue;
+                }
+                for record in String::from_utf8_lossy(&data).lines() {
+                    let record = record.split_once(' ').map_or("", |(_, record)| record);
+                    match record.split_once('=') {
+                        Some(("path", value)) => long_name = Some(value.to_owned()),
+                        Some(("size", value)) => pax_size = value.parse::<u64>().ok(),
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+        let size = pax_size.take().unwrap_or(size);
+        let padded = size.div_ceil(512) * 512;
+        let name = long_name.take().unwrap_or_else(|| {
+            let name = tar_string(&header[0..100]);
+            let prefix = tar_string(&header[345..500]);
+            if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                format!("{}/{}", prefix, name)
+            } else {
+                name
+            }
+        });
+        io::copy(&mut (&mut tar).take(padded), &mut io::sink())?;
+        // Global pax headers and the other metadata members are not listed
+        match header[156] {
+            b'g' | b'K' | b'V' => {}
+            kind => entries.push(ArchiveEntry {
+                name: name.trim_end_matches('/').to_owned(),
+                size,
+                is_dir: kind == b'5' || name.ends_with('/'),
+            }),
+        }
+    }
+    Ok(entries)
+}
+
+/// Format of a directory downloaded with `?archive=`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DirArchive {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl FromStr for DirArchive {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DirArchive, String> {
+        match s {
+            "zip" => Ok(DirArchive::Zip),
+            "tar" => Ok(DirArchive::Tar),
+            "tar.gz" | "tgz" => Ok(DirArchive::TarGz),
+            _ => Err(format!("unknown archive format: {}", s)),
+        }
+    }
+}
+
+impl DirArchive {
+    fn extension(self) -> &'static str {
+        match self {
+            DirArchive::Zip => "zip",
+            DirArchive::Tar => "tar",
+            DirArchive::TarGz => "tar.gz",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            DirArchive::Zip => "application/zip",
+            DirArchive::Tar => "application/x-tar",
+            DirArchive::TarGz => "application/gzip",
+        }
+    }
+}
+
+/// `/dir/?archive=zip|tar|tar.gz` (`--archive`): the subtree of `dir` packed in one archive
+/// under a `<name>/` folder, streamed as it is read so nothing is buffered nor written to
+/// disk. Zip members are stored uncompressed (zip64 past 4 GiB), the compressed format is
+/// `tar.gz`. The access files and the directories with their own rules are left out, as
+/// from the other recursive views, and the symlinks looping back up are not followed.
+pub fn send_dir_archive(
+    storage: Arc<dyn Storage>,
+    dir: &Path,
+    name: &str,
+    format: DirArchive,
+) -> IronResult<Response> {
+    let mut resp = Response::with(status::Ok);
+    resp.extensions.insert::<FileBody>(());
+    resp.headers.set_raw(
+        "content-type",
+        vec![format.content_type().as_bytes().to_vec()],
+    );
+    resp.headers.set_raw(
+        "content-disposition",
+        vec![format!(
+            "attachment; filename*=UTF-8''{}.{}",
+            utf8_percent_encode(name, NON_ALPHANUMERIC),
+            format.extension()
+        )
+        .into_bytes()],
+    );
+    resp.headers
+        .set(CacheControl(vec![CacheDirective::NoCache]));
+    resp.body = Some(Box::new(DirArchiveBody {
+        storage,
+        dir: dir.to_owned(),
+        name: name.to_owned(),
+        format,
+    }));
+    Ok(resp)
+}
+
+struct DirArchiveBody {
+    storage: Arc<dyn Storage>,
+    dir: PathBuf,
+    name: String,
+    format: DirArchive,
+}
+
+impl WriteBody for DirArchiveBody {
+    fn write_body(&mut self, w: &mut dyn io::Write) -> io::Result<()> {
+        let mut ancestors = Vec::new();
+        if let Some(local_path) = self.storage.local_path(&self.dir) {
+            ancestors.push(fs::canonicalize(local_path)?);
+        }
+        let mut walk = |sink: &mut dyn ArchiveSink| {
+            let modified = self.storage.stat(&self.dir)?.modified;
+            sink.add_dir(&self.name, modified)?;
+            walk_archive(&*self.storage, &self.dir, &self.name, &mut ancestors, sink)
+        };
+        match self.format {
+            DirArchive::Zip => {
+                let mut zip = ZipStream {
+                    out: w,
+                    offset: 0,
+                    entries: Vec::new(),
+                };
+                walk(&mut zip)?;
+                zip.finish()
+            }
+            DirArchive::Tar => {
+                let mut tar = TarStream { out: w };
+                walk(&mut tar)?;
+                tar.finish()
+            }
+            DirArchive::TarGz => {
+                let mut tar = TarStream {
+                    out: GzEncoder::new(w, Compression::default()),
+                };
+                walk(&mut tar)?;
+                tar.finish()?;
+                tar.out.finish().map(|_| ())
+            }
+        }
+    }
+}
+
+// Add the entries of `dir` to `sink` under `prefix/`
+fn walk_archive(
+    storage: &dyn Storage,
+    dir: &Path,
+    prefix: &str,
+    ancestors: &mut Vec<PathBuf>,
+    sink: &mut dyn ArchiveSink,
+) -> io::Result<()> {
+    let mut entries = storage.list(dir)?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for entry in entries {
+        let path = dir.join(&entry.name);
+        let local_path = storage.local_path(&path);
+        if local_path.as_deref().is_some_and(walk_excluded) {
+            continue;
+        }
+        let name = format!("{}/{}", prefix, entry.name);
+        if entry.metadata.is_dir {
+            let canonical = local_path.and_then(|path| fs::canonicalize(path).ok());
+            if canonical
+                .as_ref()
+                .is_some_and(|canonical| ancestors.contains(canonical))
+            {
+                continue;
+            }
+            sink.add_dir(&name, entry.metadata.modified)?;
+            ancestors.extend(canonical.clone());
+            walk_archive(storage, &path, &name, ancestors, sink)?;
+            if canonical.is_some() {
+                ancestors.pop();
+            }
+        } else if entry.metadata.is_file {
+            let mut data = match storage.open_range(&path, 0, None) {
+                Ok(data) => data,
+                Err(err) => {
+                    warn!("Archive skips {}: {}", path.display(), err);
+                    continue;
+                }
+            };
+            sink.add_file(&name, &entry.metadata, &mut data)?;
+        }
+    }
+    Ok(())
+}
+
+trait ArchiveSink {
+    fn add_dir(&mut self, name: &str, modified: SystemTime) -> io::Result<()>;
+
+    /// `data` must have `metadata.len` bytes, the archive fails otherwise
+    fn add_file(&mut self, name: &str, metadata: &Metadata, data: &mut dyn Read) -> io::Result<()>;
+}
+
+fn changed(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("{} changed while being archived", name),
+    )
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+// Largest value of the 11 octal digits of a ustar size field
+const TAR_MAX_OCTAL: u64 = 0o77777777777;
+
+/// ustar, with pax headers for the names over 100 bytes and the files over 8 GiB
+struct TarStream<W> {
+    out: W,
+}
+
+impl<W: io::Write> TarStream<W> {
+    fn header(&mut self, name: &str, kind: u8, size: u64, modified: SystemTime) -> io::Result<()> {
+        let mut records = String::new();
+        if name.len() > 100 {
+            records.push_str(&pax_record("path", name));
+        }
+        if size > TAR_MAX_OCTAL {
+            records.push_str(&pax_record("size", &size.to_string()));
+        }
+        if !records.is_empty() {
+            self.out.write_all(&ustar_header(
+                "././@PaxHeader",
+                b'x',
+                records.len() as u64,
+                modified,
+            ))?;
+            self.out.write_all(records.as_bytes())?;
+            self.pad(records.len() as u64)?;
+        }
+        self.out
+            .write_all(&ustar_header(name, kind, size.min(TAR_MAX_OCTAL), modified))
+    }
+
+    fn pad(&mut self, size: u64) -> io::Result<()> {
+        let padding = (512 - size % 512) % 512;
+        self.out.write_all(&[0; 512][..padding as usize])
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.write_all(&[0; 1024])?;
+        self.out.flush()
+    }
+}
+
+impl<W: io::Write> ArchiveSink for TarStream<W> {
+    fn add_dir(&mut self, name: &str, modified: SystemTime) -> io::Result<()> {
+        self.header(&format!("{}/", name), b'5', 0, modified)
+    }
+
+    fn add_file(&mut self, name: &str, metadata: &Metadata, data: &mut dyn Read) -> io::Result<()> {
+        self.header(name, b'0', metadata.len, metadata.modified)?;
+        if io::copy(&mut data.take(metadata.len), &mut self.out)? != metadata.len {
+            return Err(changed(name));
+        }
+        self.pad(metadata.len)
+    }
+}
+
+// `<length> <key>=<value>\n`, the length counting its own digits
+fn pax_record(key: &str, value: &str) -> String {
+    let base = key.len() + value.len() + 3;
+    let mut len = base + 1;
+    while len != base + len.to_string().len() {
+        len = base + len.to_string().len();
+    }
+    format!("{} {}={}\n", len, key, value)
+}
+
+fn ustar_header(name: &str, kind: u8, size: u64, modified: SystemTime) -> [u8; 512] {
+    let mut header = [0u8; 512];
+    let field = |header: &mut [u8; 512], offset: usize, len: usize, value: u64| {
+        let digits = format!("{:0width$o}", value, width = len - 1);
+        header[offset..offset + len - 1].copy_from_slice(&digits.as_bytes()[..len - 1]);
+    };
+    // Longer names are in the pax header
+    let name = name.as_bytes();
+    let name_len = name.len().min(100);
+    header[..name_len].copy_from_slice(&name[..name_len]);
+    field(
+        &mut header,
+        100,
+        8,
+        if kind == b'5' { 0o755 } else { 0o644 },
+    );
+    field(&mut header, 108, 8, 0);
+    field(&mut header, 116, 8, 0);
+    field(&mut header, 124, 12, size);
+    field(&mut header, 136, 12, unix_time(modified).min(TAR_MAX_OCTAL));
+    header[148..156].copy_from_slice(b"        ");
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    let checksum = header.iter().map(|b| u32::from(*b)).sum::<u32>();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+struct ZipEntry {
+    name: String,
+    is_dir: bool,
+    crc: u32,
+    size: u64,
+    offset: u64,
+    time: (u16, u16),
+}
+
+/// Zip written in one pass: the CRC and the sizes follow each member in a data descriptor
+struct ZipStream<W> {
+    out: W,
+    // Bytes written so far
+    offset: u64,
+    entries: Vec<ZipEntry>,
+}
+
+// Past these the zip64 fields are used
+const ZIP_MAX_U32: u64 = 0xffff_ffff;
+const ZIP_MAX_U16: usize = 0xffff;
+// UTF-8 names, and for files the data descriptor
+const ZIP_FLAG_UTF8: u16 = 1 << 11;
+const ZIP_FLAG_DESCRIPTOR: u16 = 1 << 3;
+
+impl<W: io::Write> ZipStream<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    fn local_header(&mut self, entry: &ZipEntry, zip64: bool) -> io::Result<()> {
+        let mut header = Vec::with_capacity(30 + entry.name.len() + 20);
+        header.extend(&0x0403_4b50u32.to_le_bytes());
+        header.extend(&(if zip64 { 45u16 } else { 20u16 }).to_le_bytes());
+        let flags = if entry.is_dir {
+            ZIP_FLAG_UTF8
+        } else {
+            ZIP_FLAG_UTF8 | ZIP_FLAG_DESCRIPTOR
+        };
+        header.extend(&flags.to_le_bytes());
+        // Stored
+        header.extend(&0u16.to_le_bytes());
+        header.extend(&entry.time.0.to_le_bytes());
+        header.extend(&entry.time.1.to_le_bytes());
+        // CRC and sizes, in the data descriptor
+        header.extend(&0u32.to_le_bytes());
+        let size = if zip64 { ZIP_MAX_U32 as u32 } else { 0 };
+        header.extend(&size.to_le_bytes());
+        header.extend(&size.to_le_bytes());
+        header.extend(&(entry.name.len() as u16).to_le_bytes());
+        header.extend(&(if zip64 { 20u16 } else { 0u16 }).to_le_bytes());
+        header.extend(entry.name.as_bytes());
+        if zip64 {
+            header.extend(&1u16.to_le_bytes());
+            header.extend(&16u16.to_le_bytes());
+            header.extend(&[0; 16]);
+        }
+        self.write(&header)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let directory_offset = self.offset;
+        for entry in std::mem::take(&mut self.entries) {
+            let mut extra = Vec::new();
+            if entry.size >= ZIP_MAX_U32 {
+                extra.extend(&entry.size.to_le_bytes());
+                extra.extend(&entry.size.to_le_bytes());
+            }
+            if entry.offset >= ZIP_MAX_U32 {
+                extra.extend(&entry.offset.to_le_bytes());
+            }
+            let zip64 = !extra.is_empty();
+            let mut header = Vec::with_capacity(46 + entry.name.len() + 4 + extra.len());
+            header.extend(&0x0201_4b50u32.to_le_bytes());
+            // Made by Unix
+            header.extend(&(3u16 << 8 | 45).to_le_bytes());
+            header.extend(&(if zip64 { 45u16 } else { 20u16 }).to_le_bytes());
+            let flags = if entry.is_dir {
+                ZIP_FLAG_UTF8
+            } else {
+                ZIP_FLAG_UTF8 | ZIP_FLAG_DESCRIPTOR
+            };
+            header.extend(&flags.to_le_bytes());
+            header.extend(&0u16.to_le_bytes());
+            header.extend(&entry.time.0.to_le_bytes());
+            header.extend(&entry.time.1.to_le_bytes());
+            header.extend(&entry.crc.to_le_bytes());
+            let size = entry.size.min(ZIP_MAX_U32) as u32;
+            header.extend(&size.to_le_bytes());
+            header.extend(&size.to_le_bytes());
+            header.extend(&(entry.name.len() as u16).to_le_bytes());
+            let extra_len = if zip64 { extra.len() + 4 } else { 0 };
+            header.extend(&(extra_len as u16).to_le_bytes());
+            // Comment length, disk, internal attributes
+            header.extend(&[0; 6]);
+            let attributes: u32 = if entry.is_dir {
+                0o40755 << 16 | 0x10
+            } else {
+                0o100644 << 16
+            };
+            header.extend(&attributes.to_le_bytes());
+            header.extend(&(entry.offset.min(ZIP_MAX_U32) as u32).to_le_bytes());
+            header.extend(entry.name.as_bytes());
+            if zip64 {
+                header.extend(&1u16.to_le_bytes());
+                header.extend(&(extra.len() as u16).to_le_bytes());
+                header.extend(&extra);
+            }
+            self.write(&header)?;
+            self.entries.push(entry);
+        }
+        let count = self.entries.len();
+        let directory_size = self.offset - directory_offset;
+
+        let mut end = Vec::new();
+        if count >= ZIP_MAX_U16 || directory_offset >= ZIP_MAX_U32 || directory_size >= ZIP_MAX_U32
+        {
+            let zip64_end_offset = self.offset;
+            end.extend(&0x0606_4b50u32.to_le_bytes());
+            end.extend(&44u64.to_le_bytes());
+            end.extend(&(3u16 << 8 | 45).to_le_bytes());
+            end.extend(&45u16.to_le_bytes());
+            end.extend(&[0; 8]);
+            end.extend(&(count as u64).to_le_bytes());
+            end.extend(&(count as u64).to_le_bytes());
+            end.extend(&directory_size.to_le_bytes());
+            end.extend(&directory_offset.to_le_bytes());
+            end.extend(&0x0706_4b50u32.to_le_bytes());
+            end.extend(&0u32.to_le_bytes());
+            end.extend(&zip64_end_offset.to_le_bytes());
+            end.extend(&1u32.to_le_bytes());
+        }
+        end.extend(&0x0605_4b50u32.to_le_bytes());
+        end.extend(&[0; 4]);
+        let count = count.min(ZIP_MAX_U16) as u16;
+        end.extend(&count.to_le_bytes());
+        end.extend(&count.to_le_bytes());
+        end.extend(&(directory_size.min(ZIP_MAX_U32) as u32).to_le_bytes());
+        end.extend(&(directory_offset.min(ZIP_MAX_U32) as u32).to_le_bytes());
+        end.extend(&0u16.to_le_bytes());
+        self.write(&end)?;
+        self.out.flush()
+    }
+}
+
+impl<W: io::Write> ArchiveSink for ZipStream<W> {
+    fn add_dir(&mut self, name: &str, modified: SystemTime) -> io::Result<()> {
+        let entry = ZipEntry {
+            name: format!("{}/", name),
+            is_dir: true,
+            crc: 0,
+            size: 0,
+            offset: self.offset,
+            time: dos_time(modified),
+        };
+        self.local_header(&entry, false)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn add_file(&mut self, name: &str, metadata: &Metadata, data: &mut dyn Read) -> io::Result<()> {
+        let zip64 = metadata.len >= ZIP_MAX_U32;
+        let mut entry = ZipEntry {
+            name: name.to_owned(),
+            is_dir: false,
+            crc: 0,
+            size: metadata.len,
+            offset: self.offset,
+            time: dos_time(metadata.modified),
+        };
+        self.local_header(&entry, zip64)?;
+
+        let mut crc = Crc::new();
+        let mut data = data.take(metadata.len);
+        let mut buf = vec![0; 64 * 1024];
+        let mut written = 0;
+        loop {
+            let n = match data.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            crc.update(&buf[..n]);
+            self.write(&buf[..n])?;
+            written += n as u64;
+        }
+        if written != metadata.len {
+            return Err(changed(name));
+        }
+        entry.crc = crc.sum();
+
+        let mut descriptor = Vec::with_capacity(24);
+        descriptor.extend(&0x0807_4b50u32.to_le_bytes());
+        descriptor.extend(&entry.crc.to_le_bytes());
+        if zip64 {
+            descriptor.extend(&entry.size.to_le_bytes());
+            descriptor.extend(&entry.size.to_le_bytes());
+        } else {
+            descriptor.extend(&(entry.size as u32).to_le_bytes());
+            descriptor.extend(&(entry.size as u32).to_le_bytes());
+        }
+        self.write(&descriptor)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+}
+
+// MS-DOS (time, date) in local time, from 1980 on
+fn dos_time(time: SystemTime) -> (u16, u16) {
+    let time: DateTime<Local> = time.into();
+    if time.year() < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    (
+        ((time.hour() << 11) | (time.minute() << 5) | (time.second() / 2)) as u16,
+        (((time.year() as u32 - 1980).min(127) << 9) | (time.month() << 5) | time.day()) as u16,
+    )
+}
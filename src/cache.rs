@@ -0,0 +1,90 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use iron::headers::CacheDirective;
+
+const DAY: u32 = 24 * 3600;
+
+/// Preset Cache-Control/Expires combinations
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheProfile {
+    /// max-age: 7 days for everything
+    Default,
+    /// Hashed assets are immutable for a year, HTML lives for 5 minutes
+    StaticSite,
+    /// Nothing is stored by the client or intermediary caches
+    NoStore,
+}
+
+pub struct CachePolicy {
+    pub directives: Vec<CacheDirective>,
+    /// `None` means the response must not be cached at all
+    pub max_age: Option<u32>,
+}
+
+impl CachePolicy {
+    fn public(max_age: u32, immutable: bool) -> CachePolicy {
+        let mut directives = vec![CacheDirective::Public, CacheDirective::MaxAge(max_age)];
+        if immutable {
+            directives.push(CacheDirective::Extension("immutable".to_owned(), None));
+        }
+        CachePolicy {
+            directives,
+            max_age: Some(max_age),
+        }
+    }
+}
+
+impl FromStr for CacheProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CacheProfile, String> {
+        match s {
+            "default" => Ok(CacheProfile::Default),
+            "static-site" => Ok(CacheProfile::StaticSite),
+            "no-store" => Ok(CacheProfile::NoStore),
+            _ => Err(format!("unknown cache profile: {}", s)),
+        }
+    }
+}
+
+impl CacheProfile {
+    pub fn policy(self, path: &Path) -> CachePolicy {
+        match self {
+            CacheProfile::Default => CachePolicy::public(7 * DAY, false),
+            CacheProfile::StaticSite => {
+                if is_html(path) {
+                    CachePolicy::public(300, false)
+                } else if is_hashed(path) {
+                    CachePolicy::public(365 * DAY, true)
+                } else {
+                    CachePolicy::public(DAY, false)
+                }
+            }
+            CacheProfile::NoStore => CachePolicy {
+                directives: vec![CacheDirective::NoStore],
+                max_age: None,
+            },
+        }
+    }
+}
+
+fn is_html(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm"),
+        None => false,
+    }
+}
+
+/// Asset names with a content hash, eg: `app.3f2a9c1b.js`, `main-8a7b6c5d0e.css`
+fn is_hashed(path: &Path) -> bool {
+    let stem = match path.file_stem().and_then(|stem| stem.to_str()) {
+        Some(stem) => stem,
+        None => return false,
+    };
+    stem.split(['.', '-', '_']).any(|part| {
+        part.len() >= 8
+            && part.chars().all(|c| c.is_ascii_hexdigit())
+            && part.chars().any(|c| c.is_ascii_digit())
+    })
+}
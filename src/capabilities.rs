@@ -0,0 +1,75 @@
+use iron::headers::ContentType;
+use iron::status;
+use iron::Response;
+
+use crate::util::json_escape;
+
+/// Enabled features, served on `/-/capabilities` so clients need not know the flags.
+pub struct Capabilities {
+    pub auth: bool,
+    pub api_keys: bool,
+    pub upload: bool,
+    pub sync: bool,
+    pub resumable_upload: bool,
+    pub upload_progress: bool,
+    pub upload_notifications: bool,
+    pub transfers: bool,
+    pub delete: bool,
+    pub webdav: bool,
+    pub search: bool,
+    pub diff: bool,
+    pub tags: bool,
+    pub description: bool,
+    pub range: bool,
+    pub zip_members: bool,
+    pub archive: bool,
+    pub stats: bool,
+    pub image_ops: bool,
+    pub compress: Vec<String>,
+    pub precompressed: bool,
+}
+
+impl Capabilities {
+    pub fn json(&self) -> String {
+        format!(
+            concat!(
+                r#"{{"auth":{},"api_keys":{},"upload":{},"sync":{},"resumable_upload":{},"upload_progress":{},"#,
+                r#""upload_notifications":{},"transfers":{},"delete":{},"#,
+                r#""webdav":{},"search":{},"diff":{},"tags":{},"description":{},"range":{},"#,
+                r#""zip_members":{},"archive":{},"stats":{},"image_ops":{},"compress":[{}],"#,
+                r#""precompressed":{}}}"#,
+            ),
+            self.auth,
+            self.api_keys,
+            self.upload,
+            self.sync,
+            self.resumable_upload,
+            self.upload_progress,
+            self.upload_notifications,
+            self.transfers,
+            self.delete,
+            self.webdav,
+            self.search,
+            self.diff,
+            self.tags,
+            self.description,
+            self.range,
+            self.zip_members,
+            self.archive,
+            self.stats,
+            self.image_ops,
+            self.compress
+                .iter()
+                .map(|ext| format!(r#""{}""#, json_escape(ext)))
+                .collect::<Vec<String>>()
+                .join(","),
+            self.precompressed,
+        )
+    }
+
+    pub fn response(&self) -> Response {
+        let mut resp = Response::with((status::Ok, self.json()));
+        resp.headers.set(ContentType::json());
+        resp
+    }
+}
@@ -0,0 +1,101 @@
+use std::ffi::{OsStr, OsString};
+
+/// `--filename-encoding`: the legacy encoding (GBK, Shift-JIS, CP1252, ...) of the file
+/// names which are not UTF-8, as left by old archives and Windows or Samba shares. Those
+/// names are listed decoded from it, and the UTF-8 paths of the requests are looked up
+/// encoded in it when missing as is. Converted with iconv, so any encoding it knows works.
+pub struct FilenameEncoding {
+    encoding: String,
+}
+
+impl FilenameEncoding {
+    pub fn new(encoding: &str) -> Result<FilenameEncoding, String> {
+        let names = FilenameEncoding {
+            encoding: encoding.to_owned(),
+        };
+        if convert(encoding, "UTF-8", b"a").is_none() {
+            return Err(format!("unsupported file name encoding: {}", encoding));
+        }
+        Ok(names)
+    }
+
+    /// The name in UTF-8, `None` when it is not in the encoding either. Only the names
+    /// encoded back to the same bytes are decoded, others could not be looked up.
+    pub fn decode(&self, name: &OsStr) -> Option<String> {
+        let raw = os_bytes(name)?;
+        let decoded = String::from_utf8(convert(&self.encoding, "UTF-8", raw)?).ok()?;
+        if convert("UTF-8", &self.encoding, decoded.as_bytes())? != raw {
+            return None;
+        }
+        Some(decoded)
+    }
+
+    /// The name as written in the encoding, `None` for the ASCII names (the same in all of
+    /// them) and those it can not represent
+    pub fn encode(&self, name: &str) -> Option<OsString> {
+        if name.is_ascii() {
+            return None;
+        }
+        from_os_bytes(convert("UTF-8", &self.encoding, name.as_bytes())?)
+    }
+}
+
+#[cfg(unix)]
+fn os_bytes(name: &OsStr) -> Option<&[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(name.as_bytes())
+}
+
+#[cfg(unix)]
+fn from_os_bytes(bytes: Vec<u8>) -> Option<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(OsString::from_vec(bytes))
+}
+
+/// `input` converted from the encoding `from` to `to`, `None` when it does not convert
+#[cfg(unix)]
+fn convert(from: &str, to: &str, input: &[u8]) -> Option<Vec<u8>> {
+    use std::ffi::CString;
+    use std::ptr;
+
+    let (to, from) = (CString::new(to).ok()?, CString::new(from).ok()?);
+    let cd = unsafe { libc::iconv_open(to.as_ptr(), from.as_ptr()) };
+    if cd as isize == -1 {
+        return None;
+    }
+    // Enough for any encoding to UTF-8 and back
+    let mut output = vec![0u8; input.len() * 4 + 16];
+    let mut in_ptr = input.as_ptr() as *mut libc::c_char;
+    let mut in_left = input.len();
+    let mut out_ptr = output.as_mut_ptr() as *mut libc::c_char;
+    let mut out_left = output.len();
+    let converted = unsafe {
+        libc::iconv(cd, &mut in_ptr, &mut in_left, &mut out_ptr, &mut out_left) != usize::MAX
+            // Back to the initial shift state, for the stateful encodings
+            && libc::iconv(cd, ptr::null_mut(), ptr::null_mut(), &mut out_ptr, &mut out_left)
+                != usize::MAX
+    };
+    unsafe { libc::iconv_close(cd) };
+    if !converted || in_left != 0 {
+        return None;
+    }
+    output.truncate(output.len() - out_left);
+    Some(output)
+}
+
+// The file names are UTF-16 elsewhere, never in a legacy encoding
+
+#[cfg(not(unix))]
+fn os_bytes(_name: &OsStr) -> Option<&[u8]> {
+    None
+}
+
+#[cfg(not(unix))]
+fn from_os_bytes(_bytes: Vec<u8>) -> Option<OsString> {
+    None
+}
+
+#[cfg(not(unix))]
+fn convert(_from: &str, _to: &str, _input: &[u8]) -> Option<Vec<u8>> {
+    None
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+use tracing::{info, warn};
+
+use crate::util::{random_alphanumeric, sha256_file};
+
+/// Uploads identical to a file already in the tree are stored as a hard link to it
+/// (`--dedupe`). Files are indexed by size, from a walk of the root at startup and as they
+/// are uploaded, so only files of the same size are hashed, and their digests are cached
+/// until they change. Hidden files (temporary and partial uploads, the trash) are skipped.
+pub struct Dedupe {
+    root: PathBuf,
+    by_size: Mutex<HashMap<u64, Vec<PathBuf>>>,
+    digests: Mutex<HashMap<PathBuf, (u64, SystemTime, String)>>,
+}
+
+impl Dedupe {
+    pub fn start(root: PathBuf) -> Arc<Dedupe> {
+        let dedupe = Arc::new(Dedupe {
+            root,
+            by_size: Mutex::new(HashMap::new()),
+            digests: Mutex::new(HashMap::new()),
+        });
+        let indexer = dedupe.clone();
+        thread::spawn(move || {
+            if let Err(err) = indexer.index(&indexer.root) {
+                warn!("Dedupe index failed: {}", err);
+            }
+        });
+        dedupe
+    }
+
+    /// Replace the just uploaded `target` by a hard link to an identical file, if any. This
+    /// never fails the upload, errors are only logged.
+    pub fn link(&self, target: &Path) {
+        if is_hidden(target.strip_prefix(&self.root).unwrap_or(target)) {
+            return;
+        }
+        if let Err(err) = self.try_link(target) {
+            warn!("Dedupe {} failed: {}", target.display(), err);
+        }
+    }
+
+    fn try_link(&self, target: &Path) -> io::Result<()> {
+        let metadata = fs::metadata(target)?;
+        let size = metadata.len();
+        let candidates = self
+            .by_size
+            .lock()
+            .unwrap()
+            .get(&size)
+            .cloned()
+            .unwrap_or_default();
+        let mut digest = None;
+        for candidate in candidates.iter().filter(|candidate| *candidate != target) {
+            let candidate_metadata = match fs::symlink_metadata(candidate) {
+                Ok(m) if m.is_file() && m.len() == size => m,
+                _ => continue,
+            };
+            if !can_link(&metadata, &candidate_metadata) {
+                continue;
+            }
+            let digest = match digest {
+                Some(ref digest) => digest,
+                None => digest.insert(sha256_file(target)?),
+            };
+            if self.digest(candidate, &candidate_metadata)? != *digest {
+                continue;
+            }
+            // Link next to the target then rename it over, so the file is never missing
+            let suffix = random_alphanumeric(8);
+            let tmp_path = target.with_file_name(format!(".{}.link", suffix));
+            fs::hard_link(candidate, &tmp_path)?;
+            if let Err(err) = fs::rename(&tmp_path, target) {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(err);
+            }
+            info!(
+                "Upload deduplicated: {} linked to {}",
+                target.display(),
+                candidate.display()
+            );
+            break;
+        }
+        self.add(target.to_owned(), size);
+        Ok(())
+    }
+
+    fn index(&self, dir: &Path) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                self.index(&entry.path())?;
+            } else if file_type.is_file() {
+                self.add(entry.path(), entry.metadata()?.len());
+            }
+        }
+        Ok(())
+    }
+
+    fn add(&self, path: PathBuf, size: u64) {
+        let mut by_size = self.by_size.lock().unwrap();
+        let paths = by_size.entry(size).or_default();
+        // Drop the files deleted or changed since
+        paths.retain(|path| {
+            fs::symlink_metadata(path)
+                .map(|metadata| metadata.is_file() && metadata.len() == size)
+                .unwrap_or(false)
+        });
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+
+    fn digest(&self, path: &Path, metadata: &fs::Metadata) -> io::Result<String> {
+        let modified = metadata.modified()?;
+        if let Some((size, cached_modified, digest)) = self.digests.lock().unwrap().get(path) {
+            if *size == metadata.len() && *cached_modified == modified {
+                return Ok(digest.clone());
+            }
+        }
+        let digest = sha256_file(path)?;
+        self.digests
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), (metadata.len(), modified, digest.clone()));
+        Ok(digest)
+    }
+}
+
+fn is_hidden(relative: &Path) -> bool {
+    relative
+        .components()
+        .any(|component| component.as_os_str().to_string_lossy().starts_with('.'))
+}
+
+// Hard links only work within a file system, and are pointless to the same file
+#[cfg(unix)]
+fn can_link(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() != b.ino()
+}
+
+#[cfg(not(unix))]
+fn can_link(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    true
+}
@@ -0,0 +1,174 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use htmlescape::encode_minimal;
+use iron::method;
+use iron::status;
+use iron::{IronError, IronResult, Request, Response};
+use path_dedot::ParseDot;
+use percent_encoding::percent_decode;
+use pulldown_cmark::{html, Event, Parser, Tag};
+
+use crate::storage::Storage;
+use crate::util::{encode_link_path, error_io2iron, save_atomic, StringError, WriteLocks};
+
+/// Markdown file describing its directory, rendered above the listing
+pub const DESCRIPTION_FILE: &str = ".description.md";
+/// Descriptions larger than this are neither rendered nor accepted
+const MAX_DESCRIPTION_SIZE: u64 = 64 * 1024;
+
+/// Per directory descriptions, editable on `PUT /-/description/<dir>` when `editable`
+/// (only enabled together with `--auth`, so every editor is authenticated).
+pub struct Descriptions {
+    root: PathBuf,
+    editable: bool,
+    write_locks: Arc<WriteLocks>,
+}
+
+impl Descriptions {
+    pub fn new(root: PathBuf, editable: bool, write_locks: Arc<WriteLocks>) -> Descriptions {
+        Descriptions {
+            root,
+            editable,
+            write_locks,
+        }
+    }
+
+    /// Description (and editor) block for the listing of the directory `path` of `storage`
+    pub fn render(
+        &self,
+        storage: &dyn Storage,
+        path: &Path,
+        path_prefix: &[String],
+        base_url: &str,
+    ) -> String {
+        let path = path.join(DESCRIPTION_FILE);
+        let mut markdown = String::new();
+        match storage.stat(&path) {
+            Ok(metadata) if metadata.is_file && metadata.len <= MAX_DESCRIPTION_SIZE => {
+                if let Ok(mut file) = storage.open_range(&path, 0, None) {
+                    if file.read_to_string(&mut markdown).is_err() {
+                        markdown.clear();
+                    }
+                }
+            }
+            _ => {}
+        }
+        let description = if markdown.is_empty() {
+            "".to_owned()
+        } else {
+            format!(
+                r#"<div style="margin-bottom:1em;">{}</div>"#,
+                markdown_to_html(&markdown)
+            )
+        };
+        if !self.editable {
+            return description;
+        }
+
+        let mut link = path_prefix.to_owned();
+        link.push("".to_owned());
+        format!(
+            r#"{description}
+<details style="margin-bottom:1em;">
+  <summary>Edit description</summary>
+  <textarea id="description" rows="8" cols="80">{markdown}</textarea><br />
+  <button onclick="saveDescription()">Save</button>
+</details>
+<script>
+function saveDescription() {{
+  var xhr = new XMLHttpRequest();
+  xhr.open("PUT", "{base_url}-/description/{link}");
+  xhr.onload = function () {{
+    if (xhr.status == 204) {{
+      location.reload();
+    }} else {{
+      alert("Save description failed: " + xhr.responseText);
+    }}
+  }};
+  xhr.send(document.getElementById("description").value);
+}}
+</script>
+"#,
+            description = description,
+            markdown = encode_minimal(&markdown),
+            base_url = base_url,
+            link = encode_link_path(&link),
+        )
+    }
+
+    pub fn handle(&self, req: &mut Request, path: &[String]) -> IronResult<Response> {
+        if !self.editable {
+            return Err(IronError::new(
+                StringError("description editing requires --auth".to_owned()),
+                status::Forbidden,
+            ));
+        }
+        if req.method != method::Put {
+            return Ok(Response::with(status::MethodNotAllowed));
+        }
+
+        let dir = path
+            .iter()
+            .filter(|s| !s.is_empty())
+            .map(|s| percent_decode(s.as_bytes()).decode_utf8_lossy().to_string())
+            .fold(self.root.clone(), |dir, s| dir.join(s));
+        let dir = dir.parse_dot().unwrap().to_path_buf();
+        if !dir.starts_with(&self.root) {
+            return Err(IronError::new(
+                io::Error::new(io::ErrorKind::PermissionDenied, "Permission Denied"),
+                status::Forbidden,
+            ));
+        }
+        if !dir.is_dir() {
+            return Err(IronError::new(
+                StringError("not a directory".to_owned()),
+                status::NotFound,
+            ));
+        }
+
+        let target = dir.join(DESCRIPTION_FILE);
+        let _lock = self.write_locks.lock(&target)?;
+        let mut markdown = Vec::new();
+        req.body
+            .by_ref()
+            .take(MAX_DESCRIPTION_SIZE + 1)
+            .read_to_end(&mut markdown)
+            .map_err(error_io2iron)?;
+        if markdown.len() as u64 > MAX_DESCRIPTION_SIZE {
+            return Err(IronError::new(
+                StringError("description too large".to_owned()),
+                status::PayloadTooLarge,
+            ));
+        }
+        if markdown.iter().all(u8::is_ascii_whitespace) {
+            match fs::remove_file(&target) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                rv => rv.map_err(error_io2iron)?,
+            }
+        } else {
+            save_atomic(&mut &markdown[..], &dir, &target).map_err(error_io2iron)?;
+        }
+        Ok(Response::with(status::NoContent))
+    }
+}
+
+// Raw HTML is escaped and `javascript:` links are dropped, descriptions are written by users
+fn markdown_to_html(markdown: &str) -> String {
+    let safe = |dest: &str| !dest.trim().to_ascii_lowercase().starts_with("javascript:");
+    let parser = Parser::new(markdown).map(|event| match event {
+        Event::Html(html) => Event::Text(html),
+        Event::Start(Tag::Link(kind, dest, title)) if !safe(&dest) => {
+            Event::Start(Tag::Link(kind, "#".into(), title))
+        }
+        Event::Start(Tag::Image(kind, dest, title)) if !safe(&dest) => {
+            Event::Start(Tag::Image(kind, "#".into(), title))
+        }
+        event => event,
+    });
+    let mut output = String::new();
+    html::push_html(&mut output, parser);
+    output
+}
@@ -0,0 +1,309 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use htmlescape::encode_minimal;
+use iron::headers::ContentType;
+use iron::status;
+use iron::{IronError, IronResult, Request, Response};
+use path_dedot::ParseDot;
+use sha2::{Digest, Sha256};
+
+use crate::middlewares::{check_access, walk_excluded};
+use crate::storage::Storage;
+use crate::util::{
+    brand_html, encode_link_path, error_io2iron, favicon_image, hex, json_escape, root_link,
+    StringError,
+};
+
+struct FileInfo {
+    // Relative to the root of the storage
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+struct Report {
+    only_in_a: Vec<String>,
+    only_in_b: Vec<String>,
+    // (path, reason)
+    differing: Vec<(String, &'static str)>,
+}
+
+/// Directory comparison: `/-/diff?a=/dirA&b=/dirB`
+///
+/// Files are compared by size, then by modified time, or by sha256 content hash when
+/// `hash=1` is given (mirrors rarely keep the modified time). `format=json` replies JSON.
+pub struct DirDiff {
+    storage: Arc<dyn Storage>,
+}
+
+impl DirDiff {
+    pub fn new(storage: Arc<dyn Storage>) -> DirDiff {
+        DirDiff { storage }
+    }
+
+    pub fn handle(&self, req: &mut Request, title: &str, base_url: &str) -> IronResult<Response> {
+        let mut a = None;
+        let mut b = None;
+        let mut hash = false;
+        let mut json = false;
+        for (k, v) in req.url.as_ref().query_pairs() {
+            match k.as_ref() {
+                "a" => a = Some(v.to_string()),
+                "b" => b = Some(v.to_string()),
+                "hash" => hash = v == "1" || v == "true",
+                "format" => json = v == "json",
+                _ => {}
+            }
+        }
+        let (a, b) = match (a, b) {
+            (Some(a), Some(b)) => (a, b),
+            _ => {
+                return Err(IronError::new(
+                    StringError("a and b parameters must be provided".to_owned()),
+                    status::BadRequest,
+                ))
+            }
+        };
+
+        let storage = &*self.storage;
+        let mut files_a = BTreeMap::new();
+        walk(storage, &self.resolve(req, &a)?, "", &mut files_a).map_err(error_io2iron)?;
+        let mut files_b = BTreeMap::new();
+        walk(storage, &self.resolve(req, &b)?, "", &mut files_b).map_err(error_io2iron)?;
+        let report = compare(storage, &files_a, &files_b, hash).map_err(error_io2iron)?;
+
+        if json {
+            let mut resp = Response::with((status::Ok, report.json(&a, &b)));
+            resp.headers.set(ContentType::json());
+            Ok(resp)
+        } else {
+            let mut resp = Response::with((status::Ok, report.html(&a, &b, title, base_url)));
+            resp.headers.set(ContentType::html());
+            Ok(resp)
+        }
+    }
+
+    /// The storage path of the directory `path`, below the root and allowed by the
+    /// `--access-files` rules
+    fn resolve(&self, req: &Request, path: &str) -> IronResult<PathBuf> {
+        let relative = Path::new(path.trim_start_matches('/'))
+            .parse_dot()
+            .unwrap()
+            .to_path_buf();
+        if relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            return Err(IronError::new(
+                io::Error::new(io::ErrorKind::PermissionDenied, "Permission Denied"),
+                status::Forbidden,
+            ));
+        }
+        check_access(req, &relative)?;
+        if !self
+            .storage
+            .stat(&relative)
+            .is_ok_and(|metadata| metadata.is_dir)
+        {
+            return Err(IronError::new(
+                StringError(format!("not a directory: {}", path)),
+                status::BadRequest,
+            ));
+        }
+        Ok(relative)
+    }
+}
+
+// Collect regular files under `dir` by their `/` separated relative path, through the
+// storage so that all the `--overlay` layers are compared. Symlinked directories are not
+// followed.
+fn walk(
+    storage: &dyn Storage,
+    dir: &Path,
+    prefix: &str,
+    files: &mut BTreeMap<String, FileInfo>,
+) -> io::Result<()> {
+    for entry in storage.list(dir)? {
+        let path = dir.join(&entry.name);
+        let local_path = storage.local_path(&path);
+        if local_path.as_deref().is_some_and(walk_excluded) {
+            continue;
+        }
+        let name = format!("{}{}", prefix, entry.name);
+        if entry.metadata.is_dir {
+            if local_path.is_some_and(|path| {
+                fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+            }) {
+                continue;
+            }
+            walk(storage, &path, &format!("{}/", name), files)?;
+        } else if entry.metadata.is_file {
+            files.insert(
+                name,
+                FileInfo {
+                    path,
+                    size: entry.metadata.len,
+                    modified: entry.metadata.modified,
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+fn sha256(storage: &dyn Storage, path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut storage.open_range(path, 0, None)?, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+fn compare(
+    storage: &dyn Storage,
+    files_a: &BTreeMap<String, FileInfo>,
+    files_b: &BTreeMap<String, FileInfo>,
+    hash: bool,
+) -> io::Result<Report> {
+    let mut report = Report {
+        only_in_a: Vec::new(),
+        only_in_b: Vec::new(),
+        differing: Vec::new(),
+    };
+    let names = files_a
+        .keys()
+        .chain(files_b.keys())
+        .collect::<BTreeSet<&String>>();
+    for name in names {
+        match (files_a.get(name), files_b.get(name)) {
+            (Some(a), Some(b)) => {
+                if a.size != b.size {
+                    report.differing.push((name.clone(), "size"));
+                } else if hash {
+                    if sha256(storage, &a.path)? != sha256(storage, &b.path)? {
+                        report.differing.push((name.clone(), "hash"));
+                    }
+                } else if a.modified != b.modified {
+                    report.differing.push((name.clone(), "mtime"));
+                }
+            }
+            (Some(_), None) => report.only_in_a.push(name.clone()),
+            (None, Some(_)) => report.only_in_b.push(name.clone()),
+            (None, None) => unreachable!(),
+        }
+    }
+    Ok(report)
+}
+
+impl Report {
+    fn json(&self, a: &str, b: &str) -> String {
+        let names = |names: &[String]| {
+            names
+                .iter()
+                .map(|name| format!(r#""{}""#, json_escape(name)))
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        let differing = self
+            .differing
+            .iter()
+            .map(|(name, reason)| {
+                format!(
+                    r#"{{"path":"{}","reason":"{}"}}"#,
+                    json_escape(name),
+                    reason
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+        format!(
+            r#"{{"a":"{}","b":"{}","only_in_a":[{}],"only_in_b":[{}],"differing":[{}]}}"#,
+            json_escape(a),
+            json_escape(b),
+            names(&self.only_in_a),
+            names(&self.only_in_b),
+            differing,
+        )
+    }
+
+    fn html(&self, a: &str, b: &str, title: &str, base_url: &str) -> String {
+        let link = |dir: &str, name: &str| {
+            let path = dir
+                .split('/')
+                .chain(name.split('/'))
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_owned())
+                .collect::<Vec<String>>();
+            format!(
+                r#"<a href="{}{}">{}</a>"#,
+                base_url,
+                encode_link_path(&path),
+                encode_minimal(name)
+            )
+        };
+        let only_rows = |dir: &str, names: &[String]| {
+            names
+                .iter()
+                .map(|name| format!("<tr><td>{}</td></tr>", link(dir, name)))
+                .collect::<Vec<String>>()
+                .join("\n")
+        };
+        let differing_rows = self
+            .differing
+            .iter()
+            .map(|(name, reason)| {
+                format!(
+                    "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    link(a, name),
+                    link(b, name),
+                    reason
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  {favicon_image}
+  <title>{title} · Diff</title>
+</head>
+<body>
+  {brand}
+  {root_link}
+  <hr />
+  <h3>Only in {a} ({only_in_a_count})</h3>
+  <table>
+    {only_in_a}
+  </table>
+  <h3>Only in {b} ({only_in_b_count})</h3>
+  <table>
+    {only_in_b}
+  </table>
+  <h3>Differing ({differing_count})</h3>
+  <table>
+    <tr><th>{a}</th><th>{b}</th><th>Reason</th></tr>
+    {differing}
+  </table>
+</body>
+</html>
+"#,
+            favicon_image = favicon_image(),
+            brand = brand_html(),
+            title = encode_minimal(title),
+            root_link = root_link(base_url),
+            a = encode_minimal(a),
+            b = encode_minimal(b),
+            only_in_a_count = self.only_in_a.len(),
+            only_in_a = only_rows(a, &self.only_in_a),
+            only_in_b_count = self.only_in_b.len(),
+            only_in_b = only_rows(b, &self.only_in_b),
+            differing_count = self.differing.len(),
+            differing = differing_rows,
+        )
+    }
+}
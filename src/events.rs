@@ -0,0 +1,179 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use iron::headers::ContentType;
+use iron::status;
+use iron::{Headers, IronError, IronResult, Request, Response};
+
+use crate::middlewares::request_user;
+use crate::util::{json_escape, StringError};
+
+/// Most recent events kept, a viewer polling less often misses the older ones
+const MAX_EVENTS: usize = 256;
+
+struct Event {
+    id: u64,
+    // `upload`, `copy` or `move`
+    kind: &'static str,
+    // Root relative paths, `/` separated, `from` is the source of copies and moves
+    path: String,
+    from: Option<String>,
+    user: Option<String>,
+}
+
+#[derive(Default)]
+struct Log {
+    last: u64,
+    events: VecDeque<Event>,
+}
+
+/// The uploads, copies and moves done recently, with the progress of the transfers, served
+/// on `GET /-/events?since=<id>` when uploads are enabled. With `--upload-notifications`
+/// the listings poll it to show who uploaded what and to refresh themselves. Polled rather
+/// than streamed, a held connection would take one of the few `--threads` for every open
+/// listing.
+#[derive(Default)]
+pub struct ChangeEvents {
+    log: Mutex<Log>,
+}
+
+impl ChangeEvents {
+    /// Record the upload of `path` by the user authenticated in `headers`
+    pub fn uploaded(&self, headers: &Headers, path: &str) {
+        self.record("upload", path, None, request_user(headers));
+    }
+
+    /// Record a change of `path` (copied or moved `from` there) by `user`
+    pub fn record(&self, kind: &'static str, path: &str, from: Option<&str>, user: Option<String>) {
+        let mut log = self.log.lock().unwrap();
+        log.last += 1;
+        let id = log.last;
+        if log.events.len() >= MAX_EVENTS {
+            log.events.pop_front();
+        }
+        log.events.push_back(Event {
+            id,
+            kind,
+            path: path.replace('\\', "/"),
+            from: from.map(|from| from.replace('\\', "/")),
+            user,
+        });
+    }
+
+    /// The events after `since`, or none but the last id to start from without it, and the
+    /// `transfers` JSON array
+    pub fn handle(&self, req: &Request, transfers: &str) -> IronResult<Response> {
+        let since = req
+            .url
+            .as_ref()
+            .query_pairs()
+            .find(|(k, _)| k == "since")
+            .map(|(_, v)| {
+                v.parse::<u64>().map_err(|_err| {
+                    IronError::new(
+                        StringError(format!("invalid since: {}", v)),
+                        status::BadRequest,
+                    )
+                })
+            })
+            .transpose()?;
+        let log = self.log.lock().unwrap();
+        let string = |value: &Option<String>| {
+            value
+                .as_ref()
+                .map(|value| format!(r#""{}""#, json_escape(value)))
+                .unwrap_or_else(|| "null".to_owned())
+        };
+        let events = log
+            .events
+            .iter()
+            .filter(|event| since.is_some_and(|since| event.id > since))
+            .map(|event| {
+                format!(
+                    r#"{{"id":{},"kind":"{}","path":"{}","from":{},"user":{}}}"#,
+                    event.id,
+                    event.kind,
+                    json_escape(&event.path),
+                    string(&event.from),
+                    string(&event.user),
+                )
+            })
+            .collect::<Vec<String>>();
+        let mut resp = Response::with((
+            status::Ok,
+            format!(
+                r#"{{"last":{},"events":[{}],"transfers":{}}}"#,
+                log.last,
+                events.join(","),
+                transfers
+            ),
+        ));
+        resp.headers.set(ContentType::json());
+        resp.headers
+            .set_raw("Cache-Control", vec![b"no-store".to_vec()]);
+        Ok(resp)
+    }
+}
+
+/// Toasts of the uploads, copies and moves of others and refresh of the listing when they
+/// change its directory, expects `EVENTS = { base, dir }` to be defined.
+pub const UPLOAD_NOTIFICATIONS_SCRIPT: &str = r#"
+(function () {
+  var POLL_INTERVAL = 5000;
+  var last = null;
+
+  var toasts = document.createElement("div");
+  toasts.style.cssText = "position:fixed; right:1em; bottom:1em; z-index:10;";
+  document.body.appendChild(toasts);
+
+  function toast(text) {
+    var el = document.createElement("div");
+    el.textContent = text;
+    el.style.cssText = "margin-top:0.5em; padding:0.5em 1em; background:#333; color:#fff; border-radius:4px;";
+    toasts.appendChild(el);
+    setTimeout(function () { toasts.removeChild(el); }, 6000);
+  }
+
+  function refresh() {
+    var xhr = new XMLHttpRequest();
+    xhr.open("GET", location.href);
+    xhr.setRequestHeader("Accept", "text/html");
+    xhr.onload = function () {
+      if (xhr.status != 200) return;
+      var page = new DOMParser().parseFromString(xhr.responseText, "text/html");
+      var table = page.querySelector("table");
+      if (table) document.querySelector("table").replaceWith(table);
+    };
+    xhr.send();
+  }
+
+  function poll() {
+    var xhr = new XMLHttpRequest();
+    xhr.open("GET", EVENTS.base + "-/events" + (last === null ? "" : "?since=" + last));
+    xhr.onload = function () {
+      if (xhr.status == 200) {
+        var data = JSON.parse(xhr.responseText);
+        var changed = false;
+        data.events.forEach(function (event) {
+          var who = event.user || "Someone";
+          if (event.kind == "upload") {
+            toast(who + " uploaded " + event.path.substring(event.path.lastIndexOf("/") + 1));
+          } else {
+            toast(who + (event.kind == "move" ? " moved " : " copied ") + event.from + " to " + event.path);
+          }
+          [event.path, event.kind == "move" ? event.from : null].forEach(function (path) {
+            if (path !== null && path.substring(0, path.lastIndexOf("/") + 1) == EVENTS.dir) changed = true;
+          });
+        });
+        if (changed) refresh();
+        last = data.last;
+      }
+      setTimeout(poll, POLL_INTERVAL);
+    };
+    xhr.onerror = function () { setTimeout(poll, POLL_INTERVAL); };
+    xhr.send();
+  }
+
+  poll();
+})();
+"#;
@@ -0,0 +1,258 @@
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+
+use iron::headers::ContentLength;
+use iron::method::Method;
+use iron::status;
+use iron::typemap::Key;
+use iron::{Handler, Headers, Protocol, Request, Timeouts};
+
+use hyper::net::{Fresh, HttpStream, NetworkListener};
+use hyper::server::{Listening, Request as HttpRequest, Response as HttpResponse, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use hyper::version::HttpVersion;
+use tracing::{field, info, info_span};
+
+use crate::middlewares::AuthChecker;
+
+/// Hyper handler running the iron chain (as iron's own `RawHandler` does), which also answers
+/// `Expect: 100-continue` with the final status when the request is going to be rejected
+/// anyway, so clients do not send the body for nothing.
+pub struct ExpectContinue<H> {
+    handler: H,
+    addr: SocketAddr,
+    protocol: Protocol,
+    tls: bool,
+    auth: Option<Arc<AuthChecker>>,
+    upload_size_limit: u64,
+    limits: RequestLimits,
+}
+
+/// Limits of the request head and body (`--max-header-size`, `--max-uri-length`,
+/// `--max-body-size`), checked before the request is handled. Hyper itself refuses heads
+/// over ~400 KiB by closing the connection.
+#[derive(Clone, Copy, Default)]
+pub struct RequestLimits {
+    /// Total size of the header names and values
+    pub max_header_size: Option<usize>,
+    pub max_uri_length: Option<usize>,
+    /// Of any request, uploads are also subject to the upload size limit
+    pub max_body_size: Option<u64>,
+}
+
+impl RequestLimits {
+    fn check(&self, uri: &RequestUri, headers: &Headers) -> Option<StatusCode> {
+        if let Some(max) = self.max_uri_length {
+            if uri.to_string().len() > max {
+                return Some(status::UriTooLong);
+            }
+        }
+        if let Some(max) = self.max_header_size {
+            let size = headers
+                .iter()
+                .map(|header| header.name().len() + header.value_string().len())
+                .sum::<usize>();
+            if size > max {
+                return Some(status::RequestHeaderFieldsTooLarge);
+            }
+        }
+        match (self.max_body_size, headers.get::<ContentLength>()) {
+            (Some(max), Some(&ContentLength(length))) if length > max => {
+                Some(status::PayloadTooLarge)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How the client is connected, stored in the request extensions
+///
+/// TLS connections never negotiate a protocol with ALPN (only HTTP/1.x is served), and
+/// the native-tls backend does not expose the TLS version nor the cipher.
+#[derive(Clone, Copy)]
+pub struct Connection {
+    pub protocol: &'static str,
+    pub tls: bool,
+}
+
+impl Connection {
+    pub fn label(&self) -> String {
+        if self.tls {
+            format!("{} (TLS)", self.protocol)
+        } else {
+            self.protocol.to_owned()
+        }
+    }
+}
+
+impl Key for Connection {
+    type Value = Connection;
+}
+
+/// The client's socket, to stop working for a client which went away (eg: a cancelled
+/// download) instead of only noticing on the next write. Plain HTTP only, TLS streams are
+/// not probed.
+pub struct ClientSocket(TcpStream);
+
+impl Key for ClientSocket {
+    type Value = ClientSocket;
+}
+
+impl ClientSocket {
+    /// Whether the client closed or reset the connection, HTTP/1 clients do not half-close
+    /// while waiting for the response
+    #[cfg(unix)]
+    pub fn is_gone(&self) -> bool {
+        use std::os::unix::io::AsRawFd;
+        let mut buf = [0u8; 1];
+        let n = unsafe {
+            libc::recv(
+                self.0.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        match n {
+            0 => true,
+            n if n > 0 => false,
+            _ => !matches!(
+                io::Error::last_os_error().kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+            ),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn is_gone(&self) -> bool {
+        false
+    }
+}
+
+/// Whether the client of `req` went away, so long running work can stop early
+pub fn client_gone(req: &Request) -> bool {
+    req.extensions
+        .get::<ClientSocket>()
+        .is_some_and(ClientSocket::is_gone)
+}
+
+/// Serve `handler` on `listener`, with iron's default timeouts
+pub fn listen<H, L>(
+    handler: H,
+    mut listener: L,
+    tls: bool,
+    auth: Option<Arc<AuthChecker>>,
+    upload_size_limit: u64,
+    limits: RequestLimits,
+    threads: usize,
+) -> hyper::Result<Listening>
+where
+    H: Handler,
+    L: 'static + NetworkListener + Send,
+{
+    let handler = ExpectContinue {
+        handler,
+        addr: listener.local_addr()?,
+        protocol: Protocol::http(),
+        tls,
+        auth,
+        upload_size_limit,
+        limits,
+    };
+    let timeouts = Timeouts::default();
+    let mut server = Server::new(listener);
+    server.keep_alive(timeouts.keep_alive);
+    server.set_read_timeout(timeouts.read);
+    server.set_write_timeout(timeouts.write);
+    server.handle_threads(handler, threads)
+}
+
+impl<H: Handler> hyper::server::Handler for ExpectContinue<H> {
+    fn handle(&self, http_req: HttpRequest, mut http_res: HttpResponse<Fresh>) {
+        *http_res.status_mut() = status::InternalServerError;
+        let connection = Connection {
+            protocol: match http_req.version {
+                HttpVersion::Http09 => "http/0.9",
+                HttpVersion::Http10 => "http/1.0",
+                HttpVersion::Http11 => "http/1.1",
+                HttpVersion::Http20 => "h2",
+            },
+            tls: self.tls,
+        };
+        if let Some(status) = self.limits.check(&http_req.uri, &http_req.headers) {
+            info!("Request rejected: {}", status);
+            // The body is not read, the connection can not be reused
+            *http_res.status_mut() = status;
+            http_res
+                .headers_mut()
+                .set(hyper::header::Connection::close());
+            let _ = http_res.send(status.canonical_reason().unwrap_or("").as_bytes());
+            return;
+        }
+        let socket = http_req
+            .downcast_ref::<HttpStream>()
+            .and_then(|stream| stream.0.try_clone().ok())
+            .map(ClientSocket);
+        match Request::from_http(http_req, self.addr, &self.protocol) {
+            Ok(mut req) => {
+                let span = info_span!(
+                    "request",
+                    method = %req.method,
+                    path = req.url.as_ref().path(),
+                    protocol = connection.protocol,
+                    status = field::Empty,
+                );
+                let _enter = span.enter();
+                req.extensions.insert::<Connection>(connection);
+                if let Some(socket) = socket {
+                    req.extensions.insert::<ClientSocket>(socket);
+                }
+                let resp = self.handler.handle(&mut req).unwrap_or_else(|e| e.response);
+                if let Some(status) = resp.status {
+                    span.record("status", status.to_u16());
+                }
+                // Do not open the body (file, archive) for nobody
+                if client_gone(&req) {
+                    info!("Client gone, response dropped");
+                    return;
+                }
+                info_span!("write_body").in_scope(|| resp.write_back(http_res))
+            }
+            Err(_) => {
+                *http_res.status_mut() = status::BadRequest;
+                if let Ok(res) = http_res.start() {
+                    let _ = res.end();
+                }
+            }
+        }
+    }
+
+    fn check_continue(
+        &self,
+        (method, uri, headers): (&Method, &RequestUri, &Headers),
+    ) -> StatusCode {
+        if let Some(status) = self.limits.check(uri, headers) {
+            return status;
+        }
+        if let Some(ref auth) = self.auth {
+            let uri = match uri {
+                RequestUri::AbsolutePath(path) => path.clone(),
+                uri => uri.to_string(),
+            };
+            if auth.rejects_early(method, &uri, headers) {
+                return status::Unauthorized;
+            }
+        }
+        match (method, headers.get::<ContentLength>()) {
+            (&Method::Post, Some(&ContentLength(length)))
+            | (&Method::Put, Some(&ContentLength(length)))
+                if length > self.upload_size_limit =>
+            {
+                status::PayloadTooLarge
+            }
+            _ => status::Continue,
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::str::FromStr;
+
+use rand::{thread_rng, Rng};
+
+/// Longest file name (in bytes) accepted by common file systems
+const MAX_NAME_LEN: usize = 255;
+/// Device names Windows reserves whatever the extension (eg: `nul.txt`)
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// How the file name of a multipart upload becomes the name on disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilenamePolicy {
+    /// The name sent by the client, as is
+    Keep,
+    /// The base name without control characters, characters invalid on Windows, leading
+    /// dots, reserved device names, and shortened to 255 bytes
+    Sanitize,
+    /// A random UUID, keeping the sanitized extension
+    Uuid,
+}
+
+impl FromStr for FilenamePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FilenamePolicy, String> {
+        match s {
+            "keep" => Ok(FilenamePolicy::Keep),
+            "sanitize" => Ok(FilenamePolicy::Sanitize),
+            "uuid" => Ok(FilenamePolicy::Uuid),
+            _ => Err(format!("unknown upload filename policy: {}", s)),
+        }
+    }
+}
+
+impl FilenamePolicy {
+    pub fn apply(self, name: &str) -> String {
+        match self {
+            FilenamePolicy::Keep => name.to_owned(),
+            FilenamePolicy::Sanitize => sanitize(name),
+            FilenamePolicy::Uuid => {
+                let sanitized = sanitize(name);
+                match sanitized.rsplit_once('.') {
+                    Some((stem, ext)) if !stem.is_empty() => format!("{}.{}", uuid(), ext),
+                    _ => uuid(),
+                }
+            }
+        }
+    }
+}
+
+fn sanitize(name: &str) -> String {
+    // Some clients send the full path of the file (eg: `C:\Users\me\a.txt`)
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect::<String>();
+    // No hidden files, `.`/`..`, or trailing dots and spaces (dropped by Windows)
+    let mut name = name
+        .trim_start_matches(['.', ' '])
+        .trim_end_matches(['.', ' '])
+        .to_owned();
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.trim_end().eq_ignore_ascii_case(reserved))
+    {
+        name.insert(0, '_');
+    }
+    if name.is_empty() {
+        name.push_str("upload");
+    }
+    truncate(&name, MAX_NAME_LEN)
+}
+
+// Shorten the stem to fit in `max` bytes, keeping the extension when it is short
+fn truncate(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_owned();
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() < 16 => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    let mut end = max - ext.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], ext)
+}
+
+// Random (version 4) UUID
+fn uuid() -> String {
+    let mut bytes: [u8; 16] = thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
@@ -0,0 +1,202 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use htmlescape::encode_minimal;
+use iron::headers::ContentType;
+use iron::status;
+use iron::{IronError, IronResult, Request, Response};
+use regex::Regex;
+
+use crate::expect::client_gone;
+use crate::middlewares::walk_excluded;
+use crate::storage::{Entry, Storage};
+use crate::util::{brand_html, encode_link_path, favicon_image, root_link, StringError};
+
+/// Stop searching after this many matching lines
+const MAX_MATCHES: usize = 1000;
+/// Matching lines longer than this are truncated in the result page
+const MAX_LINE_LENGTH: usize = 500;
+/// Files with a NUL byte in this many leading bytes are considered binary
+const BINARY_CHECK_SIZE: usize = 8000;
+
+struct Match {
+    // Path segments relative to root
+    path: Vec<String>,
+    line_number: usize,
+    line: String,
+}
+
+/// Recursive text search on directories: `/dir/?grep=pattern`
+pub struct Grep {
+    storage: Arc<dyn Storage>,
+    max_file_size: u64,
+}
+
+impl Grep {
+    pub fn new(storage: Arc<dyn Storage>, max_file_size: u64) -> Grep {
+        Grep {
+            storage,
+            max_file_size,
+        }
+    }
+
+    pub fn search(
+        &self,
+        req: &Request,
+        dir: &Path,
+        path_prefix: &[String],
+        pattern: &str,
+        title: &str,
+        base_url: &str,
+    ) -> IronResult<Response> {
+        let regex = Regex::new(pattern).map_err(|err| {
+            IronError::new(
+                StringError(format!("invalid pattern: {}", err)),
+                status::BadRequest,
+            )
+        })?;
+        let mut matches = Vec::new();
+        let gone = || client_gone(req);
+        self.walk(dir, path_prefix.to_vec(), &regex, &gone, &mut matches);
+
+        let rows = matches
+            .iter()
+            .map(|m| {
+                let mut line = m.line.clone();
+                if line.len() > MAX_LINE_LENGTH {
+                    let mut end = MAX_LINE_LENGTH;
+                    while !line.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    line.truncate(end);
+                    line.push('…');
+                }
+                format!(
+                    r#"<tr><td><a href="{base_url}{link}#L{line_number}">{name}:{line_number}</a></td><td><code>{line}</code></td></tr>"#,
+                    base_url = base_url,
+                    link = encode_link_path(&m.path),
+                    name = encode_minimal(&m.path[path_prefix.len()..].join("/")),
+                    line_number = m.line_number,
+                    line = encode_minimal(&line),
+                )
+            })
+            .collect::<Vec<String>>();
+        let truncated = if matches.len() >= MAX_MATCHES {
+            format!("<p>Only the first {} matches are shown.</p>", MAX_MATCHES)
+        } else {
+            "".to_owned()
+        };
+        let mut resp = Response::with((
+            status::Ok,
+            format!(
+                r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  {favicon_image}
+  <title>{title} · Search</title>
+</head>
+<body>
+  {brand}
+  {root_link}
+  <hr />
+  <div>{count} matches of <code>{pattern}</code> in <a href="{base_url}{dir}">/{dir}</a></div>
+  {truncated}
+  <table>
+    {rows}
+  </table>
+</body>
+</html>
+"#,
+                favicon_image = favicon_image(),
+                brand = brand_html(),
+                title = encode_minimal(title),
+                root_link = root_link(base_url),
+                count = matches.len(),
+                pattern = encode_minimal(pattern),
+                base_url = base_url,
+                dir = encode_link_path(path_prefix),
+                truncated = truncated,
+                rows = rows.join("\n"),
+            ),
+        ));
+        resp.headers.set(ContentType::html());
+        Ok(resp)
+    }
+
+    // Walks the storage, so that all the `--overlay` layers are searched. Unreadable
+    // entries are skipped, symlinked directories are not followed.
+    fn walk(
+        &self,
+        dir: &Path,
+        path: Vec<String>,
+        regex: &Regex,
+        gone: &dyn Fn() -> bool,
+        matches: &mut Vec<Match>,
+    ) {
+        let storage = &*self.storage;
+        let mut entries = match storage.list(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        for entry in entries {
+            if matches.len() >= MAX_MATCHES || gone() {
+                return;
+            }
+            let entry_fs_path = dir.join(&entry.name);
+            let local_path = storage.local_path(&entry_fs_path);
+            if local_path.as_deref().is_some_and(walk_excluded) {
+                continue;
+            }
+            let mut entry_path = path.clone();
+            entry_path.push(entry.name.clone());
+            if entry.metadata.is_dir {
+                let symlink = local_path.is_some_and(|path| {
+                    fs::symlink_metadata(path)
+                        .is_ok_and(|metadata| metadata.file_type().is_symlink())
+                });
+                if !symlink {
+                    self.walk(&entry_fs_path, entry_path, regex, gone, matches);
+                }
+            } else {
+                let _ = self.search_file(&entry_fs_path, &entry, entry_path, regex, matches);
+            }
+        }
+    }
+
+    fn search_file(
+        &self,
+        file: &Path,
+        entry: &Entry,
+        path: Vec<String>,
+        regex: &Regex,
+        matches: &mut Vec<Match>,
+    ) -> io::Result<()> {
+        if !entry.metadata.is_file || entry.metadata.len > self.max_file_size {
+            return Ok(());
+        }
+        let mut content = Vec::new();
+        self.storage
+            .open_range(file, 0, Some(self.max_file_size))?
+            .read_to_end(&mut content)?;
+        if content[..content.len().min(BINARY_CHECK_SIZE)].contains(&0) {
+            return Ok(());
+        }
+        for (idx, line) in String::from_utf8_lossy(&content).lines().enumerate() {
+            if matches.len() >= MAX_MATCHES {
+                break;
+            }
+            if regex.is_match(line) {
+                matches.push(Match {
+                    path: path.clone(),
+                    line_number: idx + 1,
+                    line: line.to_owned(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
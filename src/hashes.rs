@@ -0,0 +1,187 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use tracing::warn;
+
+use crate::storage::Metadata;
+use crate::util::sha256_file;
+
+/// Files hashed at the same time
+const WORKERS: usize = 2;
+/// Hex digits shown in the listing, the full digest is in the title and copied
+const SHORT_LEN: usize = 12;
+
+// The digest is only valid for this version of the file
+#[derive(Clone, PartialEq)]
+struct Version {
+    size: u64,
+    modified: (u64, u32),
+}
+
+impl Version {
+    fn of(metadata: &Metadata) -> Option<Version> {
+        let modified = metadata.modified.duration_since(UNIX_EPOCH).ok()?;
+        Some(Version {
+            size: metadata.len,
+            modified: (modified.as_secs(), modified.subsec_nanos()),
+        })
+    }
+}
+
+#[derive(Default)]
+struct State {
+    digests: HashMap<String, (Version, String)>,
+    queue: VecDeque<(String, PathBuf, Version)>,
+    queued: HashSet<String>,
+}
+
+/// SHA-256 digests of files shown in listings (`--show-hash`), computed by background
+/// workers and cached by root relative path, size and modified time. With a database file
+/// the digests survive restarts, it is appended `<path>\t<size>\t<mtime>\t<digest>` lines.
+pub struct Hashes {
+    db: Option<Mutex<fs::File>>,
+    state: Mutex<State>,
+    queued: Condvar,
+}
+
+impl Hashes {
+    pub fn start(db: Option<PathBuf>) -> io::Result<Arc<Hashes>> {
+        let mut state = State::default();
+        let db = match db {
+            Some(path) => {
+                state.digests = load_digests(&path)?;
+                // Compact the entries of files hashed several times
+                let tmp_path = path.with_extension("tmp");
+                let mut file = fs::File::create(&tmp_path)?;
+                for (path, (version, digest)) in &state.digests {
+                    write_digest(&mut file, path, version, digest)?;
+                }
+                file.sync_all()?;
+                fs::rename(&tmp_path, &path)?;
+                Some(Mutex::new(fs::OpenOptions::new().append(true).open(&path)?))
+            }
+            None => None,
+        };
+        let hashes = Arc::new(Hashes {
+            db,
+            state: Mutex::new(state),
+            queued: Condvar::new(),
+        });
+        for _ in 0..WORKERS {
+            let hashes = hashes.clone();
+            thread::spawn(move || hashes.work());
+        }
+        Ok(hashes)
+    }
+
+    /// Digest of the file `fs_path` at the root relative `path`, `None` while it is being
+    /// computed
+    pub fn get(&self, path: &str, fs_path: &Path, metadata: &Metadata) -> Option<String> {
+        let version = Version::of(metadata)?;
+        let mut state = self.state.lock().unwrap();
+        match state.digests.get(path) {
+            Some((cached, digest)) if *cached == version => return Some(digest.clone()),
+            _ => {}
+        }
+        if state.queued.insert(path.to_owned()) {
+            state
+                .queue
+                .push_back((path.to_owned(), fs_path.to_owned(), version));
+            self.queued.notify_one();
+        }
+        None
+    }
+
+    /// Listing cell of a file, with a button copying the full digest
+    pub fn cell(&self, path: &str, fs_path: &Path, metadata: &Metadata) -> String {
+        if !metadata.is_file {
+            return String::new();
+        }
+        match self.get(path, fs_path, metadata) {
+            Some(digest) => format!(
+                r#"<code title="{digest}">{short}</code> <button onclick="navigator.clipboard.writeText('{digest}')">Copy</button>"#,
+                digest = digest,
+                short = &digest[..SHORT_LEN],
+            ),
+            None => r#"<small style="color:#888;">computing...</small>"#.to_owned(),
+        }
+    }
+
+    fn work(&self) {
+        loop {
+            let (path, fs_path, version) = {
+                let mut state = self.state.lock().unwrap();
+                loop {
+                    match state.queue.pop_front() {
+                        Some(job) => break job,
+                        None => state = self.queued.wait(state).unwrap(),
+                    }
+                }
+            };
+            let result = self.hash(&path, &fs_path, &version);
+            let mut state = self.state.lock().unwrap();
+            state.queued.remove(&path);
+            match result {
+                Ok(Some(digest)) => {
+                    state.digests.insert(path, (version, digest));
+                }
+                // Changed while hashing, the next listing queues it again
+                Ok(None) => {}
+                Err(err) => warn!("Hash {} failed: {}", path, err),
+            }
+        }
+    }
+
+    fn hash(&self, path: &str, fs_path: &Path, version: &Version) -> io::Result<Option<String>> {
+        let digest = sha256_file(fs_path)?;
+        if Version::of(&Metadata::from_fs(fs_path, &fs::metadata(fs_path)?)).as_ref()
+            != Some(version)
+        {
+            return Ok(None);
+        }
+        if let Some(ref db) = self.db {
+            write_digest(&mut *db.lock().unwrap(), path, version, &digest)?;
+        }
+        Ok(Some(digest))
+    }
+}
+
+fn write_digest(w: &mut dyn Write, path: &str, version: &Version, digest: &str) -> io::Result<()> {
+    writeln!(
+        w,
+        "{}\t{}\t{}.{:09}\t{}",
+        path, version.size, version.modified.0, version.modified.1, digest
+    )
+}
+
+fn load_digests(path: &Path) -> io::Result<HashMap<String, (Version, String)>> {
+    let mut digests = HashMap::new();
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(digests),
+        Err(e) => return Err(e),
+    };
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let parts = line.rsplitn(4, '\t').collect::<Vec<&str>>();
+        if parts.len() != 4 {
+            continue;
+        }
+        let (digest, modified, size, path) = (parts[0], parts[1], parts[2], parts[3]);
+        let (secs, nanos) = modified.split_once('.').unwrap_or((modified, "0"));
+        if let (Ok(size), Ok(secs), Ok(nanos)) = (size.parse(), secs.parse(), nanos.parse()) {
+            let version = Version {
+                size,
+                modified: (secs, nanos),
+            };
+            // Later lines are more recent
+            digests.insert(path.to_owned(), (version, digest.to_owned()));
+        }
+    }
+    Ok(digests)
+}
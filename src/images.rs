@@ -0,0 +1,381 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use iron::headers::{Accept, Quality};
+use iron::Request;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::scan::{shell, shell_quote};
+use crate::storage::{FsStorage, Metadata, Storage};
+use crate::util::{hex, random_alphanumeric};
+
+/// Largest width or height that can be asked for
+const MAX_DIMENSION: u32 = 8192;
+/// Longest a conversion waits for a free slot, also the `Retry-After` of the ones turned away
+pub const QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Extensions of the images that can be resized
+const RESIZABLE: &[&str] = &["jpg", "jpeg", "png", "gif", "webp"];
+/// Extensions of the images few browsers show, with their types, transcoded for the others
+const TRANSCODED: &[(&str, &str)] = &[
+    ("heic", "image/heic"),
+    ("heif", "image/heif"),
+    ("avif", "image/avif"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Fit {
+    /// Within the box, keeping the aspect ratio
+    Contain,
+    /// Covering the box, keeping the aspect ratio, cropped around the center
+    Cover,
+    /// Stretched to the box
+    Fill,
+}
+
+/// `?w=800&h=600&fit=contain` of an image request
+#[derive(Debug)]
+pub struct Resize {
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+}
+
+impl Resize {
+    /// The size asked for in the query of `req`, `None` without `w` nor `h`
+    pub fn from_query(req: &Request) -> Result<Option<Resize>, String> {
+        let mut resize = Resize {
+            width: None,
+            height: None,
+            fit: Fit::Contain,
+        };
+        let dimension = |v: &str| match v.parse::<u32>() {
+            Ok(n) if n > 0 && n <= MAX_DIMENSION => Ok(n),
+            _ => Err(format!("invalid image dimension: {}", v)),
+        };
+        for (k, v) in req.url.as_ref().query_pairs() {
+            match &*k {
+                "w" => resize.width = Some(dimension(&v)?),
+                "h" => resize.height = Some(dimension(&v)?),
+                "fit" => {
+                    resize.fit = match &*v {
+                        "contain" => Fit::Contain,
+                        "cover" => Fit::Cover,
+                        "fill" => Fit::Fill,
+                        _ => return Err(format!("invalid image fit: {}", v)),
+                    }
+                }
+                _ => {}
+            }
+        }
+        if resize.width.is_none() && resize.height.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(resize))
+    }
+
+    /// ImageMagick options producing this size
+    fn magick_options(&self) -> String {
+        let geometry = format!(
+            "{}x{}",
+            self.width.map(|w| w.to_string()).unwrap_or_default(),
+            self.height.map(|h| h.to_string()).unwrap_or_default()
+        );
+        match (self.fit, self.width, self.height) {
+            (Fit::Cover, Some(_), Some(_)) => {
+                format!("-resize {}^ -gravity center -extent {}", geometry, geometry)
+            }
+            (Fit::Fill, Some(_), Some(_)) => format!("-resize {}!", geometry),
+            // With one side only, the other follows the aspect ratio whatever the fit
+            _ => format!("-resize {}", geometry),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Slots {
+    active: usize,
+    waiting: usize,
+}
+
+/// Resized and transcoded images (`--image-ops`), produced by a shell command and kept in a
+/// cache directory under a name derived from the source file's version, the size and the
+/// format asked for. The command gets `{input}` and `{output}` (whose extension gives the
+/// format), and `{resize}` (the ImageMagick options) or `{width}`, `{height}` and `{fit}`,
+/// empty when not resizing.
+///
+/// Any client can ask for any size, so the commands run at most one per two CPUs, as many
+/// more waiting up to `QUEUE_TIMEOUT` (`ResourceBusy` for the others), and the least
+/// recently served results are removed once the cache exceeds `cache_size` bytes.
+pub struct ImageOps {
+    command: String,
+    cache_dir: PathBuf,
+    cache: FsStorage,
+    cache_size: u64,
+    // Bytes in the cache directory
+    cache_used: Mutex<u64>,
+    max_conversions: usize,
+    slots: Mutex<Slots>,
+    freed: Condvar,
+}
+
+impl ImageOps {
+    pub fn new(command: &str, cache_dir: PathBuf, cache_size: u64) -> io::Result<ImageOps> {
+        fs::create_dir_all(&cache_dir)?;
+        let cache_used = cached_files(&cache_dir)?
+            .iter()
+            .map(|(_, len, _)| len)
+            .sum();
+        let max_conversions = thread::available_parallelism()
+            .map(|n| n.get() / 2)
+            .unwrap_or(1)
+            .max(1);
+        Ok(ImageOps {
+            command: command.to_owned(),
+            cache: FsStorage::new(cache_dir.clone(), Vec::new(), None, false),
+            cache_dir,
+            cache_size,
+            cache_used: Mutex::new(cache_used),
+            max_conversions,
+            slots: Mutex::new(Slots::default()),
+            freed: Condvar::new(),
+        })
+    }
+
+    /// Whether the file `path` is an image that can be resized or transcoded
+    pub fn is_image(path: &Path) -> bool {
+        let ext = extension(path);
+        RESIZABLE.contains(&ext.as_str()) || TRANSCODED.iter().any(|(e, _)| *e == ext)
+    }
+
+    /// Whether the file `path` is served as is or transcoded depending on `Accept`
+    pub fn is_transcoded(path: &Path) -> bool {
+        let ext = extension(path);
+        TRANSCODED.iter().any(|(e, _)| *e == ext)
+    }
+
+    /// Extension of the format the image `path` is transcoded to for the client of `req`,
+    /// `None` when it shows the image as is. Wildcards do not count, browsers accept
+    /// `image/*` without being able to show these formats.
+    pub fn fallback_format(path: &Path, req: &Request) -> Option<&'static str> {
+        let ext = extension(path);
+        let mime = TRANSCODED.iter().find(|(e, _)| *e == ext)?.1;
+        let accepted = |mime: &str| match req.headers.get::<Accept>() {
+            Some(Accept(items)) => items
+                .iter()
+                .any(|item| item.quality > Quality(0) && item.item.to_string() == mime),
+            None => false,
+        };
+        if accepted(mime) {
+            None
+        } else if accepted("image/webp") {
+            Some("webp")
+        } else {
+            Some("jpg")
+        }
+    }
+
+    /// The results, served as files of their own
+    pub fn cache(&self) -> &dyn Storage {
+        &self.cache
+    }
+
+    /// Path in `cache()` of the image `fs_path` resized and in the `format` given (an
+    /// extension), the command is only run when the cache does not have it yet
+    pub fn convert(
+        &self,
+        fs_path: &Path,
+        metadata: &Metadata,
+        resize: Option<&Resize>,
+        format: Option<&str>,
+    ) -> io::Result<PathBuf> {
+        let ext = format
+            .map(str::to_owned)
+            .unwrap_or_else(|| extension(fs_path));
+        let modified = metadata
+            .modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let key = hex(&Sha256::digest(
+            format!(
+                "{}\t{}\t{}.{}\t{:?}\t{}",
+                fs_path.display(),
+                metadata.len,
+                modified.as_secs(),
+                modified.subsec_nanos(),
+                resize,
+                ext
+            )
+            .as_bytes(),
+        ));
+        let name = PathBuf::from(format!("{}.{}", &key[..32], ext));
+        let output = self.cache_dir.join(&name);
+        if output.is_file() {
+            // Recently served, the last to be evicted
+            let _ = fs::File::options()
+                .write(true)
+                .open(&output)
+                .and_then(|file| file.set_modified(SystemTime::now()));
+            return Ok(name);
+        }
+        let _slot = self.acquire()?;
+        // Produced by a request waiting for the same one
+        if output.is_file() {
+            return Ok(name);
+        }
+
+        // Produced next to its final place then renamed, never served half written
+        let suffix = random_alphanumeric(8);
+        let tmp_path = self
+            .cache_dir
+            .join(format!(".{}.{}", suffix, name.display()));
+        let command = self
+            .command
+            .replace("{input}", &shell_quote(&fs_path.to_string_lossy()))
+            .replace("{output}", &shell_quote(&tmp_path.to_string_lossy()))
+            .replace(
+                "{resize}",
+                &resize.map(Resize::magick_options).unwrap_or_default(),
+            )
+            .replace(
+                "{width}",
+                &resize
+                    .and_then(|resize| resize.width)
+                    .map(|w| w.to_string())
+                    .unwrap_or_default(),
+            )
+            .replace(
+                "{height}",
+                &resize
+                    .and_then(|resize| resize.height)
+                    .map(|h| h.to_string())
+                    .unwrap_or_default(),
+            )
+            .replace(
+                "{fit}",
+                &resize
+                    .map(|resize| format!("{:?}", resize.fit).to_lowercase())
+                    .unwrap_or_default(),
+            );
+        let result = shell(&command).output().and_then(|output| {
+            if output.status.success() && tmp_path.is_file() {
+                Ok(())
+            } else {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                Err(io::Error::other(format!(
+                    "image command failed: {}",
+                    stderr.lines().next().unwrap_or("no output")
+                )))
+            }
+        });
+        match result.and_then(|_| fs::rename(&tmp_path, &output)) {
+            Ok(()) => {
+                let len = fs::metadata(&output).map(|metadata| metadata.len());
+                self.evict(len.unwrap_or(0), &output);
+                Ok(name)
+            }
+            Err(err) => {
+                let _ = fs::remove_file(&tmp_path);
+                Err(err)
+            }
+        }
+    }
+
+    /// A conversion slot, `ResourceBusy` when none frees up in time
+    fn acquire(&self) -> io::Result<ConversionSlot<'_>> {
+        let busy = || io::Error::new(io::ErrorKind::ResourceBusy, "too many image conversions");
+        let mut slots = self.slots.lock().unwrap();
+        if slots.active >= self.max_conversions {
+            if slots.waiting >= self.max_conversions {
+                return Err(busy());
+            }
+            slots.waiting += 1;
+            let deadline = Instant::now() + QUEUE_TIMEOUT;
+            while slots.active >= self.max_conversions {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                slots = self.freed.wait_timeout(slots, deadline - now).unwrap().0;
+            }
+            slots.waiting -= 1;
+            if slots.active >= self.max_conversions {
+                return Err(busy());
+            }
+        }
+        slots.active += 1;
+        Ok(ConversionSlot(self))
+    }
+
+    /// Count the `added` bytes of `kept`, removing the least recently used results while the
+    /// cache is over its size
+    fn evict(&self, added: u64, kept: &Path) {
+        let mut used = self.cache_used.lock().unwrap();
+        *used += added;
+        if *used <= self.cache_size {
+            return;
+        }
+        let mut files = match cached_files(&self.cache_dir) {
+            Ok(files) => files,
+            Err(err) => {
+                warn!("Image cache {}: {}", self.cache_dir.display(), err);
+                return;
+            }
+        };
+        *used = files.iter().map(|(_, len, _)| len).sum();
+        files.sort_by_key(|(_, _, modified)| *modified);
+        let mut evicted = 0;
+        for (path, len, _) in files {
+            if *used <= self.cache_size {
+                break;
+            }
+            if path != kept && fs::remove_file(&path).is_ok() {
+                *used -= len;
+                evicted += 1;
+            }
+        }
+        info!("Image cache over its size, {} images evicted", evicted);
+    }
+}
+
+/// Held while an image command runs
+struct ConversionSlot<'a>(&'a ImageOps);
+
+impl Drop for ConversionSlot<'_> {
+    fn drop(&mut self) {
+        self.0.slots.lock().unwrap().active -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// The results in the cache directory with their size and last use, leaving out the ones
+/// being produced
+fn cached_files(dir: &Path) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            files.push((
+                entry.path(),
+                metadata.len(),
+                metadata.modified().unwrap_or(UNIX_EPOCH),
+            ));
+        }
+    }
+    Ok(files)
+}
+
+/// Lowercase extension of `path`
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
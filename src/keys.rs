@@ -0,0 +1,333 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use iron::headers::{Authorization, Bearer};
+use iron::typemap::Key;
+use iron::Headers;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::util::{hex, random_alphanumeric};
+
+/// Prefix of the generated keys, to tell them apart in scripts and secret scanners
+const KEY_PREFIX: &str = "shs_";
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Scope {
+    /// GET, HEAD, PROPFIND and the read-only `/-/` endpoints
+    Read,
+    /// Uploads, WebDAV writes, copies and moves
+    Write,
+    /// DELETE and restoring from the trash
+    Delete,
+    /// `/-/admin/`, in place of the `X-Admin-Token` header
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Scope, String> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "delete" => Ok(Scope::Delete),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!(
+                "unknown scope {} (read, write, delete or admin)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Delete => "delete",
+            Scope::Admin => "admin",
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct ApiKey {
+    pub name: String,
+    // Hex encoded sha256 of the key, the key itself is only shown when created
+    hash: String,
+    pub scopes: Vec<Scope>,
+    // Root relative paths without leading nor trailing `/`, the whole tree when empty
+    pub prefixes: Vec<String>,
+}
+
+impl ApiKey {
+    /// `--token SECRET[:SCOPES]`, numbered from 1 for its name, read and write by default
+    pub fn token(arg: &str, number: usize) -> Result<ApiKey, String> {
+        let (secret, scopes) = match arg.rsplit_once(':') {
+            Some((secret, scopes)) => (
+                secret,
+                split_list(scopes)
+                    .map(str::parse)
+                    .collect::<Result<Vec<Scope>, String>>()?,
+            ),
+            None => (arg, vec![Scope::Read, Scope::Write]),
+        };
+        if secret.is_empty() {
+            return Err("no token found".to_owned());
+        }
+        Ok(ApiKey {
+            name: format!("token-{}", number),
+            hash: hash_key(secret),
+            scopes,
+            prefixes: Vec::new(),
+        })
+    }
+
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    /// Whether the root relative `path` (`/` separated) is below one of the prefixes
+    pub fn covers(&self, path: &str) -> bool {
+        let path = path.trim_matches('/');
+        self.prefixes.is_empty()
+            || self.prefixes.iter().any(|prefix| {
+                path == prefix
+                    || (path.starts_with(prefix.as_str())
+                        && path.as_bytes().get(prefix.len()) == Some(&b'/'))
+            })
+    }
+}
+
+/// Name of the key a request was authenticated with, set by `ApiKeyChecker`
+pub struct KeyAuth;
+
+impl Key for KeyAuth {
+    type Value = String;
+}
+
+/// API keys (`--api-keys`) for the automation which should not share the `--auth`
+/// credentials, sent as `Authorization: Bearer <key>`. Each key is limited to its scopes and
+/// path prefixes. Kept in a file of `<name>\t<sha256>\t<scopes>\t<prefixes>` lines, managed
+/// with the `keys` subcommand and reloaded when it changes, so that keys are added and
+/// revoked without restarting. The `--token` ones are given on the command line instead.
+pub struct ApiKeys {
+    db: Option<PathBuf>,
+    tokens: Vec<ApiKey>,
+    keys: Mutex<(Option<SystemTime>, Vec<ApiKey>)>,
+}
+
+impl ApiKeys {
+    pub fn load(db: PathBuf) -> io::Result<ApiKeys> {
+        let modified = modified(&db);
+        let keys = load_keys(&db)?;
+        Ok(ApiKeys {
+            db: Some(db),
+            tokens: Vec::new(),
+            keys: Mutex::new((modified, keys)),
+        })
+    }
+
+    /// Only the `--token` keys, without a file
+    pub fn from_tokens(tokens: Vec<ApiKey>) -> ApiKeys {
+        ApiKeys {
+            db: None,
+            tokens,
+            keys: Mutex::new((None, Vec::new())),
+        }
+    }
+
+    pub fn with_tokens(mut self, tokens: Vec<ApiKey>) -> ApiKeys {
+        self.tokens = tokens;
+        self
+    }
+
+    /// The key sent as bearer token in `headers`, `Some(None)` for an unknown key
+    pub fn authenticate(&self, headers: &Headers) -> Option<Option<ApiKey>> {
+        let token = headers.get::<Authorization<Bearer>>()?;
+        let hash = hash_key(&token.token);
+        if let Some(key) = self.tokens.iter().find(|key| key.hash == hash) {
+            return Some(Some(key.clone()));
+        }
+        let db = match self.db {
+            Some(ref db) => db,
+            None => return Some(None),
+        };
+        let mut keys = self.keys.lock().unwrap();
+        let modified = modified(db);
+        if modified != keys.0 {
+            // Revoked keys must not survive an unreadable file
+            let reloaded = load_keys(db).unwrap_or_else(|err| {
+                warn!("Can not reload API keys {}: {}", db.display(), err);
+                Vec::new()
+            });
+            *keys = (modified, reloaded);
+        }
+        Some(keys.1.iter().find(|key| key.hash == hash).cloned())
+    }
+}
+
+fn hash_key(key: &str) -> String {
+    hex(&Sha256::digest(key.as_bytes()))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn split_list(s: &str) -> impl Iterator<Item = &str> {
+    s.split(',').filter(|item| !item.is_empty())
+}
+
+fn load_keys(path: &Path) -> io::Result<Vec<ApiKey>> {
+    let mut keys = Vec::new();
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(keys),
+        Err(e) => return Err(e),
+    };
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let parts = line.splitn(4, '\t').collect::<Vec<&str>>();
+        if parts.len() != 4 {
+            continue;
+        }
+        let scopes = split_list(parts[2])
+            .map(str::parse)
+            .collect::<Result<Vec<Scope>, String>>()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        keys.push(ApiKey {
+            name: parts[0].to_owned(),
+            hash: parts[1].to_owned(),
+            scopes,
+            prefixes: split_list(parts[3]).map(str::to_owned).collect(),
+        });
+    }
+    Ok(keys)
+}
+
+fn save_keys(path: &Path, keys: &[ApiKey]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    for key in keys {
+        let scopes = key
+            .scopes
+            .iter()
+            .map(Scope::to_string)
+            .collect::<Vec<String>>();
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}",
+            key.name,
+            key.hash,
+            scopes.join(","),
+            key.prefixes.join(",")
+        )?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// The `keys` subcommand: `add`, `list` and `revoke` the keys of the file
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let db = PathBuf::from(matches.value_of("file").unwrap());
+    match manage(&db, matches) {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("keys: {}", err);
+            1
+        }
+    }
+}
+
+fn manage(db: &Path, matches: &clap::ArgMatches) -> Result<(), String> {
+    let mut keys = load_keys(db).map_err(|err| format!("{}: {}", db.display(), err))?;
+    match matches.subcommand() {
+        ("add", Some(matches)) => {
+            let name = matches.value_of("name").unwrap();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(format!("invalid name: {:?}", name));
+            }
+            if keys.iter().any(|key| key.name == name) {
+                return Err(format!("key {} already exists", name));
+            }
+            let mut scopes = matches
+                .values_of("scope")
+                .unwrap()
+                .flat_map(split_list)
+                .map(str::parse)
+                .collect::<Result<Vec<Scope>, String>>()?;
+            scopes.sort();
+            scopes.dedup();
+            let prefixes = matches
+                .values_of("prefix")
+                .map(|values| {
+                    values
+                        .map(|prefix| prefix.trim_matches('/'))
+                        .map(|prefix| {
+                            let invalid = prefix.contains(['\t', ',', '\\'])
+                                || prefix
+                                    .split('/')
+                                    .any(|s| s.is_empty() || s == "." || s == "..");
+                            if invalid {
+                                Err(format!("invalid prefix: {:?}", prefix))
+                            } else {
+                                Ok(prefix.to_owned())
+                            }
+                        })
+                        .collect::<Result<Vec<String>, String>>()
+                })
+                .transpose()?
+                .unwrap_or_default();
+            let secret = format!("{}{}", KEY_PREFIX, random_alphanumeric(32));
+            keys.push(ApiKey {
+                name: name.to_owned(),
+                hash: hash_key(&secret),
+                scopes,
+                prefixes,
+            });
+            save_keys(db, &keys).map_err(|err| format!("{}: {}", db.display(), err))?;
+            // The only time the key is shown
+            println!("{}", secret);
+        }
+        ("list", _) => {
+            for key in &keys {
+                let scopes = key
+                    .scopes
+                    .iter()
+                    .map(Scope::to_string)
+                    .collect::<Vec<String>>();
+                let prefixes = if key.prefixes.is_empty() {
+                    "/".to_owned()
+                } else {
+                    key.prefixes
+                        .iter()
+                        .map(|prefix| format!("/{}", prefix))
+                        .collect::<Vec<String>>()
+                        .join(",")
+                };
+                println!("{}\t{}\t{}", key.name, scopes.join(","), prefixes);
+            }
+        }
+        ("revoke", Some(matches)) => {
+            let name = matches.value_of("name").unwrap();
+            let count = keys.len();
+            keys.retain(|key| key.name != name);
+            if keys.len() == count {
+                return Err(format!("no key named {}", name));
+            }
+            save_keys(db, &keys).map_err(|err| format!("{}: {}", db.display(), err))?;
+        }
+        _ => return Err("expected add, list or revoke".to_owned()),
+    }
+    Ok(())
+}
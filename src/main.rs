@@ -1,43 +1,143 @@
+mod accounts;
+#[cfg(feature = "acme")]
+mod acme;
+mod admin;
+mod archive;
+mod cache;
+mod capabilities;
+mod charset;
 mod color;
+mod dedupe;
+mod description;
+mod diff;
+mod events;
+mod expect;
+mod filename;
+mod grep;
+mod hashes;
+mod images;
+mod keys;
+mod manifest;
 mod middlewares;
+mod mirror;
+mod negative;
+mod playlist;
+mod progress;
+mod scan;
+#[cfg(feature = "openssl")]
+mod selfsigned;
+mod selftest;
+#[cfg(feature = "signing")]
+mod signing;
+mod sniff;
+mod stats;
+mod storage;
+mod sync;
+mod tags;
+mod tiering;
+#[cfg(feature = "rustls")]
+mod tls;
+mod trace;
+mod transfer;
+mod trash;
 mod util;
+mod webdav;
 
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::env;
 use std::fs;
-use std::io::{self, Read, Seek, SeekFrom};
+use std::io::{self, BufReader, Read, Write};
 use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use chrono::format::{Item, StrftimeItems};
+use chrono::Local;
 use clap::crate_version;
 use htmlescape::encode_minimal;
+use hyper::net::HttpListener;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use hyper::net::HttpsListener;
 use iron::headers;
 use iron::headers::{AcceptEncoding, ContentEncoding, Encoding, QualityItem};
 use iron::method;
+use iron::mime::{Mime, SubLevel, TopLevel};
 use iron::modifiers::Redirect;
+use iron::response::WriteBody;
 use iron::status;
 use iron::status::Status;
-use iron::{Chain, Handler, Iron, IronError, IronResult, Request, Response, Set};
-use iron_cors::CorsMiddleware;
+use iron::{AroundMiddleware, Chain, Handler, IronError, IronResult, Request, Response, Set};
 use lazy_static::lazy_static;
 use mime_guess as mime_types;
-use multipart::server::{Multipart, SaveResult};
+use multipart::server::save::SaveDir;
+use multipart::server::{Entries, Multipart, SaveResult};
 use path_dedot::ParseDot;
-use percent_encoding::percent_decode;
+use percent_encoding::{percent_decode, utf8_percent_encode, NON_ALPHANUMERIC};
 use pretty_bytes::converter::convert;
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
 use termcolor::{Color, ColorSpec};
+use tracing::{info, info_span, warn};
 
+#[cfg(feature = "ldap")]
+use accounts::LdapAccounts;
+#[cfg(unix)]
+use accounts::PamAccounts;
+use accounts::{AuthProvider, CommandAccounts, Htpasswd, StaticAccount};
+#[cfg(feature = "acme")]
+use acme::{AcmeConfig, AcmeServer};
+use admin::{Admin, RuntimeState, Toggle};
+use archive::{send_dir_archive, send_zip_member, split_zip_member, view_archive, DirArchive};
+use cache::CacheProfile;
+use capabilities::Capabilities;
+use charset::FilenameEncoding;
 use color::{build_spec, Printer};
+use dedupe::Dedupe;
+use description::Descriptions;
+use diff::DirDiff;
+use events::{ChangeEvents, UPLOAD_NOTIFICATIONS_SCRIPT};
+use expect::RequestLimits;
+use filename::FilenamePolicy;
+use grep::Grep;
+use hashes::Hashes;
+use images::{ImageOps, Resize};
+use keys::{ApiKey, ApiKeys, KeyAuth};
+use mirror::Mirror;
+use negative::NegativeCache;
+use playlist::is_subtitle;
+use progress::UploadProgress;
+use scan::{is_rejected, Scanner};
+#[cfg(feature = "openssl")]
+use selfsigned::SelfSigned;
+#[cfg(feature = "signing")]
+use signing::Signer;
+use sniff::AllowedTypes;
+use stats::{Stats, StatsRecorder};
+use storage::{Check, Entry, FsStorage, MemoryStorage, Metadata, Storage};
+use sync::{check_token, SyncUpload, RESUMABLE_UPLOAD_SCRIPT};
+use tags::Tags;
+use tiering::ColdStorage;
+use transfer::{TransferKind, Transfers};
+use trash::Trash;
 use util::{
-    enable_string, encode_link_path, error_io2iron, error_resp, now_string, root_link,
-    system_time_to_date_time, StringError, FAVICON_IMAGE,
+    brand_html, can_write, csv_field, enable_string, encode_link_path, error_io2iron, error_reply,
+    favicon_image, file_etag, glob_match, is_not_modified, json_escape, load_branding, now_string,
+    parse_size, prefers_json, random_alphanumeric, relative_time, root_link,
+    system_time_to_date_time, tsv_field, ErrorDetail, StringError, WriteLocks,
+    RELATIVE_TIME_SCRIPT,
 };
+use webdav::WebDav;
 
-use middlewares::{AuthChecker, CompressionHandler, RequestLogger};
+use middlewares::{
+    is_access_file, record_stat, vary_on, AccessFiles, AccessSchedule, ApiKeyChecker, AuthChecker,
+    AuthTimer, CompressionHandler, Cors, EncodedBody, ErrorPage, FileBody, HeadHandler,
+    HostChecker, IpFilter, IpRange, MaintenanceChecker, QuotaChecker, Rate, ReadOnlyChecker,
+    RequestLogger, SlowLog, SlowRequestLogger, Throttle, UploadSlots, VaryHandler, Waf,
+};
+#[cfg(unix)]
+use middlewares::{raise_nofile_limit, FdLimit};
 
 const ORDER_ASC: &str = "asc";
 const ORDER_DESC: &str = "desc";
@@ -64,6 +164,52 @@ fn main() {
                  }
              })
              .help("Root directory"))
+        .arg(clap::Arg::with_name("overlay")
+             .long("overlay")
+             .takes_value(true)
+             .value_name("DIR")
+             .multiple(true)
+             .number_of_values(1)
+             .conflicts_with("root")
+             .validator(|s| {
+                 match fs::metadata(s) {
+                     Ok(metadata) if metadata.is_dir() => Ok(()),
+                     Ok(_) => Err("Not directory".to_owned()),
+                     Err(e) => Err(e.to_string())
+                 }
+             })
+             .help("Serve several directories merged as one root, later layers hiding the files of earlier ones, changes go to the last one\n    Example: --overlay /srv/release --overlay /srv/hotfixes"))
+        .arg(clap::Arg::with_name("tmpfs")
+             .long("tmpfs")
+             .takes_value(true)
+             .value_name("SIZE")
+             .conflicts_with_all(&[
+                 "root", "overlay", "read-only", "upload-tmp-dir", "scan-command",
+                 "upload-allow-types", "dedupe", "delete", "zip-members", "access-files",
+                 "description", "diff", "image-ops", "filename-encoding",
+             ])
+             .validator(|s| parse_size(&s).map(|_| ()).map_err(|e| e.to_string()))
+             .help("Serve an empty in-memory root accepting uploads of up to SIZE in total, they never touch the disk and are gone on exit\n    Example: --tmpfs 512M"))
+        .arg(clap::Arg::with_name("filename-encoding")
+             .long("filename-encoding")
+             .takes_value(true)
+             .value_name("ENCODING")
+             .validator(|s| FilenameEncoding::new(&s).map(|_| ()))
+             .help("Encoding of the file names which are not UTF-8 (Unix), listed and served under their decoded name instead of a mangled one\n    Example: --filename-encoding GBK (or SHIFT_JIS, CP1252, ... any known to iconv)"))
+        .arg(clap::Arg::with_name("stat-concurrency")
+             .long("stat-concurrency")
+             .takes_value(true)
+             .default_value("1")
+             .value_name("THREADS")
+             .conflicts_with("tmpfs")
+             .validator(|s| {
+                 match s.parse::<usize>() {
+                     Ok(0) => Err("must be at least 1".to_owned()),
+                     Ok(_) => Ok(()),
+                     Err(e) => Err(e.to_string())
+                 }
+             })
+             .help("Stat the entries of large directories from this many threads when listing them, much faster on network filesystems (NFS, SMB, sshfs)\n    Example: --stat-concurrency 16"))
         .arg(clap::Arg::with_name("index")
              .short("i")
              .long("index")
@@ -71,7 +217,11 @@ fn main() {
         .arg(clap::Arg::with_name("upload")
              .short("u")
              .long("upload")
-             .help("Enable upload files. (multiple select) (CSRF token required)"))
+             .help("Enable upload files. (multiple select) (CSRF token required)\n    Batch upload: POST a \"<sha256> <size> <path>\" manifest to /-/sync, then PUT the missing files to /-/sync/<path>\n    Resumable upload: PUT chunks to /-/sync/<path> with \"Content-Range: bytes <start>-<end>/<size>\" (or ?offset=<start>&total=<size>), \"bytes */<size>\" replies the bytes received\n    Server-side copy/move: POST /-/copy or /-/move?from=<path>&to=<path>, progress on /-/events, cancel with POST /-/cancel?id=<id>"))
+        .arg(clap::Arg::with_name("read-only")
+             .long("read-only")
+             .conflicts_with("upload")
+             .help("Refuse to start unless root is truly not writable (eg: a read-only bind mount), reply 405 to all mutating methods"))
         .arg(clap::Arg::with_name("redirect").long("redirect")
              .takes_value(true)
              .validator(|url_string| iron::Url::parse(url_string.as_str()).map(|_| ()))
@@ -79,9 +229,125 @@ fn main() {
         .arg(clap::Arg::with_name("nosort")
              .long("nosort")
              .help("Disable directory entries sort (by: name, modified, size)"))
+        .arg(clap::Arg::with_name("date-format")
+             .long("date-format")
+             .takes_value(true)
+             .value_name("FORMAT")
+             .validator(|s| {
+                 if StrftimeItems::new(&s).any(|item| item == Item::Error) {
+                     Err("invalid strftime format".to_owned())
+                 } else {
+                     Ok(())
+                 }
+             })
+             .help("Show the absolute modified time with this strftime format instead of a relative one\n    Example: --date-format '%Y-%m-%dT%H:%M:%S%:z' (ISO 8601)"))
+        .arg(clap::Arg::with_name("zip-members")
+             .long("zip-members")
+             .help("Serve members of zip archives, eg: /bundle.zip!/docs/index.html"))
+        .arg(clap::Arg::with_name("archive")
+             .long("archive")
+             .help("Download directories as one archive, streamed as it is packed, from links in listings or with ?archive=zip (or tar, tar.gz)"))
+        .arg(clap::Arg::with_name("image-ops")
+             .long("image-ops")
+             .help("Resize images (jpg, png, gif, webp, heic, heif, avif) on request with ?w=800&h=600&fit=contain (or cover, fill), and transcode HEIC/HEIF/AVIF to WebP or JPEG for clients not accepting them, the results are cached"))
+        .arg(clap::Arg::with_name("image-command")
+             .long("image-command")
+             .takes_value(true)
+             .value_name("COMMAND")
+             .default_value("convert {input} {resize} {output}")
+             .help("Shell command converting {input} into {output} (in the format of its extension), given {resize} (ImageMagick options) or {width}, {height} and {fit}, empty when not resizing"))
+        .arg(clap::Arg::with_name("image-cache")
+             .long("image-cache")
+             .takes_value(true)
+             .value_name("DIR")
+             .requires("image-ops")
+             .help("Directory keeping the resized images [default: simple-http-server-images in the OS temporary directory]"))
+        .arg(clap::Arg::with_name("image-cache-size")
+             .long("image-cache-size")
+             .takes_value(true)
+             .value_name("SIZE")
+             .default_value("512m")
+             .validator(|s| parse_size(&s).map(|_| ()).map_err(|e| e.to_string()))
+             .help("Size of the resized images cache, the least recently served are removed past it"))
+        .arg(clap::Arg::with_name("max-header-size")
+            .long("max-header-size")
+            .takes_value(true)
+            .value_name("SIZE")
+            .validator(|s| parse_size(&s).map(|_| ()).map_err(|e| e.to_string()))
+            .help("Reply 431 to requests whose header names and values exceed this size\n    Example: --max-header-size 16k"))
+        .arg(clap::Arg::with_name("max-uri-length")
+            .long("max-uri-length")
+            .takes_value(true)
+            .value_name("LENGTH")
+            .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+            .help("Reply 414 to requests whose URI is longer than this many bytes"))
+        .arg(clap::Arg::with_name("max-body-size")
+            .long("max-body-size")
+            .takes_value(true)
+            .value_name("SIZE")
+            .validator(|s| parse_size(&s).map(|_| ()).map_err(|e| e.to_string()))
+            .help("Reply 413 to requests (of any kind) with a larger Content-Length, uploads are also limited by --upload-size-limit"))
+        .arg(clap::Arg::with_name("read-buffer-size")
+             .long("read-buffer-size")
+             .takes_value(true)
+             .value_name("SIZE")
+             .validator(|s| match parse_size(&s) {
+                 Ok(0) => Err("read buffer size must not be 0".to_owned()),
+                 Ok(_) => Ok(()),
+                 Err(e) => Err(e.to_string()),
+             })
+             .help("Read served files in chunks of this size, larger chunks help HDD/NAS roots\n    Example: --read-buffer-size 1m"))
+        .arg(clap::Arg::with_name("fadvise-sequential")
+             .long("fadvise-sequential")
+             .help("Hint the kernel that served files are read sequentially, for more read-ahead (Linux only)"))
+        .arg(clap::Arg::with_name("negative-cache")
+             .long("negative-cache")
+             .takes_value(true)
+             .value_name("SECONDS")
+             .validator(|s| {
+                 match s.parse::<u64>() {
+                     Ok(0) => Err("must be at least 1".to_owned()),
+                     Ok(_) => Ok(()),
+                     Err(e) => Err(e.to_string())
+                 }
+             })
+             .help("Reply 404 to the paths found missing in the last SECONDS without looking them up again, for slow network roots hammered by scanners (files created meanwhile are seen at once on Linux)\n    Example: --negative-cache 10"))
+        .arg(clap::Arg::with_name("cold-storage")
+             .long("cold-storage")
+             .help("Reply 503 with Retry-After to the downloads of files on an offline tier (next to a <name>.archived marker, with the --cold-xattr attribute, or offline on Windows) instead of waiting for their recall"))
+        .arg(clap::Arg::with_name("cold-xattr")
+             .long("cold-xattr")
+             .takes_value(true)
+             .value_name("NAME")
+             .requires("cold-storage")
+             .help("Extended attribute set on the offline files (Linux)\n    Example: --cold-xattr user.archived"))
+        .arg(clap::Arg::with_name("recall-command")
+             .long("recall-command")
+             .takes_value(true)
+             .requires("cold-storage")
+             .help("Shell command ({path} is the file) started in the background to recall an offline file when it is requested, which is then answered 202\n    Example: --recall-command 'dmget {path}'"))
+        .arg(clap::Arg::with_name("recall-retry-after")
+             .long("recall-retry-after")
+             .takes_value(true)
+             .default_value("300")
+             .value_name("SECONDS")
+             .validator(|s| {
+                 match s.parse::<u32>() {
+                     Ok(0) => Err("must be at least 1".to_owned()),
+                     Ok(_) => Ok(()),
+                     Err(e) => Err(e.to_string())
+                 }
+             })
+             .help("Retry-After header value for the offline files, the recall command is not started again for a file within it"))
         .arg(clap::Arg::with_name("nocache")
              .long("nocache")
-             .help("Disable http cache"))
+             .help("Disable http cache (Cache-Control/Expires), clients still revalidate files with their ETag and Last-Modified"))
+        .arg(clap::Arg::with_name("cache-profile")
+             .long("cache-profile")
+             .takes_value(true)
+             .possible_values(&["default", "static-site", "no-store"])
+             .default_value("default")
+             .help("Cache-Control/Expires preset\n    static-site: hashed assets immutable, short HTML TTL\n    no-store: never cache"))
         .arg(clap::Arg::with_name("norange")
              .long("norange")
              .help("Disable header::Range support (partial request)"))
@@ -98,10 +364,111 @@ fn main() {
                      Err(e) => Err(e.to_string())
                  }
              })
-             .help("TLS/SSL certificate (pkcs#12 format)"))
+             .help("TLS/SSL certificate (pkcs#12 format, or PEM certificate chain with --tls-backend rustls)"))
+        .arg(clap::Arg::with_name("tls-self-signed")
+             .long("tls-self-signed")
+             .conflicts_with_all(&["cert", "acme"])
+             .help("Serve HTTPS with a certificate generated at startup for localhost, this host name and the bound address, its fingerprint is printed to pin it (needs the `only-openssl` or `acme` cargo feature)"))
+        .arg(clap::Arg::with_name("acme")
+             .long("acme")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("DOMAIN")
+             .conflicts_with("cert")
+             .requires("acme-cache")
+             .help("Serve HTTPS with a certificate for DOMAIN obtained and renewed automatically from Let's Encrypt (needs the `acme` cargo feature), the domain has to reach this host on --acme-http-port\n    Example: --acme files.example.com --acme-cache /var/lib/shs-acme -p 443"))
+        .arg(clap::Arg::with_name("acme-cache")
+             .long("acme-cache")
+             .takes_value(true)
+             .value_name("DIR")
+             .requires("acme")
+             .help("Directory keeping the ACME account key and the certificate, out of the served root"))
+        .arg(clap::Arg::with_name("acme-email")
+             .long("acme-email")
+             .takes_value(true)
+             .requires("acme")
+             .help("Contact address of the ACME account, for the expiry notices"))
+        .arg(clap::Arg::with_name("acme-directory")
+             .long("acme-directory")
+             .takes_value(true)
+             .value_name("URL")
+             .requires("acme")
+             .help("Directory of the ACME server [default: Let's Encrypt]\n    Example: --acme-directory https://acme-staging-v02.api.letsencrypt.org/directory"))
+        .arg(clap::Arg::with_name("acme-http-port")
+             .long("acme-http-port")
+             .takes_value(true)
+             .default_value("80")
+             .validator(|s| s.parse::<u16>().map(|_| ()).map_err(|e| e.to_string()))
+             .help("Port answering the HTTP-01 challenges, and redirecting the rest to HTTPS"))
+        .arg(clap::Arg::with_name("tls-backend")
+             .long("tls-backend")
+             .takes_value(true)
+             .possible_values(&["native", "rustls"])
+             .default_value(if cfg!(feature = "native-tls") { "native" } else { "rustls" })
+             .help("TLS implementation: native (OpenSSL, SChannel or Security.framework) or rustls (needs the `rustls` cargo feature, for static builds without OpenSSL)"))
+        .arg(clap::Arg::with_name("key")
+             .long("key")
+             .takes_value(true)
+             .value_name("FILE")
+             .requires("cert")
+             .help("PEM private key (PKCS#8 or RSA, unencrypted) for --tls-backend rustls, read from the --cert file by default"))
         .arg(clap::Arg::with_name("cors")
              .long("cors")
              .help("Enable CORS via the \"Access-Control-Allow-Origin\" header"))
+        .arg(clap::Arg::with_name("cors-max-age")
+             .long("cors-max-age")
+             .takes_value(true)
+             .value_name("SECONDS")
+             .requires("cors")
+             .validator(|s| s.parse::<u32>().map(|_| ()).map_err(|e| e.to_string()))
+             .help("Let browsers cache CORS preflight replies this long (\"Access-Control-Max-Age\")"))
+        .arg(clap::Arg::with_name("cors-private-network")
+             .long("cors-private-network")
+             .requires("cors")
+             .help("Answer Private Network Access preflights, so pages of public origins may reach this server on a private network"))
+        .arg(clap::Arg::with_name("cors-origin")
+             .long("cors-origin")
+             .takes_value(true)
+             .multiple(true)
+             .value_delimiter(",")
+             .require_delimiter(true)
+             .value_name("ORIGIN")
+             .requires("cors")
+             .validator(|s| Cors::parse_origin(&s).map(|_| ()))
+             .help("Only allow these origins (scheme://host[:port], * for any, the default), which are also sent the credentials\n    Example: --cors --cors-origin http://localhost:3000,http://localhost:5173"))
+        .arg(clap::Arg::with_name("cors-methods")
+             .long("cors-methods")
+             .takes_value(true)
+             .multiple(true)
+             .value_delimiter(",")
+             .require_delimiter(true)
+             .value_name("METHOD")
+             .requires("cors")
+             .validator(|s| {
+                 if !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphabetic()) {
+                     Ok(())
+                 } else {
+                     Err(format!("invalid method: {}", s))
+                 }
+             })
+             .help("Methods answered to CORS preflights (\"Access-Control-Allow-Methods\"), the one asked for by default\n    Example: --cors-methods GET,POST,PUT,DELETE"))
+        .arg(clap::Arg::with_name("cors-headers")
+             .long("cors-headers")
+             .takes_value(true)
+             .multiple(true)
+             .value_delimiter(",")
+             .require_delimiter(true)
+             .value_name("HEADER")
+             .requires("cors")
+             .validator(|s| {
+                 if !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)) {
+                     Ok(())
+                 } else {
+                     Err(format!("invalid header name: {}", s))
+                 }
+             })
+             .help("Request headers answered to CORS preflights (\"Access-Control-Allow-Headers\"), the ones asked for by default\n    Example: --cors-headers Authorization,Content-Type"))
         .arg(clap::Arg::with_name("coop")
              .long("coop")
              .help("Add \"Cross-Origin-Opener-Policy\" HTTP header and set it to \"same-origin\""))
@@ -112,6 +479,12 @@ fn main() {
              long("certpass")
              .takes_value(true)
              .help("TLS/SSL certificate password"))
+        .arg(clap::Arg::with_name("upload-filename")
+             .long("upload-filename")
+             .takes_value(true)
+             .possible_values(&["keep", "sanitize", "uuid"])
+             .default_value("sanitize")
+             .help("How multipart upload file names are stored\n    keep: as sent by the client\n    sanitize: base name without control, Windows reserved or invalid characters, at most 255 bytes\n    uuid: random name keeping the extension"))
         .arg(clap::Arg::with_name("upload_size_limit")
              .short("l")
              .long("upload-size-limit")
@@ -124,6 +497,74 @@ fn main() {
                      Err(e) => Err(e.to_string())
                  }})
              .help("Upload file size limit [bytes]"))
+        .arg(clap::Arg::with_name("upload-tmp-dir")
+             .long("upload-tmp-dir")
+             .takes_value(true)
+             .value_name("PATH")
+             .validator(|s| {
+                 match fs::metadata(s) {
+                     Ok(metadata) => {
+                         if metadata.is_dir() { Ok(()) } else {
+                             Err("Not directory".to_owned())
+                         }
+                     },
+                     Err(e) => Err(e.to_string())
+                 }
+             })
+             .help("Directory for in-progress uploads, on another filesystem than root they are copied into place once complete [default: the destination directory, the system temp directory for the interrupted resumable ones]"))
+        .arg(clap::Arg::with_name("scan-command")
+             .long("scan-command")
+             .takes_value(true)
+             .value_name("COMMAND")
+             .requires("upload")
+             .help("Scan every upload with this shell command ({path} is the file) before moving it into the tree, rejected with 422 unless it exits 0\n    Example: --scan-command 'clamscan --no-summary {path}' (use --upload-tmp-dir as quarantine)"))
+        .arg(clap::Arg::with_name("upload-allow-types")
+             .long("upload-allow-types")
+             .takes_value(true)
+             .value_name("TYPES")
+             .requires("upload")
+             .validator(|s| AllowedTypes::new(&s).map(|_| ()).map_err(|e| e.to_string()))
+             .help("Only accept uploads whose content (sniffed from magic numbers) is of these types and matches their extension, rejected with 422 otherwise\n    Example: --upload-allow-types 'image/*,application/pdf'"))
+        .arg(clap::Arg::with_name("dedupe")
+             .long("dedupe")
+             .requires("upload")
+             .help("Store uploads identical to an existing file (on the same filesystem) as hard links to it, they then share permissions and modification time"))
+        .arg(clap::Arg::with_name("upload-notifications")
+             .long("upload-notifications")
+             .requires("upload")
+             .help("Show in the listings of every viewer who uploaded, copied or moved what (\"alice uploaded build.zip\") and refresh them, polling /-/events?since=<id>"))
+        .arg(clap::Arg::with_name("mirror-to")
+             .long("mirror-to")
+             .takes_value(true)
+             .value_name("URL")
+             .requires_all(&["upload", "mirror-key"])
+             .validator(|s| match iron::Url::parse(&s) {
+                 Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Ok(()),
+                 Ok(_) => Err("not an http(s) URL".to_owned()),
+                 Err(e) => Err(e.to_string()),
+             })
+             .help("Replay the uploads, deletes, copies and moves to a second instance in the background (active-passive pair)\n    It needs --upload (--delete for deletes) and --api-keys with a write,delete key given in --mirror-key\n    Example: --mirror-to http://backup:8000 --mirror-key shs_..."))
+        .arg(clap::Arg::with_name("mirror-key")
+             .long("mirror-key")
+             .takes_value(true)
+             .value_name("KEY")
+             .requires("mirror-to")
+             .help("API key of the --mirror-to instance"))
+        .arg(clap::Arg::with_name("delete")
+             .long("delete")
+             .requires("upload")
+             .conflicts_with("overlay")
+             .help("Enable DELETE (moving to the trash, or for good with `X-Permanent: true`), listed on /-/trash and undone with POST /-/restore?id=<id> (CSRF token required)"))
+        .arg(clap::Arg::with_name("webdav")
+             .long("webdav")
+             .conflicts_with_all(&["delete", "access-files"])
+             .help("Serve WebDAV (PROPFIND, and with --upload PUT, MKCOL, COPY, MOVE and DELETE for good), to mount the root in file managers or cadaver, without CSRF token so better with --auth"))
+        .arg(clap::Arg::with_name("trash-dir")
+             .long("trash-dir")
+             .takes_value(true)
+             .value_name("PATH")
+             .requires("delete")
+             .help("Directory keeping deleted files, must be on the same filesystem as root and outside it [default: .<root name>.trash next to root]"))
         .arg(clap::Arg::with_name("ip")
              .long("ip")
              .takes_value(true)
@@ -135,6 +576,37 @@ fn main() {
                  }
              })
              .help("IP address to bind"))
+        .arg(clap::Arg::with_name("allowed-hosts")
+             .long("allowed-hosts")
+             .takes_value(true)
+             .value_name("HOSTS")
+             .help("Only answer requests whose Host header is one of these names, 421 otherwise (DNS rebinding protection)\n    Example: --allowed-hosts 'example.com,*.internal,localhost'"))
+        .arg(clap::Arg::with_name("allow-ip")
+             .long("allow-ip")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("RANGES")
+             .validator(|s| IpRange::parse_list(&s).map(|_| ()))
+             .help("Only answer the clients in these address ranges, 403 for the others before any authentication\n    Example: --allow-ip 192.168.1.0/24,fd00::/8 --allow-ip 127.0.0.1"))
+        .arg(clap::Arg::with_name("deny-ip")
+             .long("deny-ip")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("RANGES")
+             .validator(|s| IpRange::parse_list(&s).map(|_| ()))
+             .help("Answer 403 to the clients in these address ranges, even when in an --allow-ip range\n    Example: --deny-ip 192.168.1.13"))
+        .arg(clap::Arg::with_name("access-files")
+             .long("access-files")
+             .conflicts_with("overlay")
+             .help("Apply the rules of .access files to their directory and below (nearest file wins), one per line:\n    allow|deny all|<ip>[/<bits>]|user <name>  (first match decides, <name> as authenticated by --auth, --auth-file, --auth-command, --auth-pam or --auth-ldap)\n    require auth  (authenticated by the accounts, --token or, along with either, --api-keys)"))
+        .arg(clap::Arg::with_name("waf")
+             .long("waf")
+             .takes_value(true)
+             .value_name("RULES")
+             .possible_values(&["basic"])
+             .help("Reject obviously malicious requests with 403 (NUL bytes, overlong URIs, known scanner paths, encoded ..), counted on /-/stats"))
         .arg(clap::Arg::with_name("port")
              .short("p")
              .long("port")
@@ -162,13 +634,117 @@ fn main() {
                  }
              })
              .help("HTTP Basic Auth (username:password)"))
+        .arg(clap::Arg::with_name("auth-file")
+             .long("auth-file")
+             .takes_value(true)
+             .value_name("FILE")
+             .conflicts_with("auth")
+             .help("HTTP Basic Auth with the accounts of this htpasswd file, bcrypt or Argon2 hashed, reloaded when changed\n    Example: htpasswd -B -c users.htpasswd alice && simple-http-server --auth-file users.htpasswd"))
+        .arg(clap::Arg::with_name("auth-command")
+             .long("auth-command")
+             .takes_value(true)
+             .value_name("CMD")
+             .conflicts_with_all(&["auth", "auth-file"])
+             .help("HTTP Basic Auth checked by this command, given the username and the password on two lines of its standard input and exiting with 0 when they are right (successful checks are remembered for a minute)\n    Example: --auth-command /usr/sbin/pwauth"))
+        .arg(clap::Arg::with_name("auth-pam")
+             .long("auth-pam")
+             .takes_value(true)
+             .value_name("SERVICE")
+             .conflicts_with_all(&["auth", "auth-file", "auth-command"])
+             .help("HTTP Basic Auth with the accounts of this PAM service (/etc/pam.d/SERVICE), checking the system passwords needs root (successful checks are remembered for a minute)\n    Example: --auth-pam login"))
+        .arg(clap::Arg::with_name("auth-ldap")
+             .long("auth-ldap")
+             .takes_value(true)
+             .value_name("URL")
+             .requires("auth-ldap-dn")
+             .conflicts_with_all(&["auth", "auth-file", "auth-command", "auth-pam"])
+             .help("HTTP Basic Auth by binding as the user to this LDAP server, ldap:// or ldaps:// (needs the `ldap` cargo feature, successful checks are remembered for a minute)\n    Example: --auth-ldap ldaps://ldap.example.com --auth-ldap-dn \"uid={user},ou=people,dc=example,dc=com\""))
+        .arg(clap::Arg::with_name("auth-ldap-dn")
+             .long("auth-ldap-dn")
+             .takes_value(true)
+             .value_name("TEMPLATE")
+             .requires("auth-ldap")
+             .validator(|s| if s.contains("{user}") { Ok(()) } else { Err("no {user} in the DN".to_owned()) })
+             .help("DN of the users to bind as with --auth-ldap, {user} is replaced by the username"))
+        .arg(clap::Arg::with_name("auth-method")
+             .long("auth-method")
+             .takes_value(true)
+             .possible_values(&["basic", "digest"])
+             .default_value("basic")
+             .requires_if("digest", "auth")
+             .help("How clients send the --auth credentials, digest keeps the password off the network when there is no TLS"))
+        .arg(clap::Arg::with_name("sign-key")
+             .long("sign-key")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("Sign the SHA-256 digest of each served file with this Ed25519 key (needs the `signing` cargo feature), sent in the X-Content-SHA256 and X-Content-Signature headers and as a detached signature at FILE?sig, the public key is at /-/sign-key\n    Example: openssl genpkey -algorithm ed25519 -out sign.key && simple-http-server --sign-key sign.key"))
+        .arg(clap::Arg::with_name("api-keys")
+             .long("api-keys")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("Accept the API keys of this file (\"Authorization: Bearer <key>\"), each limited to its scopes and path prefixes\n    Managed with the `keys` subcommand, reloaded when changed: simple-http-server keys --file FILE add ci --scope read,write --prefix /builds"))
+        .arg(clap::Arg::with_name("token")
+             .long("token")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("SECRET[:SCOPES]")
+             .validator(|s| ApiKey::token(&s, 0).map(|_| ()))
+             .help("Accept this bearer token (\"Authorization: Bearer <secret>\"), read and write unless scoped, like an --api-keys key for the whole tree. Without the --auth* options the requests without a token are refused\n    Example: --token \"$CI_TOKEN:read\" --token \"$DEPLOY_TOKEN:read,write\""))
+        .arg(clap::Arg::with_name("client-quota")
+             .long("client-quota")
+             .takes_value(true)
+             .value_name("SIZE/PERIOD")
+             .help("Per client (auth user or IP) download quota, period: hour/day/week/month\n    Example: --client-quota 5G/day"))
+        .arg(clap::Arg::with_name("client-quota-state")
+             .long("client-quota-state")
+             .takes_value(true)
+             .value_name("PATH")
+             .requires("client-quota")
+             .help("File to persist client quota usage across restarts"))
         .arg(clap::Arg::with_name("compress")
              .short("c")
              .long("compress")
              .multiple(true)
              .value_delimiter(",")
              .takes_value(true)
-             .help("Enable file compression: gzip/deflate\n    Example: -c=js,d.ts\n    Note: disabled on partial request!"))
+             .help("Enable file compression: gzip/deflate, generated responses (JSON, manifests, search results) are compressed as well\n    Example: -c=js,d.ts\n    Note: disabled on partial request!"))
+        .arg(clap::Arg::with_name("precompressed")
+             .long("precompressed")
+             .help("Serve `<file>.br` or `<file>.gz` next to a requested file instead of it, to clients accepting that encoding (ranges apply to the compressed file)"))
+        .arg(clap::Arg::with_name("throttle")
+             .long("throttle")
+             .takes_value(true)
+             .value_name("RATE")
+             .help("Limit the bandwidth (per second) shared by all responses not in a --throttle-path class, optionally by time of day (local time, first matching window wins, unlimited outside of all)\n    Example: --throttle 2m\n    Example: --throttle '1m@09:00-18:00,unlimited@else'"))
+        .arg(clap::Arg::with_name("throttle-path")
+             .long("throttle-path")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("PATTERN RATE")
+             .help("Bandwidth class shared by the responses matching PATTERN (first match wins), RATE as for --throttle or \"unlimited\"\n    Example: --throttle-path '/isos/* 1m' --throttle-path '/docs/* unlimited'"))
+        .arg(clap::Arg::with_name("upload-throttle")
+             .long("upload-throttle")
+             .takes_value(true)
+             .value_name("RATE")
+             .validator(|s| Rate::parse(&s).map(|_| ()).map_err(|e| e.to_string()))
+             .help("Limit the bandwidth (per second) shared by all uploads, separately from --throttle, RATE as for --throttle\n    Example: --upload-throttle 10m"))
+        .arg(clap::Arg::with_name("max-concurrent-uploads")
+             .long("max-concurrent-uploads")
+             .takes_value(true)
+             .value_name("N")
+             .validator(|s| match s.parse::<usize>() {
+                 Ok(n) if n > 0 => Ok(()),
+                 _ => Err(format!("not a positive number: {}", s)),
+             })
+             .help("Uploads handled at the same time, as many more wait up to 10 seconds for one to finish and the others are answered 429"))
+        .arg(clap::Arg::with_name("max-open-files")
+             .long("max-open-files")
+             .takes_value(true)
+             .value_name("N")
+             .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+             .help("Answer 503 while the server has this many file descriptors open, instead of failing with \"Too many open files\" (Unix). By default the open files limit, raised to its maximum at startup, less a few per worker thread; 0 to disable"))
         .arg(clap::Arg::with_name("threads")
              .short("t")
              .long("threads")
@@ -201,11 +777,33 @@ fn main() {
                  }
              })
              .help("serve this file (server root relative) in place of missing files (useful for single page apps)"))
+        .arg(clap::Arg::with_name("print-config")
+             .long("print-config")
+             .takes_value(true)
+             .value_name("FORMAT")
+             .possible_values(&["json"])
+             .help("Print the effective settings (secrets left out) and exit"))
         .arg(clap::Arg::with_name("silent")
              .long("silent")
              .short("s")
              .takes_value(false)
              .help("Disable all outputs"))
+        .arg(clap::Arg::with_name("log-ignore")
+             .long("log-ignore")
+             .takes_value(true)
+             .value_name("PREFIX")
+             .multiple(true)
+             .number_of_values(1)
+             .help("Leave requests whose path starts with this prefix out of the access log (counted in /-/metrics)\n    Example: --log-ignore /healthz --log-ignore /assets/"))
+        .arg(clap::Arg::with_name("log-sample")
+             .long("log-sample")
+             .takes_value(true)
+             .value_name("RATE")
+             .validator(|s| match s.parse::<f64>() {
+                 Ok(rate) if rate > 0.0 && rate <= 1.0 => Ok(()),
+                 _ => Err("must be a number in (0, 1]".to_owned()),
+             })
+             .help("Log only this share of the successful requests, errors are always logged (the others are counted in /-/metrics)\n    Example: --log-sample 0.1"))
         .arg(clap::Arg::with_name("open")
              .long("open")
              .short("o")
@@ -222,24 +820,221 @@ fn main() {
             .default_value("Simple HTTP(s) Server")
             .takes_value(true)
             .help("Title of index page."))
+        .arg(clap::Arg::with_name("error-detail")
+            .long("error-detail")
+            .takes_value(true)
+            .possible_values(&["full", "minimal"])
+            .default_value("full")
+            .help("What error pages tell clients\n    full: the underlying error (eg: paths and IO errors)\n    minimal: only the status, the error is logged"))
+        .arg(clap::Arg::with_name("favicon")
+            .long("favicon")
+            .takes_value(true)
+            .value_name("PATH")
+            .validator(|s| fs::File::open(s).map(|_| ()).map_err(|e| e.to_string()))
+            .help("Favicon image of the pages, instead of the built-in one"))
+        .arg(clap::Arg::with_name("brand-html")
+            .long("brand-html")
+            .takes_value(true)
+            .value_name("PATH")
+            .validator(|s| fs::File::open(s).map(|_| ()).map_err(|e| e.to_string()))
+            .help("HTML snippet (eg: a logo or banner) shown at the top of every page"))
+        .arg(clap::Arg::with_name("admin-token")
+            .long("admin-token")
+            .takes_value(true)
+            .help("Enable admin endpoint (/-/admin/) authorized by the \"X-Admin-Token\" header"))
+        .arg(clap::Arg::with_name("stats")
+            .long("stats")
+            .help("Enable bandwidth and protocol statistics on /-/stats and /-/metrics (Prometheus format)"))
+        .arg(clap::Arg::with_name("description")
+            .long("description")
+            .help("Render the .description.md (markdown) of a directory above its listing, editable when --auth is set"))
+        .arg(clap::Arg::with_name("tags")
+            .long("tags")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("File to store key/value tags on files, shown in listings and filterable by ?tag=name[=value]\n    Tags are edited on /-/admin/tags/<path> (requires --admin-token)"))
+        .arg(clap::Arg::with_name("show-hash")
+            .long("show-hash")
+            .takes_value(true)
+            .value_name("ALGORITHM")
+            .possible_values(&["sha256"])
+            .help("Show file digests in listings, computed in the background (\"computing...\" until ready)"))
+        .arg(clap::Arg::with_name("hash-db")
+            .long("hash-db")
+            .takes_value(true)
+            .value_name("PATH")
+            .requires("show-hash")
+            .help("File to cache the --show-hash digests across restarts, keyed by size and modified time"))
+        .arg(clap::Arg::with_name("diff")
+            .long("diff")
+            .help("Enable directory comparison on /-/diff?a=/dirA&b=/dirB (hash=1: compare content, format=json)"))
+        .arg(clap::Arg::with_name("enable-grep")
+            .long("enable-grep")
+            .help("Enable recursive search in text files on directories: /dir/?grep=pattern (regex)"))
+        .arg(clap::Arg::with_name("grep-max-file-size")
+            .long("grep-max-file-size")
+            .takes_value(true)
+            .default_value("1M")
+            .value_name("SIZE")
+            .validator(|s| parse_size(&s).map(|_| ()).map_err(|e| e.to_string()))
+            .help("Skip files larger than this when searching"))
+        .arg(clap::Arg::with_name("slow-threshold")
+            .long("slow-threshold")
+            .takes_value(true)
+            .value_name("MS")
+            .validator(|s| {
+                match s.parse::<u64>() {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e.to_string())
+                }
+            })
+            .help("Log requests slower than this threshold with timing breakdown (auth, stat, read, write)"))
+        .arg(clap::Arg::with_name("slow-log")
+            .long("slow-log")
+            .takes_value(true)
+            .value_name("PATH")
+            .requires("slow-threshold")
+            .help("File to append slow requests to [default: stderr]"))
+        .arg(clap::Arg::with_name("trace-otlp")
+            .long("trace-otlp")
+            .takes_value(true)
+            .value_name("ENDPOINT")
+            .help("Export request spans (auth, stat, body writing) to an OpenTelemetry collector, OTLP/HTTP JSON\n    Example: --trace-otlp http://localhost:4318"))
+        .arg(clap::Arg::with_name("allow-hours")
+            .long("allow-hours")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("[PATH] HH:MM-HH:MM")
+            .help("Only serve requests (of PATH) during these hours, local time, reply 403 otherwise\n    Example: --allow-hours 08:00-18:00 --allow-hours '/exams 09:00-11:00'"))
+        .arg(clap::Arg::with_name("maintenance")
+            .long("maintenance")
+            .help("Start in maintenance mode (reply 503 to all non-admin requests)"))
+        .arg(clap::Arg::with_name("maintenance-page")
+            .long("maintenance-page")
+            .takes_value(true)
+            .value_name("PATH")
+            .validator(|s| {
+                match fs::metadata(s) {
+                    Ok(metadata) => {
+                        if metadata.is_file() { Ok(()) } else {
+                            Err("Not a file".to_owned())
+                        }
+                    },
+                    Err(e) => Err(e.to_string())
+                }
+            })
+            .help("Custom HTML page for maintenance mode"))
+        .arg(clap::Arg::with_name("maintenance-retry-after")
+            .long("maintenance-retry-after")
+            .takes_value(true)
+            .default_value("300")
+            .value_name("SECONDS")
+            .validator(|s| {
+                match s.parse::<u32>() {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e.to_string())
+                }
+            })
+            .help("Retry-After header value in maintenance mode"))
+        .subcommand(clap::SubCommand::with_name("selftest")
+            .about("Run an end-to-end test (listing, range, compression, upload, auth, storage) against a temporary server"))
+        .subcommand(clap::SubCommand::with_name("keys")
+            .about("Manage the API keys of --api-keys")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .arg(clap::Arg::with_name("file")
+                .long("file")
+                .takes_value(true)
+                .value_name("FILE")
+                .required(true)
+                .help("API keys file"))
+            .subcommand(clap::SubCommand::with_name("add")
+                .about("Create a key, printed once (only its hash is stored)")
+                .arg(clap::Arg::with_name("name")
+                    .required(true)
+                    .help("Name of the key, to list and revoke it"))
+                .arg(clap::Arg::with_name("scope")
+                    .long("scope")
+                    .takes_value(true)
+                    .multiple(true)
+                    .required(true)
+                    .value_name("SCOPES")
+                    .help("Comma separated: read, write (uploads, copies, moves), delete, admin (/-/admin/ with --admin-token)"))
+                .arg(clap::Arg::with_name("prefix")
+                    .long("prefix")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .value_name("PATH")
+                    .help("Limit the key to this directory (repeatable), the /-/ endpoints not about a path are then refused")))
+            .subcommand(clap::SubCommand::with_name("list")
+                .about("List the keys with their scopes and prefixes"))
+            .subcommand(clap::SubCommand::with_name("revoke")
+                .about("Delete a key, servers reload the file")
+                .arg(clap::Arg::with_name("name")
+                    .required(true))))
         .get_matches();
-
-    let root = matches
-        .value_of("root")
+    if matches.subcommand_matches("selftest").is_some() {
+        std::process::exit(selftest::run());
+    }
+    if let Some(matches) = matches.subcommand_matches("keys") {
+        std::process::exit(keys::run(matches));
+    }
+    // With `--overlay`, the last layer is the root and the others are looked up from
+    // the top down
+    let mut lower_layers = matches
+        .values_of_lossy("overlay")
+        .unwrap_or_default()
+        .iter()
         .map(|s| PathBuf::from(s).canonicalize().unwrap())
-        .unwrap_or_else(|| env::current_dir().unwrap());
+        .collect::<Vec<PathBuf>>();
+    let root = lower_layers.pop().unwrap_or_else(|| {
+        matches
+            .value_of("root")
+            .map(|s| PathBuf::from(s).canonicalize().unwrap())
+            .unwrap_or_else(|| env::current_dir().unwrap())
+    });
+    lower_layers.reverse();
     let index = matches.is_present("index");
-    let upload_arg = matches.is_present("upload");
+    let tmpfs = matches
+        .value_of("tmpfs")
+        .map(|size| parse_size(size).unwrap());
+    let upload_arg = matches.is_present("upload") || tmpfs.is_some();
+    let read_only = matches.is_present("read-only");
     let redirect_to = matches
         .value_of("redirect")
         .map(iron::Url::parse)
         .map(Result::unwrap);
     let sort = !matches.is_present("nosort");
+    let zip_members = matches.is_present("zip-members");
+    let archive = matches.is_present("archive");
     let cache = !matches.is_present("nocache");
+    let cache_profile = matches
+        .value_of("cache-profile")
+        .unwrap()
+        .parse::<CacheProfile>()
+        .unwrap();
     let range = !matches.is_present("norange");
     let cert = matches.value_of("cert");
     let certpass = matches.value_of("certpass");
+    let tls = cert.is_some() || matches.is_present("acme") || matches.is_present("tls-self-signed");
+    let tls_backend = matches.value_of("tls-backend").unwrap();
+    #[cfg(feature = "rustls")]
+    let tls_key = matches.value_of("key");
     let cors = matches.is_present("cors");
+    let cors_max_age = matches
+        .value_of("cors-max-age")
+        .map(|s| s.parse::<u32>().unwrap());
+    let cors_private_network = matches.is_present("cors-private-network");
+    let cors_origins = matches.values_of("cors-origin").map(|values| {
+        values
+            .map(|value| Cors::parse_origin(value).unwrap())
+            .collect::<Vec<String>>()
+    });
+    let cors_methods = matches
+        .values_of("cors-methods")
+        .map(|values| values.map(str::to_ascii_uppercase).collect::<Vec<String>>());
+    let cors_headers = matches.values_of_lossy("cors-headers");
     let coop = matches.is_present("coop");
     let coep = matches.is_present("coep");
     let ip = matches.value_of("ip").unwrap();
@@ -248,20 +1043,452 @@ fn main() {
         .value_of("upload_size_limit")
         .unwrap()
         .parse::<u64>()
+        .unwrap()
+        .min(tmpfs.unwrap_or(u64::MAX));
+    let upload_filename = matches
+        .value_of("upload-filename")
+        .unwrap()
+        .parse::<FilenamePolicy>()
         .unwrap();
+    let upload_tmp_dir = matches
+        .value_of("upload-tmp-dir")
+        .map(|s| PathBuf::from(s).canonicalize().unwrap());
+    let read_buffer_size = matches
+        .value_of("read-buffer-size")
+        .map(|size| parse_size(size).unwrap() as usize);
+    let fadvise_sequential = matches.is_present("fadvise-sequential");
+    let stat_concurrency = matches
+        .value_of("stat-concurrency")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+    let negative_cache = matches
+        .value_of("negative-cache")
+        .map(|seconds| seconds.parse::<u64>().unwrap());
+    let cold_storage = if matches.is_present("cold-storage") {
+        Some(ColdStorage::new(
+            matches.value_of("cold-xattr"),
+            matches.value_of("recall-command"),
+            matches
+                .value_of("recall-retry-after")
+                .unwrap()
+                .parse::<u32>()
+                .unwrap(),
+        ))
+    } else {
+        None
+    };
+    let allowed_types = matches
+        .value_of("upload-allow-types")
+        .map(|types| AllowedTypes::new(types).unwrap());
+    let scan_command = matches.value_of("scan-command");
+    let scanner = if scan_command.is_some() || allowed_types.is_some() {
+        Some(Arc::new(Scanner::new(scan_command, allowed_types)))
+    } else {
+        None
+    };
     let auth = matches.value_of("auth");
+    let auth_file = matches.value_of("auth-file");
+    let tokens = matches
+        .values_of("token")
+        .map(|values| {
+            values
+                .enumerate()
+                .map(|(i, arg)| ApiKey::token(arg, i + 1).unwrap())
+                .collect::<Vec<ApiKey>>()
+        })
+        .unwrap_or_default();
+    let auth_command = matches.value_of("auth-command");
+    let auth_pam = matches.value_of("auth-pam");
+    let auth_ldap = matches.value_of("auth-ldap");
+    let auth_ldap_dn = matches.value_of("auth-ldap-dn");
+    let authenticated = auth.is_some()
+        || auth_file.is_some()
+        || auth_command.is_some()
+        || auth_pam.is_some()
+        || auth_ldap.is_some()
+        || !tokens.is_empty();
+    let allow_hours = matches.values_of_lossy("allow-hours");
+    let client_quota = matches.value_of("client-quota");
+    let client_quota_state = matches.value_of("client-quota-state").map(PathBuf::from);
     let compress = matches.values_of_lossy("compress");
+    let throttle = matches.value_of("throttle");
+    let throttle_paths = matches.values_of_lossy("throttle-path");
     let threads = matches.value_of("threads").unwrap().parse::<u8>().unwrap();
     let try_file_404 = matches.value_of("try-file-404");
+    let date_format = matches.value_of("date-format").map(str::to_owned);
+    let error_detail = matches
+        .value_of("error-detail")
+        .unwrap()
+        .parse::<ErrorDetail>()
+        .unwrap();
 
-    let printer = Printer::new();
-    let color_blue = Some(build_spec(Some(Color::Blue), false));
-    let color_red = Some(build_spec(Some(Color::Red), false));
+    let sign_key = matches.value_of("sign-key");
     let addr = if IpAddr::from_str(ip).unwrap().is_ipv4() {
         format!("{}:{}", ip, port)
     } else {
         format!("[{}]:{}", ip, port)
     };
+
+    // `--print-config json`: the effective settings, before anything is opened or started
+    if matches.value_of("print-config") == Some("json") {
+        let string = |s: Option<&str>| match s {
+            Some(s) => format!(r#""{}""#, json_escape(s)),
+            None => "null".to_owned(),
+        };
+        let strings = |values: &[String]| {
+            format!(
+                "[{}]",
+                values
+                    .iter()
+                    .map(|s| string(Some(s)))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            )
+        };
+        let config = [
+            ("root", string(root.to_str())),
+            (
+                "overlay",
+                strings(
+                    &lower_layers
+                        .iter()
+                        .map(|layer| layer.to_string_lossy().to_string())
+                        .collect::<Vec<String>>(),
+                ),
+            ),
+            (
+                "tmpfs",
+                tmpfs.map_or_else(|| "null".to_owned(), |size| size.to_string()),
+            ),
+            (
+                "address",
+                string(Some(&format!(
+                    "{}://{}",
+                    if tls { "https" } else { "http" },
+                    addr
+                ))),
+            ),
+            ("base_url", string(matches.value_of("base-url"))),
+            ("title", string(matches.value_of("title"))),
+            ("threads", threads.to_string()),
+            ("index", index.to_string()),
+            ("cache", cache.to_string()),
+            ("cache_profile", string(matches.value_of("cache-profile"))),
+            ("cors", cors.to_string()),
+            (
+                "cors_origin",
+                strings(&cors_origins.clone().unwrap_or_default()),
+            ),
+            (
+                "cors_methods",
+                strings(&cors_methods.clone().unwrap_or_default()),
+            ),
+            (
+                "cors_headers",
+                strings(&cors_headers.clone().unwrap_or_default()),
+            ),
+            ("coop", coop.to_string()),
+            ("coep", coep.to_string()),
+            ("range", range.to_string()),
+            ("sort", sort.to_string()),
+            ("archive", archive.to_string()),
+            ("read_only", read_only.to_string()),
+            ("upload", upload_arg.to_string()),
+            ("upload_size_limit", upload_size_limit.to_string()),
+            (
+                "upload_filename",
+                string(matches.value_of("upload-filename")),
+            ),
+            (
+                "upload_notifications",
+                matches.is_present("upload-notifications").to_string(),
+            ),
+            ("delete", matches.is_present("delete").to_string()),
+            ("webdav", matches.is_present("webdav").to_string()),
+            // The password is left out
+            (
+                "auth_user",
+                string(auth.and_then(|auth| auth.split(':').next())),
+            ),
+            ("auth_file", string(auth_file)),
+            ("auth_command", string(auth_command)),
+            ("auth_pam", string(auth_pam)),
+            ("auth_ldap", string(auth_ldap)),
+            ("auth_ldap_dn", string(auth_ldap_dn)),
+            ("auth_method", string(matches.value_of("auth-method"))),
+            ("sign_key", string(sign_key)),
+            ("compress", strings(&compress.clone().unwrap_or_default())),
+            (
+                "precompressed",
+                matches.is_present("precompressed").to_string(),
+            ),
+            ("tls", tls.to_string()),
+            (
+                "acme",
+                strings(&matches.values_of_lossy("acme").unwrap_or_default()),
+            ),
+            ("cert", string(cert)),
+            ("tls_backend", string(cert.and(Some(tls_backend)))),
+            ("try_file_404", string(try_file_404)),
+            (
+                "negative_cache",
+                negative_cache.map_or_else(|| "null".to_owned(), |seconds| seconds.to_string()),
+            ),
+            (
+                "filename_encoding",
+                string(matches.value_of("filename-encoding")),
+            ),
+            ("stat_concurrency", stat_concurrency.to_string()),
+            ("cold_storage", cold_storage.is_some().to_string()),
+            ("recall_command", string(matches.value_of("recall-command"))),
+            ("redirect", string(matches.value_of("redirect"))),
+            ("throttle", string(throttle)),
+            (
+                "upload_throttle",
+                string(matches.value_of("upload-throttle")),
+            ),
+            (
+                "max_concurrent_uploads",
+                string(matches.value_of("max-concurrent-uploads")),
+            ),
+            ("max_open_files", string(matches.value_of("max-open-files"))),
+            (
+                "throttle_paths",
+                strings(&throttle_paths.clone().unwrap_or_default()),
+            ),
+            (
+                "allow_hours",
+                strings(&allow_hours.clone().unwrap_or_default()),
+            ),
+            ("client_quota", string(client_quota)),
+            ("allowed_hosts", string(matches.value_of("allowed-hosts"))),
+            (
+                "allow_ip",
+                strings(&matches.values_of_lossy("allow-ip").unwrap_or_default()),
+            ),
+            (
+                "deny_ip",
+                strings(&matches.values_of_lossy("deny-ip").unwrap_or_default()),
+            ),
+            (
+                "access_files",
+                matches.is_present("access-files").to_string(),
+            ),
+            ("waf", string(matches.value_of("waf"))),
+            ("admin", matches.is_present("admin-token").to_string()),
+            ("api_keys", string(matches.value_of("api-keys"))),
+            // Only how many, not the secrets
+            ("tokens", tokens.len().to_string()),
+            ("mirror_to", string(matches.value_of("mirror-to"))),
+            ("stats", matches.is_present("stats").to_string()),
+            ("error_detail", string(matches.value_of("error-detail"))),
+        ]
+        .iter()
+        .map(|(key, value)| format!(r#""{}":{}"#, key, value))
+        .collect::<Vec<String>>();
+        println!("{{{}}}", config.join(","));
+        return;
+    }
+
+    trace::init(matches.value_of("trace-otlp"));
+    // Raised before anything is opened: the default soft limit (often 1024) is what busy
+    // servers run out of first
+    #[cfg(unix)]
+    let nofile_limit = raise_nofile_limit()
+        .map_err(|e| warn!("Reading the open files limit failed: {}", e))
+        .ok();
+
+    let storage: Arc<dyn Storage> = match tmpfs {
+        Some(capacity) => Arc::new(MemoryStorage::new(capacity)),
+        None => {
+            let storage = FsStorage::new(
+                root.clone(),
+                lower_layers.clone(),
+                upload_tmp_dir.clone(),
+                fadvise_sequential,
+            )
+            .with_stat_concurrency(stat_concurrency);
+            Arc::new(match matches.value_of("filename-encoding") {
+                Some(encoding) => {
+                    storage.with_filename_encoding(FilenameEncoding::new(encoding).unwrap())
+                }
+                None => storage,
+            })
+        }
+    };
+    let storage: Arc<dyn Storage> = match negative_cache {
+        Some(seconds) => {
+            // The memory root only changes through the storage
+            let layers = if tmpfs.is_some() {
+                Vec::new()
+            } else {
+                Some(root.clone())
+                    .into_iter()
+                    .chain(lower_layers.clone())
+                    .collect()
+            };
+            Arc::new(NegativeCache::new(
+                storage,
+                Duration::from_secs(seconds),
+                layers,
+            ))
+        }
+        None => storage,
+    };
+
+    let printer = Printer::new();
+    let color_blue = Some(build_spec(Some(Color::Blue), false));
+    let color_red = Some(build_spec(Some(Color::Red), false));
+    if read_only && can_write(&root) {
+        printer
+            .print_err(
+                "{}",
+                &[(
+                    "--read-only: root is writable by this process, refusing to start",
+                    &color_red,
+                )],
+            )
+            .unwrap();
+        return;
+    }
+    #[cfg(feature = "openssl")]
+    let self_signed = if matches.is_present("tls-self-signed") {
+        match SelfSigned::generate(IpAddr::from_str(ip).unwrap()) {
+            Ok(self_signed) => Some(self_signed),
+            Err(e) => {
+                printer
+                    .print_err(
+                        "generate certificate failed: {}",
+                        &[(&*e.to_string(), &color_red)],
+                    )
+                    .unwrap();
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    #[cfg(not(feature = "openssl"))]
+    if matches.is_present("tls-self-signed") {
+        printer
+            .println_err(
+                "{}: self-signed certificates are not enabled during compilation of simple-http-server",
+                &[("ERROR", &Some(build_spec(Some(Color::Red), true)))],
+            )
+            .unwrap();
+        std::process::exit(1);
+    }
+    #[cfg(feature = "openssl")]
+    let cert_desc = match self_signed {
+        Some(ref self_signed) => format!("self-signed, SHA-256 {}", self_signed.fingerprint()),
+        None => cert.unwrap_or("").to_owned(),
+    };
+    #[cfg(not(feature = "openssl"))]
+    let cert_desc = cert.unwrap_or("").to_owned();
+    #[cfg(feature = "acme")]
+    let acme = match matches.values_of_lossy("acme") {
+        Some(domains) => {
+            let cache = PathBuf::from(matches.value_of("acme-cache").unwrap());
+            let cache = match fs::create_dir_all(&cache).and_then(|()| cache.canonicalize()) {
+                Ok(cache) => cache,
+                Err(e) => {
+                    printer
+                        .print_err("ACME cache failed: {}", &[(&*e.to_string(), &color_red)])
+                        .unwrap();
+                    std::process::exit(1);
+                }
+            };
+            // It holds the private keys
+            if Some(&root)
+                .into_iter()
+                .chain(&lower_layers)
+                .any(|layer| cache.starts_with(layer))
+            {
+                printer
+                    .print_err(
+                        "{}",
+                        &[("--acme-cache must be out of the served root", &color_red)],
+                    )
+                    .unwrap();
+                std::process::exit(1);
+            }
+            let http_port = matches.value_of("acme-http-port").unwrap();
+            let http_addr = if IpAddr::from_str(ip).unwrap().is_ipv4() {
+                format!("{}:{}", ip, http_port)
+            } else {
+                format!("[{}]:{}", ip, http_port)
+            };
+            let config = AcmeConfig {
+                domains,
+                email: matches.value_of("acme-email").map(str::to_owned),
+                directory: matches
+                    .value_of("acme-directory")
+                    .unwrap_or(acme::LETS_ENCRYPT)
+                    .to_owned(),
+                cache,
+            };
+            match AcmeServer::start(config, &http_addr, port) {
+                Ok(server) => Some(server),
+                Err(e) => {
+                    printer.print_err("{}", &[(&*e, &color_red)]).unwrap();
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "acme"))]
+    if matches.is_present("acme") {
+        printer
+            .println_err(
+                "{}: ACME support is not enabled during compilation of simple-http-server",
+                &[("ERROR", &Some(build_spec(Some(Color::Red), true)))],
+            )
+            .unwrap();
+        std::process::exit(1);
+    }
+    #[cfg(feature = "signing")]
+    let signer = match sign_key.map(|path| Signer::load(Path::new(path))) {
+        Some(Ok(signer)) => Some(Arc::new(signer)),
+        Some(Err(e)) => {
+            printer
+                .print_err("load signing key failed: {}", &[(&*e, &color_red)])
+                .unwrap();
+            std::process::exit(1);
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "signing"))]
+    if sign_key.is_some() {
+        printer
+            .println_err(
+                "{}: signing is not enabled during compilation of simple-http-server",
+                &[("ERROR", &Some(build_spec(Some(Color::Red), true)))],
+            )
+            .unwrap();
+        std::process::exit(1);
+    }
+    #[cfg(not(feature = "ldap"))]
+    if auth_ldap.is_some() {
+        printer
+            .println_err(
+                "{}: LDAP authentication is not enabled during compilation of simple-http-server",
+                &[("ERROR", &Some(build_spec(Some(Color::Red), true)))],
+            )
+            .unwrap();
+        std::process::exit(1);
+    }
+    #[cfg(not(unix))]
+    if auth_pam.is_some() {
+        printer
+            .println_err(
+                "{}: PAM authentication is only available on Unix",
+                &[("ERROR", &Some(build_spec(Some(Color::Red), true)))],
+            )
+            .unwrap();
+        std::process::exit(1);
+    }
     let compression_exts = compress
         .clone()
         .unwrap_or_default()
@@ -289,17 +1516,191 @@ fn main() {
     let base_url: &str = matches.value_of("base-url").unwrap();
     let title: &str = matches.value_of("title").unwrap();
 
+    let admin_token = matches.value_of("admin-token");
+    let slow_log = matches.value_of("slow-threshold").map(|threshold| {
+        let threshold = Duration::from_millis(threshold.parse::<u64>().unwrap());
+        let out: Box<dyn Write + Send> = match matches.value_of("slow-log") {
+            Some(path) => Box::new(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .unwrap(),
+            ),
+            None => Box::new(io::stderr()),
+        };
+        Arc::new(SlowLog::new(threshold, out))
+    });
+    let write_locks = Arc::new(WriteLocks::default());
+    let upload_progress = Arc::new(match matches.value_of("upload-throttle") {
+        Some(rate) => UploadProgress::default().with_throttle(Rate::parse(rate).unwrap()),
+        None => UploadProgress::default(),
+    });
+    let descriptions = if matches.is_present("description") {
+        Some(Descriptions::new(
+            root.clone(),
+            authenticated && !read_only,
+            write_locks.clone(),
+        ))
+    } else {
+        None
+    };
+    if let Err(e) = load_branding(
+        matches.value_of("favicon").map(Path::new),
+        matches.value_of("brand-html").map(Path::new),
+    ) {
+        printer
+            .print_err("load branding failed: {}", &[(&*e.to_string(), &color_red)])
+            .unwrap();
+        return;
+    }
+    let tags = match matches.value_of("tags") {
+        Some(path) => match Tags::load(PathBuf::from(path)) {
+            Ok(tags) => Some(Arc::new(tags)),
+            Err(e) => {
+                printer
+                    .print_err("load tags failed: {}", &[(&*e.to_string(), &color_red)])
+                    .unwrap();
+                return;
+            }
+        },
+        None => None,
+    };
+    let api_keys = match matches.value_of("api-keys") {
+        Some(path) => match ApiKeys::load(PathBuf::from(path)) {
+            Ok(keys) => Some(Arc::new(keys.with_tokens(tokens.clone()))),
+            Err(e) => {
+                printer
+                    .print_err("load API keys failed: {}", &[(&*e.to_string(), &color_red)])
+                    .unwrap();
+                return;
+            }
+        },
+        None if !tokens.is_empty() => Some(Arc::new(ApiKeys::from_tokens(tokens.clone()))),
+        None => None,
+    };
+    let hashes = if matches.is_present("show-hash") {
+        match Hashes::start(matches.value_of("hash-db").map(PathBuf::from)) {
+            Ok(hashes) => Some(hashes),
+            Err(e) => {
+                printer
+                    .print_err("load hash db failed: {}", &[(&*e.to_string(), &color_red)])
+                    .unwrap();
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    let diff = if matches.is_present("diff") {
+        Some(DirDiff::new(storage.clone()))
+    } else {
+        None
+    };
+    let grep = if matches.is_present("enable-grep") {
+        let max_file_size = parse_size(matches.value_of("grep-max-file-size").unwrap()).unwrap();
+        Some(Grep::new(storage.clone(), max_file_size))
+    } else {
+        None
+    };
+    let images = if matches.is_present("image-ops") {
+        let cache_dir = matches
+            .value_of("image-cache")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| env::temp_dir().join("simple-http-server-images"));
+        let cache_size = parse_size(matches.value_of("image-cache-size").unwrap()).unwrap();
+        match ImageOps::new(
+            matches.value_of("image-command").unwrap(),
+            cache_dir,
+            cache_size,
+        ) {
+            Ok(images) => Some(images),
+            Err(e) => {
+                printer
+                    .print_err(
+                        "open image cache failed: {}",
+                        &[(&*e.to_string(), &color_red)],
+                    )
+                    .unwrap();
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    let stats = if matches.is_present("stats") {
+        Some(Arc::new(Stats::default()))
+    } else {
+        None
+    };
+    let maintenance_page = matches
+        .value_of("maintenance-page")
+        .map(|s| fs::read_to_string(s).unwrap());
+    let maintenance_retry_after = matches
+        .value_of("maintenance-retry-after")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap();
     let upload: Option<Upload> = if upload_arg {
-        let token: String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(10)
-            .map(char::from)
-            .collect();
+        let token = random_alphanumeric(10);
         Some(Upload { csrf_token: token })
     } else {
         None
     };
 
+    let dedupe = if upload.is_some() && matches.is_present("dedupe") {
+        Some(Dedupe::start(root.clone()))
+    } else {
+        None
+    };
+    let mirror = matches.value_of("mirror-to").map(|target| {
+        Arc::new(Mirror::start(
+            target,
+            matches.value_of("mirror-key").unwrap(),
+            storage.clone(),
+        ))
+    });
+    let trash = match upload {
+        Some(ref upload) if matches.is_present("delete") => {
+            let dir = match (
+                matches.value_of("trash-dir"),
+                root.parent(),
+                root.file_name(),
+            ) {
+                (Some(dir), _, _) => PathBuf::from(dir),
+                (None, Some(parent), Some(name)) => {
+                    parent.join(format!(".{}.trash", name.to_string_lossy()))
+                }
+                _ => {
+                    printer
+                        .print_err(
+                            "open trash failed: {}",
+                            &[("--trash-dir is required to serve /", &color_red)],
+                        )
+                        .unwrap();
+                    return;
+                }
+            };
+            match Trash::open(
+                root.clone(),
+                dir,
+                storage.clone(),
+                upload.csrf_token.clone(),
+                write_locks.clone(),
+                mirror.clone(),
+            ) {
+                Ok(trash) => Some(trash),
+                Err(e) => {
+                    printer
+                        .print_err("open trash failed: {}", &[(&*e.to_string(), &color_red)])
+                        .unwrap();
+                    return;
+                }
+            }
+        }
+        _ => None,
+    };
+
     if !silent {
         printer
             .println_out(
@@ -319,6 +1720,7 @@ fn main() {
          https: {}
           Cert: {}
  Cert-Password: {}
+      Sign-Key: {}
           Root: {}
     TryFile404: {}
        Address: {}
@@ -326,36 +1728,48 @@ fn main() {
                 &vec![
                     enable_string(index),
                     enable_string(cache),
-                    enable_string(cors),
+                    match cors_origins {
+                        Some(ref origins) if cors => origins.join(", "),
+                        _ => enable_string(cors),
+                    },
                     enable_string(coop),
                     enable_string(coep),
                     enable_string(range),
                     enable_string(sort),
                     threads.to_string(),
                     enable_string(upload_arg),
-                    (if upload.is_some() {
-                        upload.as_ref().unwrap().csrf_token.as_str()
-                    } else {
-                        ""
-                    })
-                    .to_string(),
-                    auth.unwrap_or("disabled").to_string(),
+                    upload
+                        .as_ref()
+                        .map(|upload| upload.csrf_token.as_str())
+                        .unwrap_or("")
+                        .to_string(),
+                    auth.map(str::to_owned)
+                        .or_else(|| auth_file.map(|path| format!("{} (htpasswd)", path)))
+                        .or_else(|| auth_command.map(|command| format!("{} (command)", command)))
+                        .or_else(|| auth_pam.map(|service| format!("{} (PAM)", service)))
+                        .or_else(|| auth_ldap.map(|url| format!("{} (LDAP)", url)))
+                        .or_else(|| {
+                            Some(format!("{} bearer token(s)", tokens.len()))
+                                .filter(|_| !tokens.is_empty())
+                        })
+                        .unwrap_or_else(|| "disabled".to_owned()),
                     compression_string,
-                    (if cert.is_some() {
-                        "enabled"
-                    } else {
-                        "disabled"
-                    })
-                    .to_string(),
-                    cert.unwrap_or("").to_owned(),
+                    (if tls { "enabled" } else { "disabled" }).to_string(),
+                    cert_desc,
                     certpass.unwrap_or("").to_owned(),
-                    root.to_str().unwrap().to_owned(),
+                    sign_key.unwrap_or("disabled").to_owned(),
+                    match tmpfs {
+                        Some(capacity) => format!("memory ({})", convert(capacity as f64)),
+                        None => lower_layers
+                            .iter()
+                            .rev()
+                            .chain(Some(&root))
+                            .map(|layer| layer.to_str().unwrap())
+                            .collect::<Vec<&str>>()
+                            .join(" < "),
+                    },
                     try_file_404.unwrap_or("").to_owned(),
-                    format!(
-                        "{}://{}",
-                        if cert.is_some() { "https" } else { "http" },
-                        addr
-                    ),
+                    format!("{}://{}", if tls { "https" } else { "http" }, addr),
                     now_string(),
                 ]
                 .iter()
@@ -365,11 +1779,81 @@ fn main() {
             .unwrap();
     }
 
+    let events = Arc::new(ChangeEvents::default());
+    let transfers = Arc::new(Transfers::new(
+        storage.clone(),
+        root.clone(),
+        write_locks.clone(),
+        events.clone(),
+        mirror.clone(),
+    ));
+    let webdav = if matches.is_present("webdav") {
+        Some(WebDav::new(
+            storage.clone(),
+            root.clone(),
+            base_url,
+            upload.is_some(),
+            upload_size_limit,
+            scanner.clone(),
+            dedupe.clone(),
+            write_locks.clone(),
+            transfers.clone(),
+            upload_progress.clone(),
+        ))
+    } else {
+        None
+    };
+
+    let capabilities = Capabilities {
+        auth: authenticated,
+        api_keys: api_keys.is_some(),
+        upload: upload.is_some(),
+        sync: upload.is_some() && tmpfs.is_none(),
+        resumable_upload: upload.is_some() && tmpfs.is_none(),
+        upload_progress: upload.is_some(),
+        upload_notifications: matches.is_present("upload-notifications"),
+        transfers: upload.is_some(),
+        delete: trash.is_some(),
+        webdav: webdav.is_some(),
+        search: grep.is_some(),
+        diff: diff.is_some(),
+        tags: tags.is_some(),
+        description: descriptions.is_some(),
+        range,
+        zip_members,
+        archive,
+        stats: stats.is_some(),
+        image_ops: images.is_some(),
+        compress: compress.clone().unwrap_or_default(),
+        precompressed: matches.is_present("precompressed"),
+    };
+    // Batch and resumable uploads write to the file system directly
+    let sync = upload.as_ref().filter(|_| tmpfs.is_none()).map(|upload| {
+        SyncUpload::new(
+            root.clone(),
+            upload.csrf_token.clone(),
+            upload_size_limit,
+            upload_tmp_dir.clone(),
+            write_locks.clone(),
+            scanner.clone(),
+            upload_progress.clone(),
+            dedupe.clone(),
+        )
+    });
+    let runtime_state = Arc::new(RuntimeState {
+        maintenance: AtomicBool::new(matches.is_present("maintenance")),
+        upload: Toggle::new(upload.is_some()),
+        delete: Toggle::new(trash.is_some()),
+        listing: Toggle::new(true),
+        compression: Toggle::new(compress.as_ref().is_some_and(|exts| !exts.is_empty())),
+    });
     let mut chain = Chain::new(MainHandler {
-        root,
+        root: root.clone(),
+        storage: storage.clone(),
         index,
         upload,
         cache,
+        cache_profile,
         range,
         coop,
         coep,
@@ -378,18 +1862,202 @@ fn main() {
         compress: compress
             .clone()
             .map(|exts| exts.iter().map(|s| format!(".{}", s)).collect()),
-        try_file_404: try_file_404.map(PathBuf::from),
+        precompressed: matches.is_present("precompressed"),
+        zip_members,
+        archive,
+        try_file_404: try_file_404.map(|path| {
+            let path = Path::new(path);
+            let dir = path.parent().unwrap_or(Path::new("")).to_owned();
+            (
+                FsStorage::new(dir, Vec::new(), None, fadvise_sequential),
+                PathBuf::from(path.file_name().unwrap()),
+            )
+        }),
+        read_buffer_size,
+        date_format,
+        error_detail,
         upload_size_limit,
+        upload_filename,
+        in_memory_uploads: tmpfs.is_some(),
+        scanner,
+        cold_storage,
+        upload_progress,
+        events,
+        mirror,
+        upload_notifications: matches.is_present("upload-notifications"),
+        transfers,
+        dedupe,
+        base_url: base_url.to_string(),
+        title: title.to_string(),
+        admin: admin_token.map(|token| Admin::new(token, runtime_state.clone(), tags.clone())),
+        stats: stats.clone(),
+        sync,
+        trash,
+        webdav,
+        diff,
+        grep,
+        tags,
+        hashes,
+        images,
+        descriptions,
+        capabilities,
+        #[cfg(feature = "signing")]
+        signer,
+        write_locks,
+        state: runtime_state.clone(),
+    });
+    let slow_logger = slow_log.map(|log| Arc::new(SlowRequestLogger { log }));
+    if let Some(ref slow_logger) = slow_logger {
+        chain.link_before(slow_logger.clone());
+    }
+    #[cfg(unix)]
+    let fd_limit = match matches
+        .value_of("max-open-files")
+        .map(|n| n.parse::<u64>().unwrap())
+    {
+        Some(0) => None,
+        Some(ceiling) => Some(FdLimit { ceiling }),
+        None => nofile_limit.map(|limit| FdLimit::for_limit(limit, threads as u64)),
+    };
+    #[cfg(unix)]
+    if let Some(fd_limit) = fd_limit {
+        chain.link_before(fd_limit);
+    }
+    #[cfg(not(unix))]
+    if matches.is_present("max-open-files") {
+        printer
+            .println_err(
+                "{}: --max-open-files is only available on Unix",
+                &[("ERROR", &Some(build_spec(Some(Color::Red), true)))],
+            )
+            .unwrap();
+        std::process::exit(1);
+    }
+    if matches.is_present("allow-ip") || matches.is_present("deny-ip") {
+        let ranges = |name: &str| {
+            matches
+                .values_of(name)
+                .into_iter()
+                .flatten()
+                .flat_map(|ranges| IpRange::parse_list(ranges).unwrap())
+                .collect::<Vec<IpRange>>()
+        };
+        chain.link_before(IpFilter {
+            allow: ranges("allow-ip"),
+            deny: ranges("deny-ip"),
+        });
+    }
+    if matches.is_present("waf") {
+        chain.link_before(Waf {
+            stats: stats.clone(),
+        });
+    }
+    if let Some(allowed_hosts) = matches.value_of("allowed-hosts") {
+        chain.link_before(HostChecker::new(allowed_hosts));
+    }
+    if read_only {
+        chain.link_before(ReadOnlyChecker);
+    }
+    chain.link_before(MaintenanceChecker {
+        state: runtime_state.clone(),
+        page: maintenance_page,
+        retry_after: maintenance_retry_after,
         base_url: base_url.to_string(),
-        title: title.to_string(),
     });
-    if cors {
-        chain.link_around(CorsMiddleware::with_allow_any());
+    if let Some(ref allow_hours) = allow_hours {
+        match AccessSchedule::new(allow_hours, base_url) {
+            Ok(schedule) => chain.link_before(schedule),
+            Err(e) => {
+                printer.print_err("{}", &[(&*e, &color_red)]).unwrap();
+                return;
+            }
+        };
+    }
+    if let Some(ref keys) = api_keys {
+        chain.link_before(ApiKeyChecker {
+            keys: keys.clone(),
+            base_url: base_url.to_string(),
+            stats: stats.clone(),
+        });
+    }
+    let mut auth_checker = None;
+    let accounts: Option<Result<Box<dyn AuthProvider>, String>> = if let Some(auth) = auth {
+        Some(
+            StaticAccount::parse(auth)
+                .map(|account| Box::new(account) as Box<dyn AuthProvider>)
+                .map_err(|e| e.to_string()),
+        )
+    } else if let Some(path) = auth_file {
+        Some(
+            Htpasswd::load(PathBuf::from(path))
+                .map(|htpasswd| Box::new(htpasswd) as Box<dyn AuthProvider>)
+                .map_err(|e| format!("load auth file failed: {}", e)),
+        )
+    } else if let Some(command) = auth_command {
+        Some(Ok(Box::new(CommandAccounts::new(command))))
+    } else {
+        None
+    };
+    #[cfg(unix)]
+    let accounts = accounts.or_else(|| {
+        auth_pam.map(|service| {
+            PamAccounts::new(service)
+                .map(|pam| Box::new(pam) as Box<dyn AuthProvider>)
+                .map_err(|e| format!("load PAM failed: {}", e))
+        })
+    });
+    #[cfg(feature = "ldap")]
+    let accounts = accounts.or_else(|| {
+        auth_ldap.map(|url| {
+            LdapAccounts::new(url, auth_ldap_dn.unwrap_or_default())
+                .map(|ldap| Box::new(ldap) as Box<dyn AuthProvider>)
+        })
+    });
+    let checker = match accounts {
+        Some(accounts) => Some(accounts.map(|accounts| {
+            let checker = AuthChecker::new(accounts);
+            match matches.value_of("auth-method") {
+                Some("digest") => checker.with_digest(),
+                _ => checker,
+            }
+        })),
+        None => api_keys
+            .clone()
+            .filter(|_| !tokens.is_empty())
+            .map(|keys| Ok(AuthChecker::from_keys(keys))),
+    };
+    if let Some(checker) = checker {
+        match checker {
+            Ok(checker) => {
+                let checker = match api_keys {
+                    Some(ref keys) => checker.with_keys(keys.clone()),
+                    None => checker,
+                };
+                let checker = Arc::new(match stats {
+                    Some(ref stats) => checker.with_stats(stats.clone()),
+                    None => checker,
+                });
+                chain.link_before(checker.clone());
+                auth_checker = Some(checker);
+                if slow_logger.is_some() {
+                    chain.link_before(AuthTimer);
+                }
+            }
+            Err(e) => {
+                printer.print_err("{}", &[(&*e, &color_red)]).unwrap();
+                return;
+            }
+        }
     }
-    if let Some(auth) = auth {
-        match AuthChecker::new(auth) {
-            Ok(auth_checker) => {
-                chain.link_before(auth_checker);
+    if matches.is_present("access-files") {
+        chain.link_before(AccessFiles::new(root, auth_checker.clone(), base_url));
+    }
+    if let Some(client_quota) = client_quota {
+        match QuotaChecker::new(client_quota, client_quota_state) {
+            Ok(quota_checker) => {
+                let quota_checker = Arc::new(quota_checker);
+                chain.link_before(quota_checker.clone());
+                chain.link_after(quota_checker);
             }
             Err(e) => {
                 printer.print_err("{}", &[(&*e, &color_red)]).unwrap();
@@ -397,39 +2065,202 @@ fn main() {
             }
         }
     }
+    if let Some(max) = matches.value_of("max-concurrent-uploads") {
+        chain.link_before(UploadSlots::new(max.parse().unwrap()));
+    }
     if let Some(ref exts) = compress {
         if !exts.is_empty() {
-            chain.link_after(CompressionHandler);
+            chain.link_after(CompressionHandler::new(runtime_state.clone()));
         }
     }
+    if throttle.is_some() || throttle_paths.is_some() {
+        match Throttle::new(throttle, &throttle_paths.unwrap_or_default()) {
+            Ok(throttle) => chain.link_after(throttle),
+            Err(e) => {
+                printer.print_err("{}", &[(&*e, &color_red)]).unwrap();
+                return;
+            }
+        };
+    }
     if !silent {
         chain.link_after(RequestLogger {
-            printer: Printer::new(),
-            base_url: base_url.to_string(),
+            printer: Arc::new(Printer::new()),
+            ignore: matches.values_of_lossy("log-ignore").unwrap_or_default(),
+            sample: matches
+                .value_of("log-sample")
+                .map(|rate| rate.parse::<f64>().unwrap()),
+            stats: stats.clone(),
         });
     }
-    let mut server = Iron::new(chain);
-    server.threads = threads as usize;
-
-    #[cfg(feature = "native-tls")]
-    let rv = if let Some(cert) = cert {
-        use hyper_native_tls::NativeTlsServer;
-        let ssl = NativeTlsServer::new(cert, certpass.unwrap_or("")).unwrap();
-        server.https(&addr, ssl)
+    chain.link_after(ErrorPage {
+        base_url: base_url.to_string(),
+        detail: error_detail,
+    });
+    if let Some(stats) = stats {
+        chain.link_after(StatsRecorder { stats });
+    }
+    if let Some(slow_logger) = slow_logger {
+        chain.link_after(slow_logger);
+    }
+    chain.link_after(VaryHandler);
+    chain.link_after(HeadHandler);
+    let handler: Box<dyn Handler> = if cors {
+        Cors {
+            origins: cors_origins.filter(|origins| !origins.iter().any(|origin| origin == "*")),
+            methods: cors_methods.map(|methods| {
+                methods
+                    .iter()
+                    .map(|method| method.parse().unwrap())
+                    .collect()
+            }),
+            headers: cors_headers,
+            max_age: cors_max_age,
+            private_network: cors_private_network,
+        }
+        .around(Box::new(chain))
     } else {
-        server.http(&addr)
+        Box::new(chain)
     };
-    #[cfg(not(feature = "native-tls"))]
-    let rv = if cert.is_some() {
-        printer
-            .println_err(
-                "{}: TLS support is not enabled during compilation of simple-http-server",
-                &[("ERROR", &Some(build_spec(Some(Color::Red), true)))],
+    let limits = RequestLimits {
+        max_header_size: matches
+            .value_of("max-header-size")
+            .map(|size| parse_size(size).unwrap() as usize),
+        max_uri_length: matches
+            .value_of("max-uri-length")
+            .map(|length| length.parse().unwrap()),
+        max_body_size: matches
+            .value_of("max-body-size")
+            .map(|size| parse_size(size).unwrap()),
+    };
+    let rv = match cert {
+        #[cfg(feature = "acme")]
+        None if acme.is_some() => {
+            let ssl = acme.unwrap();
+            HttpsListener::new(addr.as_str(), ssl).and_then(|listener| {
+                expect::listen(
+                    handler,
+                    listener,
+                    true,
+                    auth_checker,
+                    upload_size_limit,
+                    limits,
+                    threads as usize,
+                )
+            })
+        }
+        #[cfg(all(feature = "native-tls", feature = "openssl"))]
+        None if self_signed.is_some() && tls_backend == "native" => {
+            use hyper_native_tls::native_tls::{Identity, TlsAcceptor};
+            use hyper_native_tls::NativeTlsServer;
+            let pkcs12 = &self_signed.as_ref().unwrap().pkcs12;
+            let ssl = Identity::from_pkcs12(pkcs12, "")
+                .and_then(TlsAcceptor::new)
+                .map(NativeTlsServer::from)
+                .unwrap();
+            HttpsListener::new(addr.as_str(), ssl).and_then(|listener| {
+                expect::listen(
+                    handler,
+                    listener,
+                    true,
+                    auth_checker,
+                    upload_size_limit,
+                    limits,
+                    threads as usize,
+                )
+            })
+        }
+        #[cfg(all(feature = "rustls", feature = "openssl"))]
+        None if self_signed.is_some() && tls_backend == "rustls" => {
+            let self_signed = self_signed.unwrap();
+            let ssl = tls::RustlsServer::from_der(
+                vec![rustls::Certificate(self_signed.cert)],
+                rustls::PrivateKey(self_signed.key),
             )
             .unwrap();
-        std::process::exit(1)
-    } else {
-        server.http(&addr)
+            HttpsListener::new(addr.as_str(), ssl).and_then(|listener| {
+                expect::listen(
+                    handler,
+                    listener,
+                    true,
+                    auth_checker,
+                    upload_size_limit,
+                    limits,
+                    threads as usize,
+                )
+            })
+        }
+        None if !tls => HttpListener::new(addr.as_str()).and_then(|listener| {
+            expect::listen(
+                handler,
+                listener,
+                false,
+                auth_checker,
+                upload_size_limit,
+                limits,
+                threads as usize,
+            )
+        }),
+        #[cfg(feature = "native-tls")]
+        Some(cert) if tls_backend == "native" => {
+            use hyper_native_tls::NativeTlsServer;
+            let ssl = NativeTlsServer::new(cert, certpass.unwrap_or("")).unwrap();
+            HttpsListener::new(addr.as_str(), ssl).and_then(|listener| {
+                expect::listen(
+                    handler,
+                    listener,
+                    true,
+                    auth_checker,
+                    upload_size_limit,
+                    limits,
+                    threads as usize,
+                )
+            })
+        }
+        #[cfg(feature = "rustls")]
+        Some(cert) if tls_backend == "rustls" => {
+            if certpass.is_some() {
+                printer
+                    .print_err(
+                        "{}",
+                        &[(
+                            "--certpass is not supported by rustls, use an unencrypted PEM key",
+                            &color_red,
+                        )],
+                    )
+                    .unwrap();
+                std::process::exit(1);
+            }
+            let ssl = match tls::RustlsServer::new(Path::new(cert), tls_key.map(Path::new)) {
+                Ok(ssl) => ssl,
+                Err(e) => {
+                    printer.print_err("{}", &[(&*e, &color_red)]).unwrap();
+                    std::process::exit(1);
+                }
+            };
+            HttpsListener::new(addr.as_str(), ssl).and_then(|listener| {
+                expect::listen(
+                    handler,
+                    listener,
+                    true,
+                    auth_checker,
+                    upload_size_limit,
+                    limits,
+                    threads as usize,
+                )
+            })
+        }
+        _ => {
+            printer
+                .println_err(
+                    "{}: {} TLS support is not enabled during compilation of simple-http-server",
+                    &[
+                        ("ERROR", &Some(build_spec(Some(Color::Red), true))),
+                        (tls_backend, &None),
+                    ],
+                )
+                .unwrap();
+            std::process::exit(1)
+        }
     };
 
     if let Err(e) = rv {
@@ -452,19 +2283,57 @@ struct Upload {
 
 struct MainHandler {
     root: PathBuf,
+    storage: Arc<dyn Storage>,
     index: bool,
     upload: Option<Upload>,
     cache: bool,
+    cache_profile: CacheProfile,
     range: bool,
     coop: bool,
     coep: bool,
     redirect_to: Option<iron::Url>,
     sort: bool,
     compress: Option<Vec<String>>,
-    try_file_404: Option<PathBuf>,
+    precompressed: bool,
+    zip_members: bool,
+    // `--archive`
+    archive: bool,
+    // `--try-file`, out of the root
+    try_file_404: Option<(FsStorage, PathBuf)>,
+    read_buffer_size: Option<usize>,
+    date_format: Option<String>,
+    error_detail: ErrorDetail,
     upload_size_limit: u64,
+    upload_filename: FilenamePolicy,
+    // `--tmpfs`, uploads are parsed in memory
+    in_memory_uploads: bool,
+    scanner: Option<Arc<Scanner>>,
+    cold_storage: Option<ColdStorage>,
+    upload_progress: Arc<UploadProgress>,
+    events: Arc<ChangeEvents>,
+    mirror: Option<Arc<Mirror>>,
+    upload_notifications: bool,
+    transfers: Arc<Transfers>,
+    dedupe: Option<Arc<Dedupe>>,
     base_url: String,
     title: String,
+    admin: Option<Admin>,
+    stats: Option<Arc<Stats>>,
+    sync: Option<SyncUpload>,
+    trash: Option<Trash>,
+    webdav: Option<WebDav>,
+    diff: Option<DirDiff>,
+    grep: Option<Grep>,
+    tags: Option<Arc<Tags>>,
+    hashes: Option<Arc<Hashes>>,
+    images: Option<ImageOps>,
+    descriptions: Option<Descriptions>,
+    capabilities: Capabilities,
+    // `--sign-key`
+    #[cfg(feature = "signing")]
+    signer: Option<Arc<Signer>>,
+    state: Arc<RuntimeState>,
+    write_locks: Arc<WriteLocks>,
 }
 
 impl Handler for MainHandler {
@@ -476,6 +2345,9 @@ impl Handler for MainHandler {
                 Redirect(url.clone()),
             )));
         }
+        if req.url.path().first() == Some(&"-") {
+            return self.handle_special(req);
+        }
         let path_prefix = req
             .url
             .path()
@@ -504,10 +2376,55 @@ impl Handler for MainHandler {
                 status::Forbidden,
             ));
         }
+        let relative = fs_path.strip_prefix(&self.root).unwrap().to_owned();
+
+        if self.zip_members {
+            if let Some((archive_path, member)) = split_zip_member(&*self.storage, &relative) {
+                // Reading an offline archive would wait for its recall
+                if let (Some(cold_storage), Some(local_path)) =
+                    (&self.cold_storage, self.storage.local_path(&archive_path))
+                {
+                    cold_storage.check(&local_path, &self.base_url)?;
+                }
+                return send_zip_member(&*self.storage, &archive_path, &member);
+            }
+        }
+
+        if let Some(ref webdav) = self.webdav {
+            if webdav::is_dav_method(&req.method) {
+                if webdav::is_write(&req.method) && self.upload.is_some() {
+                    self.state.upload.check("upload")?;
+                }
+                let resp = webdav.handle(req, &relative)?;
+                if req.method == method::Put
+                    && matches!(resp.status, Some(status::Created | status::NoContent))
+                {
+                    self.uploaded(&req.headers, &relative.to_string_lossy());
+                }
+                if let (method::Delete, Some(status::NoContent), Some(mirror)) =
+                    (&req.method, resp.status, &self.mirror)
+                {
+                    mirror.deleted(&req.headers, &relative.to_string_lossy(), true);
+                }
+                return Ok(resp);
+            }
+        }
+
+        if let Some(ref trash) = self.trash {
+            if req.method == method::Delete {
+                self.state.delete.check("delete")?;
+                return trash.delete(req, &fs_path);
+            }
+        }
 
         if self.upload.is_some() && req.method == method::Post {
-            if let Err((s, msg)) = self.save_files(req, &fs_path) {
-                return Ok(error_resp(s, &msg, &self.base_url));
+            self.state.upload.check("upload")?;
+            if let Err((s, msg)) = self.save_files(req, &relative) {
+                if self.error_detail == ErrorDetail::Minimal {
+                    warn!("Upload failed: {}", msg);
+                }
+                let msg = self.error_detail.message(s, &msg);
+                return Ok(error_reply(req, s, &msg, &self.base_url));
             } else if self.base_url == "/" {
                 return Ok(Response::with((status::Found, Redirect(req.url.clone()))));
             } else {
@@ -523,15 +2440,19 @@ impl Handler for MainHandler {
             }
         }
 
-        let path_metadata = match fs::metadata(&fs_path) {
+        let stat_start = Instant::now();
+        let path_metadata = info_span!("stat", path = %relative.display())
+            .in_scope(|| self.storage.stat(&relative));
+        record_stat(req, stat_start.elapsed());
+        let path_metadata = match path_metadata {
             Ok(value) => value,
             Err(err) => {
                 let status = match err.kind() {
                     io::ErrorKind::PermissionDenied => status::Forbidden,
                     io::ErrorKind::NotFound => {
-                        if let Some(ref p) = self.try_file_404 {
-                            if Some(true) == fs::metadata(p).ok().map(|meta| meta.is_file()) {
-                                return self.send_file(req, p, Some(status::NotFound));
+                        if let Some((ref storage, ref p)) = self.try_file_404 {
+                            if storage.stat(p).is_ok_and(|metadata| metadata.is_file) {
+                                return self.send_file(req, storage, p, Some(status::NotFound));
                             }
                         }
                         status::NotFound
@@ -542,58 +2463,336 @@ impl Handler for MainHandler {
             }
         };
 
-        if path_metadata.is_dir() {
+        if path_metadata.is_dir {
             let path_prefix: Vec<String> = path_prefix
                 .iter()
                 .map(|s| s.to_string_lossy().to_string())
                 .collect();
-            self.list_directory(req, &fs_path, &path_prefix, &self.base_url[..])
+            if !self.state.listing.is_on() {
+                if self.index {
+                    for name in &["index.html", "index.htm"] {
+                        let index = relative.join(name);
+                        if self
+                            .storage
+                            .stat(&index)
+                            .is_ok_and(|metadata| metadata.is_file)
+                        {
+                            return self.send_file(req, &*self.storage, &index, None);
+                        }
+                    }
+                }
+                return Err(IronError::new(
+                    StringError("listing is disabled".to_owned()),
+                    status::Forbidden,
+                ));
+            }
+            if self.archive {
+                let format = req
+                    .url
+                    .as_ref()
+                    .query_pairs()
+                    .find(|(k, _)| k == "archive")
+                    .map(|(_, v)| v.parse::<DirArchive>());
+                if let Some(format) = format {
+                    let format = format
+                        .map_err(|err| IronError::new(StringError(err), status::BadRequest))?;
+                    let name = path_prefix.last().map(String::as_str).unwrap_or("root");
+                    return send_dir_archive(self.storage.clone(), &relative, name, format);
+                }
+            }
+            if req.url.as_ref().query_pairs().any(|(k, _)| k == "m3u") {
+                return playlist::handle(
+                    req,
+                    &*self.storage,
+                    &relative,
+                    &path_prefix,
+                    &self.base_url,
+                );
+            }
+            // The manifest walks the local files
+            if let Some(ref fs_path) = self.storage.local_path(&relative) {
+                if req.url.as_ref().query_pairs().any(|(k, _)| k == "manifest") {
+                    return manifest::handle(req, fs_path);
+                }
+            }
+            if let Some(ref grep) = self.grep {
+                let pattern = req
+                    .url
+                    .as_ref()
+                    .query_pairs()
+                    .find(|(k, _)| k == "grep")
+                    .map(|(_, v)| v.to_string());
+                if let Some(pattern) = pattern {
+                    return grep.search(
+                        req,
+                        &relative,
+                        &path_prefix,
+                        &pattern,
+                        &self.title,
+                        &self.base_url,
+                    );
+                }
+            }
+            self.list_directory(req, &relative, &path_prefix, &self.base_url[..])
         } else {
-            self.send_file(req, &fs_path, None)
+            let view = req
+                .url
+                .as_ref()
+                .query_pairs()
+                .find(|(k, _)| k == "view")
+                .map(|(_, v)| v.to_string());
+            if view.as_deref() == Some("archive") {
+                let path_prefix: Vec<String> = relative
+                    .components()
+                    .map(|s| s.as_os_str().to_string_lossy().to_string())
+                    .collect();
+                return view_archive(
+                    req,
+                    &*self.storage,
+                    &relative,
+                    &path_prefix,
+                    &self.title,
+                    &self.base_url,
+                    self.zip_members,
+                );
+            }
+            #[cfg(feature = "signing")]
+            if let Some(ref signer) = self.signer {
+                if req.url.as_ref().query_pairs().any(|(k, _)| k == "sig") {
+                    return signer.sidecar(&*self.storage, &relative);
+                }
+            }
+            let images = self
+                .images
+                .as_ref()
+                .filter(|_| ImageOps::is_image(&relative));
+            if let Some(images) = images {
+                let resize = Resize::from_query(req)
+                    .map_err(|msg| IronError::new(StringError(msg), status::BadRequest))?;
+                let format = ImageOps::fallback_format(&relative, req);
+                if ImageOps::is_transcoded(&relative) {
+                    vary_on(req, "Accept");
+                }
+                let local_path = self
+                    .storage
+                    .local_path(&relative)
+                    .filter(|_| resize.is_some() || format.is_some());
+                if let Some(fs_path) = local_path {
+                    let converted = images
+                        .convert(&fs_path, &path_metadata, resize.as_ref(), format)
+                        .map_err(|err| {
+                            if err.kind() == io::ErrorKind::ResourceBusy {
+                                let mut resp = Response::with((
+                                    status::ServiceUnavailable,
+                                    "Too many images being resized, please retry later.",
+                                ));
+                                resp.headers.set_raw(
+                                    "Retry-After",
+                                    vec![images::QUEUE_TIMEOUT.as_secs().to_string().into_bytes()],
+                                );
+                                return IronError {
+                                    error: Box::new(err),
+                                    response: resp,
+                                };
+                            }
+                            warn!("Convert {} failed: {}", relative.display(), err);
+                            IronError::new(err, status::InternalServerError)
+                        })?;
+                    return self.send_file(req, images.cache(), &converted, None);
+                }
+            }
+            self.send_file(req, &*self.storage, &relative, None)
         }
     }
 }
 
 impl MainHandler {
+    /// Tell the viewers and the mirror about the upload of `path`
+    fn uploaded(&self, headers: &iron::Headers, path: &str) {
+        self.events.uploaded(headers, path);
+        if let Some(ref mirror) = self.mirror {
+            mirror.uploaded(headers, path);
+        }
+    }
+
+    /// Dispatch the special `/-/*` endpoints
+    fn handle_special(&self, req: &mut Request) -> IronResult<Response> {
+        let path = req
+            .url
+            .path()
+            .into_iter()
+            .skip(1)
+            .map(|s| s.to_owned())
+            .collect::<Vec<String>>();
+        match path.first().map(String::as_str) {
+            Some("capabilities") => return Ok(self.capabilities.response()),
+            Some("admin") => {
+                if let Some(ref admin) = self.admin {
+                    return admin.handle(req, &path[1..]);
+                }
+            }
+            Some("stats") => {
+                if let Some(ref stats) = self.stats {
+                    return Ok(stats.stats_page(&self.title, &self.base_url));
+                }
+            }
+            Some("metrics") => {
+                if let Some(ref stats) = self.stats {
+                    return Ok(stats.metrics());
+                }
+            }
+            Some("sync") => {
+                if let Some(ref sync) = self.sync {
+                    self.state.upload.check("upload")?;
+                    let resp = sync.handle(req, &path[1..])?;
+                    // Batch files are complete when created, resumable ones on the last chunk
+                    if req.method == method::Put && resp.status == Some(status::Created) {
+                        let target = path[1..]
+                            .iter()
+                            .filter(|s| !s.is_empty())
+                            .map(|s| percent_decode(s.as_bytes()).decode_utf8_lossy())
+                            .collect::<Vec<_>>()
+                            .join("/");
+                        self.uploaded(&req.headers, &target);
+                    }
+                    return Ok(resp);
+                }
+            }
+            Some("trash") if path.len() == 1 => {
+                if let Some(ref trash) = self.trash {
+                    return trash.list(req);
+                }
+            }
+            Some("restore") => {
+                if let Some(ref trash) = self.trash {
+                    self.state.delete.check("delete")?;
+                    return trash.restore(req);
+                }
+            }
+            Some("events") if self.upload.is_some() && path.len() == 1 => {
+                return self.events.handle(req, &self.transfers.json());
+            }
+            Some(kind @ ("copy" | "move")) if path.len() == 1 => {
+                if let Some(ref upload) = self.upload {
+                    self.state.upload.check("upload")?;
+                    check_token(req, &upload.csrf_token)?;
+                    let kind = if kind == "move" {
+                        TransferKind::Move
+                    } else {
+                        TransferKind::Copy
+                    };
+                    return self.transfers.start(req, kind);
+                }
+            }
+            Some("cancel") if path.len() == 1 => {
+                if let Some(ref upload) = self.upload {
+                    check_token(req, &upload.csrf_token)?;
+                    return self.transfers.cancel(req);
+                }
+            }
+            Some("upload-progress") if self.upload.is_some() && path.len() == 2 => {
+                return self.upload_progress.handle(&path[1]);
+            }
+            Some("uploads") => {
+                if let Some(ref sync) = self.sync {
+                    return sync.pending(req, &path[1..]);
+                }
+            }
+            Some("description") => {
+                if let Some(ref descriptions) = self.descriptions {
+                    return descriptions.handle(req, &path[1..]);
+                }
+            }
+            Some("diff") => {
+                if let Some(ref diff) = self.diff {
+                    return diff.handle(req, &self.title, &self.base_url);
+                }
+            }
+            #[cfg(feature = "signing")]
+            Some("sign-key") if path.len() == 1 => {
+                if let Some(ref signer) = self.signer {
+                    return Ok(signer.public_key_response());
+                }
+            }
+            _ => {}
+        }
+        Err(IronError::new(
+            StringError(format!("not found: {}", req.url.path().join("/"))),
+            status::NotFound,
+        ))
+    }
+
+    /// Response body of a served file, read in `--read-buffer-size` chunks
+    fn file_body<R: Read + Send + 'static>(&self, file: R) -> Box<dyn WriteBody> {
+        match self.read_buffer_size {
+            Some(size) => {
+                Box::new(Box::new(BufReader::with_capacity(size, file)) as Box<dyn Read + Send>)
+            }
+            None => Box::new(Box::new(file) as Box<dyn Read + Send>),
+        }
+    }
+
     fn save_files(&self, req: &mut Request, path: &Path) -> Result<(), (status::Status, String)> {
-        match Multipart::from_request(req) {
-            Ok(mut multipart) => {
+        let boundary = req
+            .headers
+            .get::<headers::ContentType>()
+            .and_then(|content_type| match **content_type {
+                Mime(TopLevel::Multipart, SubLevel::FormData, _) => content_type
+                    .get_param("boundary")
+                    .map(|boundary| boundary.to_string()),
+                _ => None,
+            });
+        match boundary {
+            Some(boundary) => {
+                let body = self.upload_progress.track(&req.headers, &mut req.body);
+                let mut multipart = Multipart::with_body(body, boundary);
                 // Fetching all data and processing it.
                 // save().temp() reads the request fully, parsing all fields and saving all files
                 // in a new temporary directory under the OS temporary directory.
-                match multipart.save().size_limit(self.upload_size_limit).temp() {
+                let save = multipart.save().size_limit(self.upload_size_limit);
+                let saved = if self.in_memory_uploads {
+                    // Nothing is written to the directory with the threshold at the size limit
+                    save.memory_threshold(self.upload_size_limit)
+                        .with_entries(Entries::new(SaveDir::Perm(env::temp_dir())))
+                } else {
+                    save.temp()
+                };
+                match saved {
                     SaveResult::Full(entries) => {
-                        // Pull out csrf field to check if token matches one generated
-                        let csrf_field = match entries
-                            .fields
-                            .get("csrf")
-                            .map(|fields| fields.first())
-                            .unwrap_or(None)
-                        {
-                            Some(field) => field,
-                            None => {
+                        // API keys are not sent by browsers, their uploads need no token
+                        if !req.extensions.contains::<KeyAuth>() {
+                            // Pull out csrf field to check if token matches one generated
+                            let csrf_field = match entries
+                                .fields
+                                .get("csrf")
+                                .map(|fields| fields.first())
+                                .unwrap_or(None)
+                            {
+                                Some(field) => field,
+                                None => {
+                                    return Err((
+                                        status::BadRequest,
+                                        String::from("csrf parameter not provided"),
+                                    ))
+                                }
+                            };
+
+                            // Read token value from field
+                            let mut token = String::new();
+                            csrf_field
+                                .data
+                                .readable()
+                                .unwrap()
+                                .read_to_string(&mut token)
+                                .unwrap();
+
+                            // Check if they match
+                            if self.upload.as_ref().unwrap().csrf_token != token {
                                 return Err((
                                     status::BadRequest,
-                                    String::from("csrf parameter not provided"),
-                                ))
+                                    String::from("csrf token does not match"),
+                                ));
                             }
-                        };
-
-                        // Read token value from field
-                        let mut token = String::new();
-                        csrf_field
-                            .data
-                            .readable()
-                            .unwrap()
-                            .read_to_string(&mut token)
-                            .unwrap();
-
-                        // Check if they match
-                        if self.upload.as_ref().unwrap().csrf_token != token {
-                            return Err((
-                                status::BadRequest,
-                                String::from("csrf token does not match"),
-                            ));
                         }
 
                         // Grab all the fields named files
@@ -607,18 +2806,69 @@ impl MainHandler {
                         for field in files_fields {
                             let mut data = field.data.readable().unwrap();
                             let headers = &field.headers;
-                            let mut target_path = path.to_owned();
-
-                            target_path.push(headers.filename.clone().unwrap());
-                            if let Err(errno) = std::fs::File::create(target_path)
-                                .and_then(|mut file| io::copy(&mut data, &mut file))
-                            {
+                            let filename = match headers.filename {
+                                Some(ref filename) => self.upload_filename.apply(filename),
+                                None => {
+                                    return Err((
+                                        status::BadRequest,
+                                        String::from("file name not provided"),
+                                    ))
+                                }
+                            };
+                            // `keep` may let through `..` or separators
+                            let mut components = Path::new(&filename).components();
+                            if !matches!(
+                                (components.next(), components.next()),
+                                (Some(std::path::Component::Normal(_)), None)
+                            ) {
+                                return Err((
+                                    status::BadRequest,
+                                    format!("invalid file name: {}", filename),
+                                ));
+                            }
+                            if is_access_file(&filename) {
+                                return Err((
+                                    status::Forbidden,
+                                    format!("can not upload {}", filename),
+                                ));
+                            }
+                            let target = path.join(&filename);
+                            let target_path = self.root.join(&target);
+                            let _lock = match self.write_locks.lock(&target_path) {
+                                Ok(lock) => lock,
+                                Err(err) => return Err((status::Locked, err.error.to_string())),
+                            };
+                            let scan = |tmp: &Path| match self.scanner {
+                                Some(ref scanner) => scanner.scan(tmp, &filename),
+                                None => Ok(()),
+                            };
+                            let check = self.scanner.as_ref().map(|_| &scan as Check);
+                            if let Err(errno) = self.storage.write(&target, &mut data, check) {
+                                if is_rejected(&errno) {
+                                    warn!("Upload rejected: {}, {}", filename, errno);
+                                    return Err((
+                                        status::UnprocessableEntity,
+                                        format!("{} {}", filename, errno),
+                                    ));
+                                }
+                                if errno.kind() == io::ErrorKind::StorageFull {
+                                    return Err((
+                                        status::InsufficientStorage,
+                                        format!("{} {}", filename, errno),
+                                    ));
+                                }
                                 return Err((
                                     status::InternalServerError,
                                     format!("Copy file failed: {}", errno),
                                 ));
                             } else {
-                                println!("  >> File saved: {}", headers.filename.clone().unwrap());
+                                info!("File saved: {}", filename);
+                                self.uploaded(&req.headers, &target.to_string_lossy());
+                                if let (Some(dedupe), Some(local_path)) =
+                                    (&self.dedupe, self.storage.local_path(&target))
+                                {
+                                    dedupe.link(&local_path);
+                                }
                             }
                         }
                         Ok(())
@@ -632,7 +2882,7 @@ impl MainHandler {
                     }
                 }
             }
-            Err(_) => Err((
+            None => Err((
                 status::BadRequest,
                 "The request is not multipart".to_owned(),
             )),
@@ -642,30 +2892,17 @@ impl MainHandler {
     fn list_directory(
         &self,
         req: &mut Request,
-        fs_path: &Path,
+        path: &Path,
         path_prefix: &[String],
         base_url: &str,
     ) -> IronResult<Response> {
-        struct Entry {
-            filename: String,
-            metadata: fs::Metadata,
-        }
-
         let mut resp = Response::with(status::Ok);
-        let mut fs_path = fs_path.to_owned();
         let mut rows = Vec::new();
+        let now = Local::now();
 
         let title_postfix: String;
 
-        let read_dir = fs::read_dir(&fs_path).map_err(error_io2iron)?;
-        let mut entries = Vec::new();
-        for entry_result in read_dir {
-            let entry = entry_result.map_err(error_io2iron)?;
-            entries.push(Entry {
-                filename: entry.file_name().into_string().unwrap(),
-                metadata: entry.metadata().map_err(error_io2iron)?,
-            });
-        }
+        let mut entries = self.storage.list(path).map_err(error_io2iron)?;
 
         // Breadcrumb navigation
         let breadcrumb = if !path_prefix.is_empty() {
@@ -701,6 +2938,20 @@ impl MainHandler {
             root_link(base_url)
         };
 
+        // `?glob=*.log`, repeatable: only the entries matching one of the patterns, kept by the
+        // sort links
+        let globs = req
+            .url
+            .as_ref()
+            .query_pairs()
+            .filter(|(k, v)| k == "glob" && !v.is_empty())
+            .map(|(_, v)| v.to_string())
+            .collect::<Vec<String>>();
+        let glob_query = globs
+            .iter()
+            .map(|glob| format!("&glob={}", utf8_percent_encode(glob, NON_ALPHANUMERIC)))
+            .collect::<String>();
+
         // Sort links
         let sort_links = if self.sort {
             let mut sort_field = Some(String::from("name"));
@@ -722,7 +2973,7 @@ impl MainHandler {
             }
 
             if let Some(field) = sort_field {
-                if !SORT_FIELDS.iter().any(|s| *s == field.as_str()) {
+                if !SORT_FIELDS.contains(&field.as_str()) {
                     return Err(IronError::new(
                         StringError(format!("Unknown sort field: {}", field)),
                         status::BadRequest,
@@ -738,18 +2989,14 @@ impl MainHandler {
                 let reverse = order == ORDER_DESC;
                 entries.sort_by(|a, b| {
                     let rv = match field.as_str() {
-                        "name" => a.filename.cmp(&b.filename),
-                        "modified" => {
-                            let a = a.metadata.modified().unwrap();
-                            let b = b.metadata.modified().unwrap();
-                            a.cmp(&b)
-                        }
+                        "name" => a.name.cmp(&b.name),
+                        "modified" => a.metadata.modified.cmp(&b.metadata.modified),
                         "size" => {
-                            if a.metadata.is_dir() == b.metadata.is_dir()
-                                || a.metadata.is_file() == b.metadata.is_file()
+                            if a.metadata.is_dir == b.metadata.is_dir
+                                || a.metadata.is_file == b.metadata.is_file
                             {
-                                a.metadata.len().cmp(&b.metadata.len())
-                            } else if a.metadata.is_dir() {
+                                a.metadata.len.cmp(&b.metadata.len)
+                            } else if a.metadata.is_dir {
                                 Ordering::Less
                             } else {
                                 Ordering::Greater
@@ -770,9 +3017,9 @@ impl MainHandler {
             format!(
                 r#"
 <tr>
-  <th><a href="{base_url}{link}?sort=name&order={name_order}">Name</a></th>
-  <th><a href="{base_url}{link}?sort=modified&order={modified_order}">Last modified</a></th>
-  <th><a href="{base_url}{link}?sort=size&order={size_order}">Size</a></th>
+  <th><a href="{base_url}{link}?sort=name&order={name_order}{glob_query}">Name</a></th>
+  <th><a href="{base_url}{link}?sort=modified&order={modified_order}{glob_query}">Last modified</a></th>
+  <th><a href="{base_url}{link}?sort=size&order={size_order}{glob_query}">Size</a></th>{hash_header}
 </tr>
 <tr><td style="border-top:1px dashed #BBB;" colspan="5"></td></tr>
 "#,
@@ -780,6 +3027,12 @@ impl MainHandler {
                 name_order = order_labels.get("name").unwrap_or(&DEFAULT_ORDER),
                 modified_order = order_labels.get("modified").unwrap_or(&DEFAULT_ORDER),
                 size_order = order_labels.get("size").unwrap_or(&DEFAULT_ORDER),
+                glob_query = glob_query,
+                hash_header = if self.hashes.is_some() {
+                    "\n  <th>SHA-256</th>"
+                } else {
+                    ""
+                },
                 base_url = base_url,
             )
         } else {
@@ -808,29 +3061,125 @@ impl MainHandler {
             rows.push(r#"<tr><td>&nbsp;</td></tr>"#.to_owned());
         }
 
+        let tag_filter = req
+            .url
+            .as_ref()
+            .query_pairs()
+            .find(|(k, _)| k == "tag")
+            .map(|(_, v)| v.to_string());
+        // `?format=csv|tsv|json`: an inventory of the entries instead of the HTML page. The JSON
+        // schema is versioned, for crawlers, and only ever gets new fields within a version:
+        // `{"version":1,"path":"/dir/","entries":[{"name":"a.txt","type":"file","size":3,
+        // "mtime":"2024-01-01T00:00:00+00:00","url":"/dir/a.txt"}]}`, `size` is null for
+        // directories and `type` one of `file`, `dir` or `other`. `"glob":["*.txt"]` follows
+        // `path` when the entries are filtered. Clients preferring JSON in
+        // `Accept` get it without the query.
+        vary_on(req, "Accept");
+        let inventory_format = req
+            .url
+            .as_ref()
+            .query_pairs()
+            .find(|(k, v)| k == "format" && (v == "csv" || v == "tsv" || v == "json"))
+            .map(|(_, v)| v.to_string())
+            .or_else(|| prefers_json(req).then(|| "json".to_owned()));
+        let (separator, field): (&str, fn(&str) -> String) = match inventory_format.as_deref() {
+            Some("tsv") => ("\t", tsv_field),
+            _ => (",", csv_field),
+        };
+        let mut inventory = if inventory_format.as_deref() == Some("json") {
+            Vec::new()
+        } else {
+            vec![["name", "size", "mtime", "type"].join(separator)]
+        };
+
         // Directory entries
-        for Entry { filename, metadata } in entries {
+        for Entry {
+            name: filename,
+            metadata,
+        } in entries
+        {
+            let tag_path = path_prefix
+                .iter()
+                .chain(Some(&filename))
+                .cloned()
+                .collect::<Vec<String>>()
+                .join("/");
+            if let (Some(tags), Some(filter)) = (&self.tags, &tag_filter) {
+                if !tags.matches(&tag_path, filter) {
+                    continue;
+                }
+            }
+            if !globs.is_empty() && !globs.iter().any(|glob| glob_match(glob, &filename)) {
+                continue;
+            }
+            if inventory_format.is_some() {
+                let size = if metadata.is_dir {
+                    String::new()
+                } else {
+                    metadata.len.to_string()
+                };
+                let kind = if metadata.is_dir {
+                    "dir"
+                } else if metadata.is_file {
+                    "file"
+                } else {
+                    "other"
+                };
+                let modified = system_time_to_date_time(metadata.modified);
+                if inventory_format.as_deref() == Some("json") {
+                    let mut link = path_prefix.to_owned();
+                    link.push(filename.clone());
+                    if metadata.is_dir {
+                        link.push("".to_owned());
+                    }
+                    inventory.push(format!(
+                        r#"{{"name":"{}","type":"{}","size":{},"mtime":"{}","url":"{}"}}"#,
+                        json_escape(&filename),
+                        kind,
+                        if size.is_empty() { "null" } else { &size },
+                        modified.to_rfc3339(),
+                        json_escape(&format!("{}{}", base_url, encode_link_path(&link))),
+                    ));
+                    continue;
+                }
+                inventory.push(
+                    [
+                        field(&filename),
+                        size,
+                        modified.to_rfc3339(),
+                        kind.to_owned(),
+                    ]
+                    .join(separator),
+                );
+                continue;
+            }
             if self.index {
                 for fname in &["index.html", "index.htm"] {
                     if filename == *fname {
                         // Automatic render index page
-                        fs_path.push(filename);
-                        return self.send_file(req, &fs_path, None);
+                        return self.send_file(req, &*self.storage, &path.join(fname), None);
                     }
                 }
             }
             // * Entry.modified
-            let file_modified = system_time_to_date_time(metadata.modified().unwrap())
-                .format("%Y-%m-%d %H:%M:%S")
-                .to_string();
+            let modified = system_time_to_date_time(metadata.modified);
+            let file_modified = match self.date_format {
+                Some(ref date_format) => encode_minimal(&modified.format(date_format).to_string()),
+                None => format!(
+                    r#"<time datetime="{}" title="{}">{}</time>"#,
+                    modified.to_rfc3339(),
+                    modified.format("%Y-%m-%d %H:%M:%S"),
+                    relative_time(modified, now),
+                ),
+            };
             // * Entry.filesize
-            let file_size = if metadata.is_dir() {
+            let file_size = if metadata.is_dir {
                 "-".to_owned()
             } else {
-                convert(metadata.len() as f64)
+                convert(metadata.len as f64)
             };
             // * Entry.linkstyle
-            let link_style = if metadata.is_dir() {
+            let link_style = if metadata.is_dir {
                 "style=\"font-weight: bold;\"".to_owned()
             } else {
                 "".to_owned()
@@ -838,11 +3187,11 @@ impl MainHandler {
             // * Entry.link
             let mut link = path_prefix.to_owned();
             link.push(filename.clone());
-            if metadata.is_dir() {
+            if metadata.is_dir {
                 link.push("".to_owned());
             }
             // * Entry.label
-            let file_name_label = if metadata.is_dir() {
+            let file_name_label = if metadata.is_dir {
                 format!("{}/", &filename)
             } else {
                 filename.clone()
@@ -852,37 +3201,178 @@ impl MainHandler {
             rows.push(format!(
                 r#"
 <tr>
-  <td><a {linkstyle} href="{base_url}{link}">{label}</a></td>
+  <td><a {linkstyle} href="{base_url}{link}">{label}</a>{tags}</td>
   <td style="color:#888;">[{modified}]</td>
-  <td><bold>{filesize}</bold></td>
+  <td><bold>{filesize}</bold></td>{hash}
 </tr>
 "#,
                 linkstyle = link_style,
                 link = encode_link_path(&link),
                 label = encode_minimal(&file_name_label),
+                tags = self
+                    .tags
+                    .as_ref()
+                    .map(|tags| tags.labels(&tag_path))
+                    .unwrap_or_default(),
                 modified = file_modified,
                 filesize = file_size,
+                hash = self
+                    .hashes
+                    .as_ref()
+                    .map(|hashes| format!(
+                        "\n  <td>{}</td>",
+                        self.storage
+                            .local_path(&path.join(&filename))
+                            .map(|fs_path| hashes.cell(&tag_path, &fs_path, &metadata))
+                            .unwrap_or_default()
+                    ))
+                    .unwrap_or_default(),
                 base_url = base_url,
             ));
         }
 
+        if inventory_format.as_deref() == Some("json") {
+            let mut dir = path_prefix.to_owned();
+            if !dir.is_empty() {
+                dir.push("".to_owned());
+            }
+            let mut resp = Response::with((
+                status::Ok,
+                format!(
+                    r#"{{"version":1,"path":"{}",{}"entries":[{}]}}"#,
+                    json_escape(&format!("{}{}", base_url, encode_link_path(&dir))),
+                    if globs.is_empty() {
+                        String::new()
+                    } else {
+                        format!(
+                            r#""glob":[{}],"#,
+                            globs
+                                .iter()
+                                .map(|glob| format!(r#""{}""#, json_escape(glob)))
+                                .collect::<Vec<String>>()
+                                .join(",")
+                        )
+                    },
+                    inventory.join(",")
+                ),
+            ));
+            resp.headers.set(headers::ContentType::json());
+            return Ok(resp);
+        }
+        if let Some(format) = inventory_format {
+            inventory.push(String::new());
+            let mut resp = Response::with((status::Ok, inventory.join("\n")));
+            resp.headers.set(headers::ContentType(
+                format!(
+                    "{}; charset=utf-8",
+                    if format == "tsv" {
+                        "text/tab-separated-values"
+                    } else {
+                        "text/csv"
+                    }
+                )
+                .parse()
+                .unwrap(),
+            ));
+            return Ok(resp);
+        }
+
         // Optional upload form
-        let upload_form = if self.upload.is_some() {
+        let upload_form = if let Some(upload) =
+            self.upload.as_ref().filter(|_| self.state.upload.is_on())
+        {
             format!(
                 r#"
 <form style="margin-top:1em; margin-bottom:1em;" action="{base_url}{path}" method="POST" enctype="multipart/form-data">
   <input type="file" name="files" accept="*" multiple />
   <input type="hidden" name="csrf" value="{csrf}"/>
   <input type="submit" value="Upload" />
-</form>
+</form>{resumable}
 "#,
                 path = encode_link_path(path_prefix),
-                csrf = self.upload.as_ref().unwrap().csrf_token,
+                csrf = upload.csrf_token,
                 base_url = base_url,
+                resumable = if self.sync.is_some() {
+                    format!(
+                        r#"
+<div style="margin-bottom:1em;">
+  <input type="file" id="resumable-files" multiple />
+  <button id="resumable-upload">Upload (resumable)</button>
+  <div id="resumable-pending"></div>
+  <div id="resumable-status"></div>
+</div>
+<script>var UPLOAD = {{ token: "{csrf}", dir: "{dir}", base: "{base_url}" }};</script>
+<script>{resumable_upload_script}</script>"#,
+                        csrf = upload.csrf_token,
+                        base_url = base_url,
+                        dir = json_escape(
+                            &path_prefix
+                                .iter()
+                                .map(|s| format!("{}/", s))
+                                .collect::<String>()
+                        ),
+                        resumable_upload_script = RESUMABLE_UPLOAD_SCRIPT,
+                    )
+                } else {
+                    String::new()
+                },
             )
         } else {
             "".to_owned()
         };
+        let description = self
+            .descriptions
+            .as_ref()
+            .map(|descriptions| descriptions.render(&*self.storage, path, path_prefix, base_url))
+            .unwrap_or_default();
+        let archive_links = if self.archive {
+            let mut link = path_prefix.to_owned();
+            link.push("".to_owned());
+            let link = format!("{}{}", base_url, encode_link_path(&link));
+            format!(
+                r#"<div style="margin-top:0.5em;">Download as <a href="{link}?archive=zip">.zip</a> / <a href="{link}?archive=tar.gz">.tar.gz</a></div>"#,
+                link = link
+            )
+        } else {
+            String::new()
+        };
+        let upload_notifications = if self.upload_notifications {
+            format!(
+                r#"<script>var EVENTS = {{ base: "{base_url}", dir: "{dir}" }};</script>
+  <script>{script}</script>"#,
+                base_url = base_url,
+                dir = json_escape(
+                    &path_prefix
+                        .iter()
+                        .map(|s| format!("{}/", s))
+                        .collect::<String>()
+                ),
+                script = UPLOAD_NOTIFICATIONS_SCRIPT,
+            )
+        } else {
+            String::new()
+        };
+        let glob_notice = if globs.is_empty() {
+            String::new()
+        } else {
+            let mut link = path_prefix.to_owned();
+            link.push("".to_owned());
+            format!(
+                r#"<div style="margin-top:0.5em;">Only showing the entries matching {globs} · <a href="{base_url}{link}">Show all</a></div>"#,
+                globs = globs
+                    .iter()
+                    .map(|glob| format!("<code>{}</code>", encode_minimal(glob)))
+                    .collect::<Vec<String>>()
+                    .join(" or "),
+                link = encode_link_path(&link),
+                base_url = base_url,
+            )
+        };
+        let search_form = if self.grep.is_some() {
+            r#"<form style="margin-bottom:1em;" method="GET"><input type="search" name="grep" placeholder="Search in files (regex)" /></form>"#
+        } else {
+            ""
+        };
 
         // Put all parts together
         resp.set_mut(format!(
@@ -896,27 +3386,46 @@ impl MainHandler {
   <style> a {{ text-decoration:none; }} </style>
 </head>
 <body>
+  {brand}
   {upload_form}
+  {search_form}
   <div>{breadcrumb}</div>
+  {glob_notice}
+  {archive_links}
+  {description}
   <hr />
   <table>
     {sort_links}
     {rows}
   </table>
+  {upload_notifications}
+  {relative_time_script}
 </body>
 </html>
 "#,
-            favicon_image = FAVICON_IMAGE,
+            favicon_image = favicon_image(),
+            brand = brand_html(),
             title = self.title,
             title_postfix = title_postfix,
             upload_form = upload_form,
+            search_form = search_form,
             breadcrumb = breadcrumb,
+            glob_notice = glob_notice,
+            archive_links = archive_links,
+            description = description,
             sort_links = sort_links,
-            rows = rows.join("\n")
+            rows = rows.join("\n"),
+            upload_notifications = upload_notifications,
+            relative_time_script = if self.date_format.is_none() {
+                RELATIVE_TIME_SCRIPT
+            } else {
+                ""
+            },
         ));
 
         resp.headers.set(headers::ContentType::html());
-        if self.compress.is_some() {
+        if self.compress.is_some() && self.state.compression.is_on() {
+            vary_on(req, "Accept-Encoding");
             if let Some(AcceptEncoding(encodings)) = req.headers.get::<AcceptEncoding>() {
                 for QualityItem { item, .. } in encodings {
                     if *item == Encoding::Deflate || *item == Encoding::Gzip {
@@ -928,42 +3437,114 @@ impl MainHandler {
         Ok(resp)
     }
 
-    fn send_file<P: AsRef<Path>>(
+    /// `--precompressed`: the `.br` or `.gz` file next to `path` in the encoding the client
+    /// prefers, brotli first when equally accepted
+    fn precompressed_sidecar(
+        &self,
+        req: &mut Request,
+        storage: &dyn Storage,
+        path: &Path,
+    ) -> Option<(PathBuf, Encoding, Metadata)> {
+        vary_on(req, "Accept-Encoding");
+        let AcceptEncoding(encodings) = req.headers.get::<AcceptEncoding>()?;
+        let mut accepted = encodings
+            .iter()
+            .filter(|QualityItem { quality, .. }| quality.0 > 0)
+            .filter_map(|QualityItem { item, quality }| match item {
+                Encoding::EncodingExt(ext) if ext == "br" => Some((quality.0, 1, "br", item)),
+                Encoding::Gzip => Some((quality.0, 0, "gz", item)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        accepted.sort_by_key(|(quality, brotli, _, _)| std::cmp::Reverse((*quality, *brotli)));
+        accepted.into_iter().find_map(|(_, _, ext, encoding)| {
+            let mut sidecar = path.as_os_str().to_owned();
+            sidecar.push(".");
+            sidecar.push(ext);
+            let sidecar = PathBuf::from(sidecar);
+            match storage.stat(&sidecar) {
+                Ok(metadata) if metadata.is_file => Some((sidecar, encoding.clone(), metadata)),
+                _ => None,
+            }
+        })
+    }
+
+    fn send_file(
         &self,
-        req: &Request,
-        path: P,
+        req: &mut Request,
+        storage: &dyn Storage,
+        path: &Path,
         status: Option<Status>,
     ) -> IronResult<Response> {
         use filetime::FileTime;
+        use iron::headers::HttpDate;
         use iron::headers::{
-            AcceptRanges, ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec, ETag,
-            EntityTag, IfMatch, IfRange, Range, RangeUnit,
-        };
-        use iron::headers::{
-            CacheControl, CacheDirective, HttpDate, IfModifiedSince, LastModified,
+            AcceptRanges, ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec, IfMatch,
+            IfRange, Range, RangeUnit,
         };
         use iron::method::Method;
 
-        let path = path.as_ref();
-        let metadata = fs::metadata(path).map_err(error_io2iron)?;
+        let sidecar = if self.precompressed && matches!(req.method, Method::Get | Method::Head) {
+            self.precompressed_sidecar(req, storage, path)
+        } else {
+            None
+        };
+        // The sidecar is its own representation, with its length, ranges and ETag
+        let (body_path, metadata) = match sidecar {
+            Some((ref sidecar_path, _, ref metadata)) => (sidecar_path.as_path(), metadata.clone()),
+            None => (path, storage.stat(path).map_err(error_io2iron)?),
+        };
+        let file_len = metadata.len;
+
+        let time = FileTime::from_system_time(metadata.modified);
+        let modified = time::Timespec::new(time.seconds(), 0);
+        let etag = file_etag(&metadata);
 
-        let time = FileTime::from_last_modification_time(&metadata);
-        let modified = time::Timespec::new(time.seconds() as i64, 0);
-        let etag = EntityTag::weak(format!(
-            "{0:x}-{1:x}.{2:x}",
-            metadata.len(),
-            modified.sec,
-            modified.nsec
-        ));
+        // Checked first, an unchanged file is not opened and its ranges not looked at
+        if status.is_none()
+            && matches!(req.method, Method::Get | Method::Head)
+            && is_not_modified(req, &etag, metadata.modified)
+        {
+            let mut resp = Response::with(status::NotModified);
+            self.set_validators(&mut resp, path, modified, etag);
+            return Ok(resp);
+        }
+
+        // Reading an offline file would wait for its recall
+        if let Some(ref cold_storage) = self.cold_storage {
+            if let Some(local_path) = storage.local_path(body_path) {
+                cold_storage.check(&local_path, &self.base_url)?;
+            }
+        }
 
         let mut resp = Response::with(status.unwrap_or(status::Ok));
+        resp.extensions.insert::<FileBody>(());
         if self.range {
             resp.headers.set(AcceptRanges(vec![RangeUnit::Bytes]));
         }
         // Set mime type
         let mime = mime_types::from_path(path).first_or_octet_stream();
+        // Subtitles are text, players otherwise guess their encoding
+        let content_type = if is_subtitle(&mime) {
+            format!("{}; charset=utf-8", mime)
+        } else {
+            mime.to_string()
+        };
         resp.headers
-            .set_raw("content-type", vec![mime.to_string().into_bytes()]);
+            .set_raw("content-type", vec![content_type.into_bytes()]);
+        // Have browsers open PDFs in their viewer, which honours `#page=N` links and, as ranges
+        // are accepted, loads large files progressively
+        if mime == mime_types::mime::APPLICATION_PDF {
+            if let Some(name) = path.file_name() {
+                let name = name.to_string_lossy();
+                let disposition = format!(
+                    "inline; filename*=UTF-8''{}",
+                    utf8_percent_encode(&name, NON_ALPHANUMERIC)
+                );
+                resp.headers
+                    .set_raw("content-disposition", vec![disposition.into_bytes()]);
+            }
+        }
         if self.coop {
             resp.headers.set_raw(
                 "Cross-Origin-Opener-Policy",
@@ -976,9 +3557,15 @@ impl MainHandler {
                 vec!["require-corp".to_string().into_bytes()],
             );
         }
+        // Of the whole file, also for ranges and precompressed sidecars
+        #[cfg(feature = "signing")]
+        if let (Some(signer), None) = (&self.signer, status) {
+            let signature = signer.sign(storage, path).map_err(error_io2iron)?;
+            signing::set_headers(&mut resp, &signature);
+        }
         match req.method {
-            Method::Head => resp.headers.set(ContentLength(metadata.len())),
-            Method::Get => {
+            // HEAD is handled as GET, the body is dropped by `HeadHandler`
+            Method::Get | Method::Head => {
                 if self.range {
                     let mut range = req.headers.get::<Range>();
 
@@ -1013,7 +3600,7 @@ impl MainHandler {
                                 let (offset, length) = match *range {
                                     ByteRangeSpec::FromTo(x, mut y) => {
                                         // "x-y"
-                                        if x >= metadata.len() || x > y {
+                                        if x >= file_len || x > y {
                                             return Err(IronError::new(
                                                 StringError(format!(
                                                     "Invalid range(x={}, y={})",
@@ -1022,41 +3609,41 @@ impl MainHandler {
                                                 status::RangeNotSatisfiable,
                                             ));
                                         }
-                                        if y >= metadata.len() {
-                                            y = metadata.len() - 1;
+                                        if y >= file_len {
+                                            y = file_len - 1;
                                         }
                                         (x, y - x + 1)
                                     }
                                     ByteRangeSpec::AllFrom(x) => {
                                         // "x-"
-                                        if x >= metadata.len() {
+                                        if x >= file_len {
                                             return Err(IronError::new(
                                                 StringError(format!(
                                                     "Range::AllFrom to large (x={}), Content-Length: {})",
-                                                    x, metadata.len())),
+                                                    x, file_len)),
                                                 status::RangeNotSatisfiable
                                             ));
                                         }
-                                        (x, metadata.len() - x)
+                                        (x, file_len - x)
                                     }
                                     ByteRangeSpec::Last(mut x) => {
                                         // "-x"
-                                        if x > metadata.len() {
-                                            x = metadata.len();
+                                        if x > file_len {
+                                            x = file_len;
                                         }
-                                        (metadata.len() - x, x)
+                                        (file_len - x, x)
                                     }
                                 };
-                                let mut file = fs::File::open(path).map_err(error_io2iron)?;
-                                file.seek(SeekFrom::Start(offset)).map_err(error_io2iron)?;
-                                let take = file.take(length);
+                                let file = storage
+                                    .open_range(body_path, offset, Some(length))
+                                    .map_err(error_io2iron)?;
 
                                 resp.headers.set(ContentLength(length));
                                 resp.headers.set(ContentRange(ContentRangeSpec::Bytes {
                                     range: Some((offset, offset + length - 1)),
-                                    instance_length: Some(metadata.len()),
+                                    instance_length: Some(file_len),
                                 }));
-                                resp.body = Some(Box::new(Box::new(take) as Box<dyn Read + Send>));
+                                resp.body = Some(self.file_body(file));
                                 resp.set_mut(status::PartialContent);
                             } else {
                                 return Err(IronError::new(
@@ -1072,15 +3659,19 @@ impl MainHandler {
                             ));
                         }
                         _ => {
-                            resp.headers.set(ContentLength(metadata.len()));
-                            let file = fs::File::open(path).map_err(error_io2iron)?;
-                            resp.body = Some(Box::new(file));
+                            resp.headers.set(ContentLength(file_len));
+                            let file = storage
+                                .open_range(body_path, 0, None)
+                                .map_err(error_io2iron)?;
+                            resp.body = Some(self.file_body(file));
                         }
                     }
                 } else {
-                    resp.headers.set(ContentLength(metadata.len()));
-                    let file = fs::File::open(path).map_err(error_io2iron)?;
-                    resp.body = Some(Box::new(file));
+                    resp.headers.set(ContentLength(file_len));
+                    let file = storage
+                        .open_range(body_path, 0, None)
+                        .map_err(error_io2iron)?;
+                    resp.body = Some(self.file_body(file));
                 }
             }
             _ => {
@@ -1088,11 +3679,19 @@ impl MainHandler {
             }
         }
 
-        if let Some(ref exts) = self.compress {
+        if let Some((_, encoding, _)) = sidecar {
+            resp.headers.set(ContentEncoding(vec![encoding]));
+            resp.extensions.insert::<EncodedBody>(());
+        } else if let Some(exts) = self
+            .compress
+            .as_ref()
+            .filter(|_| self.state.compression.is_on())
+        {
             let path_str = path.to_string_lossy();
             if resp.status != Some(status::PartialContent)
                 && exts.iter().any(|ext| path_str.ends_with(ext))
             {
+                vary_on(req, "Accept-Encoding");
                 if let Some(AcceptEncoding(encodings)) = req.headers.get::<AcceptEncoding>() {
                     for QualityItem { item, .. } in encodings {
                         if *item == Encoding::Deflate || *item == Encoding::Gzip {
@@ -1104,20 +3703,30 @@ impl MainHandler {
             }
         }
 
+        self.set_validators(&mut resp, path, modified, etag);
+        Ok(resp)
+    }
+
+    /// Validators of a served file, and its caching policy unless `--nocache`
+    fn set_validators(
+        &self,
+        resp: &mut Response,
+        path: &Path,
+        modified: time::Timespec,
+        etag: iron::headers::EntityTag,
+    ) {
+        use iron::headers::{CacheControl, ETag, Expires, HttpDate, LastModified};
+
         if self.cache {
-            static SECONDS: u32 = 7 * 24 * 3600; // max-age: 7.days()
-            if let Some(&IfModifiedSince(HttpDate(ref if_modified_since))) =
-                req.headers.get::<IfModifiedSince>()
-            {
-                if modified <= if_modified_since.to_timespec() {
-                    return Ok(Response::with(status::NotModified));
-                }
+            let policy = self.cache_profile.policy(path);
+            let expires = match policy.max_age {
+                Some(seconds) => time::now_utc() + time::Duration::seconds(i64::from(seconds)),
+                None => time::at_utc(time::Timespec::new(0, 0)),
             };
-            let cache = vec![CacheDirective::Public, CacheDirective::MaxAge(SECONDS)];
-            resp.headers.set(CacheControl(cache));
-            resp.headers.set(LastModified(HttpDate(time::at(modified))));
-            resp.headers.set(ETag(etag));
+            resp.headers.set(CacheControl(policy.directives));
+            resp.headers.set(Expires(HttpDate(expires)));
         }
-        Ok(resp)
+        resp.headers.set(LastModified(HttpDate(time::at(modified))));
+        resp.headers.set(ETag(etag));
     }
 }
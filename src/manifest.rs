@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use iron::headers::{CacheControl, CacheDirective, ContentType};
+use iron::status;
+use iron::{IronError, IronResult, Request, Response};
+
+use crate::expect::client_gone;
+use crate::middlewares::walk_excluded;
+use crate::util::{error_io2iron, json_escape, sha256_file, StringError};
+
+enum Kind {
+    File { size: u64, sha256: Option<String> },
+    Dir,
+    Link(String),
+}
+
+struct Entry {
+    kind: Kind,
+    // Seconds and nanoseconds since the epoch
+    modified: (u64, u32),
+}
+
+/// `/dir/?manifest[=json|mtree][&hash=1]`: every entry of the subtree (path, size, mtime
+/// and optionally the SHA-256 of files) so mirrors can detect changes without crawling
+/// listings. Symlinks are reported as links, not followed.
+pub fn handle(req: &Request, dir: &Path) -> IronResult<Response> {
+    let mut format = String::from("json");
+    let mut hash = false;
+    for (k, v) in req.url.as_ref().query_pairs() {
+        match k.as_ref() {
+            "manifest" if !v.is_empty() => format = v.to_string(),
+            "hash" => hash = v == "1" || v == "true",
+            _ => {}
+        }
+    }
+    if format != "json" && format != "mtree" {
+        return Err(IronError::new(
+            StringError(format!("unknown manifest format: {}", format)),
+            status::BadRequest,
+        ));
+    }
+
+    let mut entries = BTreeMap::new();
+    walk(dir, "", hash, &|| client_gone(req), &mut entries).map_err(error_io2iron)?;
+    let mut resp = if format == "mtree" {
+        let mut resp = Response::with((status::Ok, mtree(&entries)));
+        resp.headers.set(ContentType::plaintext());
+        resp
+    } else {
+        let mut resp = Response::with((status::Ok, json(&entries)));
+        resp.headers.set(ContentType::json());
+        resp
+    };
+    resp.headers
+        .set(CacheControl(vec![CacheDirective::NoCache]));
+    Ok(resp)
+}
+
+// Collect the entries under `dir` by their `/` separated relative path, until `gone`
+fn walk(
+    dir: &Path,
+    prefix: &str,
+    hash: bool,
+    gone: &dyn Fn() -> bool,
+    entries: &mut BTreeMap<String, Entry>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if gone() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "client gone",
+            ));
+        }
+        if walk_excluded(&entry.path()) {
+            continue;
+        }
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let metadata = entry.metadata()?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| (time.as_secs(), time.subsec_nanos()))
+            .unwrap_or_default();
+        let kind = if metadata.file_type().is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            Kind::Link(target.to_string_lossy().to_string())
+        } else if metadata.is_dir() {
+            walk(&entry.path(), &format!("{}/", name), hash, gone, entries)?;
+            Kind::Dir
+        } else if metadata.is_file() {
+            Kind::File {
+                size: metadata.len(),
+                sha256: if hash {
+                    Some(sha256_file(&entry.path())?)
+                } else {
+                    None
+                },
+            }
+        } else {
+            continue;
+        };
+        entries.insert(name, Entry { kind, modified });
+    }
+    Ok(())
+}
+
+fn json(entries: &BTreeMap<String, Entry>) -> String {
+    let items = entries
+        .iter()
+        .map(|(path, entry)| {
+            let details = match entry.kind {
+                Kind::File { size, ref sha256 } => format!(
+                    r#""type":"file","size":{}{}"#,
+                    size,
+                    sha256
+                        .as_ref()
+                        .map(|sha256| format!(r#","sha256":"{}""#, sha256))
+                        .unwrap_or_default()
+                ),
+                Kind::Dir => r#""type":"dir""#.to_owned(),
+                Kind::Link(ref target) => {
+                    format!(r#""type":"link","target":"{}""#, json_escape(target))
+                }
+            };
+            format!(
+                r#"{{"path":"{}",{},"mtime":{}}}"#,
+                json_escape(path),
+                details,
+                entry.modified.0
+            )
+        })
+        .collect::<Vec<String>>();
+    format!("[{}]", items.join(","))
+}
+
+// BSD mtree(5) specification, readable by `mtree -f` and libarchive (`bsdtar`)
+fn mtree(entries: &BTreeMap<String, Entry>) -> String {
+    let mut out = String::from("#mtree\n");
+    for (path, entry) in entries {
+        let details = match entry.kind {
+            Kind::File { size, ref sha256 } => format!(
+                "type=file size={}{}",
+                size,
+                sha256
+                    .as_ref()
+                    .map(|sha256| format!(" sha256digest={}", sha256))
+                    .unwrap_or_default()
+            ),
+            Kind::Dir => "type=dir".to_owned(),
+            Kind::Link(ref target) => format!("type=link link={}", mtree_escape(target)),
+        };
+        out.push_str(&format!(
+            "./{} {} time={}.{:09}\n",
+            mtree_escape(path),
+            details,
+            entry.modified.0,
+            entry.modified.1
+        ));
+    }
+    out
+}
+
+// Characters other than printable ASCII are written as `\ooo` octal bytes, as vis(3) does
+fn mtree_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'\\' | b'#' | b'*' | b'?' | b'[' => escaped.push_str(&format!("\\{:03o}", b)),
+            b'!'..=b'~' => escaped.push(b as char),
+            b => escaped.push_str(&format!("\\{:03o}", b)),
+        }
+    }
+    escaped
+}
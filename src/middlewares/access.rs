@@ -0,0 +1,288 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use iron::status;
+use iron::typemap::Key;
+use iron::{BeforeMiddleware, IronError, IronResult, Request};
+use path_dedot::ParseDot;
+use percent_encoding::percent_decode;
+use tracing::warn;
+
+use super::auth::request_uri;
+use super::ipfilter::IpRange;
+use super::{request_user, AuthChecker};
+use crate::util::{error_resp, StringError};
+
+/// Name of the sidecar files carrying the rules of a directory
+pub const ACCESS_FILE: &str = ".access";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the recursive views (search, manifest, diff) must leave `path` out: the access
+/// files themselves and the directories with their own rules, which those views would
+/// otherwise expose without checking them.
+pub fn walk_excluded(path: &Path) -> bool {
+    ENABLED.load(Ordering::Relaxed)
+        && (path.file_name() == Some(ACCESS_FILE.as_ref()) || path.join(ACCESS_FILE).is_file())
+}
+
+/// Whether `name` can not be written by uploads
+pub fn is_access_file(name: &str) -> bool {
+    ENABLED.load(Ordering::Relaxed) && name == ACCESS_FILE
+}
+
+/// Check the rules of the root relative `path` for the request, for the `/-/` endpoints
+/// acting on paths given in their query or body (sync manifests, copy and move, diff...).
+/// Always passes without `--access-files`.
+pub fn check_access(req: &Request, path: &Path) -> IronResult<()> {
+    match req.extensions.get::<AccessCheck>() {
+        Some(check) => check.check(req, path),
+        None => Ok(()),
+    }
+}
+
+enum Subject {
+    All,
+    Net(IpRange),
+    User(String),
+}
+
+impl Subject {
+    fn parse(s: &str) -> Option<Subject> {
+        if s == "all" {
+            return Some(Subject::All);
+        }
+        if let Some(user) = s.strip_prefix("user ") {
+            return Some(Subject::User(user.trim().to_owned()));
+        }
+        s.parse().ok().map(Subject::Net)
+    }
+
+    fn matches(&self, ip: IpAddr, user: Option<&str>) -> bool {
+        match *self {
+            Subject::All => true,
+            Subject::User(ref name) => user == Some(name.as_str()),
+            Subject::Net(ref range) => range.contains(ip),
+        }
+    }
+}
+
+struct Rules {
+    // `allow`/`deny` lines in order, the first matching one decides
+    rules: Vec<(bool, Subject)>,
+    require_auth: bool,
+}
+
+impl Rules {
+    fn load(path: &Path) -> Result<Rules, String> {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut rules = Rules {
+            rules: Vec::new(),
+            require_auth: false,
+        };
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || format!("invalid line: {}", line);
+            if line == "require auth" {
+                rules.require_auth = true;
+                continue;
+            }
+            let (action, subject) = line.split_once(' ').ok_or_else(invalid)?;
+            let allow = match action {
+                "allow" => true,
+                "deny" => false,
+                _ => return Err(invalid()),
+            };
+            let subject = Subject::parse(subject.trim()).ok_or_else(invalid)?;
+            rules.rules.push((allow, subject));
+        }
+        Ok(rules)
+    }
+}
+
+/// Per directory rules from `.access` sidecar files (`--access-files`), the nearest file
+/// up from the requested path applies, one directive per line:
+///
+/// - `allow <who>` / `deny <who>`: the first line matching the client decides (allowed when
+///   none does), `<who>` is `all`, an IP address or network (`10.0.0.0/8`), or
+///   `user <name>` for the user authenticated by the accounts (`--auth`, `--auth-file`,
+///   `--auth-command`, `--auth-pam` or `--auth-ldap`).
+/// - `require auth`: only the requests authenticated by the accounts, `--token` or, along
+///   with either, `--api-keys`, `401` otherwise.
+///
+/// The access files themselves can not be fetched nor written over HTTP, and the `/-/`
+/// endpoints check the paths they act on the same way. An unreadable or invalid file denies
+/// everything below it.
+pub struct AccessFiles {
+    config: Arc<AccessConfig>,
+}
+
+struct AccessConfig {
+    root: PathBuf,
+    auth: Option<Arc<AuthChecker>>,
+    base_url: String,
+}
+
+/// Kept in the request extensions by `AccessFiles` for `check_access`
+struct AccessCheck {
+    config: Arc<AccessConfig>,
+    // Whether the request is authenticated, and the user, worked out on the first check
+    client: OnceLock<(bool, Option<String>)>,
+}
+
+impl Key for AccessCheck {
+    type Value = AccessCheck;
+}
+
+impl AccessFiles {
+    pub fn new(root: PathBuf, auth: Option<Arc<AuthChecker>>, base_url: &str) -> AccessFiles {
+        ENABLED.store(true, Ordering::Relaxed);
+        AccessFiles {
+            config: Arc::new(AccessConfig {
+                root,
+                auth,
+                base_url: base_url.to_owned(),
+            }),
+        }
+    }
+}
+
+impl AccessConfig {
+    // Nearest access file from the target (a directory applies its own) up to the root
+    fn find(&self, target: &Path) -> Option<PathBuf> {
+        let mut dir = if target.is_dir() {
+            target
+        } else {
+            target.parent()?
+        };
+        loop {
+            let file = dir.join(ACCESS_FILE);
+            if file.is_file() {
+                return Some(file);
+            }
+            if dir == self.root {
+                return None;
+            }
+            dir = dir.parent()?;
+        }
+    }
+
+    fn deny(&self, status: status::Status, msg: &str) -> IronError {
+        let mut response = error_resp(status, msg, &self.base_url);
+        if let Some(auth) = self
+            .auth
+            .as_ref()
+            .filter(|_| status == status::Unauthorized)
+        {
+            response
+                .headers
+                .set_raw("WWW-Authenticate", auth.challenges());
+        }
+        IronError {
+            error: Box::new(StringError(msg.to_owned())),
+            response,
+        }
+    }
+}
+
+impl AccessCheck {
+    fn client(&self, req: &Request) -> &(bool, Option<String>) {
+        self.client.get_or_init(|| {
+            let authenticated =
+                self.config.auth.as_ref().is_some_and(|auth| {
+                    auth.authorized(&req.method, &request_uri(req), &req.headers)
+                });
+            (
+                authenticated,
+                request_user(&req.headers).filter(|_| authenticated),
+            )
+        })
+    }
+
+    fn check(&self, req: &Request, path: &Path) -> IronResult<()> {
+        let config = &*self.config;
+        if path.iter().any(|name| name == ACCESS_FILE) {
+            return Err(config.deny(status::Forbidden, "Access rules are not served."));
+        }
+        let target = match config.root.join(path).parse_dot() {
+            Ok(target) if target.starts_with(&config.root) => target.to_path_buf(),
+            // Refused by the handler
+            _ => return Ok(()),
+        };
+        let file = match config.find(&target) {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let rules = match Rules::load(&file) {
+            Ok(rules) => rules,
+            Err(err) => {
+                warn!("Access file {}: {}", file.display(), err);
+                return Err(config.deny(status::Forbidden, "Access denied."));
+            }
+        };
+
+        let (authenticated, ref user) = *self.client(req);
+        if rules.require_auth && !authenticated {
+            if config.auth.is_none() {
+                warn!(
+                    "Access file {} requires auth without accounts nor --token",
+                    file.display()
+                );
+                return Err(config.deny(status::Forbidden, "Access denied."));
+            }
+            return Err(config.deny(status::Unauthorized, "Authentication required."));
+        }
+        let allowed = rules
+            .rules
+            .iter()
+            .find(|(_, subject)| subject.matches(req.remote_addr.ip(), user.as_deref()))
+            .map(|(allow, _)| *allow)
+            .unwrap_or(true);
+        if allowed {
+            Ok(())
+        } else {
+            Err(config.deny(status::Forbidden, "Access denied."))
+        }
+    }
+}
+
+impl BeforeMiddleware for AccessFiles {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let segments = req
+            .url
+            .path()
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                percent_decode(s.as_bytes())
+                    .decode_utf8_lossy()
+                    .into_owned()
+            })
+            .collect::<Vec<String>>();
+        // The `/-/` endpoints taking their path in the URL, the others check the paths they
+        // are given themselves
+        let path = match segments.split_first() {
+            Some((special, rest)) if special == "-" => match rest.split_first() {
+                Some((endpoint, path)) if endpoint == "sync" || endpoint == "description" => {
+                    Some(path)
+                }
+                _ => None,
+            },
+            _ => Some(&segments[..]),
+        };
+        let check = AccessCheck {
+            config: self.config.clone(),
+            client: OnceLock::new(),
+        };
+        if let Some(path) = path {
+            check.check(req, &path.iter().collect::<PathBuf>())?;
+        }
+        req.extensions.insert::<AccessCheck>(check);
+        Ok(())
+    }
+}
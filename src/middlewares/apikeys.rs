@@ -0,0 +1,154 @@
+use std::sync::Arc;
+
+use iron::method::Method;
+use iron::status;
+use iron::{BeforeMiddleware, IronError, IronResult, Request, Response};
+use percent_encoding::percent_decode;
+use tracing::info_span;
+
+use super::auth::log_auth;
+use super::vary_on;
+use crate::keys::{ApiKey, ApiKeys, KeyAuth, Scope};
+use crate::stats::Stats;
+use crate::util::StringError;
+
+/// Root relative path of the decoded path `segments`, `None` when it leaves the root
+fn relative_path<S: AsRef<str>>(segments: impl Iterator<Item = S>) -> Option<String> {
+    let mut path: Vec<String> = Vec::new();
+    for segment in segments {
+        match segment.as_ref() {
+            "" | "." => {}
+            ".." => {
+                path.pop()?;
+            }
+            segment => path.push(segment.replace('\\', "/")),
+        }
+    }
+    Some(path.join("/"))
+}
+
+fn decoded(segments: &[&str]) -> Option<String> {
+    relative_path(
+        segments
+            .iter()
+            .map(|segment| percent_decode(segment.as_bytes()).decode_utf8_lossy()),
+    )
+}
+
+/// Scope required by a request and the paths it touches, empty for the `/-/` endpoints not
+/// about a path (stats, events, ...) which keys limited to prefixes can not use
+fn required(req: &Request, base_url: &str) -> (Scope, Vec<Option<String>>) {
+    let segments = req.url.path();
+    let method_scope = match req.method {
+        Method::Get | Method::Head | Method::Options => Scope::Read,
+        Method::Delete => Scope::Delete,
+        Method::Extension(ref name) if name == "PROPFIND" => Scope::Read,
+        _ => Scope::Write,
+    };
+    let query = |name: &str| {
+        req.url
+            .as_ref()
+            .query_pairs()
+            .find(|(k, _)| k == name)
+            .and_then(|(_, v)| relative_path(v.split('/')))
+    };
+    if segments.first() != Some(&"-") {
+        let mut paths = vec![decoded(&segments)];
+        // WebDAV COPY and MOVE write to the `Destination` too
+        if let Some(destination) = req
+            .headers
+            .get_raw("Destination")
+            .and_then(|values| values.first())
+        {
+            let destination = String::from_utf8_lossy(destination);
+            let url_path = match iron::url::Url::parse(&destination) {
+                Ok(url) => url.path().to_owned(),
+                Err(_) => destination.to_string(),
+            };
+            paths.push(
+                url_path
+                    .strip_prefix(base_url)
+                    .and_then(|path| decoded(&path.split('/').collect::<Vec<&str>>())),
+            );
+        }
+        return (method_scope, paths);
+    }
+    let rest = || vec![decoded(&segments[2..])];
+    match segments.get(1).copied() {
+        Some("admin") => (Scope::Admin, vec![]),
+        Some("copy" | "move") => (Scope::Write, vec![query("from"), query("to")]),
+        Some("cancel") => (Scope::Write, vec![]),
+        Some("restore") => (Scope::Delete, vec![]),
+        Some("sync") if segments.len() > 2 => (method_scope, rest()),
+        Some("description" | "uploads") if segments.len() > 2 => (method_scope, rest()),
+        _ => (method_scope, vec![]),
+    }
+}
+
+/// Check the API key (`--api-keys`) of the requests sending one as bearer token: `401` when
+/// unknown, `403` when its scopes or prefixes do not cover the request. The requests it
+/// covers are let through `--auth` and the CSRF token checks, no browser sends it on its
+/// own.
+pub struct ApiKeyChecker {
+    pub keys: Arc<ApiKeys>,
+    pub base_url: String,
+    pub stats: Option<Arc<Stats>>,
+}
+
+impl ApiKeyChecker {
+    fn check(&self, req: &Request, key: &ApiKey) -> Result<(), String> {
+        let (scope, paths) = required(req, &self.base_url);
+        if !key.allows(scope) {
+            return Err(format!("key {} lacks the {} scope", key.name, scope));
+        }
+        // Admin keys apply to the whole server
+        if scope == Scope::Admin || key.prefixes.is_empty() {
+            return Ok(());
+        }
+        if paths.is_empty() {
+            return Err(format!("key {} is limited to its prefixes", key.name));
+        }
+        match paths
+            .iter()
+            .find(|path| !path.as_ref().is_some_and(|p| key.covers(p)))
+        {
+            Some(path) => Err(format!(
+                "key {} does not cover /{}",
+                key.name,
+                path.as_deref().unwrap_or("..")
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl BeforeMiddleware for ApiKeyChecker {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let _span = info_span!("api_key").entered();
+        vary_on(req, "Authorization");
+
+        let key = match self.keys.authenticate(&req.headers) {
+            None => return Ok(()),
+            Some(Some(key)) => key,
+            Some(None) => {
+                log_auth(req, self.stats.as_deref(), ("token", "failure"), "-");
+                return Err(IronError {
+                    error: Box::new(StringError("unknown API key".to_owned())),
+                    response: Response::with((status::Unauthorized, "Unknown API key.")),
+                });
+            }
+        };
+        if let Err(msg) = self.check(req, &key) {
+            log_auth(
+                req,
+                self.stats.as_deref(),
+                ("token", "forbidden"),
+                &key.name,
+            );
+            return Err(IronError::new(StringError(msg), status::Forbidden));
+        }
+        log_auth(req, self.stats.as_deref(), ("token", "success"), &key.name);
+        req.extensions.insert::<KeyAuth>(key.name);
+        Ok(())
+    }
+}
@@ -1,57 +1,506 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use iron::headers::{Authorization, Basic};
+use iron::method::Method;
 use iron::status;
-use iron::{BeforeMiddleware, IronError, IronResult, Request, Response};
+use iron::{BeforeMiddleware, Headers, IronError, IronResult, Request, Response};
+use md5::Md5;
+use percent_encoding::percent_decode;
+use rand::{thread_rng, Rng};
+use sha2::{Digest as _, Sha256};
+use tracing::{info, info_span, warn};
+
+use super::vary_on;
+use crate::accounts::AuthProvider;
+use crate::keys::{ApiKeys, KeyAuth};
+use crate::stats::Stats;
+use crate::util::{constant_time_eq, hex, random_alphanumeric, unix_now, StringError};
+
+const REALM: &str = "main";
+/// Seconds the Digest nonces are accepted, the clients are then told to retry with a new one
+const NONCE_LIFETIME: u64 = 300;
+
+#[derive(PartialEq)]
+enum Verdict {
+    Granted,
+    Denied,
+    /// Right credentials with an expired Digest nonce
+    Stale,
+}
+
+/// `--auth-method digest` (RFC 7616, `qop=auth`), the password never crosses the network.
+/// The nonces are `<time>.<random>.<mac>`, checked without keeping them, and the nonce counts
+/// of the live ones are tracked against replays.
+struct DigestAuth {
+    secret: String,
+    counts: Mutex<HashMap<String, u32>>,
+}
 
-use crate::util::StringError;
+impl DigestAuth {
+    fn new() -> DigestAuth {
+        DigestAuth {
+            secret: random_alphanumeric(32),
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn mac(&self, issued: &str) -> String {
+        hex(&Sha256::digest(format!("{}:{}", self.secret, issued).as_bytes())[..16])
+    }
+
+    fn nonce(&self) -> String {
+        let issued = format!("{:x}.{:x}", unix_now(), thread_rng().gen::<u64>());
+        format!("{}.{}", issued, self.mac(&issued))
+    }
+
+    /// Seconds since `nonce` was issued, `None` when not one of ours
+    fn nonce_age(&self, nonce: &str) -> Option<u64> {
+        let (issued, mac) = nonce.rsplit_once('.')?;
+        if !constant_time_eq(mac.as_bytes(), self.mac(issued).as_bytes()) {
+            return None;
+        }
+        let time = u64::from_str_radix(issued.split('.').next()?, 16).ok()?;
+        Some(unix_now().saturating_sub(time))
+    }
+
+    /// `WWW-Authenticate` values, SHA-256 preferred over MD5
+    fn challenges(&self, stale: bool) -> Vec<Vec<u8>> {
+        let nonce = self.nonce();
+        ["SHA-256", "MD5"]
+            .iter()
+            .map(|algorithm| {
+                format!(
+                    r#"Digest realm="{}", qop="auth", algorithm={}, nonce="{}"{}"#,
+                    REALM,
+                    algorithm,
+                    nonce,
+                    if stale { ", stale=true" } else { "" }
+                )
+                .into_bytes()
+            })
+            .collect()
+    }
+
+    /// Check the `Authorization: Digest` parameters, remembering the nonce count when
+    /// `record` (only once per request, a check repeated would look like a replay).
+    /// The `uri` is compared decoded, clients do not all escape the same characters.
+    fn check(
+        &self,
+        params: &HashMap<String, String>,
+        (method, uri): (&Method, &str),
+        (username, password): (&str, &str),
+        record: bool,
+    ) -> Verdict {
+        let param = |name: &str| params.get(name).map(String::as_str).unwrap_or("");
+        let hash: fn(&str) -> String = match param("algorithm") {
+            "" | "MD5" => |s| hex(&Md5::digest(s.as_bytes())),
+            "SHA-256" => |s| hex(&Sha256::digest(s.as_bytes())),
+            _ => return Verdict::Denied,
+        };
+        if param("username") != username
+            || param("realm") != REALM
+            || param("qop") != "auth"
+            || percent_decode(param("uri").as_bytes()).collect::<Vec<u8>>()
+                != percent_decode(uri.as_bytes()).collect::<Vec<u8>>()
+        {
+            return Verdict::Denied;
+        }
+        let age = match self.nonce_age(param("nonce")) {
+            Some(age) => age,
+            None => return Verdict::Denied,
+        };
+        let nonce_count = match u32::from_str_radix(param("nc"), 16) {
+            Ok(nonce_count) => nonce_count,
+            Err(_) => return Verdict::Denied,
+        };
+        let expected = digest_response(
+            hash,
+            (username, REALM, password),
+            (method, param("uri")),
+            (param("nonce"), param("nc"), param("cnonce")),
+        );
+        if !constant_time_eq(
+            param("response").to_ascii_lowercase().as_bytes(),
+            expected.as_bytes(),
+        ) {
+            return Verdict::Denied;
+        }
+        if age > NONCE_LIFETIME {
+            return Verdict::Stale;
+        }
+        if record {
+            let mut counts = self.counts.lock().unwrap();
+            counts.retain(|nonce, _| {
+                self.nonce_age(nonce)
+                    .is_some_and(|age| age <= NONCE_LIFETIME)
+            });
+            let last = counts.entry(param("nonce").to_owned()).or_insert(0);
+            if nonce_count <= *last {
+                return Verdict::Denied;
+            }
+            *last = nonce_count;
+        }
+        Verdict::Granted
+    }
+}
 
 pub struct AuthChecker {
-    username: String,
-    password: String,
+    /// `None` with only the API keys, `--token` without the others
+    accounts: Option<Box<dyn AuthProvider>>,
+    keys: Option<Arc<ApiKeys>>,
+    digest: Option<DigestAuth>,
+    stats: Option<Arc<Stats>>,
 }
 
 impl AuthChecker {
-    pub fn new(s: &str) -> Result<AuthChecker, StringError> {
-        let parts = s.splitn(2, ':').collect::<Vec<&str>>();
-        if parts.len() == 2 {
-            Ok(AuthChecker {
-                username: parts[0].to_owned(),
-                password: parts[1].to_owned(),
-            })
+    /// The accounts checked by `accounts`
+    pub fn new(accounts: Box<dyn AuthProvider>) -> AuthChecker {
+        AuthChecker {
+            accounts: Some(accounts),
+            keys: None,
+            digest: None,
+            stats: None,
+        }
+    }
+
+    /// Only the requests with a known API key
+    pub fn from_keys(keys: Arc<ApiKeys>) -> AuthChecker {
+        AuthChecker {
+            accounts: None,
+            keys: Some(keys),
+            digest: None,
+            stats: None,
+        }
+    }
+
+    /// Also let in the requests with a known API key
+    pub fn with_keys(mut self, keys: Arc<ApiKeys>) -> AuthChecker {
+        self.keys = Some(keys);
+        self
+    }
+
+    /// Digest instead of Basic authentication, only for the accounts whose password is known
+    /// (`--auth`, the hashes of an htpasswd file or the other providers can not be used)
+    pub fn with_digest(mut self) -> AuthChecker {
+        self.digest = Some(DigestAuth::new());
+        self
+    }
+
+    /// Count the authentication outcomes
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> AuthChecker {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// `WWW-Authenticate` values asking for credentials
+    pub fn challenges(&self) -> Vec<Vec<u8>> {
+        match self.digest {
+            Some(ref digest) => digest.challenges(false),
+            None => {
+                let scheme = match self.accounts {
+                    None => "Bearer",
+                    Some(_) => "Basic",
+                };
+                vec![format!("{} realm=\"{}\"", scheme, REALM).into_bytes()]
+            }
+        }
+    }
+
+    /// Whether the request (`uri` as in its request line) carries the credentials of an
+    /// account or a known API key
+    pub fn authorized(&self, method: &Method, uri: &str, headers: &Headers) -> bool {
+        self.verdict(method, uri, headers, false) == Verdict::Granted
+    }
+
+    /// Whether to answer `401` before the body of the request is sent (`Expect:
+    /// 100-continue`). Not with Digest, that early reply can not carry the challenge which
+    /// the clients need to authenticate.
+    pub fn rejects_early(&self, method: &Method, uri: &str, headers: &Headers) -> bool {
+        self.digest.is_none() && !self.authorized(method, uri, headers)
+    }
+
+    fn verdict(&self, method: &Method, uri: &str, headers: &Headers, record: bool) -> Verdict {
+        if let Some(ref keys) = self.keys {
+            if matches!(keys.authenticate(headers), Some(Some(_))) {
+                return Verdict::Granted;
+            }
+        }
+        if let Some(ref digest) = self.digest {
+            let params = match digest_params(headers) {
+                Some(params) => params,
+                None => return Verdict::Denied,
+            };
+            let username = params.get("username").map(String::as_str).unwrap_or("");
+            return match self
+                .accounts
+                .as_ref()
+                .and_then(|accounts| accounts.password(username))
+            {
+                Some(password) => {
+                    digest.check(&params, (method, uri), (username, password), record)
+                }
+                None => Verdict::Denied,
+            };
+        }
+        let granted = match headers.get::<Authorization<Basic>>() {
+            Some(&Authorization(Basic {
+                ref username,
+                password: Some(ref password),
+            })) => self
+                .accounts
+                .as_ref()
+                .is_some_and(|accounts| accounts.verify(username, password)),
+            _ => false,
+        };
+        if granted {
+            Verdict::Granted
         } else {
-            Err(StringError("not valid format user & password".to_owned()))
+            Verdict::Denied
         }
     }
 }
 
 impl BeforeMiddleware for AuthChecker {
     fn before(&self, req: &mut Request) -> IronResult<()> {
-        use iron::headers::{Authorization, Basic};
+        let _span = info_span!("auth").entered();
+        vary_on(req, "Authorization");
+        // Scope already checked by `ApiKeyChecker`
+        if req.extensions.contains::<KeyAuth>() {
+            return Ok(());
+        }
 
-        match req.headers.get::<Authorization<Basic>>() {
-            Some(&Authorization(Basic {
-                ref username,
-                ref password,
-            })) => {
-                if username == self.username.as_str() && password == &Some(self.password.clone()) {
-                    Ok(())
-                } else {
-                    Err(IronError {
-                        error: Box::new(StringError("authorization error".to_owned())),
-                        response: Response::with((
-                            status::Unauthorized,
-                            "Wrong username or password.",
-                        )),
-                    })
-                }
+        let verdict = self.verdict(&req.method, &request_uri(req), &req.headers, true);
+        let mechanism = if self.digest.is_some() {
+            "digest"
+        } else {
+            "basic"
+        };
+        // Nothing to tell of the first requests without credentials, nor of stale nonces
+        // which clients renew on their own
+        match (&verdict, request_user(&req.headers)) {
+            (Verdict::Granted, user) => log_auth(
+                req,
+                self.stats.as_deref(),
+                (mechanism, "success"),
+                user.as_deref().unwrap_or("-"),
+            ),
+            (Verdict::Denied, Some(user)) => {
+                log_auth(req, self.stats.as_deref(), (mechanism, "failure"), &user)
+            }
+            _ => {}
+        }
+        if verdict == Verdict::Granted {
+            return Ok(());
+        }
+        let resp = match self.digest {
+            // Browsers only ask for the password again with a new challenge, and retry on
+            // their own with a new nonce when told it is stale
+            Some(ref digest) => {
+                let mut resp =
+                    if verdict == Verdict::Denied && digest_params(&req.headers).is_some() {
+                        Response::with((status::Unauthorized, "Wrong username or password."))
+                    } else {
+                        Response::with(status::Unauthorized)
+                    };
+                resp.headers.set_raw(
+                    "WWW-Authenticate",
+                    digest.challenges(verdict == Verdict::Stale),
+                );
+                resp
+            }
+            None if req.headers.get::<Authorization<Basic>>().is_some()
+                && self.accounts.is_some() =>
+            {
+                Response::with((status::Unauthorized, "Wrong username or password."))
             }
             None => {
                 let mut resp = Response::with(status::Unauthorized);
-                resp.headers
-                    .set_raw("WWW-Authenticate", vec![b"Basic realm=\"main\"".to_vec()]);
-                Err(IronError {
-                    error: Box::new(StringError("authorization error".to_owned())),
-                    response: resp,
-                })
+                resp.headers.set_raw("WWW-Authenticate", self.challenges());
+                resp
             }
-        }
+        };
+        Err(IronError {
+            error: Box::new(StringError("authorization error".to_owned())),
+            response: resp,
+        })
+    }
+}
+
+/// Log an authentication `(mechanism, outcome)` (`success`, `failure` or `forbidden`) as an
+/// event with the user, client IP and path, for alerting on the failures, and count it in
+/// the stats
+pub fn log_auth(
+    req: &Request,
+    stats: Option<&Stats>,
+    (mechanism, outcome): (&'static str, &'static str),
+    user: &str,
+) {
+    let ip = req.remote_addr.ip();
+    let path = req.url.as_ref().path();
+    if outcome == "success" {
+        info!(outcome, mechanism, user, %ip, path, "Authentication");
+    } else {
+        warn!(outcome, mechanism, user, %ip, path, "Authentication");
+    }
+    if let Some(stats) = stats {
+        stats.add_auth(mechanism, outcome);
+    }
+}
+
+/// The user named in the credentials of `headers`, Basic or Digest. Not verified by itself.
+pub fn request_user(headers: &Headers) -> Option<String> {
+    match headers.get::<Authorization<Basic>>() {
+        Some(auth) => Some(auth.username.clone()),
+        None => digest_params(headers)?.remove("username"),
+    }
+}
+
+/// The request target as sent, path and query
+pub fn request_uri(req: &Request) -> String {
+    let url = req.url.as_ref();
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_owned(),
+    }
+}
+
+/// The `response` of a `qop=auth` Digest client, the hex `hash` of the credentials, nonce and
+/// request
+fn digest_response(
+    hash: fn(&str) -> String,
+    (username, realm, password): (&str, &str, &str),
+    (method, uri): (&Method, &str),
+    (nonce, nc, cnonce): (&str, &str, &str),
+) -> String {
+    let ha1 = hash(&format!("{}:{}:{}", username, realm, password));
+    let ha2 = hash(&format!("{}:{}", method, uri));
+    hash(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2))
+}
+
+/// Parameters of an `Authorization: Digest` header, names lowercased
+fn digest_params(headers: &Headers) -> Option<HashMap<String, String>> {
+    let value = std::str::from_utf8(headers.get_raw("Authorization")?.first()?).ok()?;
+    let (scheme, mut rest) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Digest") {
+        return None;
+    }
+    let mut params = HashMap::new();
+    while let Some(eq) = rest.find('=') {
+        let name = rest[..eq].trim().to_ascii_lowercase();
+        rest = rest[eq + 1..].trim_start();
+        let value = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut end = quoted.len();
+                let mut chars = quoted.char_indices();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                rest = &quoted[end..];
+                value
+            }
+            None => {
+                let end = rest.find(',').unwrap_or(rest.len());
+                let value = rest[..end].trim().to_owned();
+                rest = &rest[end..];
+                value
+            }
+        };
+        params.insert(name, value);
+        rest = rest.trim_start().trim_start_matches(',');
+    }
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn md5_hex(s: &str) -> String {
+        hex(&Md5::digest(s.as_bytes()))
+    }
+
+    fn sha256_hex(s: &str) -> String {
+        hex(&Sha256::digest(s.as_bytes()))
+    }
+
+    #[test]
+    fn rfc2617_response() {
+        let response = digest_response(
+            md5_hex,
+            ("Mufasa", "testrealm@host.com", "Circle Of Life"),
+            (&Method::Get, "/dir/index.html"),
+            ("dcd98b7102dd2f0e8b11d0f600bfb0c093", "00000001", "0a4f113b"),
+        );
+        assert_eq!(response, "6629fae49393a05397450978507c4ef1");
+    }
+
+    #[test]
+    fn rfc7616_responses() {
+        let credentials = ("Mufasa", "http-auth@example.org", "Circle of Life");
+        let nonce = (
+            "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v",
+            "00000001",
+            "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ",
+        );
+        let request = (&Method::Get, "/dir/index.html");
+        assert_eq!(
+            digest_response(md5_hex, credentials, request, nonce),
+            "8ca523f5e9506fed4657c9700eebdbec"
+        );
+        assert_eq!(
+            digest_response(sha256_hex, credentials, request, nonce),
+            "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"
+        );
+    }
+
+    #[test]
+    fn uri_compared_decoded() {
+        let digest = DigestAuth::new();
+        let nonce = digest.nonce();
+        let params = |uri: &str| -> HashMap<String, String> {
+            let response = digest_response(
+                md5_hex,
+                ("alice", REALM, "secret"),
+                (&Method::Get, uri),
+                (&nonce, "00000001", "abc"),
+            );
+            [
+                ("username", "alice"),
+                ("realm", REALM),
+                ("qop", "auth"),
+                ("uri", uri),
+                ("nonce", &nonce),
+                ("nc", "00000001"),
+                ("cnonce", "abc"),
+                ("response", &response),
+            ]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+        };
+        let credentials = ("alice", "secret");
+        let request = (&Method::Get, "/dir/a%20b%2B.txt");
+        assert!(
+            digest.check(&params("/dir/a b+.txt"), request, credentials, false) == Verdict::Granted
+        );
+        assert!(
+            digest.check(&params("/dir/a%20b+.txt"), request, credentials, false)
+                == Verdict::Granted
+        );
+        assert!(
+            digest.check(&params("/dir/a%20b.txt"), request, credentials, false) == Verdict::Denied
+        );
+        assert!(
+            digest.check(&params("/dir/other.txt"), request, credentials, false) == Verdict::Denied
+        );
     }
 }
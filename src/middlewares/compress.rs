@@ -1,12 +1,28 @@
+use std::collections::HashMap;
 use std::io;
+use std::sync::{Arc, Condvar, Mutex};
 
 use flate2::{
     write::{DeflateEncoder, GzEncoder},
     Compression,
 };
-use iron::headers::{ContentEncoding, ContentLength, Encoding, TransferEncoding};
+use iron::headers::{
+    AcceptEncoding, ContentEncoding, ContentLength, ContentType, ETag, Encoding, QualityItem,
+    TransferEncoding,
+};
+use iron::method;
+use iron::mime::{Mime, TopLevel};
 use iron::response::WriteBody;
-use iron::{AfterMiddleware, IronResult, Request, Response};
+use iron::status;
+use iron::typemap::Key;
+use iron::{AfterMiddleware, IronError, IronResult, Request, Response};
+
+use super::vary_on;
+use crate::admin::RuntimeState;
+use crate::util::StringError;
+
+/// Larger bodies are streamed, each request compressing its own copy
+const MAX_COALESCED_SIZE: u64 = 32 * 1024 * 1024;
 
 // [Reference]: https://github.com/iron/iron/issues/548
 struct GzipBody(Box<dyn WriteBody>);
@@ -28,10 +44,126 @@ impl WriteBody for DeflateBody {
     }
 }
 
-pub struct CompressionHandler;
+struct SharedBody(Arc<Vec<u8>>);
+
+impl WriteBody for SharedBody {
+    fn write_body(&mut self, w: &mut dyn io::Write) -> io::Result<()> {
+        w.write_all(&self.0)
+    }
+}
+
+/// One compression pass, waited for by the identical requests arriving meanwhile
+#[derive(Default)]
+struct Job {
+    result: Mutex<Option<Result<Arc<Vec<u8>>, String>>>,
+    done: Condvar,
+}
+
+/// Marks the responses carrying file content, which the handler compresses only as
+/// `--compress` decides (by setting `Content-Encoding`)
+pub struct FileBody;
+
+impl Key for FileBody {
+    type Value = ();
+}
+
+/// Marks the responses whose body is already encoded as their `Content-Encoding` says
+/// (precompressed sidecar files), left untouched
+pub struct EncodedBody;
+
+impl Key for EncodedBody {
+    type Value = ();
+}
+
+/// Compress response bodies, identical concurrent requests of a file (same path, ETag and
+/// encoding) share a single compression pass instead of running one each. Generated text
+/// responses (JSON, manifests, search results, ...) are compressed whenever the client
+/// accepts it.
+pub struct CompressionHandler {
+    jobs: Mutex<HashMap<(String, String, String), Arc<Job>>>,
+    state: Arc<RuntimeState>,
+}
+
+impl CompressionHandler {
+    pub fn new(state: Arc<RuntimeState>) -> CompressionHandler {
+        CompressionHandler {
+            jobs: Mutex::default(),
+            state,
+        }
+    }
+
+    // Encoding for a generated response, if it is text and the client accepts one
+    fn negotiate(&self, req: &mut Request, resp: &Response) -> Option<Encoding> {
+        if resp.extensions.contains::<FileBody>()
+            || resp.body.is_none()
+            || resp.status == Some(status::PartialContent)
+            || !self.state.compression.is_on()
+        {
+            return None;
+        }
+        match resp.headers.get::<ContentType>() {
+            Some(ContentType(Mime(TopLevel::Text, _, _))) => {}
+            Some(ContentType(Mime(TopLevel::Application, sub, _)))
+                if ["json", "xml", "javascript"].contains(&sub.as_str()) => {}
+            _ => return None,
+        }
+        vary_on(req, "Accept-Encoding");
+        let AcceptEncoding(encodings) = req.headers.get::<AcceptEncoding>()?;
+        encodings
+            .iter()
+            .map(|QualityItem { item, .. }| item)
+            .find(|item| **item == Encoding::Gzip || **item == Encoding::Deflate)
+            .cloned()
+    }
+
+    fn compress(encoding: &Encoding, body: Box<dyn WriteBody>) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        match *encoding {
+            Encoding::Gzip => GzipBody(body).write_body(&mut data)?,
+            _ => DeflateBody(body).write_body(&mut data)?,
+        }
+        Ok(data)
+    }
+
+    fn coalesce(
+        &self,
+        key: (String, String, String),
+        encoding: &Encoding,
+        body: Box<dyn WriteBody>,
+    ) -> Result<Arc<Vec<u8>>, String> {
+        let (job, leader) = {
+            let mut jobs = self.jobs.lock().unwrap();
+            match jobs.get(&key) {
+                Some(job) => (job.clone(), false),
+                None => {
+                    let job = Arc::new(Job::default());
+                    jobs.insert(key.clone(), job.clone());
+                    (job, true)
+                }
+            }
+        };
+        if !leader {
+            let mut result = job.result.lock().unwrap();
+            while result.is_none() {
+                result = job.done.wait(result).unwrap();
+            }
+            return result.clone().unwrap();
+        }
+        let result = Self::compress(encoding, body)
+            .map(Arc::new)
+            .map_err(|err| err.to_string());
+        self.jobs.lock().unwrap().remove(&key);
+        *job.result.lock().unwrap() = Some(result.clone());
+        job.done.notify_all();
+        result
+    }
+}
 
 impl AfterMiddleware for CompressionHandler {
-    fn after(&self, _: &mut Request, mut resp: Response) -> IronResult<Response> {
+    fn after(&self, req: &mut Request, mut resp: Response) -> IronResult<Response> {
+        if resp.extensions.contains::<EncodedBody>() {
+            return Ok(resp);
+        }
         if let Some(&ContentLength(length)) = resp.headers.get::<ContentLength>() {
             if length <= 256 {
                 resp.headers.remove::<ContentEncoding>();
@@ -45,6 +177,9 @@ impl AfterMiddleware for CompressionHandler {
                 .iter()
                 .find(|obj| *obj == &Encoding::Deflate || *obj == &Encoding::Gzip)
                 .cloned();
+        } else if let Some(negotiated) = self.negotiate(req, &resp) {
+            resp.headers.set(ContentEncoding(vec![negotiated.clone()]));
+            encoding = Some(negotiated);
         }
         if encoding.is_none() {
             if let Some(TransferEncoding(objs)) = resp.headers.get::<TransferEncoding>() {
@@ -55,6 +190,30 @@ impl AfterMiddleware for CompressionHandler {
             }
         }
 
+        // Without an ETag there is no telling two versions of a file apart
+        let length = resp.headers.get::<ContentLength>().map(|length| length.0);
+        if let (Some(encoding), Some(ETag(tag)), Some(length)) =
+            (&encoding, resp.headers.get::<ETag>(), length)
+        {
+            if req.method != method::Head
+                && resp.body.is_some()
+                && resp.status == Some(status::Ok)
+                && length <= MAX_COALESCED_SIZE
+            {
+                let key = (
+                    req.url.as_ref().path().to_owned(),
+                    tag.tag().to_owned(),
+                    encoding.to_string(),
+                );
+                let data = self
+                    .coalesce(key, encoding, resp.body.take().unwrap())
+                    .map_err(|err| IronError::new(StringError(err), status::InternalServerError))?;
+                resp.headers.set(ContentLength(data.len() as u64));
+                resp.body = Some(Box::new(SharedBody(data)));
+                return Ok(resp);
+            }
+        }
+
         if resp.body.is_some() {
             match encoding {
                 Some(Encoding::Gzip) => {
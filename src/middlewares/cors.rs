@@ -0,0 +1,181 @@
+use iron::headers::{
+    AccessControlAllowMethods, AccessControlAllowOrigin, AccessControlMaxAge,
+    AccessControlRequestMethod, Headers,
+};
+use iron::method::Method;
+use iron::status;
+use iron::{AroundMiddleware, Handler, IronResult, Request, Response};
+
+const REQUEST_PRIVATE_NETWORK: &str = "Access-Control-Request-Private-Network";
+const ALLOW_PRIVATE_NETWORK: &str = "Access-Control-Allow-Private-Network";
+const REQUEST_HEADERS: &str = "Access-Control-Request-Headers";
+const ALLOW_HEADERS: &str = "Access-Control-Allow-Headers";
+const ALLOW_CREDENTIALS: &str = "Access-Control-Allow-Credentials";
+
+/// `--cors`: the `Access-Control-Allow-*` headers on the responses to the allowed origins,
+/// and their preflight requests answered. Wraps the whole chain, so that the preflights
+/// (which never carry credentials) are not turned away by the authentication, and that the
+/// pages can read its failures.
+pub struct Cors {
+    /// `--cors-origin`, lowercase and without trailing `/`. `None` for any origin
+    pub origins: Option<Vec<String>>,
+    /// `--cors-methods`, the method asked for by the preflight when `None`
+    pub methods: Option<Vec<Method>>,
+    /// `--cors-headers`, the headers asked for by the preflight when `None`
+    pub headers: Option<Vec<String>>,
+    /// `--cors-max-age`, how long browsers may cache the preflight replies
+    pub max_age: Option<u32>,
+    /// `--cors-private-network`, the consent of Chrome's Private Network Access letting
+    /// pages of public origins reach a server on the LAN or localhost
+    pub private_network: bool,
+}
+
+impl Cors {
+    /// A `--cors-origin` value: `*` or `scheme://host[:port]`, normalized
+    pub fn parse_origin(s: &str) -> Result<String, String> {
+        if s == "*" {
+            return Ok(s.to_owned());
+        }
+        let origin = s.trim_end_matches('/').to_ascii_lowercase();
+        let valid = match origin.split_once("://") {
+            Some(("http", host)) | Some(("https", host)) => {
+                !host.is_empty() && !host.contains(['/', '?', '#', '@'])
+            }
+            _ => false,
+        };
+        if valid {
+            Ok(origin)
+        } else {
+            Err(format!("invalid origin (scheme://host[:port]): {}", s))
+        }
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        match self.origins {
+            Some(ref origins) => origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin)),
+            None => true,
+        }
+    }
+
+    /// The headers of every response to an allowed origin
+    fn set_headers(&self, origin: &str, headers: &mut Headers) {
+        match self.origins {
+            // Named origins are trusted with the credentials (cookies, Basic auth)
+            Some(_) => {
+                headers.set(AccessControlAllowOrigin::Value(origin.to_owned()));
+                headers.set_raw(ALLOW_CREDENTIALS, vec![b"true".to_vec()]);
+            }
+            None => headers.set(AccessControlAllowOrigin::Any),
+        }
+    }
+
+    fn preflight(&self, req: &Request, requested: &Method) -> Response {
+        let mut resp = Response::with(status::Ok);
+        let methods = match self.methods {
+            Some(ref methods) => methods.clone(),
+            None => vec![requested.clone()],
+        };
+        resp.headers.set(AccessControlAllowMethods(methods));
+        match self.headers {
+            Some(ref headers) => resp
+                .headers
+                .set_raw(ALLOW_HEADERS, vec![headers.join(", ").into_bytes()]),
+            None => {
+                if let Some(requested) = req.headers.get_raw(REQUEST_HEADERS) {
+                    resp.headers.set_raw(ALLOW_HEADERS, requested.to_vec());
+                }
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            resp.headers.set(AccessControlMaxAge(max_age));
+        }
+        let private_network = req
+            .headers
+            .get_raw(REQUEST_PRIVATE_NETWORK)
+            .and_then(|values| values.first())
+            .is_some_and(|value| value.eq_ignore_ascii_case(b"true"));
+        if self.private_network && private_network {
+            resp.headers
+                .set_raw(ALLOW_PRIVATE_NETWORK, vec![b"true".to_vec()]);
+        }
+        resp
+    }
+}
+
+impl AroundMiddleware for Cors {
+    fn around(self, handler: Box<dyn Handler>) -> Box<dyn Handler> {
+        Box::new(CorsHandler {
+            cors: self,
+            handler,
+        })
+    }
+}
+
+struct CorsHandler {
+    cors: Cors,
+    handler: Box<dyn Handler>,
+}
+
+impl CorsHandler {
+    /// `Vary: Origin` when the replies depend on it, past the `VaryHandler` of the chain
+    fn vary(&self, headers: &mut Headers) {
+        if self.cors.origins.is_none() {
+            return;
+        }
+        let mut vary = headers
+            .get_raw("Vary")
+            .map(|lines| {
+                lines
+                    .iter()
+                    .map(|line| String::from_utf8_lossy(line).into_owned())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .unwrap_or_default();
+        let names = vary.split(',').map(str::trim).collect::<Vec<_>>();
+        if names
+            .iter()
+            .any(|name| *name == "*" || name.eq_ignore_ascii_case("Origin"))
+        {
+            return;
+        }
+        if !vary.is_empty() {
+            vary.push_str(", ");
+        }
+        vary.push_str("Origin");
+        headers.set_raw("Vary", vec![vary.into_bytes()]);
+    }
+}
+
+impl Handler for CorsHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let origin = req
+            .headers
+            .get_raw("Origin")
+            .and_then(|values| values.first())
+            .and_then(|value| std::str::from_utf8(value).ok())
+            .map(|value| value.trim().to_owned())
+            .filter(|origin| self.cors.allows(origin));
+        if let Some(ref origin) = origin {
+            let requested = req.headers.get::<AccessControlRequestMethod>().cloned();
+            if let (Method::Options, Some(requested)) = (&req.method, requested) {
+                let mut resp = self.cors.preflight(req, &requested.0);
+                self.cors.set_headers(origin, &mut resp.headers);
+                self.vary(&mut resp.headers);
+                return Ok(resp);
+            }
+        }
+        let mut result = self.handler.handle(req);
+        let headers = match result {
+            Ok(ref mut resp) => &mut resp.headers,
+            Err(ref mut err) => &mut err.response.headers,
+        };
+        if let Some(ref origin) = origin {
+            self.cors.set_headers(origin, headers);
+        }
+        self.vary(headers);
+        result
+    }
+}
@@ -0,0 +1,42 @@
+use iron::status;
+use iron::{AfterMiddleware, IronError, IronResult, Request, Response};
+
+use tracing::info;
+
+use crate::util::{error_json, error_resp, prefers_json, request_id, ErrorDetail};
+
+/// Render errors as an HTML page, or as `{"code","message","request_id"}` JSON for clients
+/// preferring JSON (see `prefers_json`). With `ErrorDetail::Minimal` the underlying error
+/// is only logged.
+pub struct ErrorPage {
+    pub base_url: String,
+    pub detail: ErrorDetail,
+}
+
+impl AfterMiddleware for ErrorPage {
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        let status = err.response.status.unwrap_or(status::InternalServerError);
+        let message = self.detail.message(status, &err.error.to_string());
+        if self.detail == ErrorDetail::Minimal {
+            info!("{}: {}", status, err.error);
+        }
+        let mut resp = if prefers_json(req) {
+            error_json(status, &message, &request_id(req))
+        } else if status == status::Unauthorized || err.response.body.is_some() {
+            // Unauthorized responses and custom error pages are passed through as is
+            return Err(err);
+        } else {
+            error_resp(status, &message, &self.base_url)
+        };
+        // Keep extra headers from the original response (eg: Retry-After)
+        for header in err.response.headers.iter() {
+            if resp.headers.get_raw(header.name()).is_none() {
+                resp.headers.set_raw(
+                    header.name().to_owned(),
+                    vec![header.value_string().into_bytes()],
+                );
+            }
+        }
+        Ok(resp)
+    }
+}
@@ -0,0 +1,98 @@
+use std::fs;
+use std::io;
+
+use iron::status;
+use iron::{BeforeMiddleware, IronError, IronResult, Request, Response};
+use tracing::{info, warn};
+
+use crate::util::StringError;
+
+/// Seconds the clients turned away are told to wait
+const RETRY_AFTER: u32 = 5;
+/// Descriptors kept for each connection being served (its socket and the file sent) and
+/// for the process itself (logs, listener, watchers...) under the default ceiling
+const RESERVED_PER_CONNECTION: u64 = 2;
+const RESERVED: u64 = 16;
+
+/// Raise the soft `RLIMIT_NOFILE` to the hard one, returning the limit in force
+pub fn raise_nofile_limit() -> io::Result<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let soft = limit.rlim_cur;
+    let target = limit.rlim_max;
+    // macOS refuses more than `OPEN_MAX` per process, whatever the hard limit
+    #[cfg(target_os = "macos")]
+    let target = target.min(10240);
+    if target <= soft {
+        return Ok(soft);
+    }
+    limit.rlim_cur = target;
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+        let err = io::Error::last_os_error();
+        warn!("Raising the open files limit from {} failed: {}", soft, err);
+        return Ok(soft);
+    }
+    info!("Raised the open files limit from {} to {}", soft, target);
+    Ok(target)
+}
+
+/// The descriptors open in the process. Listing them takes one more, so this fails with
+/// `EMFILE` when there is none left.
+fn open_fds() -> io::Result<u64> {
+    let dir = fs::read_dir("/proc/self/fd").or_else(|_| fs::read_dir("/dev/fd"))?;
+    // Less the one listing the directory
+    Ok((dir.count() as u64).saturating_sub(1))
+}
+
+/// `--max-open-files`: `503` to the requests arriving with `ceiling` descriptors open, so
+/// that the server slows down clients instead of failing its own `open` and `accept` calls
+/// (`EMFILE`) in the middle of the replies
+pub struct FdLimit {
+    pub ceiling: u64,
+}
+
+impl FdLimit {
+    /// The default ceiling for `limit` (`RLIMIT_NOFILE`) and the number of worker threads,
+    /// leaving descriptors to each connection they serve
+    pub fn for_limit(limit: u64, threads: u64) -> FdLimit {
+        FdLimit {
+            // Some room left to serve with very low limits
+            ceiling: limit
+                .saturating_sub(threads * RESERVED_PER_CONNECTION + RESERVED)
+                .max(limit / 2),
+        }
+    }
+}
+
+impl BeforeMiddleware for FdLimit {
+    fn before(&self, _req: &mut Request) -> IronResult<()> {
+        let open = match open_fds() {
+            Ok(open) if open < self.ceiling => return Ok(()),
+            Ok(open) => open.to_string(),
+            Err(err) if matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE)) => {
+                "limit".to_owned()
+            }
+            // Not countable here (no /proc), nothing to guard
+            Err(_) => return Ok(()),
+        };
+        warn!(
+            "Open file descriptors at {} (ceiling {}), request refused",
+            open, self.ceiling
+        );
+        let mut resp = Response::with((
+            status::ServiceUnavailable,
+            "Server is busy, please retry later.",
+        ));
+        resp.headers
+            .set_raw("Retry-After", vec![RETRY_AFTER.to_string().into_bytes()]);
+        Err(IronError {
+            error: Box::new(StringError("too many open files".to_owned())),
+            response: resp,
+        })
+    }
+}
@@ -0,0 +1,27 @@
+use std::io;
+
+use iron::method;
+use iron::response::WriteBody;
+use iron::{AfterMiddleware, IronResult, Request, Response};
+
+// Writes nothing, but keeps iron from resetting `Content-Length` to 0
+struct EmptyBody;
+
+impl WriteBody for EmptyBody {
+    fn write_body(&mut self, _: &mut dyn io::Write) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reply HEAD requests with exactly the headers of the corresponding GET response
+/// (Content-Length, ETag, Content-Encoding...) but without sending the body.
+pub struct HeadHandler;
+
+impl AfterMiddleware for HeadHandler {
+    fn after(&self, req: &mut Request, mut resp: Response) -> IronResult<Response> {
+        if req.method == method::Head && resp.body.is_some() {
+            resp.body = Some(Box::new(EmptyBody));
+        }
+        Ok(resp)
+    }
+}
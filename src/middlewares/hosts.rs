@@ -0,0 +1,52 @@
+use iron::headers::Host;
+use iron::status;
+use iron::{BeforeMiddleware, IronError, IronResult, Request};
+
+use crate::util::StringError;
+
+/// Reject requests for other host names (`--allowed-hosts`), so a DNS rebinding page can
+/// not reach the server under its own domain: `400` without a `Host` header, `421` for
+/// hosts not allowed.
+pub struct HostChecker {
+    /// Host names (`example.com`), or subdomain wildcards (`*.internal`)
+    pub patterns: Vec<String>,
+}
+
+impl HostChecker {
+    pub fn new(spec: &str) -> HostChecker {
+        HostChecker {
+            patterns: spec
+                .split(',')
+                .map(|pattern| pattern.trim().trim_end_matches('.').to_ascii_lowercase())
+                .filter(|pattern| !pattern.is_empty())
+                .collect(),
+        }
+    }
+
+    fn allows(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.patterns
+            .iter()
+            .any(|pattern| match pattern.strip_prefix('*') {
+                Some("") => true,
+                Some(suffix) => host.ends_with(suffix) && host.len() > suffix.len(),
+                None => *pattern == host,
+            })
+    }
+}
+
+impl BeforeMiddleware for HostChecker {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        match req.headers.get::<Host>() {
+            None => Err(IronError::new(
+                StringError("Host header required".to_owned()),
+                status::BadRequest,
+            )),
+            Some(host) if !self.allows(&host.hostname) => Err(IronError::new(
+                StringError(format!("host not allowed: {}", host.hostname)),
+                status::MisdirectedRequest,
+            )),
+            Some(_) => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use iron::status;
+use iron::{BeforeMiddleware, IronError, IronResult, Request};
+use tracing::info;
+
+use crate::util::StringError;
+
+/// An address range, `192.168.1.0/24`, `fd00::/8`, or a single address
+pub struct IpRange {
+    net: IpAddr,
+    bits: u8,
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IpRange, String> {
+        let invalid = || format!("invalid address range: {}", s);
+        let (ip, bits) = match s.split_once('/') {
+            Some((ip, bits)) => (ip, Some(bits)),
+            None => (s, None),
+        };
+        let net = ip.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max = if net.is_ipv4() { 32 } else { 128 };
+        let bits = match bits {
+            Some(bits) => bits
+                .parse::<u8>()
+                .ok()
+                .filter(|bits| *bits <= max)
+                .ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(IpRange { net, bits })
+    }
+}
+
+impl IpRange {
+    /// Comma separated ranges
+    pub fn parse_list(s: &str) -> Result<Vec<IpRange>, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|range| !range.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+
+    /// IPv4 ranges also match the IPv4 clients of a dual-stack socket (`::ffff:a.b.c.d`)
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match self.net {
+            IpAddr::V4(net) => match ip {
+                IpAddr::V4(ip) => prefix_eq(&net.octets(), &ip.octets(), self.bits),
+                IpAddr::V6(ip) => ip
+                    .to_ipv4_mapped()
+                    .is_some_and(|ip| prefix_eq(&net.octets(), &ip.octets(), self.bits)),
+            },
+            IpAddr::V6(net) => match ip {
+                IpAddr::V6(ip) => prefix_eq(&net.octets(), &ip.octets(), self.bits),
+                IpAddr::V4(_) => false,
+            },
+        }
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], bits: u8) -> bool {
+    a.iter().zip(b).enumerate().all(|(i, (a, b))| {
+        let bits = (bits as usize).saturating_sub(i * 8).min(8);
+        let mask = (0xff00u16 >> bits) as u8;
+        a & mask == b & mask
+    })
+}
+
+/// `--allow-ip` and `--deny-ip`: `403` for the clients in a denied range, or out of all the
+/// allowed ones when there are some. Checked before everything else, on the address of the
+/// connection (proxies are not looked through).
+pub struct IpFilter {
+    pub allow: Vec<IpRange>,
+    pub deny: Vec<IpRange>,
+}
+
+impl IpFilter {
+    fn allows(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip)))
+    }
+}
+
+impl BeforeMiddleware for IpFilter {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let ip = req.remote_addr.ip();
+        if self.allows(ip) {
+            return Ok(());
+        }
+        info!("Client address not allowed: {}", ip);
+        Err(IronError::new(
+            StringError(format!("client address not allowed: {}", ip)),
+            status::Forbidden,
+        ))
+    }
+}
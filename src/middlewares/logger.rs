@@ -1,13 +1,22 @@
+use std::io;
 use std::ops::Deref;
+use std::sync::Arc;
 
-use iron::status;
+use iron::headers::{ContentLength, ContentRange, ContentRangeSpec};
+use iron::method;
+use iron::response::WriteBody;
+use iron::status::{self, Status};
 use iron::{AfterMiddleware, IronError, IronResult, Request, Response};
 use lazy_static::lazy_static;
 use percent_encoding::percent_decode;
+use rand::{thread_rng, Rng};
 use termcolor::{Color, ColorSpec};
+use tracing::error;
 
 use crate::color::{build_spec, Printer};
-use crate::util::{error_resp, now_string};
+use crate::expect::Connection;
+use crate::stats::Stats;
+use crate::util::now_string;
 
 lazy_static! {
     static ref C_BOLD_GREEN: Option<ColorSpec> = Some(build_spec(Some(Color::Green), true));
@@ -16,61 +25,201 @@ lazy_static! {
 }
 
 pub struct RequestLogger {
-    pub printer: Printer,
-    pub base_url: String,
+    pub printer: Arc<Printer>,
+    /// Path prefixes not logged (`--log-ignore`), eg: health checks
+    pub ignore: Vec<String>,
+    /// Share of the successful requests logged (`--log-sample`), errors are always logged
+    pub sample: Option<f64>,
+    /// Counts the suppressed lines, for `/-/metrics`
+    pub stats: Option<Arc<Stats>>,
+}
+
+// One access log line, printed once the response is known or, for downloads, sent
+struct LogLine {
+    printer: Arc<Printer>,
+    remote_addr: String,
+    status: Status,
+    method: String,
+    path: String,
+    protocol: String,
+}
+
+impl LogLine {
+    fn print(&self, transfer: &str) {
+        let status_color = if self.status.is_success() {
+            C_BOLD_GREEN.deref()
+        } else if self.status.is_informational() || self.status.is_redirection() {
+            C_BOLD_YELLOW.deref()
+        } else {
+            C_BOLD_RED.deref()
+        };
+        self.printer
+            .println_out(
+                // datetime, remote-ip, status-code, method, url-path, protocol, transfer
+                "[{}] - {} - {} - {} {} {}{}",
+                &[
+                    (now_string().as_str(), &None),
+                    (self.remote_addr.as_str(), &None),
+                    (self.status.to_u16().to_string().as_str(), status_color),
+                    (self.method.as_str(), &None),
+                    (self.path.as_str(), &None),
+                    (self.protocol.as_str(), &None),
+                    (transfer, &None),
+                ],
+            )
+            .unwrap();
+    }
+}
+
+struct CountingWriter<'a> {
+    inner: &'a mut dyn io::Write,
+    count: u64,
+}
+
+impl io::Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Logs the download once its body is written, with the byte window of partial responses and
+// whether the transfer completed, so resumed (206) downloads can be told from full ones.
+struct LoggedBody {
+    inner: Box<dyn WriteBody>,
+    line: LogLine,
+    // `bytes <first>-<last>/<length>` of a 206 response
+    window: Option<String>,
+    expected: Option<u64>,
+    sent: u64,
+    complete: bool,
+}
+
+impl WriteBody for LoggedBody {
+    fn write_body(&mut self, w: &mut dyn io::Write) -> io::Result<()> {
+        let mut w = CountingWriter { inner: w, count: 0 };
+        let rv = self.inner.write_body(&mut w);
+        self.sent += w.count;
+        self.complete = rv.is_ok() && self.expected.is_none_or(|len| self.sent >= len);
+        rv
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        let window = match self.window {
+            Some(ref window) => format!(" - {}", window),
+            None => "".to_owned(),
+        };
+        let transfer = if self.complete {
+            "complete".to_owned()
+        } else {
+            format!("aborted after {} bytes", self.sent)
+        };
+        self.line.print(&format!("{} - {}", window, transfer));
+    }
 }
 
 impl RequestLogger {
+    // Whether the line is left out of the access log, counted in the stats if so
+    fn suppressed(&self, req: &Request, status: Option<Status>) -> bool {
+        let path = req.url.as_ref().path();
+        let reason = if self
+            .ignore
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+        {
+            "ignored"
+        } else if self.sample.is_some_and(|sample| {
+            status.is_some_and(|status| !status.is_client_error() && !status.is_server_error())
+                && thread_rng().gen::<f64>() >= sample
+        }) {
+            "sampled"
+        } else {
+            return false;
+        };
+        if let Some(ref stats) = self.stats {
+            stats.add_suppressed_log_line(reason);
+        }
+        true
+    }
+
+    fn log_line(&self, req: &Request, status: Status) -> LogLine {
+        LogLine {
+            printer: self.printer.clone(),
+            remote_addr: req.remote_addr.ip().to_string(),
+            status,
+            method: req.method.to_string(),
+            path: percent_decode(req.url.as_ref().path().as_bytes())
+                .decode_utf8_lossy()
+                .to_string(),
+            protocol: req
+                .extensions
+                .get::<Connection>()
+                .map(Connection::label)
+                .unwrap_or_default(),
+        }
+    }
+
     fn log(&self, req: &Request, resp: &Response) {
         if let Some(status) = resp.status {
-            let status_color = if status.is_success() {
-                C_BOLD_GREEN.deref()
-            } else if status.is_informational() || status.is_redirection() {
-                C_BOLD_YELLOW.deref()
-            } else {
-                C_BOLD_RED.deref()
-            };
-            self.printer
-                .println_out(
-                    // datetime, remote-ip, status-code, method, url-path
-                    "[{}] - {} - {} - {} {}",
-                    &[
-                        (now_string().as_str(), &None),
-                        (req.remote_addr.ip().to_string().as_str(), &None),
-                        (status.to_u16().to_string().as_str(), status_color),
-                        (req.method.to_string().as_str(), &None),
-                        (
-                            percent_decode(req.url.as_ref().path().as_bytes())
-                                .decode_utf8_lossy()
-                                .to_string()
-                                .as_str(),
-                            &None,
-                        ),
-                    ],
-                )
-                .unwrap();
+            self.log_line(req, status).print("");
         } else {
-            println!("ERROR: StatusCode missing");
+            error!("StatusCode missing");
         }
     }
 }
 
 impl AfterMiddleware for RequestLogger {
-    fn after(&self, req: &mut Request, resp: Response) -> IronResult<Response> {
-        self.log(req, &resp);
+    fn after(&self, req: &mut Request, mut resp: Response) -> IronResult<Response> {
+        if self.suppressed(req, resp.status) {
+            return Ok(resp);
+        }
+        let download = match resp.status {
+            Some(status) if status == status::Ok || status == status::PartialContent => {
+                req.method != method::Head && resp.body.is_some()
+            }
+            _ => false,
+        };
+        if !download {
+            self.log(req, &resp);
+            return Ok(resp);
+        }
+        let window = match resp.headers.get::<ContentRange>() {
+            Some(&ContentRange(ContentRangeSpec::Bytes {
+                range,
+                instance_length,
+            })) => Some(format!(
+                "bytes {}/{}",
+                range
+                    .map(|(first, last)| format!("{}-{}", first, last))
+                    .unwrap_or_else(|| "*".to_owned()),
+                instance_length
+                    .map(|len| len.to_string())
+                    .unwrap_or_else(|| "*".to_owned()),
+            )),
+            _ => None,
+        };
+        resp.body = Some(Box::new(LoggedBody {
+            inner: resp.body.take().unwrap(),
+            line: self.log_line(req, resp.status.unwrap()),
+            window,
+            expected: resp.headers.get::<ContentLength>().map(|len| len.0),
+            sent: 0,
+            complete: false,
+        }));
         Ok(resp)
     }
 
     fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
-        self.log(req, &err.response);
-        if err.response.status == Some(status::Unauthorized) {
-            Err(err)
-        } else {
-            Ok(error_resp(
-                err.response.status.unwrap_or(status::InternalServerError),
-                err.error.to_string().as_str(),
-                &self.base_url,
-            ))
+        if !self.suppressed(req, err.response.status) {
+            self.log(req, &err.response);
         }
+        Err(err)
     }
 }
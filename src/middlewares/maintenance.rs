@@ -0,0 +1,47 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use iron::headers::ContentType;
+use iron::status;
+use iron::{BeforeMiddleware, IronError, IronResult, Request, Response};
+
+use crate::admin::{RuntimeState, ADMIN_PATH_PREFIX};
+use crate::util::{error_resp, StringError};
+
+/// Reply 503 to all non-admin requests while maintenance mode is on.
+pub struct MaintenanceChecker {
+    pub state: Arc<RuntimeState>,
+    pub page: Option<String>,
+    pub retry_after: u32,
+    pub base_url: String,
+}
+
+impl BeforeMiddleware for MaintenanceChecker {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if !self.state.maintenance.load(Ordering::SeqCst)
+            || req.url.as_ref().path().starts_with(ADMIN_PATH_PREFIX)
+        {
+            return Ok(());
+        }
+        let mut resp = match self.page {
+            Some(ref page) => {
+                let mut resp = Response::with((status::ServiceUnavailable, page.clone()));
+                resp.headers.set(ContentType::html());
+                resp
+            }
+            None => error_resp(
+                status::ServiceUnavailable,
+                "Server is under maintenance, please retry later.",
+                &self.base_url,
+            ),
+        };
+        resp.headers.set_raw(
+            "Retry-After",
+            vec![self.retry_after.to_string().into_bytes()],
+        );
+        Err(IronError {
+            error: Box::new(StringError("maintenance mode".to_owned())),
+            response: resp,
+        })
+    }
+}
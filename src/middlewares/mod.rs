@@ -1,10 +1,47 @@
+mod access;
+mod apikeys;
 mod auth;
 mod compress;
+mod cors;
+mod error;
+#[cfg(unix)]
+mod fds;
+mod head;
+mod hosts;
+mod ipfilter;
 mod logger;
+mod maintenance;
+mod quota;
+mod readonly;
+mod schedule;
+mod slowlog;
+mod throttle;
+mod uploads;
+mod vary;
+mod waf;
 
 // BeforeMiddleware
-pub use self::auth::AuthChecker;
+pub use self::access::{check_access, is_access_file, walk_excluded, AccessFiles};
+pub use self::apikeys::ApiKeyChecker;
+pub use self::auth::{request_user, AuthChecker};
+#[cfg(unix)]
+pub use self::fds::{raise_nofile_limit, FdLimit};
+pub use self::hosts::HostChecker;
+pub use self::ipfilter::{IpFilter, IpRange};
+pub use self::maintenance::MaintenanceChecker;
+pub use self::quota::QuotaChecker;
+pub use self::readonly::ReadOnlyChecker;
+pub use self::schedule::AccessSchedule;
+pub use self::slowlog::{record_stat, AuthTimer, SlowLog, SlowRequestLogger};
+pub use self::uploads::UploadSlots;
+pub use self::vary::vary_on;
+pub use self::waf::Waf;
 
 // AfterMiddleware
-pub use self::compress::CompressionHandler;
+pub use self::compress::{CompressionHandler, EncodedBody, FileBody};
+pub use self::cors::Cors;
+pub use self::error::ErrorPage;
+pub use self::head::HeadHandler;
 pub use self::logger::RequestLogger;
+pub use self::throttle::{Rate, Throttle};
+pub use self::vary::VaryHandler;
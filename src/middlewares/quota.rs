@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use iron::headers::ContentLength;
+use iron::method;
+use iron::status;
+use iron::{AfterMiddleware, BeforeMiddleware, IronError, IronResult, Request, Response};
+use tracing::error;
+
+use super::{request_user, vary_on};
+use crate::util::{parse_size, unix_now, StringError};
+
+#[derive(Clone, Copy)]
+struct Usage {
+    window_start: u64,
+    bytes: u64,
+}
+
+/// Limit how many bytes one client (auth user or remote IP) may download per period.
+pub struct QuotaChecker {
+    limit: u64,
+    period: u64,
+    state_file: Option<PathBuf>,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl QuotaChecker {
+    /// `s` is in `SIZE/PERIOD` format, eg: `5G/day`, period is one of: hour, day, week, month
+    pub fn new(s: &str, state_file: Option<PathBuf>) -> Result<QuotaChecker, StringError> {
+        let parts = s.splitn(2, '/').collect::<Vec<&str>>();
+        let limit = parse_size(parts[0])?;
+        let period = match parts.get(1).copied().unwrap_or("day") {
+            "hour" => 3600,
+            "day" => 24 * 3600,
+            "week" => 7 * 24 * 3600,
+            "month" => 30 * 24 * 3600,
+            other => return Err(StringError(format!("unknown quota period: {}", other))),
+        };
+        let usage = match state_file {
+            Some(ref path) => load_usage(path)
+                .map_err(|e| StringError(format!("load quota state failed: {}", e)))?,
+            None => HashMap::new(),
+        };
+        Ok(QuotaChecker {
+            limit,
+            period,
+            state_file,
+            usage: Mutex::new(usage),
+        })
+    }
+
+    fn client_key(req: &Request) -> String {
+        match request_user(&req.headers) {
+            Some(username) => format!("user:{}", username),
+            None => format!("ip:{}", req.remote_addr.ip()),
+        }
+    }
+
+    fn current(&self, usage: &mut HashMap<String, Usage>, key: &str) -> Usage {
+        let now = unix_now();
+        let window_start = now - now % self.period;
+        let entry = usage.entry(key.to_owned()).or_insert(Usage {
+            window_start,
+            bytes: 0,
+        });
+        if entry.window_start != window_start {
+            *entry = Usage {
+                window_start,
+                bytes: 0,
+            };
+        }
+        *entry
+    }
+
+    fn set_headers(&self, resp: &mut Response, usage: Usage) {
+        let remaining = self.limit.saturating_sub(usage.bytes);
+        let reset = usage.window_start + self.period;
+        resp.headers
+            .set_raw("X-Quota-Limit", vec![self.limit.to_string().into_bytes()]);
+        resp.headers.set_raw(
+            "X-Quota-Remaining",
+            vec![remaining.to_string().into_bytes()],
+        );
+        resp.headers
+            .set_raw("X-Quota-Reset", vec![reset.to_string().into_bytes()]);
+    }
+
+    fn save(&self, usage: &HashMap<String, Usage>) -> io::Result<()> {
+        if let Some(ref path) = self.state_file {
+            let tmp_path = path.with_extension("tmp");
+            let mut file = fs::File::create(&tmp_path)?;
+            for (key, item) in usage {
+                writeln!(file, "{}\t{}\t{}", key, item.window_start, item.bytes)?;
+            }
+            file.sync_all()?;
+            fs::rename(&tmp_path, path)?;
+        }
+        Ok(())
+    }
+}
+
+fn load_usage(path: &Path) -> io::Result<HashMap<String, Usage>> {
+    let mut usage = HashMap::new();
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(usage),
+        Err(e) => return Err(e),
+    };
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let parts = line.rsplitn(3, '\t').collect::<Vec<&str>>();
+        if parts.len() != 3 {
+            continue;
+        }
+        if let (Ok(bytes), Ok(window_start)) = (parts[0].parse(), parts[1].parse()) {
+            usage.insert(
+                parts[2].to_owned(),
+                Usage {
+                    window_start,
+                    bytes,
+                },
+            );
+        }
+    }
+    Ok(usage)
+}
+
+impl BeforeMiddleware for QuotaChecker {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        vary_on(req, "Authorization");
+        let key = QuotaChecker::client_key(req);
+        let current = self.current(&mut self.usage.lock().unwrap(), &key);
+        if current.bytes < self.limit {
+            return Ok(());
+        }
+        let mut resp = Response::with((status::TooManyRequests, "Transfer quota exceeded."));
+        self.set_headers(&mut resp, current);
+        let retry_after = (current.window_start + self.period).saturating_sub(unix_now());
+        resp.headers
+            .set_raw("Retry-After", vec![retry_after.to_string().into_bytes()]);
+        Err(IronError {
+            error: Box::new(StringError(format!("transfer quota exceeded: {}", key))),
+            response: resp,
+        })
+    }
+}
+
+impl AfterMiddleware for QuotaChecker {
+    fn after(&self, req: &mut Request, mut resp: Response) -> IronResult<Response> {
+        let key = QuotaChecker::client_key(req);
+        let mut usage = self.usage.lock().unwrap();
+        let mut current = self.current(&mut usage, &key);
+        if req.method != method::Head && resp.body.is_some() {
+            if let Some(&ContentLength(length)) = resp.headers.get::<ContentLength>() {
+                current.bytes += length;
+                usage.insert(key, current);
+                if let Err(e) = self.save(&usage) {
+                    error!("Save quota state failed: {}", e);
+                }
+            }
+        }
+        self.set_headers(&mut resp, current);
+        Ok(resp)
+    }
+}
@@ -0,0 +1,30 @@
+use iron::headers::Allow;
+use iron::method::Method;
+use iron::status;
+use iron::{BeforeMiddleware, IronError, IronResult, Request, Response};
+
+use crate::admin::ADMIN_PATH_PREFIX;
+use crate::util::StringError;
+
+/// Reply 405 to all mutating methods in `--read-only` mode, the admin endpoint excepted
+/// since it only changes server state.
+pub struct ReadOnlyChecker;
+
+impl BeforeMiddleware for ReadOnlyChecker {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        match req.method {
+            Method::Get | Method::Head | Method::Options => return Ok(()),
+            // `--webdav` listing
+            Method::Extension(ref name) if name == "PROPFIND" => return Ok(()),
+            _ if req.url.as_ref().path().starts_with(ADMIN_PATH_PREFIX) => return Ok(()),
+            _ => {}
+        }
+        let mut resp = Response::with((status::MethodNotAllowed, "Server is read-only."));
+        resp.headers
+            .set(Allow(vec![Method::Get, Method::Head, Method::Options]));
+        Err(IronError {
+            error: Box::new(StringError("read-only mode".to_owned())),
+            response: resp,
+        })
+    }
+}
@@ -0,0 +1,127 @@
+use chrono::{Local, NaiveTime};
+use iron::status;
+use iron::{BeforeMiddleware, IronError, IronResult, Request};
+use percent_encoding::percent_decode;
+
+use crate::admin::ADMIN_PATH_PREFIX;
+use crate::util::{error_resp, StringError};
+
+struct Window {
+    // Path prefix, "/" for the whole server
+    prefix: String,
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    fn matches(&self, path: &str) -> bool {
+        self.prefix == "/" || path == self.prefix || path.starts_with(&format!("{}/", self.prefix))
+    }
+
+    fn contains(&self, time: NaiveTime) -> bool {
+        window_contains(self.start, self.end, time)
+    }
+
+    fn label(&self) -> String {
+        format!(
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// Parse a `HH:MM-HH:MM` window
+pub(super) fn parse_window(s: &str) -> Option<(NaiveTime, NaiveTime)> {
+    let (start, end) = s.split_once('-')?;
+    let parse = |s: &str| NaiveTime::parse_from_str(s.trim(), "%H:%M").ok();
+    Some((parse(start)?, parse(end)?))
+}
+
+// Windows ending before they start span midnight (eg: 22:00-06:00), an empty
+// window (eg: 00:00-00:00) is the whole day
+pub(super) fn window_contains(start: NaiveTime, end: NaiveTime, time: NaiveTime) -> bool {
+    if start == end {
+        true
+    } else if start < end {
+        start <= time && time < end
+    } else {
+        start <= time || time < end
+    }
+}
+
+/// Reply 403 outside of the allowed hours (local time), the most specific path prefix
+/// with a window decides, several windows of the same prefix are combined.
+pub struct AccessSchedule {
+    windows: Vec<Window>,
+    base_url: String,
+}
+
+impl AccessSchedule {
+    /// Rules are `HH:MM-HH:MM` or `<path> HH:MM-HH:MM`
+    pub fn new(rules: &[String], base_url: &str) -> Result<AccessSchedule, StringError> {
+        let mut windows = Vec::new();
+        for rule in rules {
+            let (prefix, hours) = match rule.trim().rsplit_once(' ') {
+                Some((prefix, hours)) => (prefix.trim(), hours),
+                None => ("/", rule.trim()),
+            };
+            let (start, end) = parse_window(hours)
+                .ok_or_else(|| StringError(format!("invalid --allow-hours: {}", rule)))?;
+            let prefix = prefix.trim_end_matches('*').trim_end_matches('/');
+            windows.push(Window {
+                prefix: format!("/{}", prefix.trim_start_matches('/')),
+                start,
+                end,
+            });
+        }
+        Ok(AccessSchedule {
+            windows,
+            base_url: base_url.to_owned(),
+        })
+    }
+}
+
+impl BeforeMiddleware for AccessSchedule {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let path = percent_decode(req.url.as_ref().path().as_bytes())
+            .decode_utf8_lossy()
+            .to_string();
+        if path.starts_with(ADMIN_PATH_PREFIX) {
+            return Ok(());
+        }
+        let windows = self
+            .windows
+            .iter()
+            .filter(|window| window.matches(&path))
+            .collect::<Vec<&Window>>();
+        let prefix_len = match windows.iter().map(|window| window.prefix.len()).max() {
+            Some(len) => len,
+            None => return Ok(()),
+        };
+        let windows = windows
+            .into_iter()
+            .filter(|window| window.prefix.len() == prefix_len)
+            .collect::<Vec<&Window>>();
+        let now = Local::now().time();
+        if windows.iter().any(|window| window.contains(now)) {
+            return Ok(());
+        }
+        let hours = windows
+            .iter()
+            .map(|window| window.label())
+            .collect::<Vec<String>>()
+            .join(", ");
+        Err(IronError {
+            error: Box::new(StringError("outside of allowed hours".to_owned())),
+            response: error_resp(
+                status::Forbidden,
+                &format!(
+                    "This is only available during {}, please come back later.",
+                    hours
+                ),
+                &self.base_url,
+            ),
+        })
+    }
+}
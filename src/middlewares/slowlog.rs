@@ -0,0 +1,188 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use iron::response::WriteBody;
+use iron::typemap::Key;
+use iron::{AfterMiddleware, BeforeMiddleware, IronError, IronResult, Request, Response};
+use percent_encoding::percent_decode;
+
+use crate::util::now_string;
+
+/// Per request timing breakdown, stored in request extensions
+#[derive(Clone, Copy)]
+pub struct Timing {
+    start: Instant,
+    auth: Option<Duration>,
+    stat: Option<Duration>,
+}
+
+impl Key for Timing {
+    type Value = Timing;
+}
+
+/// Record how long the filesystem `stat` took for this request
+pub fn record_stat(req: &mut Request, elapsed: Duration) {
+    if let Some(timing) = req.extensions.get_mut::<Timing>() {
+        timing.stat = Some(elapsed);
+    }
+}
+
+pub struct SlowLog {
+    threshold: Duration,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl SlowLog {
+    pub fn new(threshold: Duration, out: Box<dyn Write + Send>) -> SlowLog {
+        SlowLog {
+            threshold,
+            out: Mutex::new(out),
+        }
+    }
+
+    fn log(&self, request: &str, timing: Timing, read: Duration, write: Duration) {
+        let total = timing.start.elapsed();
+        if total < self.threshold {
+            return;
+        }
+        let ms = |d: Option<Duration>| {
+            d.map(|d| format!("{}ms", d.as_millis()))
+                .unwrap_or_else(|| "-".to_owned())
+        };
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(
+            out,
+            "[{}] - {} - total={} auth={} stat={} read={} write={}",
+            now_string(),
+            request,
+            ms(Some(total)),
+            ms(timing.auth),
+            ms(timing.stat),
+            ms(Some(read)),
+            ms(Some(write)),
+        );
+        let _ = out.flush();
+    }
+}
+
+struct TimingWriter<'a> {
+    inner: &'a mut dyn io::Write,
+    elapsed: Duration,
+}
+
+impl io::Write for TimingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let rv = self.inner.write(buf);
+        self.elapsed += start.elapsed();
+        rv
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let start = Instant::now();
+        let rv = self.inner.flush();
+        self.elapsed += start.elapsed();
+        rv
+    }
+}
+
+// Time spent in the socket writer is the "write" phase, the rest is the "read" phase
+struct TimingBody {
+    inner: Box<dyn WriteBody>,
+    request: String,
+    timing: Timing,
+    log: Arc<SlowLog>,
+}
+
+impl WriteBody for TimingBody {
+    fn write_body(&mut self, w: &mut dyn io::Write) -> io::Result<()> {
+        let start = Instant::now();
+        let mut w = TimingWriter {
+            inner: w,
+            elapsed: Duration::from_secs(0),
+        };
+        let rv = self.inner.write_body(&mut w);
+        let read = start.elapsed().checked_sub(w.elapsed).unwrap_or_default();
+        self.log.log(&self.request, self.timing, read, w.elapsed);
+        rv
+    }
+}
+
+/// Log requests slower than the threshold with a timing breakdown
+pub struct SlowRequestLogger {
+    pub log: Arc<SlowLog>,
+}
+
+impl SlowRequestLogger {
+    fn request_line(req: &Request, resp: &Response) -> String {
+        format!(
+            "{} - {} - {} {}",
+            req.remote_addr.ip(),
+            resp.status.map(|s| s.to_u16()).unwrap_or(0),
+            req.method,
+            percent_decode(req.url.as_ref().path().as_bytes()).decode_utf8_lossy()
+        )
+    }
+}
+
+impl BeforeMiddleware for SlowRequestLogger {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<Timing>(Timing {
+            start: Instant::now(),
+            auth: None,
+            stat: None,
+        });
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for SlowRequestLogger {
+    fn after(&self, req: &mut Request, mut resp: Response) -> IronResult<Response> {
+        if let Some(timing) = req.extensions.get::<Timing>().copied() {
+            let request = SlowRequestLogger::request_line(req, &resp);
+            match resp.body.take() {
+                Some(inner) => {
+                    resp.body = Some(Box::new(TimingBody {
+                        inner,
+                        request,
+                        timing,
+                        log: self.log.clone(),
+                    }));
+                }
+                None => self.log.log(
+                    &request,
+                    timing,
+                    Duration::from_secs(0),
+                    Duration::from_secs(0),
+                ),
+            }
+        }
+        Ok(resp)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        if let Some(timing) = req.extensions.get::<Timing>().copied() {
+            let request = SlowRequestLogger::request_line(req, &err.response);
+            self.log.log(
+                &request,
+                timing,
+                Duration::from_secs(0),
+                Duration::from_secs(0),
+            );
+        }
+        Err(err)
+    }
+}
+
+/// Record the time spent in authorization, linked right after the auth middleware
+pub struct AuthTimer;
+
+impl BeforeMiddleware for AuthTimer {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if let Some(timing) = req.extensions.get_mut::<Timing>() {
+            timing.auth = Some(timing.start.elapsed());
+        }
+        Ok(())
+    }
+}
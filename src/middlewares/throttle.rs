@@ -0,0 +1,199 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, NaiveTime};
+use iron::method;
+use iron::response::WriteBody;
+use iron::{AfterMiddleware, IronResult, Request, Response};
+use percent_encoding::percent_decode;
+
+use super::schedule::{parse_window, window_contains};
+use crate::util::{parse_size, StringError};
+
+/// Largest write done at once, so one response does not take a whole second of budget
+const MAX_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Bandwidth shared by all the responses of one class, with one second of burst
+struct TokenBucket {
+    rate: u64,
+    // Available bytes (negative when overdrawn) and when it was last refilled
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    // Take `n` bytes, returns how long to wait before sending them
+    fn take(&self, n: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.1).as_secs_f64() * self.rate as f64;
+        state.0 = (state.0 + refill).min(self.rate as f64) - n as f64;
+        state.1 = now;
+        if state.0 < 0.0 {
+            Duration::from_secs_f64(-state.0 / self.rate as f64)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+type Window = (NaiveTime, NaiveTime);
+
+/// A rate, or rates by time of day (eg: `1m@09:00-18:00,unlimited@else`): the first window
+/// containing the local time applies, `else` or a rate without window always does, and
+/// nothing limits outside of all windows. It is looked up on each write, so long
+/// responses follow the changes.
+pub struct Rate {
+    // Window (`None` for `else`) and bucket (`None` for unlimited)
+    rates: Vec<(Option<Window>, Option<Arc<TokenBucket>>)>,
+}
+
+impl Rate {
+    pub fn parse(s: &str) -> Result<Rate, StringError> {
+        let invalid = || StringError(format!("invalid throttle rate: {}", s));
+        let mut rates = Vec::new();
+        for part in s.split(',') {
+            let (rate, window) = match part.trim().split_once('@') {
+                Some((rate, "else")) => (rate, None),
+                Some((rate, window)) => (rate, Some(parse_window(window).ok_or_else(invalid)?)),
+                None => (part.trim(), None),
+            };
+            let bucket = match rate {
+                "unlimited" => None,
+                rate => match parse_size(rate).map_err(|_| invalid())? {
+                    0 => return Err(invalid()),
+                    rate => Some(Arc::new(TokenBucket::new(rate))),
+                },
+            };
+            rates.push((window, bucket));
+        }
+        Ok(Rate { rates })
+    }
+
+    fn is_limited(&self) -> bool {
+        self.rates.iter().any(|(_, bucket)| bucket.is_some())
+    }
+
+    fn current(&self) -> Option<&TokenBucket> {
+        let now = Local::now().time();
+        self.rates
+            .iter()
+            .find(|(window, _)| window.is_none_or(|(start, end)| window_contains(start, end, now)))
+            .and_then(|(_, bucket)| bucket.as_deref())
+    }
+
+    /// Wait until `n` more bytes fit in the current rate
+    pub fn pace(&self, n: usize) {
+        if let Some(bucket) = self.current() {
+            thread::sleep(bucket.take(n));
+        }
+    }
+}
+
+struct ThrottledWriter<'a> {
+    inner: &'a mut dyn io::Write,
+    rate: &'a Rate,
+}
+
+impl io::Write for ThrottledWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let buf = &buf[..buf.len().min(MAX_CHUNK_SIZE)];
+        self.rate.pace(buf.len());
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct ThrottledBody {
+    inner: Box<dyn WriteBody>,
+    rate: Arc<Rate>,
+}
+
+impl WriteBody for ThrottledBody {
+    fn write_body(&mut self, w: &mut dyn io::Write) -> io::Result<()> {
+        self.inner.write_body(&mut ThrottledWriter {
+            inner: w,
+            rate: &self.rate,
+        })
+    }
+}
+
+struct PathClass {
+    // Exact path, or prefix when the pattern ends with `*`
+    pattern: String,
+    rate: Arc<Rate>,
+}
+
+impl PathClass {
+    fn matches(&self, path: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.pattern,
+        }
+    }
+}
+
+/// Token bucket bandwidth limit on response bodies: the first matching `--throttle-path`
+/// class, or the global `--throttle` limit for all other responses.
+pub struct Throttle {
+    global: Option<Arc<Rate>>,
+    classes: Vec<PathClass>,
+}
+
+impl Throttle {
+    /// Rates are bytes per second (eg: `512k`), `unlimited`, or by time of day (see `Rate`),
+    /// classes are `<pattern> <rate>`
+    pub fn new(rate: Option<&str>, classes: &[String]) -> Result<Throttle, StringError> {
+        let global = match rate {
+            Some(rate) => Some(Arc::new(Rate::parse(rate)?)),
+            None => None,
+        };
+        let classes = classes
+            .iter()
+            .map(|class| {
+                let (pattern, rate) = class
+                    .trim()
+                    .rsplit_once(' ')
+                    .ok_or_else(|| StringError(format!("invalid --throttle-path: {}", class)))?;
+                Ok(PathClass {
+                    pattern: format!("/{}", pattern.trim().trim_start_matches('/')),
+                    rate: Arc::new(Rate::parse(rate)?),
+                })
+            })
+            .collect::<Result<Vec<PathClass>, StringError>>()?;
+        Ok(Throttle { global, classes })
+    }
+}
+
+impl AfterMiddleware for Throttle {
+    fn after(&self, req: &mut Request, mut resp: Response) -> IronResult<Response> {
+        if req.method == method::Head || resp.body.is_none() {
+            return Ok(resp);
+        }
+        let path = percent_decode(req.url.as_ref().path().as_bytes())
+            .decode_utf8_lossy()
+            .to_string();
+        let rate = match self.classes.iter().find(|class| class.matches(&path)) {
+            Some(class) => Some(&class.rate),
+            None => self.global.as_ref(),
+        };
+        if let Some(rate) = rate.filter(|rate| rate.is_limited()) {
+            resp.body = Some(Box::new(ThrottledBody {
+                inner: resp.body.take().unwrap(),
+                rate: rate.clone(),
+            }));
+        }
+        Ok(resp)
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use iron::headers::{ContentLength, TransferEncoding};
+use iron::method::Method;
+use iron::status;
+use iron::typemap::Key;
+use iron::{BeforeMiddleware, IronError, IronResult, Request, Response};
+use tracing::info_span;
+
+use crate::util::StringError;
+
+/// Longest an upload waits for a free slot, also the `Retry-After` of the ones turned away
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Slots {
+    active: usize,
+    waiting: usize,
+}
+
+struct Shared {
+    max: usize,
+    slots: Mutex<Slots>,
+    freed: Condvar,
+}
+
+/// The slot of an upload, in its request extensions: freed when the request is dropped
+pub struct UploadSlot(Arc<Shared>);
+
+impl Key for UploadSlot {
+    type Value = UploadSlot;
+}
+
+impl Drop for UploadSlot {
+    fn drop(&mut self) {
+        self.0.slots.lock().unwrap().active -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// `--max-concurrent-uploads`: the uploads over the limit wait for one to finish, at most
+/// as many as the limit and for `QUEUE_TIMEOUT`, the others are answered `429`. Waiting
+/// uploads hold a worker thread, the short queue leaves the others to the downloads.
+pub struct UploadSlots {
+    shared: Arc<Shared>,
+}
+
+impl UploadSlots {
+    pub fn new(max: usize) -> UploadSlots {
+        UploadSlots {
+            shared: Arc::new(Shared {
+                max,
+                slots: Mutex::new(Slots::default()),
+                freed: Condvar::new(),
+            }),
+        }
+    }
+
+    fn acquire(&self) -> Option<UploadSlot> {
+        let shared = &self.shared;
+        let mut slots = shared.slots.lock().unwrap();
+        if slots.active >= shared.max {
+            if slots.waiting >= shared.max {
+                return None;
+            }
+            slots.waiting += 1;
+            let deadline = Instant::now() + QUEUE_TIMEOUT;
+            while slots.active >= shared.max {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                slots = shared.freed.wait_timeout(slots, deadline - now).unwrap().0;
+            }
+            slots.waiting -= 1;
+            if slots.active >= shared.max {
+                return None;
+            }
+        }
+        slots.active += 1;
+        Some(UploadSlot(shared.clone()))
+    }
+}
+
+/// Requests sending a body to store: uploads, WebDAV and batch or resumable chunks
+fn is_upload(req: &Request) -> bool {
+    matches!(req.method, Method::Post | Method::Put)
+        && (req
+            .headers
+            .get::<ContentLength>()
+            .is_some_and(|length| length.0 > 0)
+            || req.headers.has::<TransferEncoding>())
+}
+
+impl BeforeMiddleware for UploadSlots {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if !is_upload(req) {
+            return Ok(());
+        }
+        let _span = info_span!("upload_slot").entered();
+        match self.acquire() {
+            Some(slot) => {
+                req.extensions.insert::<UploadSlot>(slot);
+                Ok(())
+            }
+            None => {
+                let mut resp = Response::with((
+                    status::TooManyRequests,
+                    "Too many uploads in progress, please retry later.",
+                ));
+                resp.headers.set_raw(
+                    "Retry-After",
+                    vec![QUEUE_TIMEOUT.as_secs().to_string().into_bytes()],
+                );
+                Err(IronError {
+                    error: Box::new(StringError("too many uploads".to_owned())),
+                    response: resp,
+                })
+            }
+        }
+    }
+}
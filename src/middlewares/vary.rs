@@ -0,0 +1,67 @@
+use iron::typemap::Key;
+use iron::{AfterMiddleware, IronError, IronResult, Request, Response};
+
+/// Request headers that influenced the response, stored in request extensions
+struct VaryOn;
+
+impl Key for VaryOn {
+    type Value = Vec<&'static str>;
+}
+
+/// Record that the response to this request depends on the given request header
+pub fn vary_on(req: &mut Request, header: &'static str) {
+    if let Some(headers) = req.extensions.get_mut::<VaryOn>() {
+        if !headers.iter().any(|h| h.eq_ignore_ascii_case(header)) {
+            headers.push(header);
+        }
+        return;
+    }
+    req.extensions.insert::<VaryOn>(vec![header]);
+}
+
+/// Finalize the `Vary` header from everything recorded by `vary_on`, merged with
+/// whatever `Vary` the response already carries, so caches never mix up variants.
+pub struct VaryHandler;
+
+impl VaryHandler {
+    fn finalize(req: &Request, resp: &mut Response) {
+        let recorded = match req.extensions.get::<VaryOn>() {
+            Some(headers) => headers,
+            None => return,
+        };
+        let mut names: Vec<String> = Vec::new();
+        if let Some(lines) = resp.headers.get_raw("Vary") {
+            for line in lines {
+                for name in String::from_utf8_lossy(line).split(',') {
+                    let name = name.trim();
+                    if !name.is_empty() {
+                        names.push(name.to_owned());
+                    }
+                }
+            }
+        }
+        if names.iter().any(|name| name == "*") {
+            resp.headers.set_raw("Vary", vec![b"*".to_vec()]);
+            return;
+        }
+        for header in recorded {
+            if !names.iter().any(|name| name.eq_ignore_ascii_case(header)) {
+                names.push((*header).to_owned());
+            }
+        }
+        resp.headers
+            .set_raw("Vary", vec![names.join(", ").into_bytes()]);
+    }
+}
+
+impl AfterMiddleware for VaryHandler {
+    fn after(&self, req: &mut Request, mut resp: Response) -> IronResult<Response> {
+        VaryHandler::finalize(req, &mut resp);
+        Ok(resp)
+    }
+
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        VaryHandler::finalize(req, &mut err.response);
+        Err(err)
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use iron::status;
+use iron::{BeforeMiddleware, IronError, IronResult, Request};
+use percent_encoding::percent_decode;
+use tracing::warn;
+
+use crate::stats::Stats;
+use crate::util::StringError;
+
+/// Longest request URI accepted, browsers and tools stay well below
+const MAX_URI_LENGTH: usize = 4096;
+/// Path prefixes probed by vulnerability scanners, none of which a file server has
+const SCANNER_PATHS: &[&str] = &[
+    "/.env",
+    "/.git/",
+    "/.svn/",
+    "/.aws/",
+    "/.ssh/",
+    "/wp-admin",
+    "/wp-login.php",
+    "/xmlrpc.php",
+    "/phpmyadmin",
+    "/cgi-bin/",
+    "/vendor/phpunit/",
+    "/server-status",
+    "/actuator/",
+    "/boaform/",
+    "/HNAP1",
+];
+
+/// Built-in rules (`--waf basic`) rejecting obviously malicious requests with `403` before
+/// the file system is touched: NUL bytes, overlong URIs, known scanner paths and encoded
+/// `..` segments. Blocks are counted per rule in the stats.
+pub struct Waf {
+    pub stats: Option<Arc<Stats>>,
+}
+
+impl Waf {
+    // The rule the request breaks, if any
+    fn rule(req: &Request) -> Option<&'static str> {
+        let url = req.url.as_ref();
+        if url.as_str().len() > MAX_URI_LENGTH {
+            return Some("overlong-uri");
+        }
+        let path = url.path();
+        let decoded = percent_decode(path.as_bytes()).collect::<Vec<u8>>();
+        if decoded.contains(&0) || url.query().is_some_and(|query| query.contains("%00")) {
+            return Some("null-byte");
+        }
+        // `..` are resolved by the URL parser, unless encoded
+        if String::from_utf8_lossy(&decoded)
+            .split(['/', '\\'])
+            .any(|segment| segment == "..")
+        {
+            return Some("traversal");
+        }
+        let lowercase = path.to_ascii_lowercase();
+        if SCANNER_PATHS
+            .iter()
+            .any(|prefix| lowercase.starts_with(&prefix.to_ascii_lowercase()))
+        {
+            return Some("scanner-path");
+        }
+        None
+    }
+}
+
+impl BeforeMiddleware for Waf {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let rule = match Self::rule(req) {
+            Some(rule) => rule,
+            None => return Ok(()),
+        };
+        warn!("Blocked by WAF rule {}: {}", rule, req.remote_addr.ip());
+        if let Some(ref stats) = self.stats {
+            stats.add_waf_block(rule);
+        }
+        Err(IronError::new(
+            StringError(format!("blocked by rule {}", rule)),
+            status::Forbidden,
+        ))
+    }
+}
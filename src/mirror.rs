@@ -0,0 +1,272 @@
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use hyper::client::{Body, Client, RequestBuilder};
+use hyper::header::{Authorization, Bearer, Headers};
+use hyper::status::StatusCode;
+use tracing::{info, warn};
+use url::form_urlencoded;
+
+use crate::storage::Storage;
+use crate::transfer::TransferKind;
+use crate::util::encode_link_path;
+
+/// Header marking the replayed requests, which the mirror does not replay in turn (so two
+/// instances mirroring to each other do not loop)
+const MIRRORED_HEADER: &str = "X-Mirrored";
+/// Attempts to replay an operation while the mirror is unreachable or busy
+const MAX_ATTEMPTS: u32 = 8;
+/// Delay before the second attempt, doubled for each of the next ones
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Replays taking longer are given up, the mirror is probably stuck
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+enum Op {
+    Upload(String),
+    Delete { path: String, permanent: bool },
+    Transfer(TransferKind, String, String),
+}
+
+/// Whether the request is itself replayed by the mirroring instance
+pub fn is_replay(headers: &Headers) -> bool {
+    headers.get_raw(MIRRORED_HEADER).is_some()
+}
+
+/// Replays the uploads, deletes, copies and moves (`--mirror-to`) to a second instance, in
+/// their order, in the background so the writers never wait for it. The mirror has to run
+/// with `--upload` (and `--delete` for the deletes), and with `--api-keys` accepting the
+/// write and delete scopes of the `--mirror-key`. Operations are retried for a few minutes
+/// while it is down, then given up with a warning. Directories only exist there through the
+/// files they hold.
+pub struct Mirror {
+    queue: Sender<Op>,
+}
+
+impl Mirror {
+    pub fn start(target: &str, key: &str, storage: Arc<dyn Storage>) -> Mirror {
+        let (queue, ops) = mpsc::channel();
+        let replayer = Replayer {
+            base: target.trim_end_matches('/').to_owned(),
+            key: key.to_owned(),
+            storage,
+            client: client(),
+        };
+        thread::Builder::new()
+            .name("mirror".to_owned())
+            .spawn(move || {
+                for op in ops {
+                    replayer.replay(&op);
+                }
+            })
+            .unwrap();
+        Mirror { queue }
+    }
+
+    fn push(&self, headers: &Headers, op: Op) {
+        if !is_replay(headers) {
+            let _ = self.queue.send(op);
+        }
+    }
+
+    /// The file (or directory restored from the trash) `path` was written
+    pub fn uploaded(&self, headers: &Headers, path: &str) {
+        self.push(headers, Op::Upload(path.replace('\\', "/")));
+    }
+
+    /// `path` was moved to the trash, or removed for good when `permanent`
+    pub fn deleted(&self, headers: &Headers, path: &str, permanent: bool) {
+        let path = path.replace('\\', "/");
+        self.push(headers, Op::Delete { path, permanent });
+    }
+
+    /// `from` was copied or moved to `to`, by a request replayed itself when `replayed`
+    pub fn transferred(&self, replayed: bool, kind: TransferKind, from: &Path, to: &Path) {
+        if !replayed {
+            let _ = self.queue.send(Op::Transfer(
+                kind,
+                from.to_string_lossy().replace('\\', "/"),
+                to.to_string_lossy().replace('\\', "/"),
+            ));
+        }
+    }
+}
+
+#[cfg(feature = "native-tls")]
+fn client() -> Client {
+    use hyper::net::HttpsConnector;
+    use hyper_native_tls::NativeTlsClient;
+
+    let mut client = match NativeTlsClient::new() {
+        Ok(tls) => Client::with_connector(HttpsConnector::new(tls)),
+        Err(err) => {
+            warn!("Mirror: no TLS ({}), only http:// works", err);
+            Client::new()
+        }
+    };
+    client.set_read_timeout(Some(REQUEST_TIMEOUT));
+    client.set_write_timeout(Some(REQUEST_TIMEOUT));
+    client
+}
+
+#[cfg(not(feature = "native-tls"))]
+fn client() -> Client {
+    let mut client = Client::new();
+    client.set_read_timeout(Some(REQUEST_TIMEOUT));
+    client.set_write_timeout(Some(REQUEST_TIMEOUT));
+    client
+}
+
+enum Failure {
+    // Worth trying again later: unreachable, busy or locked
+    Retry(String),
+    Fatal(String),
+}
+
+struct Replayer {
+    base: String,
+    key: String,
+    storage: Arc<dyn Storage>,
+    client: Client,
+}
+
+impl Replayer {
+    fn replay(&self, op: &Op) {
+        let mut delay = RETRY_DELAY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let failure = match self.send(op) {
+                Ok(()) => return,
+                Err(Failure::Retry(err)) if attempt < MAX_ATTEMPTS => err,
+                Err(Failure::Retry(err)) | Err(Failure::Fatal(err)) => {
+                    warn!("Mirror: {} given up: {}", describe(op), err);
+                    return;
+                }
+            };
+            info!(
+                "Mirror: {} failed ({}), retrying in {}s",
+                describe(op),
+                failure,
+                delay.as_secs()
+            );
+            thread::sleep(delay);
+            delay *= 2;
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base, encode_path(path))
+    }
+
+    fn headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer {
+            token: self.key.clone(),
+        }));
+        headers.set_raw(MIRRORED_HEADER, vec![b"1".to_vec()]);
+        headers
+    }
+
+    fn send(&self, op: &Op) -> Result<(), Failure> {
+        match op {
+            Op::Upload(path) => self.upload(path),
+            Op::Delete { path, permanent } => {
+                let mut headers = self.headers();
+                if *permanent {
+                    headers.set_raw("X-Permanent", vec![b"true".to_vec()]);
+                }
+                let request = self.client.delete(&self.url(path)).headers(headers);
+                match execute(request)? {
+                    StatusCode::Ok | StatusCode::NoContent | StatusCode::NotFound => Ok(()),
+                    status => Err(Failure::Fatal(status.to_string())),
+                }
+            }
+            Op::Transfer(kind, from, to) => {
+                let query = form_urlencoded::Serializer::new(String::new())
+                    .append_pair("from", &format!("/{}", from))
+                    .append_pair("to", &format!("/{}", to))
+                    .finish();
+                let endpoint = match kind {
+                    TransferKind::Copy => "copy",
+                    TransferKind::Move => "move",
+                };
+                let url = format!("{}/-/{}?{}", self.base, endpoint, query);
+                let request = self.client.post(&url).headers(self.headers());
+                match execute(request)? {
+                    StatusCode::Accepted => Ok(()),
+                    // Out of sync, send the result instead
+                    StatusCode::NotFound | StatusCode::Conflict => self.upload(to),
+                    status => Err(Failure::Fatal(status.to_string())),
+                }
+            }
+        }
+    }
+
+    /// Send the file `path`, or the files below the directory `path`
+    fn upload(&self, path: &str) -> Result<(), Failure> {
+        let metadata = match self.storage.stat(Path::new(path)) {
+            Ok(metadata) => metadata,
+            // Deleted since, the delete follows
+            Err(_) => return Ok(()),
+        };
+        if metadata.is_dir {
+            let entries = self
+                .storage
+                .list(Path::new(path))
+                .map_err(|err| Failure::Fatal(err.to_string()))?;
+            for entry in entries {
+                self.upload(&format!("{}/{}", path, entry.name))?;
+            }
+            return Ok(());
+        }
+        let mut data = self
+            .storage
+            .open_range(Path::new(path), 0, None)
+            .map_err(|err| Failure::Fatal(err.to_string()))?;
+        let url = format!("{}/-/sync/{}", self.base, encode_path(path));
+        let request = self
+            .client
+            .put(&url)
+            .headers(self.headers())
+            .body(Body::SizedBody(&mut data, metadata.len));
+        match execute(request)? {
+            StatusCode::Created => Ok(()),
+            status => Err(Failure::Fatal(status.to_string())),
+        }
+    }
+}
+
+fn encode_path(path: &str) -> String {
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(str::to_owned)
+        .collect::<Vec<String>>();
+    encode_link_path(&segments)
+}
+
+/// Status of the reply, the statuses worth retrying as failures
+fn execute(request: RequestBuilder) -> Result<StatusCode, Failure> {
+    let mut resp = request
+        .send()
+        .map_err(|err| Failure::Retry(err.to_string()))?;
+    // Drain the body so that the connection is reused
+    let _ = io::copy(&mut resp.by_ref().take(1 << 16), &mut io::sink());
+    match resp.status {
+        StatusCode::Locked | StatusCode::ServiceUnavailable | StatusCode::BadGateway => {
+            Err(Failure::Retry(resp.status.to_string()))
+        }
+        status => Ok(status),
+    }
+}
+
+fn describe(op: &Op) -> String {
+    match op {
+        Op::Upload(path) => format!("upload of {}", path),
+        Op::Delete { path, .. } => format!("delete of {}", path),
+        Op::Transfer(TransferKind::Copy, from, to) => format!("copy of {} to {}", from, to),
+        Op::Transfer(TransferKind::Move, from, to) => format!("move of {} to {}", from, to),
+    }
+}
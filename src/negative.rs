@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::storage::{Check, Entry, Metadata, Storage};
+
+/// Most missing paths remembered at once, the new ones are not cached past it
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Default)]
+struct Misses {
+    expires: HashMap<PathBuf, Instant>,
+    // Bumped by every invalidation, a lookup racing one is not cached
+    generation: u64,
+}
+
+impl Misses {
+    /// Forget the missing paths not kept by `keep`
+    fn invalidate(&mut self, keep: impl Fn(&Path) -> bool) {
+        self.expires.retain(|path, _| keep(path));
+        self.generation += 1;
+    }
+}
+
+/// `--negative-cache`: the paths found missing are answered `NotFound` for a while without
+/// asking the storage again, sparing slow network file systems the scanners and the
+/// misconfigured clients requesting the same missing paths over and over. Writes through
+/// the storage forget the paths they create. The changes made behind the server's back
+/// (batch uploads, other processes) are picked up with inotify on Linux, elsewhere they
+/// show up when the entries expire.
+pub struct NegativeCache {
+    inner: Arc<dyn Storage>,
+    ttl: Duration,
+    misses: Arc<Mutex<Misses>>,
+    watcher: Option<Watcher>,
+}
+
+impl NegativeCache {
+    /// `layers` are the local directories behind `inner` to watch, none when all the
+    /// changes go through it
+    pub fn new(inner: Arc<dyn Storage>, ttl: Duration, layers: Vec<PathBuf>) -> NegativeCache {
+        let misses = Arc::new(Mutex::new(Misses::default()));
+        let watcher = if layers.is_empty() {
+            None
+        } else {
+            match Watcher::start(layers, misses.clone()) {
+                Ok(watcher) => Some(watcher),
+                Err(ref err) if err.kind() == io::ErrorKind::Unsupported => None,
+                Err(err) => {
+                    warn!(
+                        "Negative cache watcher failed, entries only expire: {}",
+                        err
+                    );
+                    None
+                }
+            }
+        };
+        NegativeCache {
+            inner,
+            ttl,
+            misses,
+            watcher,
+        }
+    }
+
+    fn is_cached(&self, path: &Path) -> bool {
+        let mut misses = self.misses.lock().unwrap();
+        match misses.expires.get(path) {
+            Some(expires) if *expires > Instant::now() => true,
+            Some(_) => {
+                misses.expires.remove(path);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn remember(&self, path: &Path, generation: u64) {
+        let mut misses = self.misses.lock().unwrap();
+        if misses.generation != generation {
+            return;
+        }
+        if misses.expires.len() >= MAX_ENTRIES {
+            let now = Instant::now();
+            misses.expires.retain(|_, expires| *expires > now);
+            if misses.expires.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        misses
+            .expires
+            .insert(path.to_owned(), Instant::now() + self.ttl);
+    }
+}
+
+fn is_not_found<T>(result: &io::Result<T>) -> bool {
+    matches!(result, Err(err) if err.kind() == io::ErrorKind::NotFound)
+}
+
+impl Storage for NegativeCache {
+    fn stat(&self, path: &Path) -> io::Result<Metadata> {
+        if self.is_cached(path) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        }
+        let generation = self.misses.lock().unwrap().generation;
+        let mut result = self.inner.stat(path);
+        if !is_not_found(&result) {
+            return result;
+        }
+        if let Some(ref watcher) = self.watcher {
+            if !watcher.watch(path) {
+                return result;
+            }
+            // What was created before the watch is only seen by looking again
+            result = self.inner.stat(path);
+            if !is_not_found(&result) {
+                return result;
+            }
+        }
+        self.remember(path, generation);
+        result
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<Entry>> {
+        self.inner.list(path)
+    }
+
+    fn open_range(
+        &self,
+        path: &Path,
+        offset: u64,
+        len: Option<u64>,
+    ) -> io::Result<Box<dyn Read + Send>> {
+        self.inner.open_range(path, offset, len)
+    }
+
+    fn write(&self, path: &Path, data: &mut dyn Read, check: Option<Check>) -> io::Result<u64> {
+        let result = self.inner.write(path, data, check);
+        // The file and the directories created for it
+        self.misses
+            .lock()
+            .unwrap()
+            .invalidate(|miss| !path.starts_with(miss));
+        result
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        self.inner.delete(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let result = self.inner.create_dir(path);
+        self.misses
+            .lock()
+            .unwrap()
+            .invalidate(|miss| !path.starts_with(miss));
+        result
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let result = self.inner.rename(from, to);
+        self.misses
+            .lock()
+            .unwrap()
+            .invalidate(|miss| !to.starts_with(miss));
+        result
+    }
+
+    fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        self.inner.local_path(path)
+    }
+}
+
+/// Most directories watched at once, the missing paths needing more are not cached
+#[cfg(target_os = "linux")]
+const MAX_WATCHES: usize = 4096;
+
+/// Watches the nearest existing directory up from each missing path, in every layer, where
+/// anything making it exist has to show up first, and forgets the missing paths below the
+/// directories changing.
+#[cfg(target_os = "linux")]
+struct Watcher {
+    fd: i32,
+    layers: Vec<PathBuf>,
+    // Watch descriptor to the watched directory, relative to the layers
+    dirs: Arc<Mutex<HashMap<i32, PathBuf>>>,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    fn start(layers: Vec<PathBuf>, misses: Arc<Mutex<Misses>>) -> io::Result<Watcher> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let dirs = Arc::new(Mutex::new(HashMap::new()));
+        let events_dirs = dirs.clone();
+        std::thread::spawn(move || Watcher::read_events(fd, &events_dirs, &misses));
+        Ok(Watcher { fd, layers, dirs })
+    }
+
+    /// Watch what can make `path` exist, false when it can not be
+    fn watch(&self, path: &Path) -> bool {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        for layer in &self.layers {
+            let dir = match path.ancestors().find(|dir| layer.join(dir).is_dir()) {
+                Some(dir) => dir,
+                // The layer itself is gone
+                None => return false,
+            };
+            let c_path = match CString::new(layer.join(dir).as_os_str().as_bytes()) {
+                Ok(c_path) => c_path,
+                Err(_) => return false,
+            };
+            let wd = unsafe {
+                libc::inotify_add_watch(
+                    self.fd,
+                    c_path.as_ptr(),
+                    libc::IN_CREATE
+                        | libc::IN_MOVED_TO
+                        | libc::IN_DELETE_SELF
+                        | libc::IN_MOVE_SELF
+                        | libc::IN_ONLYDIR,
+                )
+            };
+            if wd < 0 {
+                return false;
+            }
+            let mut dirs = self.dirs.lock().unwrap();
+            if !dirs.contains_key(&wd) {
+                if dirs.len() >= MAX_WATCHES {
+                    unsafe { libc::inotify_rm_watch(self.fd, wd) };
+                    return false;
+                }
+                dirs.insert(wd, dir.to_owned());
+            }
+        }
+        true
+    }
+
+    fn read_events(fd: i32, dirs: &Mutex<HashMap<i32, PathBuf>>, misses: &Mutex<Misses>) {
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                warn!(
+                    "Negative cache watcher stopped, entries only expire: {}",
+                    err
+                );
+                return;
+            }
+            let mut offset = 0;
+            while offset + header <= n as usize {
+                let event = unsafe {
+                    std::ptr::read_unaligned(buf.as_ptr().add(offset) as *const libc::inotify_event)
+                };
+                offset += header + event.len as usize;
+                if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                    misses.lock().unwrap().invalidate(|_| false);
+                    continue;
+                }
+                // The watch is gone with its directory
+                let dir = if event.mask & libc::IN_IGNORED != 0 {
+                    dirs.lock().unwrap().remove(&event.wd)
+                } else {
+                    dirs.lock().unwrap().get(&event.wd).cloned()
+                };
+                if let Some(dir) = dir {
+                    misses
+                        .lock()
+                        .unwrap()
+                        .invalidate(|miss| !miss.starts_with(&dir));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Watcher;
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    fn start(_layers: Vec<PathBuf>, _misses: Arc<Mutex<Misses>>) -> io::Result<Watcher> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "no file system notifications",
+        ))
+    }
+
+    fn watch(&self, _path: &Path) -> bool {
+        false
+    }
+}
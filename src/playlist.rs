@@ -0,0 +1,78 @@
+use std::path::Path;
+
+use iron::headers::{CacheControl, CacheDirective};
+use iron::status;
+use iron::{IronResult, Request, Response};
+use mime_guess as mime_types;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+use crate::storage::Storage;
+use crate::util::{encode_link_path, error_io2iron};
+
+/// Whether the file `name` is audio or video
+fn is_media(name: &str) -> bool {
+    mime_types::from_path(name)
+        .first()
+        .is_some_and(|mime| mime.type_() == "audio" || mime.type_() == "video")
+}
+
+/// `/dir/?m3u`: the audio and video files of the directory as an M3U playlist of absolute
+/// URLs, sorted by name, downloaded as `<dir>.m3u8` so it opens in a media player.
+pub fn handle(
+    req: &Request,
+    storage: &dyn Storage,
+    dir: &Path,
+    path_prefix: &[String],
+    base_url: &str,
+) -> IronResult<Response> {
+    let mut names = storage
+        .list(dir)
+        .map_err(error_io2iron)?
+        .into_iter()
+        .filter(|entry| entry.metadata.is_file && is_media(&entry.name))
+        .map(|entry| entry.name)
+        .collect::<Vec<String>>();
+    names.sort();
+
+    let page_url: iron::url::Url = req.url.clone().into();
+    let mut playlist = String::from("#EXTM3U\n");
+    for name in names {
+        let mut link = path_prefix.to_owned();
+        link.push(name.clone());
+        let url = match page_url.join(&format!("{}{}", base_url, encode_link_path(&link))) {
+            Ok(url) => url,
+            Err(_) => continue,
+        };
+        let title = Path::new(&name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or(name);
+        // A line break would start a new entry
+        let title = title.replace(['\r', '\n'], " ");
+        playlist.push_str(&format!("#EXTINF:-1,{}\n{}\n", title, url));
+    }
+
+    let mut resp = Response::with((status::Ok, playlist));
+    resp.headers.set_raw(
+        "content-type",
+        vec![b"audio/x-mpegurl; charset=utf-8".to_vec()],
+    );
+    let name = path_prefix.last().map(String::as_str).unwrap_or("playlist");
+    resp.headers.set_raw(
+        "content-disposition",
+        vec![format!(
+            "attachment; filename*=UTF-8''{}.m3u8",
+            utf8_percent_encode(name, NON_ALPHANUMERIC)
+        )
+        .into_bytes()],
+    );
+    resp.headers
+        .set(CacheControl(vec![CacheDirective::NoCache]));
+    Ok(resp)
+}
+
+/// Whether `mime` is a subtitle type, served as UTF-8 text
+pub fn is_subtitle(mime: &mime_types::Mime) -> bool {
+    (mime.type_() == "text" && mime.subtype() == "vtt")
+        || (mime.type_() == "application" && mime.subtype() == "x-subrip")
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use iron::headers::{ContentLength, ContentType};
+use iron::status;
+use iron::{Headers, IronError, IronResult, Response};
+
+use crate::middlewares::Rate;
+use crate::util::{json_escape, StringError};
+
+/// Header with the client chosen id of an upload, to poll `/-/upload-progress/<id>`
+pub const UPLOAD_ID_HEADER: &str = "X-Upload-Id";
+/// Ids longer than this are ignored
+const MAX_UPLOAD_ID_LENGTH: usize = 128;
+/// Finished uploads are still reported this long, for the last poll of the client
+const FINISHED_RETENTION: Duration = Duration::from_secs(300);
+
+struct Progress {
+    received: u64,
+    // Request Content-Length, unknown for chunked bodies
+    total: Option<u64>,
+    finished: Option<Instant>,
+}
+
+/// Bytes received of the in-flight uploads carrying an `X-Upload-Id` header, reported as
+/// JSON on `GET /-/upload-progress/<id>` for clients without JavaScript (eg: CLI tools).
+/// All the upload bodies are read through it, also paced to `--upload-throttle`.
+#[derive(Default)]
+pub struct UploadProgress {
+    uploads: Mutex<HashMap<String, Progress>>,
+    throttle: Option<Rate>,
+}
+
+impl UploadProgress {
+    /// Bandwidth shared by all the uploads
+    pub fn with_throttle(mut self, rate: Rate) -> UploadProgress {
+        self.throttle = Some(rate);
+        self
+    }
+
+    /// Count the bytes read from `body`, when the request has an upload id
+    pub fn track<R: Read>(&self, headers: &Headers, body: R) -> ProgressReader<'_, R> {
+        let id = headers
+            .get_raw(UPLOAD_ID_HEADER)
+            .and_then(|values| values.first())
+            .map(|value| String::from_utf8_lossy(value).to_string())
+            .filter(|id| !id.is_empty() && id.len() <= MAX_UPLOAD_ID_LENGTH);
+        if let Some(ref id) = id {
+            let mut uploads = self.uploads.lock().unwrap();
+            uploads.retain(|_, progress| {
+                progress
+                    .finished
+                    .is_none_or(|finished| finished.elapsed() < FINISHED_RETENTION)
+            });
+            uploads.insert(
+                id.clone(),
+                Progress {
+                    received: 0,
+                    total: headers.get::<ContentLength>().map(|len| len.0),
+                    finished: None,
+                },
+            );
+        }
+        ProgressReader {
+            inner: body,
+            id,
+            progress: self,
+        }
+    }
+
+    pub fn handle(&self, id: &str) -> IronResult<Response> {
+        let uploads = self.uploads.lock().unwrap();
+        let progress = uploads.get(id).ok_or_else(|| {
+            IronError::new(
+                StringError(format!("no upload with id {}", id)),
+                status::NotFound,
+            )
+        })?;
+        let mut resp = Response::with((
+            status::Ok,
+            format!(
+                r#"{{"id":"{}","received":{},"total":{},"done":{}}}"#,
+                json_escape(id),
+                progress.received,
+                progress
+                    .total
+                    .map(|total| total.to_string())
+                    .unwrap_or_else(|| "null".to_owned()),
+                progress.finished.is_some(),
+            ),
+        ));
+        resp.headers.set(ContentType::json());
+        Ok(resp)
+    }
+}
+
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    id: Option<String>,
+    progress: &'a UploadProgress,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(ref rate) = self.progress.throttle {
+            rate.pace(n);
+        }
+        if let Some(ref id) = self.id {
+            if let Some(progress) = self.progress.uploads.lock().unwrap().get_mut(id) {
+                progress.received += n as u64;
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl<R> Drop for ProgressReader<'_, R> {
+    fn drop(&mut self) {
+        if let Some(ref id) = self.id {
+            if let Some(progress) = self.progress.uploads.lock().unwrap().get_mut(id) {
+                progress.finished = Some(Instant::now());
+            }
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use iron::status;
+use iron::IronError;
+use tracing::warn;
+
+use crate::sniff::AllowedTypes;
+use crate::util::StringError;
+
+/// Content checks run on every upload while it is still in its temporary file, the upload
+/// is only moved into the tree when its sniffed type is allowed and the command exits with 0.
+pub struct Scanner {
+    command: Option<String>,
+    allowed_types: Option<AllowedTypes>,
+}
+
+/// The scanner refused an upload
+#[derive(Debug)]
+pub struct ScanRejected(pub String);
+
+impl fmt::Display for ScanRejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rejected by scanner: {}", self.0)
+    }
+}
+
+impl Error for ScanRejected {}
+
+impl Scanner {
+    /// `{path}` in `command` is replaced by the file to scan, appended when missing
+    pub fn new(command: Option<&str>, allowed_types: Option<AllowedTypes>) -> Scanner {
+        let command = command.map(|command| {
+            if command.contains("{path}") {
+                command.to_owned()
+            } else {
+                format!("{} {{path}}", command)
+            }
+        });
+        Scanner {
+            command,
+            allowed_types,
+        }
+    }
+
+    /// Check the upload at `path`, to be stored as `name`
+    pub fn scan(&self, path: &Path, name: &str) -> io::Result<()> {
+        if let Some(ref allowed_types) = self.allowed_types {
+            allowed_types
+                .check(path, name)?
+                .map_err(|reason| io::Error::other(ScanRejected(reason)))?;
+        }
+        let command = match self.command {
+            Some(ref command) => command.replace("{path}", &shell_quote(&path.to_string_lossy())),
+            None => return Ok(()),
+        };
+        let output = shell(&command).output()?;
+        if output.status.success() {
+            return Ok(());
+        }
+        let report = String::from_utf8_lossy(&output.stdout);
+        let report = report.lines().rev().find(|line| !line.trim().is_empty());
+        let reason = match (report, output.status.code()) {
+            (Some(line), _) => line.trim().to_owned(),
+            (None, Some(code)) => format!("exit code {}", code),
+            (None, None) => "killed by signal".to_owned(),
+        };
+        Err(io::Error::other(ScanRejected(reason)))
+    }
+}
+
+/// Whether a save failed because the scanner rejected the file
+pub fn is_rejected(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<ScanRejected>())
+}
+
+#[cfg(unix)]
+pub fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(not(unix))]
+pub fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(unix)]
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r#"'\''"#))
+}
+
+#[cfg(not(unix))]
+pub fn shell_quote(s: &str) -> String {
+    format!("\"{}\"", s)
+}
+
+/// Log the rejected upload of `name` and build the `422` reply
+pub fn reject(name: &str, err: &io::Error) -> IronError {
+    warn!("Upload rejected: {}, {}", name, err);
+    IronError::new(
+        StringError(format!("{} {}", name, err)),
+        status::UnprocessableEntity,
+    )
+}
@@ -0,0 +1,103 @@
+use std::net::IpAddr;
+
+use openssl::asn1::{Asn1Integer, Asn1Time};
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509};
+
+/// Days the generated certificates are valid, they only live as long as the process
+const VALID_DAYS: u32 = 30;
+
+/// Certificate and key generated at startup (`--tls-self-signed`), never written to disk.
+/// Clients have to trust it explicitly, or pin its fingerprint.
+pub struct SelfSigned {
+    /// DER encoded certificate
+    pub cert: Vec<u8>,
+    /// DER encoded PKCS#8 private key, for rustls
+    #[cfg_attr(not(feature = "rustls"), allow(dead_code))]
+    pub key: Vec<u8>,
+    /// Both, in a PKCS#12 archive without password
+    pub pkcs12: Vec<u8>,
+}
+
+impl SelfSigned {
+    /// For `localhost`, the host name and the loopback addresses, with `ip` unless it is
+    /// unspecified
+    pub fn generate(ip: IpAddr) -> Result<SelfSigned, ErrorStack> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = EcKey::generate(&group).and_then(PKey::from_ec_key)?;
+        let hostname = hostname();
+
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, hostname.as_deref().unwrap_or("localhost"))?;
+        let name = name.build();
+        let mut serial = BigNum::new()?;
+        serial.rand(127, MsbOption::MAYBE_ZERO, false)?;
+
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(&*Asn1Integer::from_bn(&serial)?)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_pubkey(&key)?;
+        builder.set_not_before(&*Asn1Time::days_from_now(0)?)?;
+        builder.set_not_after(&*Asn1Time::days_from_now(VALID_DAYS)?)?;
+        let mut san = SubjectAlternativeName::new();
+        san.dns("localhost");
+        if let Some(ref hostname) = hostname {
+            san.dns(hostname);
+        }
+        san.ip("127.0.0.1").ip("::1");
+        if !ip.is_unspecified() && !ip.is_loopback() {
+            san.ip(&ip.to_string());
+        }
+        let san = san.build(&builder.x509v3_context(None, None))?;
+        builder.append_extension(san)?;
+        builder.sign(&key, MessageDigest::sha256())?;
+        let cert = builder.build();
+
+        let pkcs12 = Pkcs12::builder()
+            .name("simple-http-server")
+            .pkey(&key)
+            .cert(&cert)
+            .build2("")?
+            .to_der()?;
+        Ok(SelfSigned {
+            cert: cert.to_der()?,
+            key: key.private_key_to_pkcs8()?,
+            pkcs12,
+        })
+    }
+
+    /// SHA-256 fingerprint of the certificate, as `AB:CD:...`
+    pub fn fingerprint(&self) -> String {
+        openssl::sha::sha256(&self.cert)
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<String>>()
+            .join(":")
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec())
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
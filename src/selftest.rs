@@ -0,0 +1,311 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flate2::read::GzDecoder;
+use hyper::client::{Client, RequestBuilder};
+use hyper::header::{
+    AcceptEncoding, Authorization, Basic, ByteRangeSpec, ContentEncoding, Encoding, Headers,
+    QualityItem, Range,
+};
+use hyper::status::StatusCode;
+
+use crate::negative::NegativeCache;
+use crate::storage::{self, FsStorage, MemoryStorage};
+use crate::util::random_alphanumeric;
+
+const USERNAME: &str = "selftest";
+const PASSWORD: &str = "selftest";
+/// How long the server may take to start listening
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+type TestResult = Result<(), String>;
+type Test = fn(&Server) -> TestResult;
+
+struct Reply {
+    status: StatusCode,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+struct Server {
+    child: Child,
+    root: PathBuf,
+    base: String,
+    client: Client,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+impl Server {
+    fn start() -> io::Result<Server> {
+        let suffix = random_alphanumeric(8);
+        let root = env::temp_dir().join(format!("simple-http-server-selftest-{}", suffix));
+        fs::create_dir_all(root.join("sub"))?;
+        fs::write(root.join("hello.txt"), content())?;
+
+        // Ephemeral port, released right before the server binds it
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let child = Command::new(env::current_exe()?)
+            .arg(&root)
+            .args(["--ip", "127.0.0.1", "--port", &port.to_string()])
+            .args(["--upload", "--silent", "--compress", "txt"])
+            .args(["--auth", &format!("{}:{}", USERNAME, PASSWORD)])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let server = Server {
+            child,
+            root,
+            base: format!("http://127.0.0.1:{}", port),
+            client: Client::new(),
+        };
+        let start = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            if start.elapsed() > START_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "server did not start",
+                ));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Ok(server)
+    }
+
+    fn send(&self, request: RequestBuilder, auth: bool) -> Result<Reply, String> {
+        let request = if auth {
+            request.header(Authorization(Basic {
+                username: USERNAME.to_owned(),
+                password: Some(PASSWORD.to_owned()),
+            }))
+        } else {
+            request
+        };
+        let mut resp = request.send().map_err(|err| err.to_string())?;
+        let mut body = Vec::new();
+        resp.read_to_end(&mut body).map_err(|err| err.to_string())?;
+        Ok(Reply {
+            status: resp.status,
+            headers: resp.headers.clone(),
+            body,
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base, path)
+    }
+}
+
+fn content() -> Vec<u8> {
+    "hello simple-http-server\n".repeat(100).into_bytes()
+}
+
+fn expect_status(reply: &Reply, status: StatusCode) -> TestResult {
+    if reply.status == status {
+        Ok(())
+    } else {
+        Err(format!("expected {}, got {}", status, reply.status))
+    }
+}
+
+fn test_auth(server: &Server) -> TestResult {
+    let reply = server.send(server.client.get(&server.url("/")), false)?;
+    expect_status(&reply, StatusCode::Unauthorized)
+}
+
+fn test_listing(server: &Server) -> TestResult {
+    let reply = server.send(server.client.get(&server.url("/")), true)?;
+    expect_status(&reply, StatusCode::Ok)?;
+    let body = String::from_utf8_lossy(&reply.body);
+    if !body.contains("hello.txt") || !body.contains("sub/") {
+        return Err("entries missing from the listing".to_owned());
+    }
+    Ok(())
+}
+
+fn test_range(server: &Server) -> TestResult {
+    let request = server
+        .client
+        .get(&server.url("/hello.txt"))
+        .header(Range::Bytes(vec![ByteRangeSpec::FromTo(6, 11)]));
+    let reply = server.send(request, true)?;
+    expect_status(&reply, StatusCode::PartialContent)?;
+    if reply.body[..] != content()[6..12] {
+        return Err(format!(
+            "wrong range content: {:?}",
+            String::from_utf8_lossy(&reply.body)
+        ));
+    }
+    Ok(())
+}
+
+fn test_compression(server: &Server) -> TestResult {
+    let request = server
+        .client
+        .get(&server.url("/hello.txt"))
+        .header(AcceptEncoding(vec![QualityItem::new(
+            Encoding::Gzip,
+            Default::default(),
+        )]));
+    let reply = server.send(request, true)?;
+    expect_status(&reply, StatusCode::Ok)?;
+    if reply.headers.get::<ContentEncoding>() != Some(&ContentEncoding(vec![Encoding::Gzip])) {
+        return Err("response is not gzip encoded".to_owned());
+    }
+    let mut decoded = Vec::new();
+    GzDecoder::new(&reply.body[..])
+        .read_to_end(&mut decoded)
+        .map_err(|err| format!("invalid gzip body: {}", err))?;
+    if decoded != content() {
+        return Err("decompressed content differs".to_owned());
+    }
+    Ok(())
+}
+
+/// Headers of the API writes, with the CSRF token of the upload form
+fn token_headers(server: &Server) -> Result<Headers, String> {
+    let listing = server.send(server.client.get(&server.url("/")), true)?;
+    let listing = String::from_utf8_lossy(&listing.body);
+    let token = listing
+        .split(r#"name="csrf" value=""#)
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .ok_or_else(|| "csrf token not found in the listing".to_owned())?;
+    let mut headers = Headers::new();
+    headers.set_raw("X-Csrf-Token", vec![token.as_bytes().to_vec()]);
+    Ok(headers)
+}
+
+fn test_upload(server: &Server) -> TestResult {
+    let data = b"uploaded by selftest\n";
+    let request = server
+        .client
+        .put(&server.url("/-/sync/sub/uploaded.txt"))
+        .headers(token_headers(server)?)
+        .body(&data[..]);
+    expect_status(&server.send(request, true)?, StatusCode::Created)?;
+
+    let reply = server.send(server.client.get(&server.url("/sub/uploaded.txt")), true)?;
+    expect_status(&reply, StatusCode::Ok)?;
+    if reply.body != data {
+        return Err("uploaded content differs".to_owned());
+    }
+    if !server.root.join("sub/uploaded.txt").is_file() {
+        return Err("uploaded file missing from root".to_owned());
+    }
+    Ok(())
+}
+
+fn test_partial_upload(server: &Server) -> TestResult {
+    let request = server
+        .client
+        .put(&server.url("/-/sync/sub/partial.txt?offset=0&total=100"))
+        .headers(token_headers(server)?)
+        .body(&b"first chunk"[..]);
+    expect_status(&server.send(request, true)?, StatusCode::Accepted)?;
+
+    // Kept out of the root, not served by the file handler
+    let reply = server.send(
+        server
+            .client
+            .get(&server.url("/.upload-partials/sub%252Fpartial.txt")),
+        true,
+    )?;
+    expect_status(&reply, StatusCode::NotFound)?;
+    let listing = server.send(server.client.get(&server.url("/")), true)?;
+    if String::from_utf8_lossy(&listing.body).contains(".upload-partials") {
+        return Err("partial uploads listed in the root".to_owned());
+    }
+    let request = server
+        .client
+        .get(&server.url("/-/uploads/pending"))
+        .headers(token_headers(server)?);
+    let pending = server.send(request, true)?;
+    expect_status(&pending, StatusCode::Ok)?;
+    if !String::from_utf8_lossy(&pending.body).contains(r#""path":"sub/partial.txt""#) {
+        return Err("partial upload not pending".to_owned());
+    }
+
+    let rest = [b'.'; 100 - 11];
+    let request = server
+        .client
+        .put(&server.url("/-/sync/sub/partial.txt?offset=11&total=100"))
+        .headers(token_headers(server)?)
+        .body(&rest[..]);
+    expect_status(&server.send(request, true)?, StatusCode::Created)?;
+    if fs::metadata(server.root.join("sub/partial.txt"))
+        .map(|m| m.len())
+        .ok()
+        != Some(100)
+    {
+        return Err("completed upload missing from root".to_owned());
+    }
+    Ok(())
+}
+
+fn test_storage(server: &Server) -> TestResult {
+    let dir = server.root.join(".storage");
+    fs::create_dir(&dir).map_err(|err| err.to_string())?;
+    storage::conformance(&FsStorage::new(dir, Vec::new(), None, false))?;
+    storage::conformance(&MemoryStorage::new(1 << 20))?;
+    // A missing path cached by the first lookup must be forgotten once written
+    storage::conformance(&NegativeCache::new(
+        Arc::new(MemoryStorage::new(1 << 20)),
+        Duration::from_secs(60),
+        Vec::new(),
+    ))
+}
+
+/// `simple-http-server selftest`: run the binary on an ephemeral port against a temporary
+/// root and check the main features end to end, returns the process exit code.
+pub fn run() -> i32 {
+    let server = match Server::start() {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("selftest: can not start the server: {}", err);
+            return 1;
+        }
+    };
+    let tests: &[(&str, Test)] = &[
+        ("auth", test_auth),
+        ("listing", test_listing),
+        ("range", test_range),
+        ("compression", test_compression),
+        ("upload", test_upload),
+        ("partial upload", test_partial_upload),
+        ("storage", test_storage),
+    ];
+    let mut failed = 0;
+    for (name, test) in tests {
+        match test(&server) {
+            Ok(()) => println!("test {} ... ok", name),
+            Err(err) => {
+                failed += 1;
+                println!("test {} ... FAILED: {}", name, err);
+            }
+        }
+    }
+    println!(
+        "selftest: {} passed; {} failed",
+        tests.len() - failed,
+        failed
+    );
+    if failed == 0 {
+        0
+    } else {
+        1
+    }
+}
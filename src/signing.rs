@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use iron::headers::ContentType;
+use iron::status;
+use iron::{IronResult, Response};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use sha2::{Digest, Sha256};
+
+use crate::storage::Storage;
+use crate::util::{error_io2iron, hex, pem_blocks};
+
+/// DER of an Ed25519 `SubjectPublicKeyInfo` up to the 32 bytes of the key
+const SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// Size and modified time, a signature is only valid for this version of the file
+type Version = (u64, SystemTime);
+
+#[derive(Clone)]
+pub struct Signature {
+    /// SHA-256 of the file content, hex encoded as `sha256sum` prints it
+    pub digest: String,
+    /// Ed25519 signature of the 32 bytes of the digest, base64 encoded
+    pub signature: String,
+}
+
+/// `--sign-key`: Ed25519 signatures of the SHA-256 digests of the served files, for mirrors
+/// to check that their copies come from this server. Cached by path, size and modified
+/// time, a file is hashed on its first request after each change.
+pub struct Signer {
+    key: Ed25519KeyPair,
+    signatures: Mutex<HashMap<PathBuf, (Version, Signature)>>,
+}
+
+impl Signer {
+    /// From an unencrypted PKCS#8 PEM file, as written by `openssl genpkey -algorithm ed25519`
+    pub fn load(path: &Path) -> Result<Signer, String> {
+        let der = pem_blocks(path)?
+            .into_iter()
+            .find(|(label, _)| label == "PRIVATE KEY")
+            .map(|(_, der)| der)
+            .ok_or_else(|| format!("{}: no PRIVATE KEY found", path.display()))?;
+        let key = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der)
+            .map_err(|err| format!("{}: not an Ed25519 key: {}", path.display(), err))?;
+        Ok(Signer {
+            key,
+            signatures: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The public key as a PEM `PUBLIC KEY`, for `openssl pkeyutl -verify -pubin -inkey`
+    pub fn public_key(&self) -> String {
+        let mut der = SPKI_PREFIX.to_vec();
+        der.extend_from_slice(self.key.public_key().as_ref());
+        format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            base64::encode(&der)
+        )
+    }
+
+    /// Digest and signature of the file `path` of `storage`
+    pub fn sign(&self, storage: &dyn Storage, path: &Path) -> io::Result<Signature> {
+        let metadata = storage.stat(path)?;
+        let version: Version = (metadata.len, metadata.modified);
+        let key = storage.local_path(path).unwrap_or_else(|| path.to_owned());
+        if let Some((cached, signature)) = self.signatures.lock().unwrap().get(&key) {
+            if *cached == version {
+                return Ok(signature.clone());
+            }
+        }
+        // Not holding the lock, large files take a while
+        let mut hasher = Sha256::new();
+        io::copy(&mut storage.open_range(path, 0, None)?, &mut hasher)?;
+        let digest = hasher.finalize();
+        let signature = Signature {
+            digest: hex(&digest),
+            signature: base64::encode(self.key.sign(&digest).as_ref()),
+        };
+        self.signatures
+            .lock()
+            .unwrap()
+            .insert(key, (version, signature.clone()));
+        Ok(signature)
+    }
+
+    /// `?sig`: the signature of the file alone, as a detached signature file
+    pub fn sidecar(&self, storage: &dyn Storage, path: &Path) -> IronResult<Response> {
+        let signature = self.sign(storage, path).map_err(error_io2iron)?;
+        let mut resp = Response::with((status::Ok, format!("{}\n", signature.signature)));
+        resp.headers.set(ContentType::plaintext());
+        set_headers(&mut resp, &signature);
+        Ok(resp)
+    }
+
+    /// `GET /-/sign-key`
+    pub fn public_key_response(&self) -> Response {
+        let mut resp = Response::with((status::Ok, self.public_key()));
+        resp.headers.set(ContentType::plaintext());
+        resp
+    }
+}
+
+/// The headers carrying the digest and signature of a file
+pub fn set_headers(resp: &mut Response, signature: &Signature) {
+    resp.headers.set_raw(
+        "X-Content-SHA256",
+        vec![signature.digest.clone().into_bytes()],
+    );
+    resp.headers.set_raw(
+        "X-Content-Signature",
+        vec![format!("ed25519={}", signature.signature).into_bytes()],
+    );
+}
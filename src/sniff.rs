@@ -0,0 +1,194 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use mime_guess as mime_types;
+use zip::ZipArchive;
+
+use crate::util::StringError;
+
+/// Bytes read to recognize a file
+const HEAD_LEN: usize = 4096;
+
+/// (offset, magic number, type), the first match wins
+const MAGIC: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (8, b"WEBP", "image/webp"),
+    (0, b"BM", "image/bmp"),
+    (0, b"II*\x00", "image/tiff"),
+    (0, b"MM\x00*", "image/tiff"),
+    (0, b"\x00\x00\x01\x00", "image/x-icon"),
+    (4, b"ftypavif", "image/avif"),
+    (4, b"ftypheic", "image/heic"),
+    (4, b"ftypqt", "video/quicktime"),
+    (4, b"ftyp", "video/mp4"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\x00", "application/x-xz"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (0, b"Rar!\x1a\x07", "application/vnd.rar"),
+    (
+        0,
+        b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1",
+        "application/x-ole-storage",
+    ),
+    (0, b"\x7fELF", "application/x-executable"),
+    (0, b"MZ", "application/x-msdownload"),
+    (0, b"ID3", "audio/mpeg"),
+    (0, b"fLaC", "audio/flac"),
+    (0, b"OggS", "audio/ogg"),
+    (8, b"WAVE", "audio/wav"),
+    (0, b"\x1a\x45\xdf\xa3", "video/webm"),
+];
+
+/// Content types of zip based documents, by a member only they have
+const ZIP_MEMBERS: &[(&str, &str)] = &[
+    (
+        "word/document.xml",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ),
+    (
+        "xl/workbook.xml",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    (
+        "ppt/presentation.xml",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    ),
+    ("META-INF/MANIFEST.MF", "application/java-archive"),
+];
+
+/// Legacy Office documents, all stored in OLE compound files
+const OLE_TYPES: &[&str] = &[
+    "application/msword",
+    "application/vnd.ms-excel",
+    "application/vnd.ms-powerpoint",
+    "application/vnd.ms-outlook",
+];
+
+/// Content type of the file from its first bytes (and members of zip archives), text
+/// files are `text/plain` and unknown binary files `application/octet-stream`.
+pub fn sniff(path: &Path) -> io::Result<String> {
+    let mut head = Vec::with_capacity(HEAD_LEN);
+    fs::File::open(path)?
+        .take(HEAD_LEN as u64)
+        .read_to_end(&mut head)?;
+
+    if head.starts_with(b"PK\x03\x04") {
+        return Ok(sniff_zip(path));
+    }
+    if let Some((_, _, mime)) = MAGIC
+        .iter()
+        .find(|(offset, magic, _)| head.get(*offset..offset + magic.len()) == Some(magic))
+    {
+        return Ok((*mime).to_owned());
+    }
+    let text = match std::str::from_utf8(&head) {
+        Ok(text) => text,
+        // Cut in the middle of a character
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&head[..err.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return Ok("application/octet-stream".to_owned()),
+    };
+    if text.contains('\0') {
+        return Ok("application/octet-stream".to_owned());
+    }
+    let start = text.trim_start_matches('\u{feff}').trim_start();
+    if start.starts_with("<svg") || (start.starts_with("<?xml") && text.contains("<svg")) {
+        return Ok("image/svg+xml".to_owned());
+    }
+    Ok("text/plain".to_owned())
+}
+
+fn sniff_zip(path: &Path) -> String {
+    let mut archive = match fs::File::open(path).map(ZipArchive::new) {
+        Ok(Ok(archive)) => archive,
+        _ => return "application/zip".to_owned(),
+    };
+    // OpenDocument and EPUB store their type in a first, uncompressed `mimetype` member
+    if let Ok(mut member) = archive.by_name("mimetype") {
+        let mut mime = String::new();
+        if (&mut member).take(256).read_to_string(&mut mime).is_ok() && !mime.is_empty() {
+            return mime.trim().to_owned();
+        }
+    }
+    ZIP_MEMBERS
+        .iter()
+        .find(|(name, _)| archive.by_name(name).is_ok())
+        .map(|(_, mime)| (*mime).to_owned())
+        .unwrap_or_else(|| "application/zip".to_owned())
+}
+
+/// Content types allowed for uploads (`--upload-allow-types`), eg: `image/*,application/pdf`
+pub struct AllowedTypes(Vec<String>);
+
+impl AllowedTypes {
+    pub fn new(spec: &str) -> Result<AllowedTypes, StringError> {
+        let patterns = spec
+            .split(',')
+            .map(|pattern| pattern.trim().to_ascii_lowercase())
+            .filter(|pattern| !pattern.is_empty())
+            .collect::<Vec<String>>();
+        if let Some(pattern) = patterns.iter().find(|pattern| !pattern.contains('/')) {
+            return Err(StringError(format!("invalid content type: {}", pattern)));
+        }
+        if patterns.is_empty() {
+            return Err(StringError("no content type allowed".to_owned()));
+        }
+        Ok(AllowedTypes(patterns))
+    }
+
+    fn allows(&self, mime: &str) -> bool {
+        self.0
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some("*") => true,
+                Some(top) => mime.split('/').next() == Some(top),
+                None => pattern == mime,
+            })
+    }
+
+    /// Check the content of the uploaded file at `path`, to be stored as `name`: its
+    /// sniffed type must agree with the extension and be allowed. Returns why not.
+    pub fn check(&self, path: &Path, name: &str) -> io::Result<Result<(), String>> {
+        let sniffed = sniff(path)?;
+        let guesses = mime_types::from_path(name)
+            .iter()
+            .map(|guess| guess.essence_str().to_owned())
+            .collect::<Vec<String>>();
+        // Plain text and containers are refined by the extension (eg: `text/csv`)
+        let compatible = |guess: &str| match sniffed.as_str() {
+            "text/plain" => {
+                guess.starts_with("text/") || guess.ends_with("/json") || guess.ends_with("/xml")
+            }
+            "application/x-ole-storage" => OLE_TYPES.contains(&guess),
+            "application/zip" => guess.contains("zip"),
+            sniffed => guess == sniffed,
+        };
+        let mime = match guesses.iter().find(|guess| compatible(guess)) {
+            Some(guess) => guess.clone(),
+            // Unknown content and text can not be told apart from their extension
+            None if guesses.is_empty()
+                || sniffed == "text/plain"
+                || sniffed == "application/octet-stream" =>
+            {
+                sniffed
+            }
+            None => {
+                return Ok(Err(format!(
+                    "content type {} does not match the extension of {}",
+                    sniffed, name
+                )))
+            }
+        };
+        if !self.allows(&mime) {
+            return Ok(Err(format!("content type {} is not allowed", mime)));
+        }
+        Ok(Ok(()))
+    }
+}
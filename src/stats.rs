@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use htmlescape::encode_minimal;
+use iron::headers::ContentType;
+use iron::method;
+use iron::response::WriteBody;
+use iron::status;
+use iron::{AfterMiddleware, IronResult, Request, Response};
+use percent_encoding::percent_decode;
+use pretty_bytes::converter::convert;
+
+use crate::expect::Connection;
+use crate::util::{brand_html, favicon_image, root_link};
+
+const METRICS_PREFIX: &str = "simple_http_server";
+
+/// Counters shown on `/-/stats` and exported on `/-/metrics`
+#[derive(Default)]
+pub struct Stats {
+    prefix_bytes: Mutex<BTreeMap<String, u64>>,
+    // Requests by (protocol, tls)
+    protocol_requests: Mutex<BTreeMap<(&'static str, bool), u64>>,
+    // Access log lines left out, by reason (`ignored` or `sampled`)
+    suppressed_log_lines: Mutex<BTreeMap<&'static str, u64>>,
+    // Requests blocked by `--waf`, by rule
+    waf_blocks: Mutex<BTreeMap<&'static str, u64>>,
+    // Authentications by (mechanism, outcome)
+    auth_outcomes: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+}
+
+impl Stats {
+    pub fn add_prefix_bytes(&self, prefix: &str, bytes: u64) {
+        *self
+            .prefix_bytes
+            .lock()
+            .unwrap()
+            .entry(prefix.to_owned())
+            .or_insert(0) += bytes;
+    }
+
+    pub fn add_request(&self, connection: &Connection) {
+        *self
+            .protocol_requests
+            .lock()
+            .unwrap()
+            .entry((connection.protocol, connection.tls))
+            .or_insert(0) += 1;
+    }
+
+    pub fn add_suppressed_log_line(&self, reason: &'static str) {
+        *self
+            .suppressed_log_lines
+            .lock()
+            .unwrap()
+            .entry(reason)
+            .or_insert(0) += 1;
+    }
+
+    pub fn add_waf_block(&self, rule: &'static str) {
+        *self.waf_blocks.lock().unwrap().entry(rule).or_insert(0) += 1;
+    }
+
+    pub fn add_auth(&self, mechanism: &'static str, outcome: &'static str) {
+        *self
+            .auth_outcomes
+            .lock()
+            .unwrap()
+            .entry((mechanism, outcome))
+            .or_insert(0) += 1;
+    }
+
+    pub fn stats_page(&self, title: &str, base_url: &str) -> Response {
+        let rows = self
+            .prefix_bytes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(prefix, bytes)| {
+                format!(
+                    "<tr><td>{}</td><td>{}</td></tr>",
+                    encode_minimal(prefix),
+                    convert(*bytes as f64)
+                )
+            })
+            .collect::<Vec<String>>();
+        let protocol_rows = self
+            .protocol_requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&(protocol, tls), requests)| {
+                let connection = Connection { protocol, tls };
+                format!(
+                    "<tr><td>{}</td><td>{}</td></tr>",
+                    connection.label(),
+                    requests
+                )
+            })
+            .collect::<Vec<String>>();
+        let waf_rows = self
+            .waf_blocks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(rule, blocks)| format!("<tr><td>{}</td><td>{}</td></tr>", rule, blocks))
+            .collect::<Vec<String>>();
+        let auth_rows = self
+            .auth_outcomes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((mechanism, outcome), count)| {
+                format!(
+                    "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    mechanism, outcome, count
+                )
+            })
+            .collect::<Vec<String>>();
+        let mut resp = Response::with((
+            status::Ok,
+            format!(
+                r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  {favicon_image}
+  <title>{title} · Stats</title>
+</head>
+<body>
+  {brand}
+  {root_link}
+  <hr />
+  <table>
+    <tr><th>Path prefix</th><th>Bytes served</th></tr>
+    {rows}
+  </table>
+  <table>
+    <tr><th>Protocol</th><th>Requests</th></tr>
+    {protocol_rows}
+  </table>
+  <table>
+    <tr><th>WAF rule</th><th>Blocked requests</th></tr>
+    {waf_rows}
+  </table>
+  <table>
+    <tr><th>Authentication</th><th>Outcome</th><th>Requests</th></tr>
+    {auth_rows}
+  </table>
+</body>
+</html>
+"#,
+                favicon_image = favicon_image(),
+                brand = brand_html(),
+                title = encode_minimal(title),
+                root_link = root_link(base_url),
+                rows = rows.join("\n"),
+                protocol_rows = protocol_rows.join("\n"),
+                waf_rows = waf_rows.join("\n"),
+                auth_rows = auth_rows.join("\n"),
+            ),
+        ));
+        resp.headers.set(ContentType::html());
+        resp
+    }
+
+    /// Prometheus text format
+    pub fn metrics(&self) -> Response {
+        let mut lines = vec![
+            format!(
+                "# HELP {}_prefix_bytes_total Bytes served per top-level directory",
+                METRICS_PREFIX
+            ),
+            format!("# TYPE {}_prefix_bytes_total counter", METRICS_PREFIX),
+        ];
+        for (prefix, bytes) in self.prefix_bytes.lock().unwrap().iter() {
+            lines.push(format!(
+                r#"{}_prefix_bytes_total{{prefix="{}"}} {}"#,
+                METRICS_PREFIX,
+                escape_label(prefix),
+                bytes
+            ));
+        }
+        lines.push(format!(
+            "# HELP {}_requests_total Requests per negotiated protocol",
+            METRICS_PREFIX
+        ));
+        lines.push(format!("# TYPE {}_requests_total counter", METRICS_PREFIX));
+        for (&(protocol, tls), requests) in self.protocol_requests.lock().unwrap().iter() {
+            lines.push(format!(
+                r#"{}_requests_total{{protocol="{}",tls="{}"}} {}"#,
+                METRICS_PREFIX, protocol, tls, requests
+            ));
+        }
+        lines.push(format!(
+            "# HELP {}_log_lines_suppressed_total Access log lines left out by --log-ignore and --log-sample",
+            METRICS_PREFIX
+        ));
+        lines.push(format!(
+            "# TYPE {}_log_lines_suppressed_total counter",
+            METRICS_PREFIX
+        ));
+        for (reason, count) in self.suppressed_log_lines.lock().unwrap().iter() {
+            lines.push(format!(
+                r#"{}_log_lines_suppressed_total{{reason="{}"}} {}"#,
+                METRICS_PREFIX, reason, count
+            ));
+        }
+        lines.push(format!(
+            "# HELP {}_waf_blocked_total Requests blocked by --waf, per rule",
+            METRICS_PREFIX
+        ));
+        lines.push(format!(
+            "# TYPE {}_waf_blocked_total counter",
+            METRICS_PREFIX
+        ));
+        for (rule, blocks) in self.waf_blocks.lock().unwrap().iter() {
+            lines.push(format!(
+                r#"{}_waf_blocked_total{{rule="{}"}} {}"#,
+                METRICS_PREFIX, rule, blocks
+            ));
+        }
+        lines.push(format!(
+            "# HELP {}_auth_total Authentications per mechanism and outcome",
+            METRICS_PREFIX
+        ));
+        lines.push(format!("# TYPE {}_auth_total counter", METRICS_PREFIX));
+        for ((mechanism, outcome), count) in self.auth_outcomes.lock().unwrap().iter() {
+            lines.push(format!(
+                r#"{}_auth_total{{mechanism="{}",outcome="{}"}} {}"#,
+                METRICS_PREFIX, mechanism, outcome, count
+            ));
+        }
+        let mut resp = Response::with((status::Ok, lines.join("\n") + "\n"));
+        resp.headers
+            .set_raw("content-type", vec![b"text/plain; version=0.0.4".to_vec()]);
+        resp
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+struct CountingWriter<'a> {
+    inner: &'a mut dyn io::Write,
+    count: u64,
+}
+
+impl io::Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Count the bytes really written (after compression) to the client
+struct CountingBody {
+    inner: Box<dyn WriteBody>,
+    prefix: String,
+    stats: Arc<Stats>,
+}
+
+impl WriteBody for CountingBody {
+    fn write_body(&mut self, w: &mut dyn io::Write) -> io::Result<()> {
+        let mut w = CountingWriter { inner: w, count: 0 };
+        let rv = self.inner.write_body(&mut w);
+        self.stats.add_prefix_bytes(&self.prefix, w.count);
+        rv
+    }
+}
+
+/// Account the bytes served by top-level directory and the requests by protocol
+pub struct StatsRecorder {
+    pub stats: Arc<Stats>,
+}
+
+impl AfterMiddleware for StatsRecorder {
+    fn after(&self, req: &mut Request, mut resp: Response) -> IronResult<Response> {
+        if let Some(connection) = req.extensions.get::<Connection>() {
+            self.stats.add_request(connection);
+        }
+        if req.method != method::Head && resp.body.is_some() {
+            let path = req.url.path();
+            // Files directly under root are accounted as "/"
+            let prefix = if path.len() > 1 {
+                percent_decode(path[0].as_bytes())
+                    .decode_utf8_lossy()
+                    .to_string()
+            } else {
+                "/".to_owned()
+            };
+            resp.body = Some(Box::new(CountingBody {
+                inner: resp.body.take().unwrap(),
+                prefix,
+                stats: self.stats.clone(),
+            }));
+        }
+        Ok(resp)
+    }
+}
@@ -0,0 +1,671 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::charset::FilenameEncoding;
+use crate::util::{file_size, random_alphanumeric, save_atomic_checked, StableFile};
+
+/// Check of the complete data of a write, see `Storage::write`
+pub type Check<'a> = &'a dyn Fn(&Path) -> io::Result<()>;
+
+/// What the handler needs to know about a file or directory
+#[derive(Clone)]
+pub struct Metadata {
+    pub is_dir: bool,
+    pub is_file: bool,
+    /// Size in bytes, of the device for block devices
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+impl Metadata {
+    pub fn from_fs(path: &Path, metadata: &fs::Metadata) -> Metadata {
+        Metadata {
+            is_dir: metadata.is_dir(),
+            is_file: metadata.is_file(),
+            len: file_size(path, metadata),
+            modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+        }
+    }
+}
+
+pub struct Entry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+/// Where the served files are kept. Paths are relative to the root of the storage and
+/// already checked to stay below it, `""` being the root itself.
+pub trait Storage: Send + Sync {
+    fn stat(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Entries of the directory `path`, in no particular order
+    fn list(&self, path: &Path) -> io::Result<Vec<Entry>>;
+
+    /// Content of the file `path` from `offset`, `len` bytes or up to the end
+    fn open_range(
+        &self,
+        path: &Path,
+        offset: u64,
+        len: Option<u64>,
+    ) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Atomically create or replace the file `path`, in an existing directory, with `data`.
+    /// `check`, when given, is run on a local temporary file with the complete data first,
+    /// the file is left untouched when it fails.
+    fn write(&self, path: &Path, data: &mut dyn Read, check: Option<Check>) -> io::Result<u64>;
+
+    /// Remove the file or directory `path`, with its content
+    fn delete(&self, path: &Path) -> io::Result<()>;
+
+    /// Create the directory `path`, in an existing directory
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Move the file or directory `from` to `to`, in an existing directory, replacing the
+    /// file or empty directory there
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Local file system path of `path`, for the features working on files directly
+    /// (search, manifest, hashes), `None` when the storage has none
+    fn local_path(&self, _path: &Path) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// The local file system under the root, with the `--overlay` layers looked up from the
+/// top down. Writes and deletes only touch the top layer, directories only found in the
+/// lower layers are created in it as needed.
+pub struct FsStorage {
+    root: PathBuf,
+    lower_layers: Vec<PathBuf>,
+    tmp_dir: Option<PathBuf>,
+    fadvise_sequential: bool,
+    filename_encoding: Option<FilenameEncoding>,
+    stat_concurrency: usize,
+}
+
+/// Fewest entries worth a thread of their own when listing, below it the threads cost more
+/// than the parallel stats save
+const MIN_ENTRIES_PER_STAT_THREAD: usize = 32;
+
+impl FsStorage {
+    pub fn new(
+        root: PathBuf,
+        lower_layers: Vec<PathBuf>,
+        tmp_dir: Option<PathBuf>,
+        fadvise_sequential: bool,
+    ) -> FsStorage {
+        FsStorage {
+            root,
+            lower_layers,
+            tmp_dir,
+            fadvise_sequential,
+            filename_encoding: None,
+            stat_concurrency: 1,
+        }
+    }
+
+    /// Stat the entries of the listed directories from up to `threads` threads, to hide the
+    /// round trips of network filesystems
+    pub fn with_stat_concurrency(mut self, threads: usize) -> FsStorage {
+        self.stat_concurrency = threads.max(1);
+        self
+    }
+
+    /// The entries with their metadata, stat'ed in parallel as allowed by `stat_concurrency`
+    fn stat_entries(&self, mut entries: Vec<(String, fs::DirEntry)>) -> io::Result<Vec<Entry>> {
+        let stat = |(name, entry): (String, fs::DirEntry)| {
+            Ok(Entry {
+                metadata: Metadata::from_fs(&entry.path(), &entry.metadata()?),
+                name,
+            })
+        };
+        let threads = self
+            .stat_concurrency
+            .min(entries.len() / MIN_ENTRIES_PER_STAT_THREAD);
+        if threads <= 1 {
+            return entries.into_iter().map(stat).collect();
+        }
+        let chunk_size = entries.len().div_ceil(threads);
+        let mut chunks = Vec::with_capacity(threads);
+        while !entries.is_empty() {
+            chunks.push(entries.split_off(entries.len().saturating_sub(chunk_size)));
+        }
+        thread::scope(|scope| {
+            let workers = chunks
+                .into_iter()
+                .map(|chunk| {
+                    scope.spawn(move || chunk.into_iter().map(stat).collect::<io::Result<Vec<_>>>())
+                })
+                .collect::<Vec<_>>();
+            let mut stated = Vec::new();
+            for worker in workers {
+                stated.extend(worker.join().unwrap()?);
+            }
+            Ok(stated)
+        })
+    }
+
+    /// Also find and list the files named in the legacy `encoding`
+    pub fn with_filename_encoding(mut self, encoding: FilenameEncoding) -> FsStorage {
+        self.filename_encoding = Some(encoding);
+        self
+    }
+
+    /// `path` as named on disk, with the components only found in the legacy encoding
+    /// replaced by their encoded name
+    fn on_disk<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        let encoding = match self.filename_encoding {
+            Some(ref encoding) if path.to_str().is_some_and(|path| !path.is_ascii()) => encoding,
+            _ => return Cow::Borrowed(path),
+        };
+        let exists = |path: &Path| {
+            self.layer_paths(path)
+                .iter()
+                .any(|path| fs::symlink_metadata(path).is_ok())
+        };
+        let mut on_disk = PathBuf::new();
+        for component in path.components() {
+            let name = component.as_os_str();
+            let legacy = name
+                .to_str()
+                .and_then(|name| encoding.encode(name))
+                .filter(|legacy| !exists(&on_disk.join(name)) && exists(&on_disk.join(legacy)));
+            on_disk.push(legacy.as_deref().unwrap_or(name));
+        }
+        Cow::Owned(on_disk)
+    }
+
+    /// `path` in each layer, from the top down
+    fn layer_paths(&self, path: &Path) -> Vec<PathBuf> {
+        Some(&self.root)
+            .into_iter()
+            .chain(&self.lower_layers)
+            .map(|layer| layer.join(path))
+            .collect()
+    }
+
+    /// `path` in the top layer, with its directory created there when it is only found in
+    /// the lower layers
+    fn top_layer_target(&self, path: &Path) -> io::Result<PathBuf> {
+        let target = self.root.join(self.on_disk(path));
+        let dir = target.parent().unwrap_or(&self.root);
+        if !dir.exists() && self.resolve(path.parent().unwrap_or(path)).is_dir() {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(target)
+    }
+
+    /// The topmost layer's version of `path`, the top layer's when none has it
+    fn resolve(&self, path: &Path) -> PathBuf {
+        let path = &*self.on_disk(path);
+        if self.lower_layers.is_empty() {
+            return self.root.join(path);
+        }
+        self.layer_paths(path)
+            .into_iter()
+            .find(|path| fs::symlink_metadata(path).is_ok())
+            .unwrap_or_else(|| self.root.join(path))
+    }
+}
+
+impl Storage for FsStorage {
+    fn stat(&self, path: &Path) -> io::Result<Metadata> {
+        let fs_path = self.resolve(path);
+        let metadata = fs::metadata(&fs_path)?;
+        Ok(Metadata::from_fs(&fs_path, &metadata))
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<Entry>> {
+        // Entries of all the layers, the upper ones hiding the lower ones
+        let dirs = if self.lower_layers.is_empty() {
+            vec![self.resolve(path)]
+        } else {
+            self.layer_paths(&self.on_disk(path))
+                .into_iter()
+                .filter(|dir| dir.is_dir())
+                .collect()
+        };
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+        for dir in dirs {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let name = match entry.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(name) => self
+                        .filename_encoding
+                        .as_ref()
+                        .and_then(|encoding| encoding.decode(&name))
+                        .unwrap_or_else(|| name.to_string_lossy().into_owned()),
+                };
+                if !seen.insert(name.clone()) {
+                    continue;
+                }
+                entries.push((name, entry));
+            }
+        }
+        self.stat_entries(entries)
+    }
+
+    fn open_range(
+        &self,
+        path: &Path,
+        offset: u64,
+        len: Option<u64>,
+    ) -> io::Result<Box<dyn Read + Send>> {
+        let mut file = StableFile::open(&self.resolve(path))?;
+        if self.fadvise_sequential {
+            file.advise_sequential();
+        }
+        if offset > 0 {
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        Ok(match len {
+            Some(len) => Box::new(file.take(len)),
+            None => Box::new(file),
+        })
+    }
+
+    fn write(&self, path: &Path, mut data: &mut dyn Read, check: Option<Check>) -> io::Result<u64> {
+        let target = self.top_layer_target(path)?;
+        let dir = target.parent().unwrap_or(&self.root);
+        let tmp_dir = self.tmp_dir.as_deref().unwrap_or(dir);
+        save_atomic_checked(&mut data, tmp_dir, &target, |tmp: &Path| match check {
+            Some(check) => check(tmp),
+            None => Ok(()),
+        })
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        let target = self.root.join(self.on_disk(path));
+        if fs::symlink_metadata(&target)?.is_dir() {
+            fs::remove_dir_all(target)
+        } else {
+            fs::remove_file(target)
+        }
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        if self.resolve(path).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "already exists",
+            ));
+        }
+        fs::create_dir(self.top_layer_target(path)?)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let source = self.root.join(self.on_disk(from));
+        if fs::symlink_metadata(&source).is_err() && self.resolve(from) != source {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "only found in a lower layer",
+            ));
+        }
+        fs::rename(source, self.top_layer_target(to)?)
+    }
+
+    fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        Some(self.resolve(path))
+    }
+}
+
+struct MemoryFile {
+    data: Arc<[u8]>,
+    modified: SystemTime,
+}
+
+/// Files kept in memory (`--tmpfs`), never written to disk and gone on exit. There are no
+/// directories besides the root, and the files total at most `capacity` bytes.
+pub struct MemoryStorage {
+    capacity: u64,
+    created: SystemTime,
+    files: RwLock<BTreeMap<String, MemoryFile>>,
+}
+
+impl MemoryStorage {
+    pub fn new(capacity: u64) -> MemoryStorage {
+        MemoryStorage {
+            capacity,
+            created: SystemTime::now(),
+            files: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Bytes stored besides the file `except`
+    fn used(files: &BTreeMap<String, MemoryFile>, except: &str) -> u64 {
+        files
+            .iter()
+            .filter(|(name, _)| *name != except)
+            .map(|(_, file)| file.data.len() as u64)
+            .sum()
+    }
+}
+
+/// Name of the file `path` directly under the root
+fn root_file_name(path: &Path) -> io::Result<String> {
+    let mut components = path.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => Ok(name.to_string_lossy().into_owned()),
+        _ => Err(io::Error::new(io::ErrorKind::NotFound, "no such file")),
+    }
+}
+
+fn is_root(path: &Path) -> bool {
+    path.components().next().is_none()
+}
+
+impl Storage for MemoryStorage {
+    fn stat(&self, path: &Path) -> io::Result<Metadata> {
+        let files = self.files.read().unwrap();
+        if is_root(path) {
+            return Ok(Metadata {
+                is_dir: true,
+                is_file: false,
+                len: 0,
+                modified: files
+                    .values()
+                    .map(|file| file.modified)
+                    .max()
+                    .unwrap_or(self.created),
+            });
+        }
+        let name = root_file_name(path)?;
+        match files.get(&name) {
+            Some(file) => Ok(Metadata {
+                is_dir: false,
+                is_file: true,
+                len: file.data.len() as u64,
+                modified: file.modified,
+            }),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no such file")),
+        }
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<Entry>> {
+        if !is_root(path) {
+            self.stat(path)?;
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                "not a directory",
+            ));
+        }
+        Ok(self
+            .files
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, file)| Entry {
+                name: name.clone(),
+                metadata: Metadata {
+                    is_dir: false,
+                    is_file: true,
+                    len: file.data.len() as u64,
+                    modified: file.modified,
+                },
+            })
+            .collect())
+    }
+
+    fn open_range(
+        &self,
+        path: &Path,
+        offset: u64,
+        len: Option<u64>,
+    ) -> io::Result<Box<dyn Read + Send>> {
+        let name = root_file_name(path)?;
+        // Served from a snapshot, replacing or deleting the file does not affect it
+        let data = match self.files.read().unwrap().get(&name) {
+            Some(file) => file.data.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "no such file")),
+        };
+        let mut cursor = io::Cursor::new(data);
+        cursor.set_position(offset);
+        Ok(match len {
+            Some(len) => Box::new(cursor.take(len)),
+            None => Box::new(cursor),
+        })
+    }
+
+    fn write(&self, path: &Path, data: &mut dyn Read, check: Option<Check>) -> io::Result<u64> {
+        let name = root_file_name(path)?;
+        let full = || io::Error::new(io::ErrorKind::StorageFull, "tmpfs is full");
+        let available = self
+            .capacity
+            .saturating_sub(Self::used(&self.files.read().unwrap(), &name));
+        let mut content = Vec::new();
+        data.take(available + 1).read_to_end(&mut content)?;
+        if content.len() as u64 > available {
+            return Err(full());
+        }
+        if let Some(check) = check {
+            // Checks run on files, only then is the data written out, and removed right after
+            let suffix = random_alphanumeric(8);
+            let tmp_path = env::temp_dir().join(format!(".tmpfs.{}.part", suffix));
+            let result = fs::write(&tmp_path, &content).and_then(|_| check(&tmp_path));
+            let _ = fs::remove_file(&tmp_path);
+            result?;
+        }
+        let mut files = self.files.write().unwrap();
+        // Other writes may have taken the space meanwhile
+        if Self::used(&files, &name) + content.len() as u64 > self.capacity {
+            return Err(full());
+        }
+        let len = content.len() as u64;
+        files.insert(
+            name,
+            MemoryFile {
+                data: content.into(),
+                modified: SystemTime::now(),
+            },
+        );
+        Ok(len)
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        let name = root_file_name(path)?;
+        match self.files.write().unwrap().remove(&name) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no such file")),
+        }
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        if self.stat(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "already exists",
+            ));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "tmpfs has no directories",
+        ))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let from = root_file_name(from)?;
+        let to = root_file_name(to)?;
+        let mut files = self.files.write().unwrap();
+        match files.remove(&from) {
+            Some(file) => {
+                files.insert(to, file);
+                Ok(())
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no such file")),
+        }
+    }
+}
+
+/// Check that an empty `storage` behaves as the handler expects of every backend, run by
+/// `selftest` and the unit tests. Leaves the storage empty again when it succeeds.
+pub fn conformance(storage: &dyn Storage) -> Result<(), String> {
+    let path = Path::new("conformance.txt");
+    let content = b"storage conformance\n";
+    let read = |offset: u64, len: Option<u64>| -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        storage
+            .open_range(path, offset, len)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|err| format!("open_range({}, {:?}): {}", offset, len, err))?;
+        Ok(data)
+    };
+    let not_found = |result: io::Result<()>, op: &str| match result {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(format!("{} of a missing file: {}, not NotFound", op, err)),
+        Ok(()) => Err(format!("{} of a missing file succeeded", op)),
+    };
+
+    not_found(storage.stat(path).map(|_| ()), "stat")?;
+    not_found(storage.open_range(path, 0, None).map(|_| ()), "open_range")?;
+    let root = storage
+        .stat(Path::new(""))
+        .map_err(|err| format!("stat root: {}", err))?;
+    if !root.is_dir {
+        return Err("root is not a directory".to_owned());
+    }
+
+    let written = storage
+        .write(path, &mut &content[..], None)
+        .map_err(|err| format!("write: {}", err))?;
+    if written != content.len() as u64 {
+        return Err(format!("write returned {} bytes", written));
+    }
+    let metadata = storage.stat(path).map_err(|err| format!("stat: {}", err))?;
+    if !metadata.is_file || metadata.is_dir || metadata.len != content.len() as u64 {
+        return Err("stat does not match the written file".to_owned());
+    }
+    let entries = storage
+        .list(Path::new(""))
+        .map_err(|err| format!("list: {}", err))?;
+    match entries.iter().find(|entry| entry.name == "conformance.txt") {
+        Some(entry) if entry.metadata.is_file && entry.metadata.len == metadata.len => {}
+        _ => return Err("written file missing from the listing".to_owned()),
+    }
+    if read(0, None)? != content {
+        return Err("content differs".to_owned());
+    }
+    if read(8, Some(11))? != content[8..19] {
+        return Err("range content differs".to_owned());
+    }
+    if read(8, Some(1000))? != content[8..] {
+        return Err("range past the end differs".to_owned());
+    }
+
+    let rejected = storage.write(
+        path,
+        &mut &b"rejected"[..],
+        Some(&|_| Err(io::Error::new(io::ErrorKind::InvalidData, "rejected"))),
+    );
+    if rejected.is_ok() {
+        return Err("write succeeded although its check failed".to_owned());
+    }
+    if read(0, None)? != content {
+        return Err("failed write changed the file".to_owned());
+    }
+    storage
+        .write(path, &mut &b"replaced"[..], Some(&|_| Ok(())))
+        .map_err(|err| format!("replace: {}", err))?;
+    if read(0, None)? != b"replaced" {
+        return Err("replaced content differs".to_owned());
+    }
+
+    let renamed = Path::new("conformance-renamed.txt");
+    storage
+        .rename(path, renamed)
+        .map_err(|err| format!("rename: {}", err))?;
+    not_found(storage.stat(path).map(|_| ()), "stat of the renamed")?;
+    match storage.stat(renamed) {
+        Ok(metadata) if metadata.is_file && metadata.len == 8 => {}
+        _ => return Err("renamed file missing".to_owned()),
+    }
+    storage
+        .rename(renamed, path)
+        .map_err(|err| format!("rename back: {}", err))?;
+
+    // Backends without directories refuse them
+    let dir = Path::new("conformance-dir");
+    match storage.create_dir(dir) {
+        Ok(()) => {
+            if !storage.stat(dir).is_ok_and(|metadata| metadata.is_dir) {
+                return Err("created directory missing".to_owned());
+            }
+            match storage.create_dir(dir) {
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                _ => return Err("directory created twice".to_owned()),
+            }
+            storage
+                .write(&dir.join("file.txt"), &mut &content[..], None)
+                .map_err(|err| format!("write in a directory: {}", err))?;
+            if storage.list(dir).map(|entries| entries.len()).ok() != Some(1) {
+                return Err("directory listing differs".to_owned());
+            }
+            storage
+                .delete(dir)
+                .map_err(|err| format!("delete directory: {}", err))?;
+            not_found(
+                storage.stat(dir).map(|_| ()),
+                "stat of the deleted directory",
+            )?;
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::Unsupported => {}
+        Err(err) => return Err(format!("create_dir: {}", err)),
+    }
+
+    storage
+        .delete(path)
+        .map_err(|err| format!("delete: {}", err))?;
+    not_found(storage.stat(path).map(|_| ()), "stat")?;
+    not_found(storage.delete(path), "delete")?;
+    let entries = storage
+        .list(Path::new(""))
+        .map_err(|err| format!("list: {}", err))?;
+    if entries.iter().any(|entry| entry.name == "conformance.txt") {
+        return Err("deleted file still listed".to_owned());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::negative::NegativeCache;
+
+    #[test]
+    fn fs_storage_conformance() {
+        let dir = env::temp_dir().join(format!(
+            "simple-http-server-storage-test-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let result = conformance(&FsStorage::new(dir.clone(), Vec::new(), None, false));
+        fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+    }
+
+    #[test]
+    fn memory_storage_conformance() {
+        conformance(&MemoryStorage::new(1 << 20)).unwrap();
+    }
+
+    #[test]
+    fn negative_cache_conformance() {
+        // A missing path cached by the first lookup must be forgotten once written
+        conformance(&NegativeCache::new(
+            Arc::new(MemoryStorage::new(1 << 20)),
+            Duration::from_secs(60),
+            Vec::new(),
+        ))
+        .unwrap();
+    }
+}
@@ -0,0 +1,581 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use iron::headers::{ContentRange, ContentRangeSpec, ContentType};
+use iron::method;
+use iron::status;
+use iron::{IronError, IronResult, Request, Response};
+use path_dedot::ParseDot;
+use percent_encoding::{percent_decode, utf8_percent_encode, AsciiSet, CONTROLS};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::dedupe::Dedupe;
+use crate::keys::KeyAuth;
+use crate::middlewares::{check_access, is_access_file};
+use crate::progress::UploadProgress;
+use crate::scan::{is_rejected, reject, Scanner};
+use crate::util::{
+    check_preconditions, error_io2iron, hex, json_escape, move_file, save_atomic_checked,
+    sha256_file, StringError, WriteLocks,
+};
+
+/// Header carrying the upload CSRF token, for clients which can not post a form
+const TOKEN_HEADER: &str = "X-Csrf-Token";
+/// Optional header with the hex sha256 of an uploaded file, checked before it is saved
+const SHA256_HEADER: &str = "X-Content-Sha256";
+/// Directory (under the upload tmp dir) keeping interrupted resumable uploads
+const PARTIALS_DIR: &str = ".upload-partials";
+/// Partial uploads are named by their root relative path with `/` escaped
+const PARTIAL_NAME_ENCODE_SET: &AsciiSet = &CONTROLS.add(b'/').add(b'\\').add(b'%');
+
+/// Browser side of resumable uploads, expects `UPLOAD = { token, dir, base }` to be defined.
+/// Interrupted uploads are remembered in localStorage and offered for resuming, the file
+/// has to be selected again since browsers do not keep file handles across page loads.
+pub const RESUMABLE_UPLOAD_SCRIPT: &str = r#"
+(function () {
+  var CHUNK_SIZE = 1 << 20;
+  var STORAGE_KEY = "simple-http-server.uploads";
+
+  function load() {
+    try {
+      return JSON.parse(localStorage.getItem(STORAGE_KEY)) || {};
+    } catch (e) {
+      return {};
+    }
+  }
+
+  function store(uploads) {
+    localStorage.setItem(STORAGE_KEY, JSON.stringify(uploads));
+  }
+
+  function forget(path) {
+    var uploads = load();
+    delete uploads[path];
+    store(uploads);
+  }
+
+  function encodePath(path) {
+    return path.split("/").map(encodeURIComponent).join("/");
+  }
+
+  function send(file, path, offset, status, done) {
+    var uploads = load();
+    uploads[path] = { name: file.name, size: file.size, lastModified: file.lastModified };
+    store(uploads);
+    status.textContent = file.name + ": " + Math.floor(offset * 100 / (file.size || 1)) + "%";
+
+    var xhr = new XMLHttpRequest();
+    xhr.open("PUT", UPLOAD.base + "-/sync/" + encodePath(path) + "?offset=" + offset + "&total=" + file.size);
+    xhr.setRequestHeader("X-Csrf-Token", UPLOAD.token);
+    xhr.onload = function () {
+      if (xhr.status == 201) {
+        forget(path);
+        status.textContent = file.name + ": done";
+        done();
+      } else if (xhr.status == 202 || xhr.status == 409) {
+        send(file, path, parseInt(xhr.responseText, 10), status, done);
+      } else {
+        status.textContent = file.name + ": failed, " + xhr.responseText;
+      }
+    };
+    xhr.onerror = function () {
+      status.textContent = file.name + ": connection lost, retrying...";
+      setTimeout(function () { send(file, path, offset, status, done); }, 3000);
+    };
+    xhr.send(file.slice(offset, Math.min(offset + CHUNK_SIZE, file.size)));
+  }
+
+  function sendAll(files) {
+    var remaining = files.length;
+    files.forEach(function (item) {
+      var status = document.createElement("div");
+      document.getElementById("resumable-status").appendChild(status);
+      send(item.file, item.path, item.offset, status, function () {
+        remaining -= 1;
+        if (remaining == 0) {
+          location.reload();
+        }
+      });
+    });
+  }
+
+  document.getElementById("resumable-upload").onclick = function () {
+    var files = Array.prototype.slice.call(document.getElementById("resumable-files").files);
+    sendAll(files.map(function (file) {
+      return { file: file, path: UPLOAD.dir + file.name, offset: 0 };
+    }));
+  };
+
+  var xhr = new XMLHttpRequest();
+  xhr.open("GET", UPLOAD.base + "-/uploads/pending");
+  xhr.setRequestHeader("X-Csrf-Token", UPLOAD.token);
+  xhr.onload = function () {
+    if (xhr.status != 200) {
+      return;
+    }
+    var received = {};
+    JSON.parse(xhr.responseText).forEach(function (item) {
+      received[item.path] = item.size;
+    });
+    var uploads = load();
+    var pending = document.getElementById("resumable-pending");
+    Object.keys(uploads).forEach(function (path) {
+      if (!(path in received)) {
+        forget(path);
+        return;
+      }
+      var upload = uploads[path];
+      var row = document.createElement("div");
+      var label = document.createElement("span");
+      label.textContent = path + " (" + received[path] + " / " + upload.size + " bytes) ";
+      var input = document.createElement("input");
+      input.type = "file";
+      input.style.display = "none";
+      input.onchange = function () {
+        var file = input.files[0];
+        if (!file || file.name != upload.name || file.size != upload.size) {
+          alert("Please select the same file: " + upload.name + " (" + upload.size + " bytes)");
+          return;
+        }
+        row.parentNode.removeChild(row);
+        sendAll([{ file: file, path: path, offset: received[path] }]);
+      };
+      var button = document.createElement("button");
+      button.textContent = "Resume";
+      button.onclick = function () { input.click(); };
+      row.appendChild(label);
+      row.appendChild(button);
+      row.appendChild(input);
+      pending.appendChild(row);
+    });
+  };
+  xhr.send();
+})();
+"#;
+
+/// Batch upload endpoint: `/-/sync`
+///
+/// - `POST /-/sync` with a manifest of `<sha256> <size> <path>` lines (root relative),
+///   replies the paths whose content differs or which are missing, one per line.
+/// - `PUT /-/sync/<path>` uploads one of those files.
+/// - `PUT /-/sync/<path>?offset=<N>&total=<SIZE>` (or with `Content-Range: bytes
+///   <N>-<END>/<SIZE>`) appends a chunk to a resumable upload, replies `202` with the bytes
+///   received so far (`409` when `offset` is off), or `201` once the file is complete and,
+///   if an `X-Content-Sha256` header came with the last chunk, checked. An empty `PUT` with
+///   `Content-Range: bytes */<SIZE>` only replies the bytes received so far.
+/// - Both honor `If-Match`/`If-None-Match` (`*` or ETags as served by `GET`), replying
+///   `412` when the file changed meanwhile (or exists, for `If-None-Match: *`).
+/// - Uploads with an `X-Upload-Id` header report their progress on `/-/upload-progress/<id>`.
+/// - `GET /-/uploads/pending` lists interrupted resumable uploads as JSON.
+pub struct SyncUpload {
+    root: PathBuf,
+    csrf_token: String,
+    size_limit: u64,
+    tmp_dir: Option<PathBuf>,
+    // Never under the root, the partial uploads would be served whatever the access rules
+    partials_dir: PathBuf,
+    write_locks: Arc<WriteLocks>,
+    scanner: Option<Arc<Scanner>>,
+    progress: Arc<UploadProgress>,
+    dedupe: Option<Arc<Dedupe>>,
+}
+
+impl SyncUpload {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        root: PathBuf,
+        csrf_token: String,
+        size_limit: u64,
+        tmp_dir: Option<PathBuf>,
+        write_locks: Arc<WriteLocks>,
+        scanner: Option<Arc<Scanner>>,
+        progress: Arc<UploadProgress>,
+        dedupe: Option<Arc<Dedupe>>,
+    ) -> SyncUpload {
+        let partials_dir = match tmp_dir {
+            Some(ref tmp_dir) => tmp_dir.join(PARTIALS_DIR),
+            // One per root, the partial uploads are named by their root relative path
+            None => env::temp_dir().join(format!(
+                "simple-http-server-partials-{}",
+                &hex(&Sha256::digest(root.to_string_lossy().as_bytes()))[..16]
+            )),
+        };
+        SyncUpload {
+            root,
+            csrf_token,
+            size_limit,
+            tmp_dir,
+            partials_dir,
+            write_locks,
+            scanner,
+            progress,
+            dedupe,
+        }
+    }
+
+    fn dedupe(&self, target: &Path) {
+        if let Some(ref dedupe) = self.dedupe {
+            dedupe.link(target);
+        }
+    }
+
+    fn scan(&self, path: &Path, name: &str) -> io::Result<()> {
+        match self.scanner {
+            Some(ref scanner) => scanner.scan(path, name),
+            None => Ok(()),
+        }
+    }
+
+    pub fn handle(&self, req: &mut Request, path: &[String]) -> IronResult<Response> {
+        check_token(req, &self.csrf_token)?;
+        match req.method {
+            method::Post if path.iter().all(|s| s.is_empty()) => self.missing(req),
+            method::Put => {
+                let path = path
+                    .iter()
+                    .filter(|s| !s.is_empty())
+                    .map(|s| {
+                        percent_decode(s.as_bytes())
+                            .decode_utf8()
+                            .map(|s| s.to_string())
+                            .map_err(|_err| bad_request(format!("invalid path: {}", s)))
+                    })
+                    .collect::<Result<Vec<String>, _>>()?
+                    .join("/");
+                let mut offset = None;
+                let mut total = None;
+                for (k, v) in req.url.as_ref().query_pairs() {
+                    if k == "offset" {
+                        offset = Some(
+                            v.parse::<u64>()
+                                .map_err(|_err| bad_request(format!("invalid offset: {}", v)))?,
+                        );
+                    } else if k == "total" {
+                        total = Some(
+                            v.parse::<u64>()
+                                .map_err(|_err| bad_request(format!("invalid total: {}", v)))?,
+                        );
+                    }
+                }
+                let content_range = match req.headers.get::<ContentRange>() {
+                    Some(&ContentRange(ContentRangeSpec::Bytes {
+                        range,
+                        instance_length: Some(length),
+                    })) if offset.is_none() && total.is_none() => Some((range, length)),
+                    None if req.headers.get_raw("Content-Range").is_none() => None,
+                    _ => return Err(bad_request("invalid Content-Range".to_owned())),
+                };
+                match (offset, total, content_range) {
+                    (None, None, None) => self.save(req, &path),
+                    (Some(offset), Some(total), None) => self.save_chunk(req, &path, offset, total),
+                    (None, None, Some((Some((start, end)), total)))
+                        if start <= end && end < total =>
+                    {
+                        self.save_chunk(req, &path, start, total)
+                    }
+                    (None, None, Some((None, _))) => self.received(req, &path),
+                    (None, None, Some(_)) => Err(bad_request("invalid Content-Range".to_owned())),
+                    _ => Err(bad_request(
+                        "offset and total must be provided together".to_owned(),
+                    )),
+                }
+            }
+            _ => Ok(Response::with(status::MethodNotAllowed)),
+        }
+    }
+
+    /// `GET /-/uploads/pending`: `[{"path":"dir/file.bin","size":1048576}]`
+    pub fn pending(&self, req: &mut Request, path: &[String]) -> IronResult<Response> {
+        check_token(req, &self.csrf_token)?;
+        match (&req.method, path.first().map(String::as_str)) {
+            (method::Get, Some("pending")) => {}
+            (_, Some("pending")) => return Ok(Response::with(status::MethodNotAllowed)),
+            _ => {
+                return Err(IronError::new(
+                    StringError("unknown uploads endpoint".to_owned()),
+                    status::NotFound,
+                ))
+            }
+        }
+
+        let mut items = Vec::new();
+        match fs::read_dir(&self.partials_dir) {
+            Ok(read_dir) => {
+                for entry in read_dir {
+                    let entry = entry.map_err(error_io2iron)?;
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    let path = percent_decode(name.as_bytes()).decode_utf8_lossy();
+                    // Leaving out the uploads to directories the client can not access
+                    if check_access(req, Path::new(&*path)).is_err() {
+                        continue;
+                    }
+                    let size = entry.metadata().map_err(error_io2iron)?.len();
+                    items.push(format!(
+                        r#"{{"path":"{}","size":{}}}"#,
+                        json_escape(&path),
+                        size
+                    ));
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(error_io2iron(err)),
+        }
+        items.sort();
+
+        let mut resp = Response::with((status::Ok, format!("[{}]", items.join(","))));
+        resp.headers.set(ContentType::json());
+        Ok(resp)
+    }
+
+    /// Where the resumable upload of `target` is kept until complete
+    fn partial_path(&self, target: &Path) -> PathBuf {
+        let relative = target.strip_prefix(&self.root).unwrap().to_string_lossy();
+        self.partials_dir.join(
+            utf8_percent_encode(&relative.replace('\\', "/"), PARTIAL_NAME_ENCODE_SET).to_string(),
+        )
+    }
+
+    /// `202` with the bytes of the resumable upload of `path` received so far
+    fn received(&self, req: &Request, path: &str) -> IronResult<Response> {
+        let target = self.resolve(req, path)?;
+        let received = match fs::metadata(self.partial_path(&target)) {
+            Ok(metadata) => metadata.len(),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(error_io2iron(err)),
+        };
+        Ok(Response::with((status::Accepted, received.to_string())))
+    }
+
+    /// The file `path` under the root, checked against the `--access-files` rules
+    fn resolve(&self, req: &Request, path: &str) -> IronResult<PathBuf> {
+        let path = path.trim_start_matches('/');
+        if path.is_empty() {
+            return Err(bad_request("empty path".to_owned()));
+        }
+        let fs_path = self.root.join(path).parse_dot().unwrap().to_path_buf();
+        if !fs_path.starts_with(&self.root)
+            || fs_path == self.root
+            || path.split('/').any(is_access_file)
+        {
+            return Err(IronError::new(
+                io::Error::new(io::ErrorKind::PermissionDenied, "Permission Denied"),
+                status::Forbidden,
+            ));
+        }
+        check_access(req, fs_path.strip_prefix(&self.root).unwrap())?;
+        Ok(fs_path)
+    }
+
+    fn missing(&self, req: &mut Request) -> IronResult<Response> {
+        let mut manifest = String::new();
+        req.body
+            .by_ref()
+            .take(self.size_limit)
+            .read_to_string(&mut manifest)
+            .map_err(|err| bad_request(format!("read manifest failed: {}", err)))?;
+
+        let mut missing = Vec::new();
+        for line in manifest.lines().filter(|line| !line.trim().is_empty()) {
+            let parts = line.splitn(3, ' ').collect::<Vec<&str>>();
+            if parts.len() != 3 {
+                return Err(bad_request(format!("invalid manifest line: {}", line)));
+            }
+            let size = parts[1]
+                .parse::<u64>()
+                .map_err(|_err| bad_request(format!("invalid size: {}", line)))?;
+            let fs_path = self.resolve(req, parts[2])?;
+            if !same_content(&fs_path, size, parts[0]) {
+                missing.push(parts[2]);
+            }
+        }
+
+        let mut resp = Response::with((status::Ok, missing.join("\n")));
+        resp.headers.set(ContentType::plaintext());
+        Ok(resp)
+    }
+
+    fn save(&self, req: &mut Request, path: &str) -> IronResult<Response> {
+        let target = self.resolve(req, path)?;
+        let _lock = self.write_locks.lock(&target)?;
+        check_preconditions(req, &target)?;
+        let parent = target.parent().unwrap();
+        fs::create_dir_all(parent).map_err(error_io2iron)?;
+        let expected = req
+            .headers
+            .get_raw(SHA256_HEADER)
+            .and_then(|values| values.first())
+            .map(|value| String::from_utf8_lossy(value).to_ascii_lowercase());
+
+        let mut data = CheckedReader {
+            inner: self
+                .progress
+                .track(&req.headers, &mut req.body)
+                .take(self.size_limit + 1),
+            hasher: Sha256::new(),
+            expected,
+            size: 0,
+            size_limit: self.size_limit,
+        };
+        let tmp_dir = self.tmp_dir.as_deref().unwrap_or(parent);
+        match save_atomic_checked(&mut data, tmp_dir, &target, |tmp| self.scan(tmp, path)) {
+            Ok(size) => {
+                info!("File synced: {} ({} bytes)", path, size);
+                self.dedupe(&target);
+                Ok(Response::with(status::Created))
+            }
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                Err(bad_request(err.to_string()))
+            }
+            Err(ref err) if is_rejected(err) => Err(reject(path, err)),
+            Err(err) => Err(error_io2iron(err)),
+        }
+    }
+
+    fn save_chunk(
+        &self,
+        req: &mut Request,
+        path: &str,
+        offset: u64,
+        total: u64,
+    ) -> IronResult<Response> {
+        let target = self.resolve(req, path)?;
+        let _lock = self.write_locks.lock(&target)?;
+        check_preconditions(req, &target)?;
+        if total > self.size_limit {
+            return Err(bad_request(
+                "file size exceeds upload size limit".to_owned(),
+            ));
+        }
+        fs::create_dir_all(&self.partials_dir).map_err(error_io2iron)?;
+        let partial = self.partial_path(&target);
+
+        let mut file = if offset == 0 {
+            fs::File::create(&partial)
+        } else {
+            fs::OpenOptions::new().append(true).open(&partial)
+        };
+        let received = match file {
+            Ok(ref file) => file.metadata().map_err(error_io2iron)?.len(),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(error_io2iron(err)),
+        };
+        if received != offset || offset > total {
+            return Ok(Response::with((status::Conflict, received.to_string())));
+        }
+        let file = file.as_mut().unwrap();
+        let mut body = self
+            .progress
+            .track(&req.headers, &mut req.body)
+            .take(total - offset);
+        let written = io::copy(&mut body, file)
+            .and_then(|written| file.sync_all().map(|_| written))
+            .map_err(error_io2iron)?;
+        let received = offset + written;
+        if received < total {
+            return Ok(Response::with((status::Accepted, received.to_string())));
+        }
+
+        // The whole file is checked, the chunks are not
+        let expected = req
+            .headers
+            .get_raw(SHA256_HEADER)
+            .and_then(|values| values.first())
+            .map(|value| String::from_utf8_lossy(value).to_ascii_lowercase());
+        if let Some(expected) = expected {
+            let actual = sha256_file(&partial).map_err(error_io2iron)?;
+            if actual != expected {
+                let _ = fs::remove_file(&partial);
+                return Err(bad_request(format!(
+                    "sha256 mismatch: expected {}, got {}",
+                    expected, actual
+                )));
+            }
+        }
+        match self.scan(&partial, path) {
+            Ok(()) => {}
+            Err(ref err) if is_rejected(err) => {
+                let _ = fs::remove_file(&partial);
+                return Err(reject(path, err));
+            }
+            Err(err) => return Err(error_io2iron(err)),
+        }
+        fs::create_dir_all(target.parent().unwrap()).map_err(error_io2iron)?;
+        move_file(&partial, &target).map_err(error_io2iron)?;
+        info!("File synced: {} ({} bytes)", path, received);
+        self.dedupe(&target);
+        Ok(Response::with((status::Created, received.to_string())))
+    }
+}
+
+/// Check the `X-Csrf-Token` header of API writes (they can not post the upload form)
+pub fn check_token(req: &Request, csrf_token: &str) -> IronResult<()> {
+    // Only scripts send API keys
+    if req.extensions.contains::<KeyAuth>() {
+        return Ok(());
+    }
+    let token = req
+        .headers
+        .get_raw(TOKEN_HEADER)
+        .and_then(|values| values.first())
+        .map(|value| String::from_utf8_lossy(value).to_string());
+    if token.as_deref() != Some(csrf_token) {
+        return Err(IronError::new(
+            StringError("csrf token does not match".to_owned()),
+            status::Forbidden,
+        ));
+    }
+    Ok(())
+}
+
+fn bad_request(msg: String) -> IronError {
+    IronError::new(StringError(msg), status::BadRequest)
+}
+
+fn same_content(path: &Path, size: u64, sha256: &str) -> bool {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() && metadata.len() == size => {}
+        _ => return false,
+    }
+    sha256_file(path)
+        .map(|hash| hash == sha256.to_ascii_lowercase())
+        .unwrap_or(false)
+}
+
+// Fails at the end of the body when it is too large or does not match the expected
+// sha256, so `save_atomic` discards the temporary file instead of renaming it.
+struct CheckedReader<R> {
+    inner: R,
+    hasher: Sha256,
+    expected: Option<String>,
+    size: u64,
+    size_limit: u64,
+}
+
+impl<R: Read> Read for CheckedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.size += n as u64;
+        if self.size > self.size_limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file size exceeds upload size limit",
+            ));
+        }
+        if n == 0 {
+            if let Some(ref expected) = self.expected {
+                if hex(&self.hasher.clone().finalize()) != *expected {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "sha256 does not match",
+                    ));
+                }
+            }
+        }
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use htmlescape::encode_minimal;
+
+use crate::util::json_escape;
+
+type FileTags = BTreeMap<String, String>;
+
+/// Key/value tags on files (eg: `latest`, `signed=gpg`), keyed by the root relative path
+/// and persisted in a sidecar file of `<path>\t<key>\t<value>` lines.
+pub struct Tags {
+    db: PathBuf,
+    tags: Mutex<BTreeMap<String, FileTags>>,
+}
+
+impl Tags {
+    pub fn load(db: PathBuf) -> io::Result<Tags> {
+        let tags = load_tags(&db)?;
+        Ok(Tags {
+            db,
+            tags: Mutex::new(tags),
+        })
+    }
+
+    pub fn get(&self, path: &str) -> FileTags {
+        self.tags
+            .lock()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn set(&self, path: &str, key: &str, value: &str) -> io::Result<()> {
+        let mut tags = self.tags.lock().unwrap();
+        tags.entry(path.to_owned())
+            .or_default()
+            .insert(key.to_owned(), value.to_owned());
+        self.save(&tags)
+    }
+
+    /// Remove one tag, or all tags of the file when `key` is `None`
+    pub fn remove(&self, path: &str, key: Option<&str>) -> io::Result<()> {
+        let mut tags = self.tags.lock().unwrap();
+        match key {
+            Some(key) => {
+                if let Some(file_tags) = tags.get_mut(path) {
+                    file_tags.remove(key);
+                    if file_tags.is_empty() {
+                        tags.remove(path);
+                    }
+                }
+            }
+            None => {
+                tags.remove(path);
+            }
+        }
+        self.save(&tags)
+    }
+
+    /// Filter is either a tag name (`release`) or a name and value (`channel=beta`)
+    pub fn matches(&self, path: &str, filter: &str) -> bool {
+        let tags = self.tags.lock().unwrap();
+        let file_tags = match tags.get(path) {
+            Some(file_tags) => file_tags,
+            None => return false,
+        };
+        match filter.find('=') {
+            Some(idx) => {
+                file_tags.get(&filter[..idx]).map(String::as_str) == Some(&filter[idx + 1..])
+            }
+            None => file_tags.contains_key(filter),
+        }
+    }
+
+    /// Tags rendered as labels for the directory listing
+    pub fn labels(&self, path: &str) -> String {
+        self.get(path)
+            .iter()
+            .map(|(key, value)| {
+                let label = if value.is_empty() {
+                    encode_minimal(key)
+                } else {
+                    format!("{}={}", encode_minimal(key), encode_minimal(value))
+                };
+                format!(
+                    r#" <small style="background:#EEE; padding:0 4px;">{}</small>"#,
+                    label
+                )
+            })
+            .collect()
+    }
+
+    pub fn json(&self, path: &str) -> String {
+        let items = self
+            .get(path)
+            .iter()
+            .map(|(key, value)| format!(r#""{}":"{}""#, json_escape(key), json_escape(value)))
+            .collect::<Vec<String>>();
+        format!("{{{}}}", items.join(","))
+    }
+
+    fn save(&self, tags: &BTreeMap<String, FileTags>) -> io::Result<()> {
+        let tmp_path = self.db.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        for (path, file_tags) in tags {
+            for (key, value) in file_tags {
+                writeln!(file, "{}\t{}\t{}", path, key, value)?;
+            }
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.db)
+    }
+}
+
+fn load_tags(path: &Path) -> io::Result<BTreeMap<String, FileTags>> {
+    let mut tags = BTreeMap::new();
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(tags),
+        Err(e) => return Err(e),
+    };
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let parts = line.splitn(3, '\t').collect::<Vec<&str>>();
+        if parts.len() != 3 {
+            continue;
+        }
+        tags.entry(parts[0].to_owned())
+            .or_default()
+            .insert(parts[1].to_owned(), parts[2].to_owned());
+    }
+    Ok(tags)
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use iron::status;
+use iron::IronError;
+use tracing::{info, warn};
+
+use crate::scan::{shell, shell_quote};
+use crate::util::{error_resp, StringError};
+
+/// Suffix of the marker files flagging their sibling as archived
+const MARKER_SUFFIX: &str = ".archived";
+
+struct Recall {
+    child: Option<Child>,
+    started: Instant,
+}
+
+/// `--cold-storage`: the files moved to an offline or slow tier (tape, HSM stubs, cloud
+/// placeholders) are not read, which would hold a worker thread until the recall completes,
+/// but answered `503` with `Retry-After`, or `202` once the `--recall-command` was started
+/// for them. A file is offline when a `<name>.archived` marker sits next to it, when it has
+/// the `--cold-xattr` extended attribute (Linux) or the offline / recall on access
+/// attributes (Windows). The recall command is started at most once per `Retry-After`
+/// period for a file.
+pub struct ColdStorage {
+    xattr: Option<String>,
+    recall_command: Option<String>,
+    retry_after: u32,
+    recalls: Mutex<HashMap<PathBuf, Recall>>,
+}
+
+impl ColdStorage {
+    /// `{path}` in `recall_command` is replaced by the file to recall, appended when missing
+    pub fn new(xattr: Option<&str>, recall_command: Option<&str>, retry_after: u32) -> ColdStorage {
+        let recall_command = recall_command.map(|command| {
+            if command.contains("{path}") {
+                command.to_owned()
+            } else {
+                format!("{} {{path}}", command)
+            }
+        });
+        ColdStorage {
+            xattr: xattr.map(str::to_owned),
+            recall_command,
+            retry_after,
+            recalls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_offline(&self, path: &Path) -> bool {
+        let mut marker = path.as_os_str().to_owned();
+        marker.push(MARKER_SUFFIX);
+        Path::new(&marker).exists()
+            || self
+                .xattr
+                .as_ref()
+                .is_some_and(|name| has_xattr(path, name))
+            || has_offline_attributes(path)
+    }
+
+    /// The reply to send in place of the local file `path` when it is offline, after
+    /// starting its recall
+    pub fn check(&self, path: &Path, base_url: &str) -> Result<(), IronError> {
+        if !self.is_offline(path) {
+            return Ok(());
+        }
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let (status, msg) = if self.recall(path) {
+            (
+                status::Accepted,
+                format!(
+                    "{} is being recalled from archive storage, please retry later.",
+                    name
+                ),
+            )
+        } else {
+            (
+                status::ServiceUnavailable,
+                format!("{} is in archive storage, please retry later.", name),
+            )
+        };
+        let mut resp = error_resp(status, &msg, base_url);
+        resp.headers.set_raw(
+            "Retry-After",
+            vec![self.retry_after.to_string().into_bytes()],
+        );
+        Err(IronError {
+            error: Box::new(StringError(format!("{} is offline", path.display()))),
+            response: resp,
+        })
+    }
+
+    /// Start the recall of `path` unless already done recently, whether a recall is underway
+    fn recall(&self, path: &Path) -> bool {
+        let command = match self.recall_command {
+            Some(ref command) => command,
+            None => return false,
+        };
+        let retry_after = Duration::from_secs(u64::from(self.retry_after));
+        let mut recalls = self.recalls.lock().unwrap();
+        // Reap the finished recalls, and forget them once their period is over
+        recalls.retain(|path, recall| {
+            if let Some(ref mut child) = recall.child {
+                match child.try_wait() {
+                    Ok(None) => return true,
+                    Ok(Some(status)) if !status.success() => {
+                        warn!("Recall of {} failed: {}", path.display(), status)
+                    }
+                    Ok(Some(_)) => {}
+                    Err(err) => warn!("Recall of {} failed: {}", path.display(), err),
+                }
+                recall.child = None;
+            }
+            recall.started.elapsed() < retry_after
+        });
+        if recalls.contains_key(path) {
+            return true;
+        }
+        let command = command.replace("{path}", &shell_quote(&path.to_string_lossy()));
+        match shell(&command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => {
+                info!("Recalling {} from archive storage", path.display());
+                recalls.insert(
+                    path.to_owned(),
+                    Recall {
+                        child: Some(child),
+                        started: Instant::now(),
+                    },
+                );
+                true
+            }
+            Err(err) => {
+                warn!("Can not start the recall of {}: {}", path.display(), err);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn has_xattr(path: &Path, name: &str) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let (path, name) = match (
+        CString::new(path.as_os_str().as_bytes()),
+        CString::new(name),
+    ) {
+        (Ok(path), Ok(name)) => (path, name),
+        _ => return false,
+    };
+    // Only the size is asked, the attribute is not read
+    unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) >= 0 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn has_xattr(_path: &Path, _name: &str) -> bool {
+    false
+}
+
+#[cfg(windows)]
+fn has_offline_attributes(path: &Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+
+    const FILE_ATTRIBUTE_OFFLINE: u32 = 0x1000;
+    const FILE_ATTRIBUTE_RECALL_ON_OPEN: u32 = 0x40000;
+    const FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS: u32 = 0x400000;
+    std::fs::metadata(path).is_ok_and(|metadata| {
+        metadata.file_attributes()
+            & (FILE_ATTRIBUTE_OFFLINE
+                | FILE_ATTRIBUTE_RECALL_ON_OPEN
+                | FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS)
+            != 0
+    })
+}
+
+#[cfg(not(windows))]
+fn has_offline_attributes(_path: &Path) -> bool {
+    false
+}
@@ -0,0 +1,129 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use hyper::net::{HttpStream, NetworkStream, SslServer};
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection, StreamOwned};
+
+use crate::util::pem_blocks;
+
+/// Clients taking longer to complete the handshake are dropped, they hold a worker thread
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// HTTPS with rustls (`--tls-backend rustls`), from PEM files: the certificate chain and
+/// an unencrypted PKCS#8 or RSA key, in the same file or in `--key`.
+#[derive(Clone)]
+pub struct RustlsServer {
+    config: Arc<ServerConfig>,
+}
+
+impl RustlsServer {
+    pub fn new(cert: &Path, key: Option<&Path>) -> Result<RustlsServer, String> {
+        let certs = pem_blocks(cert)?
+            .into_iter()
+            .filter(|(label, _)| label == "CERTIFICATE")
+            .map(|(_, der)| Certificate(der))
+            .collect::<Vec<Certificate>>();
+        if certs.is_empty() {
+            return Err(format!("{}: no PEM certificate", cert.display()));
+        }
+        let key_path = key.unwrap_or(cert);
+        let blocks = pem_blocks(key_path)?;
+        let key = match blocks
+            .iter()
+            .find(|(label, _)| label.ends_with("PRIVATE KEY"))
+        {
+            Some((label, der)) if label == "PRIVATE KEY" || label == "RSA PRIVATE KEY" => {
+                PrivateKey(der.clone())
+            }
+            Some((label, _)) => {
+                return Err(format!(
+                    "{}: {} is not supported, convert it to PKCS#8 (openssl pkcs8 -topk8 -nocrypt)",
+                    key_path.display(),
+                    label
+                ))
+            }
+            None => return Err(format!("{}: no PEM private key", key_path.display())),
+        };
+        RustlsServer::from_der(certs, key).map_err(|err| format!("{}: {}", key_path.display(), err))
+    }
+
+    /// From the DER encoded certificate chain and key
+    pub fn from_der(certs: Vec<Certificate>, key: PrivateKey) -> Result<RustlsServer, String> {
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|err| err.to_string())?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(RustlsServer {
+            config: Arc::new(config),
+        })
+    }
+}
+
+impl SslServer for RustlsServer {
+    type Stream = RustlsStream;
+
+    fn wrap_server(&self, mut sock: HttpStream) -> hyper::Result<RustlsStream> {
+        let mut conn = ServerConnection::new(self.config.clone())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        // The server sets its own timeouts for the requests
+        sock.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+        Ok(RustlsStream(Arc::new(Mutex::new(StreamOwned::new(
+            conn, sock,
+        )))))
+    }
+}
+
+/// A TLS connection, shared by the clones the server reads and writes the requests with
+#[derive(Clone)]
+pub struct RustlsStream(Arc<Mutex<StreamOwned<ServerConnection, HttpStream>>>);
+
+impl RustlsStream {
+    fn lock(&self) -> MutexGuard<'_, StreamOwned<ServerConnection, HttpStream>> {
+        self.0.lock().unwrap()
+    }
+}
+
+impl Read for RustlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock().read(buf)
+    }
+}
+
+impl Write for RustlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+impl NetworkStream for RustlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.lock().sock.peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock().sock.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock().sock.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        let mut stream = self.lock();
+        stream.conn.send_close_notify();
+        let _ = stream.flush();
+        stream.sock.close(how)
+    }
+}
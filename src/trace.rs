@@ -0,0 +1,203 @@
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::header::ContentType;
+use rand::{thread_rng, Rng};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{warn, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+use crate::util::{hex, json_escape, now_string};
+
+/// Finished spans waiting for the exporter, more are dropped
+const MAX_QUEUED_SPANS: usize = 4096;
+/// Spans sent in one export request
+const MAX_BATCH_SIZE: usize = 512;
+/// Pending spans are exported at least this often
+const EXPORT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Print events to stdout (as the request log does) and export spans to an OpenTelemetry
+/// collector on `otlp_endpoint` (OTLP/HTTP with JSON encoding, eg: `http://localhost:4318`).
+pub fn init(otlp_endpoint: Option<&str>) {
+    let otlp = otlp_endpoint.map(|endpoint| {
+        let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED_SPANS);
+        let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+        thread::spawn(move || export(&url, receiver));
+        OtlpLayer { sender }
+    });
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_timer(LocalTime)
+                .with_target(false),
+        )
+        .with(otlp)
+        .init();
+}
+
+// Same format as the request log
+struct LocalTime;
+
+impl FormatTime for LocalTime {
+    fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+        write!(w, "[{}]", now_string())
+    }
+}
+
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    start: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+}
+
+impl Visit for SpanData {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.attributes.push((field.name(), value.to_owned()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.attributes.push((field.name(), format!("{:?}", value)));
+    }
+}
+
+struct OtlpLayer {
+    sender: SyncSender<String>,
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|data| (data.trace_id, data.span_id))
+        });
+        let mut data = SpanData {
+            trace_id: parent
+                .map(|(trace_id, _)| trace_id)
+                .unwrap_or_else(|| thread_rng().gen()),
+            span_id: thread_rng().gen(),
+            parent_span_id: parent.map(|(_, span_id)| span_id),
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+        };
+        attrs.record(&mut data);
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(data);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let extensions = span.extensions();
+        let data = match extensions.get::<SpanData>() {
+            Some(data) => data,
+            None => return,
+        };
+        let nanos = |t: SystemTime| {
+            t.duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0)
+        };
+        let attributes = data
+            .attributes
+            .iter()
+            .map(|(key, value)| {
+                format!(
+                    r#"{{"key":"{}","value":{{"stringValue":"{}"}}}}"#,
+                    key,
+                    json_escape(value)
+                )
+            })
+            .collect::<Vec<String>>();
+        let json = format!(
+            concat!(
+                r#"{{"traceId":"{}","spanId":"{}","parentSpanId":"{}","name":"{}","kind":{},"#,
+                r#""startTimeUnixNano":"{}","endTimeUnixNano":"{}","attributes":[{}]}}"#,
+            ),
+            hex(&data.trace_id),
+            hex(&data.span_id),
+            data.parent_span_id.map(|id| hex(&id)).unwrap_or_default(),
+            json_escape(span.name()),
+            // SPAN_KIND_SERVER for the request, SPAN_KIND_INTERNAL for its steps
+            if data.parent_span_id.is_some() { 1 } else { 2 },
+            nanos(data.start),
+            nanos(SystemTime::now()),
+            attributes.join(","),
+        );
+        // Never slow requests down when the collector is unreachable
+        let _ = self.sender.try_send(json);
+    }
+}
+
+fn export(url: &str, receiver: Receiver<String>) {
+    let client = hyper::Client::new();
+    let mut batch = Vec::new();
+    let mut last_export = Instant::now();
+    loop {
+        let disconnected =
+            match receiver.recv_timeout(EXPORT_INTERVAL.saturating_sub(last_export.elapsed())) {
+                Ok(span) => {
+                    batch.push(span);
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+        if batch.len() < MAX_BATCH_SIZE && last_export.elapsed() < EXPORT_INTERVAL && !disconnected
+        {
+            continue;
+        }
+        last_export = Instant::now();
+        if !batch.is_empty() {
+            let body = format!(
+                concat!(
+                    r#"{{"resourceSpans":[{{"resource":{{"attributes":[{{"key":"service.name","#,
+                    r#""value":{{"stringValue":"simple-http-server"}}}}]}},"#,
+                    r#""scopeSpans":[{{"scope":{{"name":"simple-http-server"}},"spans":[{}]}}]}}]}}"#,
+                ),
+                batch.join(",")
+            );
+            batch.clear();
+            match client
+                .post(url)
+                .header(ContentType::json())
+                .body(body.as_str())
+                .send()
+            {
+                Ok(ref resp) if resp.status.is_success() => {}
+                Ok(resp) => warn!("Export spans to {} failed: {}", url, resp.status),
+                Err(err) => warn!("Export spans to {} failed: {}", url, err),
+            }
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
@@ -0,0 +1,502 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use iron::headers::ContentType;
+use iron::method;
+use iron::status;
+use iron::{Headers, IronError, IronResult, Request, Response};
+use tracing::{info, warn};
+
+use crate::events::ChangeEvents;
+use crate::middlewares::{check_access, is_access_file, request_user};
+use crate::mirror::{is_replay, Mirror};
+use crate::storage::{Metadata, Storage};
+use crate::util::{error_io2iron, json_escape, StringError, WriteLocks};
+
+/// Transfers started by the API running at once, more are refused with `503`
+const MAX_RUNNING: usize = 4;
+/// Finished transfers are still reported this long, for the last poll of the client
+const FINISHED_RETENTION: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferKind {
+    Copy,
+    Move,
+}
+
+impl TransferKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            TransferKind::Copy => "copy",
+            TransferKind::Move => "move",
+        }
+    }
+}
+
+enum State {
+    Running,
+    Done,
+    Cancelled,
+    Failed(String),
+}
+
+/// One server-side copy or move, its progress counted in bytes copied
+pub struct Transfer {
+    id: u64,
+    kind: TransferKind,
+    from: PathBuf,
+    to: PathBuf,
+    user: Option<String>,
+    // Requested by the instance mirroring to this one, not replayed back
+    replayed: bool,
+    total: AtomicU64,
+    done: AtomicU64,
+    cancelled: AtomicBool,
+    // With the time it finished
+    state: Mutex<(State, Option<Instant>)>,
+}
+
+impl Transfer {
+    /// Whether `POST /-/cancel` stopped it
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn is_running(&self) -> bool {
+        matches!(self.state.lock().unwrap().0, State::Running)
+    }
+
+    fn json(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let (name, error) = match state.0 {
+            State::Running => ("running", None),
+            State::Done => ("done", None),
+            State::Cancelled => ("cancelled", None),
+            State::Failed(ref error) => ("failed", Some(error)),
+        };
+        format!(
+            r#"{{"id":{},"kind":"{}","from":"{}","to":"{}","total":{},"done":{},"state":"{}","error":{}}}"#,
+            self.id,
+            self.kind.as_str(),
+            json_escape(&self.from.to_string_lossy()),
+            json_escape(&self.to.to_string_lossy()),
+            self.total.load(Ordering::Relaxed),
+            self.done.load(Ordering::Relaxed),
+            name,
+            error
+                .map(|error| format!(r#""{}""#, json_escape(error)))
+                .unwrap_or_else(|| "null".to_owned()),
+        )
+    }
+}
+
+// Not `Interrupted`, which `io::copy` retries
+fn cancelled() -> io::Error {
+    io::Error::other("transfer cancelled")
+}
+
+/// Counts the bytes copied, and fails once the transfer is cancelled
+struct Progress<'a, R> {
+    inner: R,
+    transfer: &'a Transfer,
+}
+
+impl<R: Read> Read for Progress<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.transfer.is_cancelled() {
+            return Err(cancelled());
+        }
+        let n = self.inner.read(buf)?;
+        self.transfer.done.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Server-side copies and moves, of WebDAV `COPY`/`MOVE` and of `POST /-/copy` and
+/// `POST /-/move?from=<path>&to=<path>` (the latter run in the background). Either way
+/// their progress is reported in `/-/events` and they are cancelled with
+/// `POST /-/cancel?id=<id>`.
+///
+/// Copies are made under a hidden name next to the destination, only renamed into place
+/// (replacing the destination) once complete, so that a failed or cancelled transfer leaves
+/// the tree as it was. Moves are renames, or copies then deletes of the source when it is
+/// on another device.
+pub struct Transfers {
+    storage: Arc<dyn Storage>,
+    root: PathBuf,
+    write_locks: Arc<WriteLocks>,
+    events: Arc<ChangeEvents>,
+    mirror: Option<Arc<Mirror>>,
+    transfers: Mutex<HashMap<u64, Arc<Transfer>>>,
+    last_id: AtomicU64,
+}
+
+impl Transfers {
+    pub fn new(
+        storage: Arc<dyn Storage>,
+        root: PathBuf,
+        write_locks: Arc<WriteLocks>,
+        events: Arc<ChangeEvents>,
+        mirror: Option<Arc<Mirror>>,
+    ) -> Transfers {
+        Transfers {
+            storage,
+            root,
+            write_locks,
+            events,
+            mirror,
+            transfers: Mutex::default(),
+            last_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Track a new transfer of `from` to `to` by the request with `headers`
+    pub fn register(
+        &self,
+        kind: TransferKind,
+        from: &Path,
+        to: &Path,
+        headers: &Headers,
+    ) -> Arc<Transfer> {
+        let transfer = Arc::new(Transfer {
+            id: self.last_id.fetch_add(1, Ordering::Relaxed) + 1,
+            kind,
+            from: from.to_owned(),
+            to: to.to_owned(),
+            user: request_user(headers),
+            replayed: is_replay(headers),
+            total: AtomicU64::new(0),
+            done: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+            state: Mutex::new((State::Running, None)),
+        });
+        let mut transfers = self.transfers.lock().unwrap();
+        transfers.retain(|_, transfer| {
+            transfer
+                .state
+                .lock()
+                .unwrap()
+                .1
+                .is_none_or(|finished| finished.elapsed() < FINISHED_RETENTION)
+        });
+        transfers.insert(transfer.id, transfer.clone());
+        transfer
+    }
+
+    /// Copy (only the directory itself unless `recursive`) or move the `from` of `transfer`
+    /// (described by `metadata`) to its `to`, replacing what is there. The caller checked
+    /// that it may and holds the write locks.
+    pub fn run(&self, transfer: &Transfer, metadata: &Metadata, recursive: bool) -> io::Result<()> {
+        let result = self.transfer(transfer, metadata, recursive);
+        let state = match result {
+            Ok(()) => {
+                info!(
+                    "{}: {} to {}",
+                    if transfer.kind == TransferKind::Move {
+                        "Moved"
+                    } else {
+                        "Copied"
+                    },
+                    transfer.from.display(),
+                    transfer.to.display()
+                );
+                self.events.record(
+                    transfer.kind.as_str(),
+                    &transfer.to.to_string_lossy(),
+                    Some(&transfer.from.to_string_lossy()),
+                    transfer.user.clone(),
+                );
+                if let Some(ref mirror) = self.mirror {
+                    mirror.transferred(
+                        transfer.replayed,
+                        transfer.kind,
+                        &transfer.from,
+                        &transfer.to,
+                    );
+                }
+                State::Done
+            }
+            Err(_) if transfer.is_cancelled() => State::Cancelled,
+            Err(ref err) => {
+                warn!(
+                    "Transfer of {} to {} failed: {}",
+                    transfer.from.display(),
+                    transfer.to.display(),
+                    err
+                );
+                State::Failed(err.to_string())
+            }
+        };
+        *transfer.state.lock().unwrap() = (state, Some(Instant::now()));
+        result
+    }
+
+    fn transfer(
+        &self,
+        transfer: &Transfer,
+        metadata: &Metadata,
+        recursive: bool,
+    ) -> io::Result<()> {
+        let (from, to) = (&transfer.from, &transfer.to);
+        let name = to.file_name().unwrap_or_default().to_string_lossy();
+        let staging = to.with_file_name(format!(".{}.transfer-{}", name, transfer.id));
+        let renamed = transfer.kind == TransferKind::Move
+            && match self.storage.rename(from, &staging) {
+                Ok(()) => true,
+                Err(err) if err.kind() == io::ErrorKind::CrossesDevices => false,
+                Err(err) => return Err(err),
+            };
+        if !renamed {
+            transfer
+                .total
+                .store(self.size(from, metadata, recursive)?, Ordering::Relaxed);
+            if let Err(err) = self.copy(transfer, from, metadata, &staging, recursive) {
+                let _ = self.storage.delete(&staging);
+                return Err(err);
+            }
+        }
+        let placed = match self.storage.stat(to) {
+            Ok(_) => self.storage.delete(to),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+        .and_then(|_| self.storage.rename(&staging, to));
+        if let Err(err) = placed {
+            // Undone, the staged copy is dropped and the renamed source put back
+            if renamed {
+                let _ = self.storage.rename(&staging, from);
+            } else {
+                let _ = self.storage.delete(&staging);
+            }
+            return Err(err);
+        }
+        if transfer.kind == TransferKind::Move && !renamed {
+            self.storage.delete(from)?;
+        }
+        Ok(())
+    }
+
+    fn size(&self, path: &Path, metadata: &Metadata, recursive: bool) -> io::Result<u64> {
+        if !metadata.is_dir {
+            return Ok(metadata.len);
+        }
+        let mut size = 0;
+        if recursive {
+            for entry in self.storage.list(path)? {
+                size += self.size(&path.join(&entry.name), &entry.metadata, true)?;
+            }
+        }
+        Ok(size)
+    }
+
+    fn copy(
+        &self,
+        transfer: &Transfer,
+        from: &Path,
+        metadata: &Metadata,
+        to: &Path,
+        recursive: bool,
+    ) -> io::Result<()> {
+        if transfer.is_cancelled() {
+            return Err(cancelled());
+        }
+        if !metadata.is_dir {
+            let mut data = Progress {
+                inner: self.storage.open_range(from, 0, None)?,
+                transfer,
+            };
+            return self.storage.write(to, &mut data, None).map(|_| ());
+        }
+        self.storage.create_dir(to)?;
+        if recursive {
+            for entry in self.storage.list(from)? {
+                self.copy(
+                    transfer,
+                    &from.join(&entry.name),
+                    &entry.metadata,
+                    &to.join(&entry.name),
+                    true,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `POST /-/copy` or `POST /-/move?from=<path>&to=<path>` (root relative paths),
+    /// `&overwrite=false` refuses to replace the destination: replies `202` with the id of
+    /// the transfer, left to run in the background
+    pub fn start(self: &Arc<Self>, req: &Request, kind: TransferKind) -> IronResult<Response> {
+        if req.method != method::Post {
+            return Ok(Response::with(status::MethodNotAllowed));
+        }
+        let mut from = None;
+        let mut to = None;
+        let mut overwrite = true;
+        for (k, v) in req.url.as_ref().query_pairs() {
+            match &*k {
+                "from" => from = Some(parse_path(&v)?),
+                "to" => to = Some(parse_path(&v)?),
+                "overwrite" => overwrite = v != "false",
+                _ => {}
+            }
+        }
+        let (from, to) = match (from, to) {
+            (Some(from), Some(to)) => (from, to),
+            _ => {
+                return Err(error(
+                    "from and to are required".to_owned(),
+                    status::BadRequest,
+                ))
+            }
+        };
+        let access_file = |path: &Path| {
+            path.iter()
+                .any(|name| is_access_file(&name.to_string_lossy()))
+        };
+        if from.components().next().is_none()
+            || to.starts_with(&from)
+            || to.file_name().is_none()
+            || access_file(&from)
+            || access_file(&to)
+        {
+            return Err(error(
+                format!(
+                    "can not {} {} to {}",
+                    kind.as_str(),
+                    from.display(),
+                    to.display()
+                ),
+                status::Forbidden,
+            ));
+        }
+        // Both the source and the destination directory must allow the client
+        check_access(req, &from)?;
+        check_access(req, &to)?;
+        let metadata = self.storage.stat(&from).map_err(error_io2iron)?;
+        let parent = to.parent().unwrap_or(Path::new(""));
+        if !self
+            .storage
+            .stat(parent)
+            .is_ok_and(|metadata| metadata.is_dir)
+        {
+            return Err(error(
+                format!("{} does not exist", parent.display()),
+                status::Conflict,
+            ));
+        }
+        if !overwrite && self.storage.stat(&to).is_ok() {
+            return Err(error(
+                format!("{} already exists", to.display()),
+                status::PreconditionFailed,
+            ));
+        }
+        let running = self
+            .transfers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|transfer| transfer.is_running())
+            .count();
+        if running >= MAX_RUNNING {
+            return Err(error(
+                "too many transfers running".to_owned(),
+                status::ServiceUnavailable,
+            ));
+        }
+
+        let transfer = self.register(kind, &from, &to, &req.headers);
+        let transfers = self.clone();
+        let id = transfer.id;
+        std::thread::spawn(move || {
+            let locks = transfers
+                .write_locks
+                .lock(&transfers.root.join(&transfer.from))
+                .and_then(|from_lock| {
+                    transfers
+                        .write_locks
+                        .lock(&transfers.root.join(&transfer.to))
+                        .map(|to_lock| (from_lock, to_lock))
+                });
+            match locks {
+                Ok(_locks) => {
+                    let _ = transfers.run(&transfer, &metadata, true);
+                }
+                Err(err) => {
+                    *transfer.state.lock().unwrap() =
+                        (State::Failed(err.error.to_string()), Some(Instant::now()));
+                }
+            }
+        });
+
+        let mut resp = Response::with((status::Accepted, format!(r#"{{"id":{}}}"#, id)));
+        resp.headers.set(ContentType::json());
+        Ok(resp)
+    }
+
+    /// `POST /-/cancel?id=<id>`: stop a running transfer, what it copied is removed again
+    pub fn cancel(&self, req: &Request) -> IronResult<Response> {
+        if req.method != method::Post {
+            return Ok(Response::with(status::MethodNotAllowed));
+        }
+        let id = req
+            .url
+            .as_ref()
+            .query_pairs()
+            .find(|(k, _)| k == "id")
+            .and_then(|(_, v)| v.parse::<u64>().ok());
+        let transfers = self.transfers.lock().unwrap();
+        match id.and_then(|id| transfers.get(&id)) {
+            Some(transfer) if transfer.is_running() => {
+                transfer.cancelled.store(true, Ordering::Relaxed);
+                Ok(Response::with(status::NoContent))
+            }
+            Some(transfer) => Err(error(
+                format!("transfer {} is not running", transfer.id),
+                status::Conflict,
+            )),
+            None => Err(error("no such transfer".to_owned(), status::NotFound)),
+        }
+    }
+
+    /// The running and recently finished transfers, as a JSON array
+    pub fn json(&self) -> String {
+        let transfers = self.transfers.lock().unwrap();
+        let mut transfers = transfers.values().collect::<Vec<_>>();
+        transfers.sort_by_key(|transfer| transfer.id);
+        format!(
+            "[{}]",
+            transfers
+                .iter()
+                .map(|transfer| transfer.json())
+                .collect::<Vec<String>>()
+                .join(",")
+        )
+    }
+}
+
+fn error(msg: String, status: status::Status) -> IronError {
+    IronError::new(StringError(msg), status)
+}
+
+/// Root relative path of the API, `/` separated
+fn parse_path(value: &str) -> IronResult<PathBuf> {
+    let mut path = PathBuf::new();
+    for segment in value.split('/').filter(|s| !s.is_empty()) {
+        if segment == "." || segment == ".." || segment.contains('\\') {
+            return Err(error(
+                format!("invalid path: {}", value),
+                status::BadRequest,
+            ));
+        }
+        path.push(segment);
+    }
+    // Served by the special handlers instead
+    if path.starts_with("-") {
+        return Err(error(format!("can not write {}", value), status::Forbidden));
+    }
+    Ok(path)
+}
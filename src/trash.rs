@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use iron::headers::ContentType;
+use iron::method;
+use iron::status;
+use iron::{IronError, IronResult, Request, Response};
+use path_dedot::ParseDot;
+use tracing::info;
+
+use crate::middlewares::check_access;
+use crate::mirror::Mirror;
+use crate::storage::Storage;
+use crate::sync::check_token;
+use crate::util::{
+    error_io2iron, json_escape, random_alphanumeric, unix_now, StringError, WriteLocks,
+};
+
+/// Header asking `DELETE` to remove the entry for good instead of moving it to the trash
+const PERMANENT_HEADER: &str = "X-Permanent";
+/// Index of the trashed entries, kept in the trash directory
+const INDEX_NAME: &str = "index";
+
+struct Trashed {
+    // Root relative path the entry was deleted from
+    path: String,
+    deleted: u64,
+}
+
+/// Soft delete over the API (`--delete`):
+///
+/// - `DELETE /<path>` moves the file or directory to the trash and replies
+///   `{"id":"<id>","path":"<path>"}`, with `X-Permanent: true` it is removed for good (`204`).
+/// - `GET /-/trash` lists the trashed entries as JSON.
+/// - `POST /-/restore?id=<id>` moves one back where it was, `409` when the path is taken.
+///
+/// Writes need the `X-Csrf-Token` header. Entries are kept as `<trash>/<id>` and indexed by
+/// `<trash>/index` lines of `<id>\t<deleted>\t<path>`.
+pub struct Trash {
+    root: PathBuf,
+    dir: PathBuf,
+    storage: Arc<dyn Storage>,
+    csrf_token: String,
+    write_locks: Arc<WriteLocks>,
+    mirror: Option<Arc<Mirror>>,
+    entries: Mutex<BTreeMap<String, Trashed>>,
+}
+
+impl Trash {
+    pub fn open(
+        root: PathBuf,
+        dir: PathBuf,
+        storage: Arc<dyn Storage>,
+        csrf_token: String,
+        write_locks: Arc<WriteLocks>,
+        mirror: Option<Arc<Mirror>>,
+    ) -> io::Result<Trash> {
+        // Served otherwise, with the deleted files and their paths whatever the access rules
+        let absolute = env::current_dir()?.join(&dir);
+        if absolute.parse_dot()?.starts_with(&root) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is under the root", dir.display()),
+            ));
+        }
+        fs::create_dir_all(&dir)?;
+        let entries = load_index(&dir.join(INDEX_NAME))?;
+        Ok(Trash {
+            root,
+            dir,
+            storage,
+            csrf_token,
+            write_locks,
+            mirror,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// `DELETE` of `fs_path` (under the root)
+    pub fn delete(&self, req: &Request, fs_path: &Path) -> IronResult<Response> {
+        check_token(req, &self.csrf_token)?;
+        if fs_path == self.root || fs_path.starts_with(&self.dir) {
+            return Err(IronError::new(
+                StringError(format!("can not delete {}", fs_path.display())),
+                status::Forbidden,
+            ));
+        }
+        let _lock = self.write_locks.lock(fs_path)?;
+        fs::symlink_metadata(fs_path).map_err(error_io2iron)?;
+        let path = fs_path
+            .strip_prefix(&self.root)
+            .unwrap()
+            .to_string_lossy()
+            .replace('\\', "/");
+
+        let permanent = req
+            .headers
+            .get_raw(PERMANENT_HEADER)
+            .and_then(|values| values.first())
+            .is_some_and(|value| value.eq_ignore_ascii_case(b"true"));
+        if permanent {
+            self.storage
+                .delete(Path::new(&path))
+                .map_err(error_io2iron)?;
+            info!("Deleted: {}", path);
+            if let Some(ref mirror) = self.mirror {
+                mirror.deleted(&req.headers, &path, true);
+            }
+            return Ok(Response::with(status::NoContent));
+        }
+
+        let deleted = unix_now();
+        let suffix = random_alphanumeric(8);
+        let id = format!("{}-{}", deleted, suffix);
+        let mut entries = self.entries.lock().unwrap();
+        fs::rename(fs_path, self.dir.join(&id)).map_err(error_io2iron)?;
+        info!("Moved to trash: {} ({})", path, id);
+        let resp = entry_response(&id, &path);
+        if let Some(ref mirror) = self.mirror {
+            mirror.deleted(&req.headers, &path, false);
+        }
+        entries.insert(id, Trashed { path, deleted });
+        self.save(&entries).map_err(error_io2iron)?;
+        Ok(resp)
+    }
+
+    /// `GET /-/trash`: `[{"id":"1700000000-a1B2c3D4","path":"dir/file.txt","deleted":1700000000}]`
+    pub fn list(&self, req: &Request) -> IronResult<Response> {
+        let items = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            // Leaving out the entries of directories the client can not access
+            .filter(|(_, entry)| check_access(req, Path::new(&entry.path)).is_ok())
+            .map(|(id, entry)| {
+                format!(
+                    r#"{{"id":"{}","path":"{}","deleted":{}}}"#,
+                    json_escape(id),
+                    json_escape(&entry.path),
+                    entry.deleted
+                )
+            })
+            .collect::<Vec<String>>();
+        let mut resp = Response::with((status::Ok, format!("[{}]", items.join(","))));
+        resp.headers.set(ContentType::json());
+        Ok(resp)
+    }
+
+    /// `POST /-/restore?id=<id>`
+    pub fn restore(&self, req: &Request) -> IronResult<Response> {
+        if req.method != method::Post {
+            return Ok(Response::with(status::MethodNotAllowed));
+        }
+        check_token(req, &self.csrf_token)?;
+        let id = req
+            .url
+            .as_ref()
+            .query_pairs()
+            .find(|(k, _)| k == "id")
+            .map(|(_, v)| v.to_string())
+            .unwrap_or_default();
+        let mut entries = self.entries.lock().unwrap();
+        let path = match entries.get(&id) {
+            Some(entry) => entry.path.clone(),
+            None => {
+                return Err(IronError::new(
+                    StringError(format!("not in trash: {}", id)),
+                    status::NotFound,
+                ))
+            }
+        };
+        check_access(req, Path::new(&path))?;
+        // The index is only written by `delete`, still never restored outside the root
+        let target = match self.root.join(&path).parse_dot() {
+            Ok(target) if target.starts_with(&self.root) && target != self.root => {
+                target.to_path_buf()
+            }
+            _ => {
+                return Err(IronError::new(
+                    StringError(format!("invalid path in the trash index: {}", path)),
+                    status::InternalServerError,
+                ))
+            }
+        };
+        let _lock = self.write_locks.lock(&target)?;
+        if fs::symlink_metadata(&target).is_ok() {
+            return Err(IronError::new(
+                StringError(format!("already exists: {}", path)),
+                status::Conflict,
+            ));
+        }
+        fs::create_dir_all(target.parent().unwrap()).map_err(error_io2iron)?;
+        fs::rename(self.dir.join(&id), &target).map_err(error_io2iron)?;
+        info!("Restored from trash: {} ({})", path, id);
+        entries.remove(&id);
+        self.save(&entries).map_err(error_io2iron)?;
+        if let Some(ref mirror) = self.mirror {
+            mirror.uploaded(&req.headers, &path);
+        }
+        Ok(entry_response(&id, &path))
+    }
+
+    fn save(&self, entries: &BTreeMap<String, Trashed>) -> io::Result<()> {
+        let db = self.dir.join(INDEX_NAME);
+        let tmp_path = db.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        for (id, entry) in entries {
+            writeln!(file, "{}\t{}\t{}", id, entry.deleted, entry.path)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, &db)
+    }
+}
+
+fn entry_response(id: &str, path: &str) -> Response {
+    let mut resp = Response::with((
+        status::Ok,
+        format!(
+            r#"{{"id":"{}","path":"{}"}}"#,
+            json_escape(id),
+            json_escape(path)
+        ),
+    ));
+    resp.headers.set(ContentType::json());
+    resp
+}
+
+fn load_index(path: &Path) -> io::Result<BTreeMap<String, Trashed>> {
+    let mut entries = BTreeMap::new();
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(entries),
+        Err(e) => return Err(e),
+    };
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let parts = line.splitn(3, '\t').collect::<Vec<&str>>();
+        if parts.len() != 3 {
+            continue;
+        }
+        if let Ok(deleted) = parts[1].parse() {
+            entries.insert(
+                parts[0].to_owned(),
+                Trashed {
+                    path: parts[2].to_owned(),
+                    deleted,
+                },
+            );
+        }
+    }
+    Ok(entries)
+}
@@ -1,14 +1,26 @@
+use std::collections::HashSet;
 use std::error::Error;
 use std::fmt;
-use std::io;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use chrono::{DateTime, Local, TimeZone};
 use iron::headers;
+use iron::mime::{Mime, TopLevel};
 use iron::status;
-use iron::{IronError, Response};
+use iron::{IronError, Request, Response};
 use percent_encoding::{utf8_percent_encode, AsciiSet};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::storage::Metadata;
 
 /// https://url.spec.whatwg.org/#fragment-percent-encode-set
 const FRAGMENT_ENCODE_SET: &AsciiSet = &percent_encoding::CONTROLS
@@ -22,7 +34,51 @@ const PATH_ENCODE_SET: &AsciiSet = &FRAGMENT_ENCODE_SET.add(b'#').add(b'?').add(
 const PATH_SEGMENT_ENCODE_SET: &AsciiSet = &PATH_ENCODE_SET.add(b'/').add(b'%').add(b'[').add(b']');
 
 // Site favicon image
-pub const FAVICON_IMAGE: &str = r#"<link rel="shortcut icon" href="data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAGAAAABgCAYAAADimHc4AAAACXBIWXMAAAsTAAALEwEAmpwYAAAPyElEQVR4nO1dfXQU1RUfq7a2p/ZU29ra09P/+lf/6Ac9p5bahpnshiAIRUCzGyCQnSwBlA8Bc1Qg8iFqwPANUkAkmVnCkg8+TEKygYAmIElQDCEQAkkApQpqwMzsZ/T13EmWzLyZ/ZjZ2ewS9p5z/9idmTfv3d9779533313CCJBCUpQghKUoAQlKEEJSlCC4oieZG89klzYM4Ji+GyK4TZQLF9FsXwTyXKXKYa/STKcp4/5r+E/kuE/JRm+mmS4jSNYflYy22NIsd96NNbtuGsoaRd6iLL1GEmWf4tk+dMky31HsTyKhKEMKIti+dUUw41KrUA/inU7445Ilh9Gsdx66NWRCjwM7qZYvgBGB4HQfcS9Skm16AGK4aZRDN8yCEJXHh0M3wp1GLYNPUjcKzTJjn5IMlwWyXIdagWWzPJo6kEnevOER3YN/oNrmsBguE6S5WdC3YihTCNYZxLJ8ue0CMm034nePeNFjg6fwPh1//87z3hR2n5tQJAMd5Es5FKIoUaUrefXJMMXBuzZNh5llbvQ8g/dKGWP/PpLR1yoql/AoQAAhnsXHnHJ7oGy4R3wLnhnEDDYJDv3G2IoEPQoiuFvKDXUYOPRghoXKj7vRe+3e9GkUqdsynnrhEci3HAA8POqeo9Qhvg+eEf5JR8qbvWhF2tcQh0URwPLfwkWGXG30iQ7up9i+OVKpiQIZa7DjUrOD0wpsw7Le+zyOreiYMMFABh6PH7v7CrXnetQhznVbhlQfSAIdV8BbSHuJhq+4+bD/Ysmxbl816cDgnd0+NDGRrlShZERSKhqAACe75CDu6lJOrKgTmllAXVHVZId/ZS4GwhWniTDnVDq9TlHXajqslQ4le0+NL5Y2vBny5zC/3oBUHnJh57DhDtunxNVYO8A3bH0A7eyfmC4RmNBz2NEPJNht/P3FMtfwCs/1u5EOz9RnssXH5NOEUYbj9gW6QiJFABg5qwXGbBnlhxXnuJ2fOJBT9vlo4FkuDZoIxGP9KT921+BGYdXGkxC8Vwv5rILXkHg4vtzaoNPPVoBAIYRiFtFUAele8EwUJqSAARoKxF3cz7DNeKVpctdgsURSCCv1Ep7/5i9TnToojdqAEDZ8A7xc68cC6zowTKzvC/XHyTLNcSNTgCXAngg8UpmVbiEuTeYMFKLpMJ4vV55mtILAOAVdVLQRxU5UXl7YNArLvmEjqSkmOPCOqIY/g28ctBrDmPKFmcwMSnRM0/t7bPPow0A9Gp4l/hZACXYM9CRMhVGApioMRX+CJYbidv5YG0E61HA1R0+9EyJtDFLAyhEvQEAfhVT/BNKeKFOoYDDLSmh7bFarP2rkHtcWC1i1k5pAKUm5l3NXqmJagusDKMBQMkFr8zUfA9bmwRSzGMw6whkEBO3BclyRRIh2Hj03wCmJs6LaqTDOas8PMtHLwCA8Xl90ZHw6rDtY4/QVqllxDODKnzYzMAFEMyawBc7eC/a0OgZdAA2NEhX32AdhdJbfoa2yiwjWw81KMIHnznF8OcVFNI9zSTDtQ3KdifF8LNj3VgqXpnhs6MqfNi6g92jmDeUjU8mWe5KVHfVSJabHutGUnHOyYVcRnSkj9B9+NwPPn01im9Lk1TxTSxRrzz1UsJ+hjWAuByoo5rnQQaSUcDwrVGJthjBOP+JNxo8jGoqi3s+51W7Yg7A3Gp3WB7SQGxr8co2c5IK+Sd0B4BkuHfEL4GludrGWjDbe/VJT8wByMMiLLSsSabjbgqG26Kr8MG8olj+G/FLYBdLbUVx+59ROYKiAUAhtiofu8+pugx8Rw9CJXU1SUfYuNHiF4ws4kPuWCl5PyXKysYH9ZYOFgDQDnxlC34fNWXAzlpKEaaQGW6UbgCQDPe2uHDYQFfbUPC1iMsA5efoiD0AwBOKpWUVNKsfmTMr8WmIz9MRAP5jceHrGtQpKuD8U1JlN6NSuwLWGwBrhVR4WtoHz0imIZY/rYvwk3ff/gXuci4NsMUYjJdh4SGwPeiIEwAg8EtcFtRVbRngBZYCwH2nS2i8EFiFRRNoaSTuAX09xCaIYxABWIF1DgBESznjsOiOEYU9yZEDYONfiHT+B87G5kjwRjriBAB8+pilcXrE9QAcEokcAJbbFOnwBJ56QNo7IIjWEScAQMiMuKyMQ9pGee4HmJua4TboAADvEBcKylRL5fDtvEjWAA6dAYC64KE0WsrJ/0gW4VcVMQD4AYodZ7RNHeMxU29fq3aB6Q2A/by0PNiv1lLOdmwkwZm1yAFguS5xoXs1Cg6PxzkQZvyPYxAAgLqIy4K6aimn6JzMEuqIGACS5b/CGzvU+Jlil3DCBgJ2wThY3+hBG5s8wn8TSjSeuhF0AH8zcgAYzhNrAVFR5JeOuoU96XUNygzXcrAIvnCZZDi3HgC4Yy0kKkqcc9QdUPA4K50zCM2cN3IAhugUNL7YKXgxwwUAOO+k/BxDCO6OGAB8DxgUjR5K+GBbbJXwsSs+VH+tV+DjXb1o7UkeWdfY0FPT56DRmfPQkq1F6Hin6849d+694kM14SphhvtcjxHQHA0zFM5oOWIIwIdXB4R6pNMnCN9opiVsXboa1V7iFEDoRY5OeZkgmyiYodKjRmt1WoixMV6I1YsAqOroFXo9DgDwtJyVyNF2SwbCBwBCCJcGxfDlkQPQlyTjTqGgjLQIbAp2iFp85tcRCwBEwoQQdCXh+9k8bwmqbLkpAwFGkXgkvIa5ImAbV48R8PxQdMbViwS5vy04AMCTZuegg59cVwShpjPApgzLz9U9DvQ/xdpWiQvjzB1dLxLivlavTOCLVq6R/Td+xgJU2nhVBkLdtT7FPB4LcYHMAPqcesQ2ZMo0WDC4pzDcs2COQQCAbZGPAKfTiV7L3yz7/2nLHFT04UUZCLVdvSj9oHj+575P2tX9c0IP6s+3MzB9aIiIePuU1EKAKckRJwCAPsIF7fV6kdvtRnlb35Vde2rabFRwtFlSRtkFH9rQ6EVTD7j4/jqd0UX4/QCsETf2edFJ83AZDkLjKQMccQLAltPKAAB7PB60pWCP7HrqlGy0vaLhThnQPlisrW9wu54rdTaDzHQDAEIs8INtakNKcI8jnNk9fDn2ANRd7UXrGj0BAfAzW/a+7J6UyVa0qfQ4qrvWKxgVd1bNp9yctUKH7chggVlqYyiB8cNxbBgHsqMNQNXlXkFooQAALq10oJT0LCkI6Vlo2e5K3G3xZW4teoDQk0iW2ypuMBzxUdvo6YekltCajzwxB6D0gi9sAICrj9ej1ClW2f3Z+XtFI8CzVlfhCwDscQ4XNxgCUveo7MH40Z75DlfMAYAgLDUAANc1nEajM2bKnqHfKoDpB+U3ef6qOwAQci3kWFOR0SRUDOWzZc6YA7D1Y/UAADc1t6Bxlhdkz6Uv3tA9zGqNTg66oXRAYx3malYLAPC5tnY0cYbch2Qw0wefmDT/x1E5oqQl2d5QBcDr9aLLXVdR2qwFCu4Ly7HU9PSf6Q4CZBeMtfCoOAIA+LPrX6Cp815W8iE1Jpmsv4xC2kmpLrjXAfB6veiLGzeRNSdXCYTW1HT6d7qCQDLOf4OvQ4ubGjJmjcYOamw9PfgHtQ9d9OkKAPA33bfQnKWrFHSCpdNgmvEHXUGA1I74yhbPBReIwXoSPztTg18oUgD8rgM9AQD+tqcH5azKVxoJ/0uZPP1PugEACSrwZB3gqt4fhqcU9wsZbOoDtSIBAOq4XucpSMyBPKkGs+WblOeyhusGQl+mc6mrevKB0An3qhX2iJep3GmLBABIzCcW/uamwM44rRzIk2ow0ZwxPVO/7LyQtAgXBpx+CZmwCYuzGWN3CluD0QYAnIjwLjEAe1v1ByCYJ9Vgpj3GdHqifglaFXKEgr8fT1EpVYJewasqfiZQllw9AVhV17ca9wsfpqLaTl9UAPDze/YypXWCz2jO0ucgHySwg0R2uFDg7FWw6ehlLOQPEj6Fe0JRCwCQycsfn+QHoPCsV3BJ6A1AD8cJ64Pm822C72hu7htyEEyWZkLntJVtuGDM+50Bz5SVnpenrQw375AWAMTOQKH3N3rQ0U5fRACA/Q9z/at569ELS15Hk+fkoDHTZilZQQr6wMITehIkNVUCAQ4/BwpDWayQuNUWRvRdpIlbAQD27IBTTisAMM9bFi4JS+DyEUDXEnqTMBIUpiM4mA3CxpVzRbtPMF/F90LC1FA7bmoAgHdCzmrx/ZuavEKEm1oASiqq0afnLkj+O+ioVS18g8lyfWQa/UciGgQ6IWjybuwg9EYsiwow5P+PZvJuiAUS7wuHA0BB8QHhGtj40vmeRxOz58ssHYPJcs1gppuMZrrcYKLfM5gteUYT/WKyKWvC2MzMh4loUr91tCLc9PWzFdLXr9Qhfb3f6pFYaIcHgm5rr/hQwdngZihMM+8we+9cGznZiq5+fl1yz7t7S3Er52Jubu4PiFhT/2epJCtm8Qr4xRqXcF4MkrYqfcABsploBQBOuCh+wKHdKyhfiAfyH84IBAAIf+MuVnZ98+49EgBufPW1bJfMkJ41logHArcF7juSsK1vj3lZoE+YHHWh6svhAwBzPjyD3wNlw6FsCK8MxxXhcrnRm5t3KM7hYzOfR923bktAkN0bDSUbCVFMD6k146IpzI/4gJ8pPYyP+IRyR/O8Ey1dszGoIrUfqpQAcLGjSxY1YUjL/BsRT9Sf9jJbS/K/ZP9nrBROqsDpFVnipHABOOVpx4W7YHlegNXrwG+w+cHnIwZh4crV+DO7iXik/gyM02K1udMfQFW37pRnkt2O7g/DbOwwpln/bjRbnOL/a080SACAVS+22vXqviGjNyWzzn9Ami/INBVtwZMs9xl8NnHtKc+fxXUIsWA6Z0jL/G3/fdvF18DFgFtMmQsX42WsJO4GSoUIvL4wyNV6fcwTTirCN22ED4TucQ7PzUWKpqHRRH+lLHzLydHmmY/474PFk9FMfy++p+XCRQkIZYdr8GnoZlSiJKJNKfZbj8L5BMg6Ivqc7Zn+T9d+7f+cLYRNCv+xPATFHqJYLh8y/EJ8/pht6CfhvMtootco9PyapEmzZF/KMJoth8X3rVi/VbYr9ox1rqSsFDNtjYqQhgoNs1ofNJjofBgJ4CYwmujVSRkZDyndazDRI8XCHTVlBrr+xZcSELbb9uGjqZUgiHv36606030Gs6VFLOBtjF0CQHtnl4I+0cn/nyCCMJjpLLFwx2fNQbe/7UFd1z5H63cWoqenz1bSJ1sTstOJQKkazPQNsYAti5bIFmISU9ZM5+j1/gQRwihYHmr90K/Mu8EUjVrQ7r1KxsnZjxlNtCuI8C9Dr0/KyNDnsF6C5GQwWXYp9PjaZDM9Li7c0UOdRqZbHzeY6DqDib5tNNEFyWmZf1FTwP8BFGtYl0ixR4gAAAAASUVORK5CYII=" />"#;
+const FAVICON_IMAGE: &str = r#"<link rel="shortcut icon" href="data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAGAAAABgCAYAAADimHc4AAAACXBIWXMAAAsTAAALEwEAmpwYAAAPyElEQVR4nO1dfXQU1RUfq7a2p/ZU29ra09P/+lf/6Ac9p5bahpnshiAIRUCzGyCQnSwBlA8Bc1Qg8iFqwPANUkAkmVnCkg8+TEKygYAmIElQDCEQAkkApQpqwMzsZ/T13EmWzLyZ/ZjZ2ewS9p5z/9idmTfv3d9779533313CCJBCUpQghKUoAQlKEEJSlCC4oieZG89klzYM4Ji+GyK4TZQLF9FsXwTyXKXKYa/STKcp4/5r+E/kuE/JRm+mmS4jSNYflYy22NIsd96NNbtuGsoaRd6iLL1GEmWf4tk+dMky31HsTyKhKEMKIti+dUUw41KrUA/inU7445Ilh9Gsdx66NWRCjwM7qZYvgBGB4HQfcS9Skm16AGK4aZRDN8yCEJXHh0M3wp1GLYNPUjcKzTJjn5IMlwWyXIdagWWzPJo6kEnevOER3YN/oNrmsBguE6S5WdC3YihTCNYZxLJ8ue0CMm034nePeNFjg6fwPh1//87z3hR2n5tQJAMd5Es5FKIoUaUrefXJMMXBuzZNh5llbvQ8g/dKGWP/PpLR1yoql/AoQAAhnsXHnHJ7oGy4R3wLnhnEDDYJDv3G2IoEPQoiuFvKDXUYOPRghoXKj7vRe+3e9GkUqdsynnrhEci3HAA8POqeo9Qhvg+eEf5JR8qbvWhF2tcQh0URwPLfwkWGXG30iQ7up9i+OVKpiQIZa7DjUrOD0wpsw7Le+zyOreiYMMFABh6PH7v7CrXnetQhznVbhlQfSAIdV8BbSHuJhq+4+bD/Ysmxbl816cDgnd0+NDGRrlShZERSKhqAACe75CDu6lJOrKgTmllAXVHVZId/ZS4GwhWniTDnVDq9TlHXajqslQ4le0+NL5Y2vBny5zC/3oBUHnJh57DhDtunxNVYO8A3bH0A7eyfmC4RmNBz2NEPJNht/P3FMtfwCs/1u5EOz9RnssXH5NOEUYbj9gW6QiJFABg5qwXGbBnlhxXnuJ2fOJBT9vlo4FkuDZoIxGP9KT921+BGYdXGkxC8Vwv5rILXkHg4vtzaoNPPVoBAIYRiFtFUAele8EwUJqSAARoKxF3cz7DNeKVpctdgsURSCCv1Ep7/5i9TnToojdqAEDZ8A7xc68cC6zowTKzvC/XHyTLNcSNTgCXAngg8UpmVbiEuTeYMFKLpMJ4vV55mtILAOAVdVLQRxU5UXl7YNArLvmEjqSkmOPCOqIY/g28ctBrDmPKFmcwMSnRM0/t7bPPow0A9Gp4l/hZACXYM9CRMhVGApioMRX+CJYbidv5YG0E61HA1R0+9EyJtDFLAyhEvQEAfhVT/BNKeKFOoYDDLSmh7bFarP2rkHtcWC1i1k5pAKUm5l3NXqmJagusDKMBQMkFr8zUfA9bmwRSzGMw6whkEBO3BclyRRIh2Hj03wCmJs6LaqTDOas8PMtHLwCA8Xl90ZHw6rDtY4/QVqllxDODKnzYzMAFEMyawBc7eC/a0OgZdAA2NEhX32AdhdJbfoa2yiwjWw81KMIHnznF8OcVFNI9zSTDtQ3KdifF8LNj3VgqXpnhs6MqfNi6g92jmDeUjU8mWe5KVHfVSJabHutGUnHOyYVcRnSkj9B9+NwPPn01im9Lk1TxTSxRrzz1UsJ+hjWAuByoo5rnQQaSUcDwrVGJthjBOP+JNxo8jGoqi3s+51W7Yg7A3Gp3WB7SQGxr8co2c5IK+Sd0B4BkuHfEL4GludrGWjDbe/VJT8wByMMiLLSsSabjbgqG26Kr8MG8olj+G/FLYBdLbUVx+59ROYKiAUAhtiofu8+pugx8Rw9CJXU1SUfYuNHiF4ws4kPuWCl5PyXKysYH9ZYOFgDQDnxlC34fNWXAzlpKEaaQGW6UbgCQDPe2uHDYQFfbUPC1iMsA5efoiD0AwBOKpWUVNKsfmTMr8WmIz9MRAP5jceHrGtQpKuD8U1JlN6NSuwLWGwBrhVR4WtoHz0imIZY/rYvwk3ff/gXuci4NsMUYjJdh4SGwPeiIEwAg8EtcFtRVbRngBZYCwH2nS2i8EFiFRRNoaSTuAX09xCaIYxABWIF1DgBESznjsOiOEYU9yZEDYONfiHT+B87G5kjwRjriBAB8+pilcXrE9QAcEokcAJbbFOnwBJ56QNo7IIjWEScAQMiMuKyMQ9pGee4HmJua4TboAADvEBcKylRL5fDtvEjWAA6dAYC64KE0WsrJ/0gW4VcVMQD4AYodZ7RNHeMxU29fq3aB6Q2A/by0PNiv1lLOdmwkwZm1yAFguS5xoXs1Cg6PxzkQZvyPYxAAgLqIy4K6aimn6JzMEuqIGACS5b/CGzvU+Jlil3DCBgJ2wThY3+hBG5s8wn8TSjSeuhF0AH8zcgAYzhNrAVFR5JeOuoU96XUNygzXcrAIvnCZZDi3HgC4Yy0kKkqcc9QdUPA4K50zCM2cN3IAhugUNL7YKXgxwwUAOO+k/BxDCO6OGAB8DxgUjR5K+GBbbJXwsSs+VH+tV+DjXb1o7UkeWdfY0FPT56DRmfPQkq1F6Hin6849d+694kM14SphhvtcjxHQHA0zFM5oOWIIwIdXB4R6pNMnCN9opiVsXboa1V7iFEDoRY5OeZkgmyiYodKjRmt1WoixMV6I1YsAqOroFXo9DgDwtJyVyNF2SwbCBwBCCJcGxfDlkQPQlyTjTqGgjLQIbAp2iFp85tcRCwBEwoQQdCXh+9k8bwmqbLkpAwFGkXgkvIa5ImAbV48R8PxQdMbViwS5vy04AMCTZuegg59cVwShpjPApgzLz9U9DvQ/xdpWiQvjzB1dLxLivlavTOCLVq6R/Td+xgJU2nhVBkLdtT7FPB4LcYHMAPqcesQ2ZMo0WDC4pzDcs2COQQCAbZGPAKfTiV7L3yz7/2nLHFT04UUZCLVdvSj9oHj+575P2tX9c0IP6s+3MzB9aIiIePuU1EKAKckRJwCAPsIF7fV6kdvtRnlb35Vde2rabFRwtFlSRtkFH9rQ6EVTD7j4/jqd0UX4/QCsETf2edFJ83AZDkLjKQMccQLAltPKAAB7PB60pWCP7HrqlGy0vaLhThnQPlisrW9wu54rdTaDzHQDAEIs8INtakNKcI8jnNk9fDn2ANRd7UXrGj0BAfAzW/a+7J6UyVa0qfQ4qrvWKxgVd1bNp9yctUKH7chggVlqYyiB8cNxbBgHsqMNQNXlXkFooQAALq10oJT0LCkI6Vlo2e5K3G3xZW4teoDQk0iW2ypuMBzxUdvo6YekltCajzwxB6D0gi9sAICrj9ej1ClW2f3Z+XtFI8CzVlfhCwDscQ4XNxgCUveo7MH40Z75DlfMAYAgLDUAANc1nEajM2bKnqHfKoDpB+U3ef6qOwAQci3kWFOR0SRUDOWzZc6YA7D1Y/UAADc1t6Bxlhdkz6Uv3tA9zGqNTg66oXRAYx3malYLAPC5tnY0cYbch2Qw0wefmDT/x1E5oqQl2d5QBcDr9aLLXVdR2qwFCu4Ly7HU9PSf6Q4CZBeMtfCoOAIA+LPrX6Cp815W8iE1Jpmsv4xC2kmpLrjXAfB6veiLGzeRNSdXCYTW1HT6d7qCQDLOf4OvQ4ubGjJmjcYOamw9PfgHtQ9d9OkKAPA33bfQnKWrFHSCpdNgmvEHXUGA1I74yhbPBReIwXoSPztTg18oUgD8rgM9AQD+tqcH5azKVxoJ/0uZPP1PugEACSrwZB3gqt4fhqcU9wsZbOoDtSIBAOq4XucpSMyBPKkGs+WblOeyhusGQl+mc6mrevKB0An3qhX2iJep3GmLBABIzCcW/uamwM44rRzIk2ow0ZwxPVO/7LyQtAgXBpx+CZmwCYuzGWN3CluD0QYAnIjwLjEAe1v1ByCYJ9Vgpj3GdHqifglaFXKEgr8fT1EpVYJewasqfiZQllw9AVhV17ca9wsfpqLaTl9UAPDze/YypXWCz2jO0ucgHySwg0R2uFDg7FWw6ehlLOQPEj6Fe0JRCwCQycsfn+QHoPCsV3BJ6A1AD8cJ64Pm822C72hu7htyEEyWZkLntJVtuGDM+50Bz5SVnpenrQw375AWAMTOQKH3N3rQ0U5fRACA/Q9z/at569ELS15Hk+fkoDHTZilZQQr6wMITehIkNVUCAQ4/BwpDWayQuNUWRvRdpIlbAQD27IBTTisAMM9bFi4JS+DyEUDXEnqTMBIUpiM4mA3CxpVzRbtPMF/F90LC1FA7bmoAgHdCzmrx/ZuavEKEm1oASiqq0afnLkj+O+ioVS18g8lyfWQa/UciGgQ6IWjybuwg9EYsiwow5P+PZvJuiAUS7wuHA0BB8QHhGtj40vmeRxOz58ssHYPJcs1gppuMZrrcYKLfM5gteUYT/WKyKWvC2MzMh4loUr91tCLc9PWzFdLXr9Qhfb3f6pFYaIcHgm5rr/hQwdngZihMM+8we+9cGznZiq5+fl1yz7t7S3Er52Jubu4PiFhT/2epJCtm8Qr4xRqXcF4MkrYqfcABsploBQBOuCh+wKHdKyhfiAfyH84IBAAIf+MuVnZ98+49EgBufPW1bJfMkJ41logHArcF7juSsK1vj3lZoE+YHHWh6svhAwBzPjyD3wNlw6FsCK8MxxXhcrnRm5t3KM7hYzOfR923bktAkN0bDSUbCVFMD6k146IpzI/4gJ8pPYyP+IRyR/O8Ey1dszGoIrUfqpQAcLGjSxY1YUjL/BsRT9Sf9jJbS/K/ZP9nrBROqsDpFVnipHABOOVpx4W7YHlegNXrwG+w+cHnIwZh4crV+DO7iXik/gyM02K1udMfQFW37pRnkt2O7g/DbOwwpln/bjRbnOL/a080SACAVS+22vXqviGjNyWzzn9Ami/INBVtwZMs9xl8NnHtKc+fxXUIsWA6Z0jL/G3/fdvF18DFgFtMmQsX42WsJO4GSoUIvL4wyNV6fcwTTirCN22ED4TucQ7PzUWKpqHRRH+lLHzLydHmmY/474PFk9FMfy++p+XCRQkIZYdr8GnoZlSiJKJNKfZbj8L5BMg6Ivqc7Zn+T9d+7f+cLYRNCv+xPATFHqJYLh8y/EJ8/pht6CfhvMtootco9PyapEmzZF/KMJoth8X3rVi/VbYr9ox1rqSsFDNtjYqQhgoNs1ofNJjofBgJ4CYwmujVSRkZDyndazDRI8XCHTVlBrr+xZcSELbb9uGjqZUgiHv36606030Gs6VFLOBtjF0CQHtnl4I+0cn/nyCCMJjpLLFwx2fNQbe/7UFd1z5H63cWoqenz1bSJ1sTstOJQKkazPQNsYAti5bIFmISU9ZM5+j1/gQRwihYHmr90K/Mu8EUjVrQ7r1KxsnZjxlNtCuI8C9Dr0/KyNDnsF6C5GQwWXYp9PjaZDM9Li7c0UOdRqZbHzeY6DqDib5tNNEFyWmZf1FTwP8BFGtYl0ixR4gAAAAASUVORK5CYII=" />"#;
+
+/// `--favicon` and `--brand-html`, read once at startup
+struct Branding {
+    favicon_image: Option<String>,
+    brand_html: String,
+}
+
+static BRANDING: OnceLock<Branding> = OnceLock::new();
+
+/// Load the custom favicon (embedded as a data URI) and the HTML shown at the top of every
+/// page, before the server starts
+pub fn load_branding(favicon: Option<&Path>, brand_html: Option<&Path>) -> io::Result<()> {
+    let favicon_image = match favicon {
+        Some(path) => Some(format!(
+            r#"<link rel="shortcut icon" href="data:{};base64,{}" />"#,
+            mime_guess::from_path(path).first_or_octet_stream(),
+            base64::encode(&fs::read(path)?)
+        )),
+        None => None,
+    };
+    let brand_html = match brand_html {
+        Some(path) => fs::read_to_string(path)?,
+        None => String::new(),
+    };
+    let _ = BRANDING.set(Branding {
+        favicon_image,
+        brand_html,
+    });
+    Ok(())
+}
+
+pub fn favicon_image() -> &'static str {
+    BRANDING
+        .get()
+        .and_then(|branding| branding.favicon_image.as_deref())
+        .unwrap_or(FAVICON_IMAGE)
+}
+
+pub fn brand_html() -> &'static str {
+    BRANDING
+        .get()
+        .map(|branding| branding.brand_html.as_str())
+        .unwrap_or_default()
+}
 
 pub fn root_link(baseurl: &str) -> String {
     format!(
@@ -58,6 +114,183 @@ pub fn enable_string(value: bool) -> String {
     (if value { "enabled" } else { "disabled" }).to_owned()
 }
 
+/// Parse human readable size like `512`, `100K`, `5G` (1024 based)
+pub fn parse_size(s: &str) -> Result<u64, StringError> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(idx) => s.split_at(idx),
+        None => (s, ""),
+    };
+    let num = num
+        .parse::<f64>()
+        .map_err(|e| StringError(format!("invalid size {}: {}", s, e)))?;
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1 << 10,
+        "m" | "mb" => 1 << 20,
+        "g" | "gb" => 1 << 30,
+        "t" | "tb" => 1 << 40,
+        _ => return Err(StringError(format!("invalid size unit: {}", s))),
+    };
+    Ok((num * multiplier as f64) as u64)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compare secrets (tokens, MACs) in a time independent of where they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// `len` random letters and digits, for the temporary file names and the generated secrets
+pub fn random_alphanumeric(len: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|dur| dur.as_secs())
+        .unwrap_or(0)
+}
+
+/// Hex encoded sha256 of the file content
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+/// The DER blocks of a PEM file, with their labels (`CERTIFICATE`, `PRIVATE KEY`, ...)
+#[cfg(any(feature = "rustls", feature = "signing"))]
+pub fn pem_blocks(path: &Path) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let pem = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let mut blocks = Vec::new();
+    let mut current: Option<(String, String)> = None;
+    for line in pem.lines().map(str::trim) {
+        if let Some(label) = line
+            .strip_prefix("-----BEGIN ")
+            .and_then(|line| line.strip_suffix("-----"))
+        {
+            current = Some((label.to_owned(), String::new()));
+        } else if line.starts_with("-----END ") {
+            if let Some((label, data)) = current.take() {
+                let der = base64::decode(&data)
+                    .map_err(|err| format!("{}: invalid {}: {}", path.display(), label, err))?;
+                blocks.push((label, der));
+            }
+        } else if let Some((_, ref mut data)) = current {
+            data.push_str(line);
+        }
+    }
+    Ok(blocks)
+}
+
+/// Escape a string to be put in a JSON string literal
+pub fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            // Safe to embed in `<script>`
+            '<' => escaped.push_str("\\u003c"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Whether `name` matches the shell wildcard `pattern`: `*` any run of characters, `?` any
+/// one, `[abc]`, `[a-z]` or `[!a-z]` one of (or none of) a set
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let name = name.chars().collect::<Vec<char>>();
+    let (mut p, mut n) = (0, 0);
+    // Pattern position after the last `*`, and the name position it now matches up to
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, n));
+            continue;
+        }
+        if let Some(len) = glob_match_one(&pattern[p..], name[n]) {
+            p += len;
+            n += 1;
+            continue;
+        }
+        // Let the last `*` take one more character
+        match star {
+            Some((star_p, star_n)) => {
+                p = star_p;
+                n = star_n + 1;
+                star = Some((star_p, n));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Length of the start of `pattern` matching `c`, `None` when it does not
+fn glob_match_one(pattern: &[char], c: char) -> Option<usize> {
+    match *pattern.first()? {
+        '?' => Some(1),
+        '[' => {
+            let negated = matches!(pattern.get(1), Some('!') | Some('^'));
+            let start = if negated { 2 } else { 1 };
+            // `]` right after the opening bracket is part of the set, an unclosed `[` is literal
+            let end = match pattern
+                .get(start + 1..)
+                .and_then(|rest| rest.iter().position(|&c| c == ']'))
+            {
+                Some(pos) => start + 1 + pos,
+                None => return (c == '[').then_some(1),
+            };
+            let set = &pattern[start..end];
+            let mut found = false;
+            let mut i = 0;
+            while i < set.len() {
+                if set.get(i + 1) == Some(&'-') && i + 2 < set.len() {
+                    found |= set[i] <= c && c <= set[i + 2];
+                    i += 3;
+                } else {
+                    found |= set[i] == c;
+                    i += 1;
+                }
+            }
+            (found != negated).then_some(end + 1)
+        }
+        p => (p == c).then_some(1),
+    }
+}
+
+/// Quote a CSV field (RFC 4180) when it contains a separator, quote or line break
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Escape a TSV field, tabs and line breaks can not appear in one
+pub fn tsv_field(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
 pub fn encode_link_path(path: &[String]) -> String {
     path.iter()
         .map(|s| utf8_percent_encode(s, PATH_SEGMENT_ENCODE_SET).to_string())
@@ -74,6 +307,354 @@ pub fn error_io2iron(err: io::Error) -> IronError {
     IronError::new(err, status)
 }
 
+/// Write `data` to a temporary file under `tmp_dir`, fsync it and rename it to `target`,
+/// so a dropped connection never leaves a truncated file at `target`.
+pub fn save_atomic<R: Read>(data: &mut R, tmp_dir: &Path, target: &Path) -> io::Result<u64> {
+    save_atomic_checked(data, tmp_dir, target, |_| Ok(()))
+}
+
+/// Same as `save_atomic`, but `check` is run on the complete temporary file first and the
+/// target is left untouched when it fails.
+pub fn save_atomic_checked<R, F>(
+    data: &mut R,
+    tmp_dir: &Path,
+    target: &Path,
+    check: F,
+) -> io::Result<u64>
+where
+    R: Read,
+    F: FnOnce(&Path) -> io::Result<()>,
+{
+    let suffix = random_alphanumeric(8);
+    let mut filename = target
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    // Leave room for the prefix and suffix within the 255 bytes of a file name
+    let mut end = filename.len().min(240);
+    while !filename.is_char_boundary(end) {
+        end -= 1;
+    }
+    filename.truncate(end);
+    let tmp_path = tmp_dir.join(format!(".{}.{}.part", filename, suffix));
+    let result = fs::File::create(&tmp_path)
+        .and_then(|mut file| {
+            let size = io::copy(data, &mut file)?;
+            file.sync_all()?;
+            Ok(size)
+        })
+        .and_then(|size| check(&tmp_path).map(|_| size))
+        .and_then(|size| move_file(&tmp_path, target).map(|_| size));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Rename `from` to `to`, or when they are on different file systems (eg: `--upload-tmp-dir`
+/// on a larger disk than a tmpfs root) stream it to a temporary file next to `to` first, so
+/// `to` still appears complete at once.
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(ref err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            let dir = to.parent().unwrap_or_else(|| Path::new("."));
+            save_atomic(&mut fs::File::open(from)?, dir, to)?;
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/// Paths being written right now, a second writer of the same path is refused (`423 Locked`)
+/// instead of racing the first one.
+#[derive(Default)]
+pub struct WriteLocks(Mutex<HashSet<PathBuf>>);
+
+/// Write lock on one path, released when dropped
+pub struct WriteLock<'a> {
+    locks: &'a WriteLocks,
+    path: PathBuf,
+}
+
+impl WriteLocks {
+    pub fn lock(&self, path: &Path) -> Result<WriteLock<'_>, IronError> {
+        if !self.0.lock().unwrap().insert(path.to_owned()) {
+            return Err(IronError::new(
+                StringError(format!(
+                    "{} is being written by another request",
+                    path.display()
+                )),
+                status::Locked,
+            ));
+        }
+        Ok(WriteLock {
+            locks: self,
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for WriteLock<'_> {
+    fn drop(&mut self) {
+        self.locks.0.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// Probe whether the process can create files in `dir`: an unnamed `O_TMPFILE` on Linux
+/// (nothing is left behind), elsewhere a uniquely named file which is removed again.
+pub fn can_write(dir: &Path) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        match fs::OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_TMPFILE)
+            .open(dir)
+        {
+            Ok(_) => return true,
+            // Not supported by the filesystem, fall back to a named file
+            Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {}
+            Err(_) => return false,
+        }
+    }
+    let suffix = random_alphanumeric(8);
+    let probe = dir.join(format!(".write-probe.{}", suffix));
+    match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+    {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Size of a regular file, or of a block device (whose metadata reports 0)
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub fn file_size(path: &Path, metadata: &fs::Metadata) -> u64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if metadata.file_type().is_block_device() {
+            return fs::File::open(path)
+                .and_then(|mut file| file.seek(SeekFrom::End(0)))
+                .unwrap_or(0);
+        }
+    }
+    metadata.len()
+}
+
+/// Weak ETag of a served file, from its size and modified time
+pub fn file_etag(metadata: &Metadata) -> headers::EntityTag {
+    let time = filetime::FileTime::from_system_time(metadata.modified);
+    headers::EntityTag::weak(format!("{:x}-{:x}.0", metadata.len, time.seconds()))
+}
+
+/// Check the `If-Match`/`If-None-Match` preconditions of a write to `target`, so that
+/// concurrent editors get `412` instead of overwriting each other's changes.
+pub fn check_preconditions(req: &Request, target: &Path) -> Result<(), IronError> {
+    let etag = fs::metadata(target)
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| file_etag(&Metadata::from_fs(target, &metadata)));
+    let failed = match req.headers.get::<headers::IfMatch>() {
+        Some(headers::IfMatch::Any) => etag.is_none(),
+        Some(headers::IfMatch::Items(items)) => !etag
+            .as_ref()
+            .is_some_and(|etag| items.iter().any(|item| item.weak_eq(etag))),
+        None => false,
+    } || match req.headers.get::<headers::IfNoneMatch>() {
+        Some(headers::IfNoneMatch::Any) => etag.is_some(),
+        Some(headers::IfNoneMatch::Items(items)) => etag
+            .as_ref()
+            .is_some_and(|etag| items.iter().any(|item| item.weak_eq(etag))),
+        None => false,
+    };
+    if failed {
+        return Err(IronError::new(
+            StringError(format!("precondition failed: {}", target.display())),
+            status::PreconditionFailed,
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the `If-None-Match` (or without it `If-Modified-Since`) validators of a read
+/// still match the served file, to be answered `304 Not Modified`
+pub fn is_not_modified(req: &Request, etag: &headers::EntityTag, modified: SystemTime) -> bool {
+    match req.headers.get::<headers::IfNoneMatch>() {
+        Some(headers::IfNoneMatch::Any) => true,
+        Some(headers::IfNoneMatch::Items(items)) => items.iter().any(|item| item.weak_eq(etag)),
+        None => match req.headers.get::<headers::IfModifiedSince>() {
+            Some(headers::IfModifiedSince(headers::HttpDate(since))) => {
+                let modified = filetime::FileTime::from_system_time(modified).seconds();
+                modified <= since.to_timespec().sec
+            }
+            None => false,
+        },
+    }
+}
+
+// Check the file for changes every this many bytes read
+const STABLE_FILE_CHECK_INTERVAL: u64 = 1024 * 1024;
+
+/// File reader which fails when the file shrinks, is rewritten in place or is replaced
+/// during the transfer, instead of serving interleaved old/new bytes.
+///
+/// Holes of sparse files are detected by `SEEK_DATA/SEEK_HOLE` (on Linux) and filled
+/// with zeros without reading the disk.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct StableFile {
+    file: fs::File,
+    path: PathBuf,
+    len: u64,
+    modified: Option<SystemTime>,
+    regular: bool,
+    unchecked: u64,
+    pos: u64,
+    sparse: bool,
+    // `[pos, hole_end)` is a hole, `[pos, data_end)` is data
+    hole_end: u64,
+    data_end: u64,
+}
+
+impl StableFile {
+    pub fn open(path: &Path) -> io::Result<StableFile> {
+        let file = fs::File::open(path)?;
+        let metadata = file.metadata()?;
+        #[cfg(target_os = "linux")]
+        let sparse = {
+            use std::os::unix::fs::MetadataExt;
+            metadata.is_file() && metadata.blocks() * 512 < metadata.len()
+        };
+        #[cfg(not(target_os = "linux"))]
+        let sparse = false;
+        Ok(StableFile {
+            file,
+            path: path.to_owned(),
+            len: file_size(path, &metadata),
+            modified: metadata.modified().ok(),
+            regular: metadata.is_file(),
+            unchecked: 0,
+            pos: 0,
+            sparse,
+            hole_end: 0,
+            data_end: 0,
+        })
+    }
+
+    /// Hint the kernel that the file is read sequentially, doubling its read-ahead window
+    #[cfg(target_os = "linux")]
+    pub fn advise_sequential(&self) {
+        use std::os::unix::io::AsRawFd;
+
+        // Only a hint, failures are harmless
+        unsafe { libc::posix_fadvise(self.file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL) };
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn advise_sequential(&self) {}
+
+    fn changed(&self) -> io::Result<bool> {
+        if !self.regular {
+            return Ok(false);
+        }
+        let metadata = self.file.metadata()?;
+        if metadata.len() < self.len
+            || (metadata.len() == self.len && metadata.modified().ok() != self.modified)
+        {
+            return Ok(true);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            // Replaced by another file (eg: `mv new old`)
+            if let Ok(current) = fs::metadata(&self.path) {
+                if current.ino() != metadata.ino() || current.dev() != metadata.dev() {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// How many bytes of hole are left from the current position
+    #[cfg(target_os = "linux")]
+    fn hole_len(&mut self) -> io::Result<u64> {
+        use std::os::unix::io::AsRawFd;
+
+        if !self.sparse || self.pos < self.data_end {
+            return Ok(0);
+        }
+        if self.pos < self.hole_end {
+            return Ok(self.hole_end - self.pos);
+        }
+        let fd = self.file.as_raw_fd();
+        let pos = self.pos as libc::off_t;
+        let data = unsafe { libc::lseek(fd, pos, libc::SEEK_DATA) };
+        if data < 0 {
+            if io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) {
+                // No more data: hole until the end of file
+                self.hole_end = self.len;
+            } else {
+                self.sparse = false;
+            }
+        } else if data as u64 > self.pos {
+            self.hole_end = data as u64;
+        } else {
+            let hole = unsafe { libc::lseek(fd, pos, libc::SEEK_HOLE) };
+            self.data_end = if hole < 0 { self.len } else { hole as u64 };
+        }
+        // SEEK_DATA/SEEK_HOLE moved the file offset, move it back
+        self.file.seek(SeekFrom::Start(self.pos))?;
+        Ok(self.hole_end.saturating_sub(self.pos))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn hole_len(&mut self) -> io::Result<u64> {
+        Ok(0)
+    }
+}
+
+impl Read for StableFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let hole_len = self.hole_len()?;
+        let n = if hole_len > 0 {
+            let n = hole_len.min(buf.len() as u64) as usize;
+            buf[..n].iter_mut().for_each(|b| *b = 0);
+            self.file.seek(SeekFrom::Current(n as i64))?;
+            n
+        } else {
+            self.file.read(buf)?
+        };
+        self.pos += n as u64;
+        self.unchecked += n as u64;
+        if n == 0 || self.unchecked >= STABLE_FILE_CHECK_INTERVAL {
+            self.unchecked = 0;
+            if self.changed()? {
+                warn!(
+                    "File changed during transfer, response aborted: {}",
+                    self.path.display()
+                );
+                return Err(io::Error::other("file changed during transfer"));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Seek for StableFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.file.seek(pos)?;
+        self.hole_end = 0;
+        self.data_end = 0;
+        Ok(self.pos)
+    }
+}
+
 /* TODO: may not used
 
 use iron::headers::{Range, ByteRangeSpec};
@@ -144,6 +725,153 @@ pub fn system_time_to_date_time(t: SystemTime) -> DateTime<Local> {
     Local.timestamp_opt(sec, nsec).unwrap()
 }
 
+/// How long ago `t` was (eg: `3 min ago`), replaced by the browser's language with
+/// `RELATIVE_TIME_SCRIPT`
+pub fn relative_time(t: DateTime<Local>, now: DateTime<Local>) -> String {
+    let secs = (now - t).num_seconds();
+    let ago = |n: i64, unit: &str| {
+        if n == 1 {
+            format!("1 {} ago", unit)
+        } else {
+            format!("{} {}s ago", n, unit)
+        }
+    };
+    match secs {
+        i64::MIN..=-1 => "in the future".to_owned(),
+        0..=59 => "just now".to_owned(),
+        60..=3599 => format!("{} min ago", secs / 60),
+        3600..=86_399 => ago(secs / 3600, "hour"),
+        86_400..=2_591_999 => ago(secs / 86_400, "day"),
+        2_592_000..=31_535_999 => ago(secs / 2_592_000, "month"),
+        _ => ago(secs / 31_536_000, "year"),
+    }
+}
+
+/// Localize the `<time datetime="...">` relative dates of a listing
+pub const RELATIVE_TIME_SCRIPT: &str = r#"<script>
+(function () {
+  if (!window.Intl || !Intl.RelativeTimeFormat) return;
+  var format = new Intl.RelativeTimeFormat(navigator.language, { numeric: "auto" });
+  var units = [["year", 31536000], ["month", 2592000], ["day", 86400], ["hour", 3600], ["minute", 60], ["second", 1]];
+  var now = Date.now();
+  document.querySelectorAll("time[datetime]").forEach(function (el) {
+    var secs = (new Date(el.getAttribute("datetime")).getTime() - now) / 1000;
+    for (var i = 0; i < units.length; i++) {
+      if (Math.abs(secs) >= units[i][1] || i == units.length - 1) {
+        el.textContent = format.format(Math.round(secs / units[i][1]), units[i][0]);
+        break;
+      }
+    }
+  });
+})();
+</script>"#;
+
+/// Whether the client asked for JSON: `?json`, `?format=json` or an `Accept` header
+/// preferring `application/json` over HTML
+pub fn prefers_json(req: &Request) -> bool {
+    if let Some(query) = req.url.query() {
+        if query
+            .split('&')
+            .any(|pair| pair == "json" || pair.starts_with("json=") || pair == "format=json")
+        {
+            return true;
+        }
+    }
+    let accept = match req.headers.get::<headers::Accept>() {
+        Some(accept) => accept,
+        None => return false,
+    };
+    let quality = |sub_level: &str| {
+        accept
+            .iter()
+            .filter(|item| {
+                let Mime(ref top, ref sub, _) = item.item;
+                *top == TopLevel::Application && sub.as_str() == sub_level
+                    || *top == TopLevel::Text && sub.as_str() == sub_level
+            })
+            .map(|item| item.quality)
+            .max()
+    };
+    match (quality("json"), quality("html")) {
+        (Some(json), Some(html)) => json > html,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+/// Id of the request in error replies, the client's `X-Request-Id` when it sent a sane one
+pub fn request_id(req: &Request) -> String {
+    let id = req
+        .headers
+        .get_raw("X-Request-Id")
+        .and_then(|values| values.first())
+        .map(|value| String::from_utf8_lossy(value).to_string())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= 64
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        });
+    id.unwrap_or_else(|| random_alphanumeric(16))
+}
+
+pub fn error_json(s: status::Status, msg: &str, request_id: &str) -> Response {
+    let mut resp = Response::with((
+        s,
+        format!(
+            r#"{{"code":{},"message":"{}","request_id":"{}"}}"#,
+            s.to_u16(),
+            json_escape(msg),
+            request_id
+        ),
+    ));
+    resp.headers.set(headers::ContentType::json());
+    resp.headers
+        .set_raw("X-Request-Id", vec![request_id.as_bytes().to_vec()]);
+    resp
+}
+
+/// How much of an error is shown to clients (`--error-detail`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorDetail {
+    /// The underlying error, eg: the IO error with the path
+    Full,
+    /// Only the reason of the status code, nothing about paths or the file system leaks
+    Minimal,
+}
+
+impl FromStr for ErrorDetail {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ErrorDetail, String> {
+        match s {
+            "full" => Ok(ErrorDetail::Full),
+            "minimal" => Ok(ErrorDetail::Minimal),
+            _ => Err(format!("unknown error detail: {}", s)),
+        }
+    }
+}
+
+impl ErrorDetail {
+    /// Message of an error reply with status `s`
+    pub fn message(self, s: status::Status, msg: &str) -> String {
+        match self {
+            ErrorDetail::Full => msg.to_owned(),
+            ErrorDetail::Minimal => s.canonical_reason().unwrap_or("Error").to_owned(),
+        }
+    }
+}
+
+/// Error reply in the format the client prefers, see `prefers_json`
+pub fn error_reply(req: &Request, s: status::Status, msg: &str, baseurl: &str) -> Response {
+    if prefers_json(req) {
+        error_json(s, msg, &request_id(req))
+    } else {
+        error_resp(s, msg, baseurl)
+    }
+}
+
 pub fn error_resp(s: status::Status, msg: &str, baseurl: &str) -> Response {
     let mut resp = Response::with((
         s,
@@ -156,13 +884,15 @@ pub fn error_resp(s: status::Status, msg: &str, baseurl: &str) -> Response {
   <title>Simple HTTP(s) Server</title>
 </head>
 <body>
+  {brand}
   {root_link}
   <hr />
   <div>[<strong style=color:red;>ERROR {code}</strong>]: {msg}</div>
 </body>
 </html>
 "#,
-            favicon_image = FAVICON_IMAGE,
+            favicon_image = favicon_image(),
+            brand = brand_html(),
             root_link = root_link(baseurl),
             code = s.to_u16(),
             msg = msg
@@ -0,0 +1,537 @@
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use iron::headers::{Allow, ContentLength, HttpDate};
+use iron::method::Method;
+use iron::status;
+use iron::{IronError, IronResult, Request, Response};
+use mime_guess as mime_types;
+use percent_encoding::percent_decode;
+use tracing::{info, warn};
+
+use crate::dedupe::Dedupe;
+use crate::progress::UploadProgress;
+use crate::scan::{is_rejected, Scanner};
+use crate::storage::{Check, Metadata, Storage};
+use crate::transfer::{TransferKind, Transfers};
+use crate::util::{
+    check_preconditions, encode_link_path, error_io2iron, file_etag, StringError, WriteLocks,
+};
+
+/// `PROPFIND` bodies are not looked at, only read up to this size
+const MAX_PROPFIND_BODY: u64 = 64 * 1024;
+
+/// Whether `method` is handled by `WebDav` rather than served as a `GET`
+pub fn is_dav_method(method: &Method) -> bool {
+    match *method {
+        Method::Options | Method::Put | Method::Delete => true,
+        Method::Extension(ref name) => {
+            ["PROPFIND", "MKCOL", "COPY", "MOVE"].contains(&name.as_str())
+        }
+        _ => false,
+    }
+}
+
+/// Whether `method` changes the tree
+pub fn is_write(method: &Method) -> bool {
+    match *method {
+        Method::Put | Method::Delete => true,
+        Method::Extension(ref name) => ["MKCOL", "COPY", "MOVE"].contains(&name.as_str()),
+        _ => false,
+    }
+}
+
+fn error(msg: String, status: status::Status) -> IronError {
+    IronError::new(StringError(msg), status)
+}
+
+/// WebDAV class 1 (`--webdav`), so that the root can be mounted by file managers (Windows
+/// Explorer, macOS Finder, GNOME Files) and used with `cadaver` or `rclone`:
+///
+/// - `OPTIONS` advertises `DAV: 1` and the methods allowed.
+/// - `PROPFIND` with `Depth: 0` or `1` replies `207` with the live properties of the
+///   resource and its members: `displayname`, `resourcetype`, `getcontentlength`,
+///   `getcontenttype`, `getlastmodified` and `getetag`. The request body is not looked at,
+///   all of them are always returned. `Depth: infinity` (the default) is refused.
+/// - With `--upload`: `PUT` of a file, `MKCOL` of a directory, `COPY` and `MOVE` to the
+///   `Destination` (replaced unless `Overwrite: F`) as tracked `Transfers`, and `DELETE`,
+///   removing for good.
+///
+/// Locks (class 2) are not supported, clients needing them (Finder) mount read-only. Writes
+/// go through the same size limit, throttle, scanner and deduplication as the other uploads.
+pub struct WebDav {
+    storage: Arc<dyn Storage>,
+    root: PathBuf,
+    base_url: String,
+    writable: bool,
+    upload_size_limit: u64,
+    scanner: Option<Arc<Scanner>>,
+    dedupe: Option<Arc<Dedupe>>,
+    write_locks: Arc<WriteLocks>,
+    transfers: Arc<Transfers>,
+    progress: Arc<UploadProgress>,
+}
+
+impl WebDav {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        storage: Arc<dyn Storage>,
+        root: PathBuf,
+        base_url: &str,
+        writable: bool,
+        upload_size_limit: u64,
+        scanner: Option<Arc<Scanner>>,
+        dedupe: Option<Arc<Dedupe>>,
+        write_locks: Arc<WriteLocks>,
+        transfers: Arc<Transfers>,
+        progress: Arc<UploadProgress>,
+    ) -> WebDav {
+        WebDav {
+            storage,
+            root,
+            base_url: base_url.to_owned(),
+            writable,
+            upload_size_limit,
+            scanner,
+            dedupe,
+            write_locks,
+            transfers,
+            progress,
+        }
+    }
+
+    fn allowed(&self) -> Vec<Method> {
+        let mut methods = vec![
+            Method::Get,
+            Method::Head,
+            Method::Options,
+            Method::Extension("PROPFIND".to_owned()),
+        ];
+        if self.writable {
+            methods.extend(vec![
+                Method::Put,
+                Method::Delete,
+                Method::Extension("MKCOL".to_owned()),
+                Method::Extension("COPY".to_owned()),
+                Method::Extension("MOVE".to_owned()),
+            ]);
+        }
+        methods
+    }
+
+    /// A method of `is_dav_method` on `path`, relative to the root
+    pub fn handle(&self, req: &mut Request, path: &Path) -> IronResult<Response> {
+        if is_write(&req.method) && !self.writable {
+            let mut resp = Response::with((status::MethodNotAllowed, "Uploads are disabled."));
+            resp.headers.set(Allow(self.allowed()));
+            return Ok(resp);
+        }
+        match req.method {
+            Method::Options => {
+                let mut resp = Response::with(status::Ok);
+                resp.headers.set_raw("DAV", vec![b"1".to_vec()]);
+                // Office asks for it before saving over WebDAV
+                resp.headers.set_raw("MS-Author-Via", vec![b"DAV".to_vec()]);
+                resp.headers.set(Allow(self.allowed()));
+                Ok(resp)
+            }
+            Method::Put => self.put(req, path),
+            Method::Delete => self.delete(path),
+            Method::Extension(ref name) if name == "PROPFIND" => self.propfind(req, path),
+            Method::Extension(ref name) if name == "MKCOL" => self.mkcol(req, path),
+            Method::Extension(ref name) if name == "COPY" => self.copy_or_move(req, path, false),
+            Method::Extension(ref name) if name == "MOVE" => self.copy_or_move(req, path, true),
+            _ => Ok(Response::with(status::MethodNotAllowed)),
+        }
+    }
+
+    fn propfind(&self, req: &mut Request, path: &Path) -> IronResult<Response> {
+        let depth = match header(req, "Depth").as_deref() {
+            Some("0") => 0,
+            Some("1") => 1,
+            Some("infinity") | None => {
+                let mut resp = Response::with((
+                    status::Forbidden,
+                    r#"<?xml version="1.0" encoding="utf-8"?>
+<D:error xmlns:D="DAV:"><D:propfind-finite-depth/></D:error>
+"#,
+                ));
+                resp.headers.set_raw(
+                    "content-type",
+                    vec![b"application/xml; charset=utf-8".to_vec()],
+                );
+                return Ok(resp);
+            }
+            Some(depth) => {
+                return Err(error(
+                    format!("invalid depth: {}", depth),
+                    status::BadRequest,
+                ))
+            }
+        };
+        io::copy(
+            &mut (&mut req.body).take(MAX_PROPFIND_BODY),
+            &mut io::sink(),
+        )
+        .map_err(error_io2iron)?;
+
+        let metadata = self.storage.stat(path).map_err(error_io2iron)?;
+        let segments = segments(path);
+        let mut body = String::from(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+"#,
+        );
+        body.push_str(&self.response(&segments, &metadata));
+        if metadata.is_dir && depth == 1 {
+            let mut entries = self.storage.list(path).map_err(error_io2iron)?;
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+            for entry in entries {
+                let mut member = segments.clone();
+                member.push(entry.name);
+                body.push_str(&self.response(&member, &entry.metadata));
+            }
+        }
+        body.push_str("</D:multistatus>\n");
+
+        let mut resp = Response::with((status::MultiStatus, body));
+        resp.headers.set_raw(
+            "content-type",
+            vec![b"application/xml; charset=utf-8".to_vec()],
+        );
+        Ok(resp)
+    }
+
+    /// `<D:response>` of the resource at `segments` for a multistatus
+    fn response(&self, segments: &[String], metadata: &Metadata) -> String {
+        let mut link = segments.to_owned();
+        if metadata.is_dir && !link.is_empty() {
+            link.push(String::new());
+        }
+        let href = format!("{}{}", self.base_url, encode_link_path(&link));
+        let modified = metadata
+            .modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let modified = HttpDate(time::at_utc(time::Timespec::new(
+            modified.as_secs() as i64,
+            0,
+        )));
+        let mut props = format!(
+            "<D:displayname>{}</D:displayname>\n",
+            htmlescape::encode_minimal(segments.last().map(String::as_str).unwrap_or(""))
+        );
+        if metadata.is_dir {
+            props.push_str("<D:resourcetype><D:collection/></D:resourcetype>\n");
+        } else {
+            let mime = segments
+                .last()
+                .and_then(|name| mime_types::from_path(name).first())
+                .map(|mime| mime.to_string())
+                .unwrap_or_else(|| "application/octet-stream".to_owned());
+            props.push_str(&format!(
+                "<D:resourcetype/>\n<D:getcontentlength>{}</D:getcontentlength>\n<D:getcontenttype>{}</D:getcontenttype>\n<D:getetag>{}</D:getetag>\n",
+                metadata.len,
+                htmlescape::encode_minimal(&mime),
+                htmlescape::encode_minimal(&file_etag(metadata).to_string()),
+            ));
+        }
+        props.push_str(&format!(
+            "<D:getlastmodified>{}</D:getlastmodified>\n",
+            modified
+        ));
+        format!(
+            "<D:response>\n<D:href>{}</D:href>\n<D:propstat>\n<D:prop>\n{}</D:prop>\n<D:status>HTTP/1.1 200 OK</D:status>\n</D:propstat>\n</D:response>\n",
+            htmlescape::encode_minimal(&href),
+            props
+        )
+    }
+
+    fn put(&self, req: &mut Request, path: &Path) -> IronResult<Response> {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => {
+                return Err(error(
+                    "can not put the root".to_owned(),
+                    status::MethodNotAllowed,
+                ))
+            }
+        };
+        self.check_parent(path)?;
+        let _lock = self.write_locks.lock(&self.root.join(path))?;
+        let existed = match self.storage.stat(path) {
+            Ok(metadata) if metadata.is_dir => {
+                return Err(error(
+                    format!("{} is a directory", path.display()),
+                    status::MethodNotAllowed,
+                ))
+            }
+            Ok(_) => true,
+            Err(_) => false,
+        };
+        if let Some(local_path) = self.storage.local_path(path) {
+            check_preconditions(req, &local_path)?;
+        }
+
+        let mut data = SizeLimit {
+            inner: self
+                .progress
+                .track(&req.headers, &mut req.body)
+                .take(self.upload_size_limit + 1),
+            size: 0,
+            size_limit: self.upload_size_limit,
+        };
+        let scan = |tmp: &Path| match self.scanner {
+            Some(ref scanner) => scanner.scan(tmp, &name),
+            None => Ok(()),
+        };
+        let check = self.scanner.as_ref().map(|_| &scan as Check);
+        match self.storage.write(path, &mut data, check) {
+            Ok(size) => {
+                info!("File saved: {} ({} bytes)", path.display(), size);
+                if let (Some(dedupe), Some(local_path)) =
+                    (&self.dedupe, self.storage.local_path(path))
+                {
+                    dedupe.link(&local_path);
+                }
+                Ok(Response::with(if existed {
+                    status::NoContent
+                } else {
+                    status::Created
+                }))
+            }
+            Err(ref err) if is_rejected(err) => {
+                warn!("Upload rejected: {}, {}", name, err);
+                Err(error(
+                    format!("{} {}", name, err),
+                    status::UnprocessableEntity,
+                ))
+            }
+            Err(err) => Err(write_error(err)),
+        }
+    }
+
+    fn delete(&self, path: &Path) -> IronResult<Response> {
+        if path.components().next().is_none() {
+            return Err(error(
+                "can not delete the root".to_owned(),
+                status::Forbidden,
+            ));
+        }
+        let _lock = self.write_locks.lock(&self.root.join(path))?;
+        self.storage.delete(path).map_err(error_io2iron)?;
+        info!("Deleted: {}", path.display());
+        Ok(Response::with(status::NoContent))
+    }
+
+    fn mkcol(&self, req: &mut Request, path: &Path) -> IronResult<Response> {
+        if req
+            .headers
+            .get::<ContentLength>()
+            .is_some_and(|length| length.0 > 0)
+        {
+            return Err(error(
+                "MKCOL with a body".to_owned(),
+                status::UnsupportedMediaType,
+            ));
+        }
+        if self.storage.stat(path).is_ok() {
+            return Err(error(
+                format!("{} already exists", path.display()),
+                status::MethodNotAllowed,
+            ));
+        }
+        self.check_parent(path)?;
+        let _lock = self.write_locks.lock(&self.root.join(path))?;
+        match self.storage.create_dir(path) {
+            Ok(()) => {
+                info!("Directory created: {}", path.display());
+                Ok(Response::with(status::Created))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => Err(error(
+                format!("{} already exists", path.display()),
+                status::MethodNotAllowed,
+            )),
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+                Err(IronError::new(err, status::Forbidden))
+            }
+            Err(err) => Err(write_error(err)),
+        }
+    }
+
+    fn copy_or_move(&self, req: &mut Request, path: &Path, is_move: bool) -> IronResult<Response> {
+        let recursive = match header(req, "Depth").as_deref() {
+            Some("infinity") | None => true,
+            Some("0") if !is_move => false,
+            Some(depth) => {
+                return Err(error(
+                    format!("invalid depth: {}", depth),
+                    status::BadRequest,
+                ))
+            }
+        };
+        let overwrite = match header(req, "Overwrite").as_deref() {
+            Some("T") | None => true,
+            Some("F") => false,
+            Some(overwrite) => {
+                return Err(error(
+                    format!("invalid overwrite: {}", overwrite),
+                    status::BadRequest,
+                ))
+            }
+        };
+        let destination = match header(req, "Destination") {
+            Some(destination) => self.destination(&destination)?,
+            None => return Err(error("no destination".to_owned(), status::BadRequest)),
+        };
+        if path.components().next().is_none() || destination.starts_with(path) {
+            return Err(error(
+                format!(
+                    "can not copy or move {} to {}",
+                    path.display(),
+                    destination.display()
+                ),
+                status::Forbidden,
+            ));
+        }
+        let metadata = self.storage.stat(path).map_err(error_io2iron)?;
+        self.check_parent(&destination)?;
+
+        let _lock = self.write_locks.lock(&self.root.join(path))?;
+        let _destination_lock = self.write_locks.lock(&self.root.join(&destination))?;
+        let existed = self.storage.stat(&destination).is_ok();
+        if existed && !overwrite {
+            return Err(error(
+                format!("{} already exists", destination.display()),
+                status::PreconditionFailed,
+            ));
+        }
+        let kind = if is_move {
+            TransferKind::Move
+        } else {
+            TransferKind::Copy
+        };
+        let transfer = self
+            .transfers
+            .register(kind, path, &destination, &req.headers);
+        match self.transfers.run(&transfer, &metadata, recursive) {
+            Ok(()) => {}
+            Err(err) if transfer.is_cancelled() => {
+                return Err(IronError::new(err, status::Conflict))
+            }
+            Err(err) => return Err(write_error(err)),
+        }
+        Ok(Response::with(if existed {
+            status::NoContent
+        } else {
+            status::Created
+        }))
+    }
+
+    /// Root relative path of the `Destination` header, a URL or an absolute path
+    fn destination(&self, destination: &str) -> IronResult<PathBuf> {
+        let url_path = match iron::url::Url::parse(destination) {
+            Ok(url) => url.path().to_owned(),
+            Err(_) => destination
+                .split(['?', '#'])
+                .next()
+                .unwrap_or("")
+                .to_owned(),
+        };
+        let invalid = || {
+            error(
+                format!("invalid destination: {}", destination),
+                status::BadRequest,
+            )
+        };
+        let relative = url_path
+            .strip_prefix(&self.base_url)
+            .or_else(|| {
+                // The base URL of a collection, without its trailing slash
+                (url_path == self.base_url.trim_end_matches('/')).then_some("")
+            })
+            .ok_or_else(invalid)?;
+        let mut path = PathBuf::new();
+        for segment in relative.split('/').filter(|s| !s.is_empty()) {
+            let segment = percent_decode(segment.as_bytes())
+                .decode_utf8()
+                .map_err(|_err| invalid())?;
+            if segment == "." || segment == ".." || segment.contains(['/', '\\']) {
+                return Err(invalid());
+            }
+            path.push(&*segment);
+        }
+        // Served by the special handlers instead
+        if path.starts_with("-") {
+            return Err(error(
+                format!("can not write {}", destination),
+                status::Forbidden,
+            ));
+        }
+        Ok(path)
+    }
+
+    /// `409` unless the directory of `path` exists
+    fn check_parent(&self, path: &Path) -> IronResult<()> {
+        let parent = path.parent().unwrap_or(Path::new(""));
+        if self
+            .storage
+            .stat(parent)
+            .is_ok_and(|metadata| metadata.is_dir)
+        {
+            Ok(())
+        } else {
+            Err(error(
+                format!("{} does not exist", parent.display()),
+                status::Conflict,
+            ))
+        }
+    }
+}
+
+fn header(req: &Request, name: &str) -> Option<String> {
+    req.headers
+        .get_raw(name)
+        .and_then(|values| values.first())
+        .map(|value| String::from_utf8_lossy(value).trim().to_owned())
+}
+
+fn segments(path: &Path) -> Vec<String> {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect()
+}
+
+fn write_error(err: io::Error) -> IronError {
+    let status = match err.kind() {
+        io::ErrorKind::FileTooLarge => status::PayloadTooLarge,
+        io::ErrorKind::StorageFull => status::InsufficientStorage,
+        io::ErrorKind::NotFound => status::Conflict,
+        io::ErrorKind::PermissionDenied => status::Forbidden,
+        _ => status::InternalServerError,
+    };
+    IronError::new(err, status)
+}
+
+/// Fails reads past `size_limit` bytes in total
+struct SizeLimit<R> {
+    inner: R,
+    size: u64,
+    size_limit: u64,
+}
+
+impl<R: Read> Read for SizeLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.size += n as u64;
+        if self.size > self.size_limit {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                "file size exceeds upload size limit",
+            ));
+        }
+        Ok(n)
+    }
+}